@@ -228,6 +228,20 @@ impl Client {
         )
     }
 
+    /// Forwards a plugin's answer to a custom command sent through
+    /// `plugin_rpc`.
+    pub fn command_response(&self, view_id: ViewId, plugin: &str, method: &str, result: &Value) {
+        self.0.send_rpc_notification(
+            "command_response",
+            &json!({
+                "view_id": view_id,
+                "plugin": plugin,
+                "method": method,
+                "result": result,
+            }),
+        )
+    }
+
     pub fn schedule_idle(&self, token: usize) {
         self.0.schedule_idle(token)
     }
@@ -31,7 +31,7 @@ use crate::plugins::rpc::{
 };
 use crate::rpc::{EditNotification, EditRequest, LineRange, Position as ClientPosition};
 
-use crate::config::{BufferItems, Table};
+use crate::config::{BufferConfig, BufferItems, Table};
 use crate::styles::ThemeStyleMap;
 
 use crate::client::Client;
@@ -65,7 +65,7 @@ pub struct EventContext<'a> {
     pub(crate) buffer_id: BufferId,
     pub(crate) editor: &'a RefCell<Editor>,
     pub(crate) info: Option<&'a FileInfo>,
-    pub(crate) config: &'a BufferItems,
+    pub(crate) config: &'a BufferConfig,
     pub(crate) recorder: &'a RefCell<Recorder>,
     pub(crate) language: LanguageId,
     pub(crate) view: &'a RefCell<View>,
@@ -88,7 +88,7 @@ impl<'a> EventContext<'a> {
         let mut editor = self.editor.borrow_mut();
         let mut view = self.view.borrow_mut();
         let mut kill_ring = self.kill_ring.borrow_mut();
-        f(&mut editor, &mut view, &mut kill_ring, &self.config)
+        f(&mut editor, &mut view, &mut kill_ring, &self.config.items)
     }
 
     /// Executes a closure with a mutable reference to the view and a reference
@@ -152,7 +152,7 @@ impl<'a> EventContext<'a> {
         match cmd {
             SpecialEvent::Resize(size) => {
                 self.with_view(|view, _| view.set_size(size));
-                if self.config.word_wrap {
+                if self.config.items.word_wrap {
                     self.update_wrap_settings(false);
                 }
             }
@@ -242,6 +242,12 @@ impl<'a> EventContext<'a> {
             }
             RemoveStatusItem { key } => self.client.remove_status_item(self.view_id, &key),
             ShowHover { request_id, result } => self.do_show_hover(request_id, result),
+            CommandResponse { method, result } => {
+                // the plugin may have been stopped since it sent this
+                if let Some(plug) = self.plugins.iter().find(|p| p.id == plugin) {
+                    self.client.command_response(self.view_id, &plug.name, &method, &result);
+                }
+            }
         };
         self.after_edit(&plugin.to_string());
         self.render_if_needed();
@@ -452,7 +458,8 @@ impl<'a> EventContext<'a> {
             .map(|v| v.borrow().get_view_id())
             .collect();
 
-        let changes = serde_json::to_value(self.config).unwrap();
+        // the whole table, so that plugins get their own sections
+        let changes = self.config.to_table();
         let path = self.info.map(|info| info.path.to_owned());
         PluginBufferInfo::new(
             self.buffer_id,
@@ -462,7 +469,7 @@ impl<'a> EventContext<'a> {
             nb_lines,
             path,
             self.language.clone(),
-            changes.as_object().unwrap().to_owned(),
+            changes,
         )
     }
 
@@ -499,8 +506,8 @@ impl<'a> EventContext<'a> {
     /// except in the resize case; during live resize we want to delay recalculation
     /// to avoid unnecessary work.
     fn update_wrap_settings(&mut self, rewrap_immediately: bool) {
-        let wrap_width = self.config.wrap_width;
-        let word_wrap = self.config.word_wrap;
+        let wrap_width = self.config.items.wrap_width;
+        let word_wrap = self.config.items.word_wrap;
         self.with_view(|view, text| view.update_wrap_settings(text, wrap_width, word_wrap));
         if rewrap_immediately {
             self.rewrap();
@@ -699,7 +706,7 @@ mod tests {
                 buffer_id,
                 view: &self.view,
                 editor: &self.editor,
-                config: &config.items,
+                config,
                 language,
                 info: None,
                 siblings: Vec::new(),
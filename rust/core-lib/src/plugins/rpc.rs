@@ -187,6 +187,7 @@ pub enum PluginNotification {
     UpdateStatusItem { key: String, value: String },
     RemoveStatusItem { key: String },
     ShowHover { request_id: usize, result: Result<Hover, RemoteError> },
+    CommandResponse { method: String, result: Value },
 }
 
 /// Range expressed in terms of PluginPosition. Meant to be sent from
@@ -374,4 +375,18 @@ mod tests {
             _ => panic!("{:?}", de.cmd),
         }
     }
+
+    #[test]
+    fn test_de_command_response() {
+        let json = r#"{"method": "command_response", "params": {"view_id": "view-id-1",
+            "plugin_id": 42, "method": "custom_method", "result": {"foo": "bar"}}}"#;
+        let de: PluginCommand<PluginNotification> = serde_json::from_str(json).unwrap();
+        match de.cmd {
+            PluginNotification::CommandResponse { ref method, ref result } => {
+                assert_eq!(method, "custom_method");
+                assert_eq!(result, &json!({"foo": "bar"}));
+            }
+            _ => panic!("{:?}", de.cmd),
+        }
+    }
 }
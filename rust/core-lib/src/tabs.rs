@@ -276,7 +276,7 @@ impl CoreState {
                 buffer_id,
                 view,
                 editor,
-                config: &config.items,
+                config,
                 recorder: &self.recorder,
                 language,
                 info,
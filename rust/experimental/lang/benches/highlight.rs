@@ -0,0 +1,58 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Highlighting throughput, per language: a whole fixture highlighted
+//! from scratch, as when a document is opened.
+
+#![feature(test)]
+
+/// Run as:
+/// ```
+/// run nightly cargo bench --bench highlight --features tools
+/// ```
+extern crate test;
+extern crate xi_lang;
+
+use test::Bencher;
+use xi_lang::bench::{highlight, Fixture};
+
+/// A copy of `core-lib/src/event_context.rs`, kept here so that the
+/// numbers stay comparable as the original changes.
+const EVENT_CONTEXT: &str = include_str!("fixtures/event_context.rs");
+/// The same file as Markdown, its doc comments as prose and its code in
+/// indented blocks.
+const EVENT_CONTEXT_MD: &str = include_str!("fixtures/event_context.md");
+
+fn bench_fixture(b: &mut Bencher, language: &str, path: &str, text: &str) {
+    let fixture = Fixture::new(language, path, text);
+    b.bytes = text.len() as u64;
+    b.iter(|| highlight(&fixture).unwrap());
+}
+
+#[bench]
+fn highlight_rust(b: &mut Bencher) {
+    bench_fixture(b, "Rust", "event_context.rs", EVENT_CONTEXT);
+}
+
+#[bench]
+fn highlight_rust_indented(b: &mut Bencher) {
+    bench_fixture(b, "Rust (Indented)", "event_context.md", EVENT_CONTEXT_MD);
+}
+
+/// A single long line.
+#[cfg(feature = "tree-sitter")]
+#[bench]
+fn highlight_minified_json(b: &mut Bencher) {
+    bench_fixture(b, "JSON", "minified.json", include_str!("fixtures/minified.json"));
+}
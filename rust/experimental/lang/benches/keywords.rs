@@ -0,0 +1,80 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of keyword lookup.
+
+#![feature(test)]
+
+/// Run as:
+/// ```
+/// run nightly cargo bench --bench keywords
+/// ```
+extern crate test;
+extern crate xi_lang;
+
+use test::Bencher;
+use xi_lang::peg::KeywordSet;
+
+const KEYWORDS: &[&str] = &[
+    "Self", "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn",
+    "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use",
+    "where", "while",
+];
+
+const PRIM_TYPES: &[&str] =
+    &["bool", "char", "f32", "f64", "i32", "i64", "isize", "str", "u32", "u64", "u8", "usize"];
+
+/// Identifier-heavy code, with near misses of keywords.
+const TEXT: &str = "pub fn highlight_one_line(view_state: &mut ViewState, structs: usize) \
+                    -> Option<u32> { let iffy = selfish + inline; for item in items.iter() \
+                    { if item.matches(letter) { return Some(item.len() as u32) } } typed }";
+
+fn words() -> Vec<&'static [u8]> {
+    TEXT.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(str::as_bytes)
+        .collect()
+}
+
+/// The previous matcher: a binary search of each table in turn.
+#[bench]
+fn lookup_sorted_tables(b: &mut Bencher) {
+    let keywords: Vec<&[u8]> = KEYWORDS.iter().map(|word| word.as_bytes()).collect();
+    let prim_types: Vec<&[u8]> = PRIM_TYPES.iter().map(|word| word.as_bytes()).collect();
+    let words = words();
+    b.iter(|| {
+        for word in &words {
+            let class = if keywords.binary_search(word).is_ok() {
+                Some(1)
+            } else if prim_types.binary_search(word).is_ok() {
+                Some(2)
+            } else {
+                None
+            };
+            test::black_box(class);
+        }
+    })
+}
+
+#[bench]
+fn lookup_keyword_set(b: &mut Bencher) {
+    let set = KeywordSet::new(&[(KEYWORDS, 1), (PRIM_TYPES, 2)]);
+    let words = words();
+    b.iter(|| {
+        for word in &words {
+            test::black_box(set.get(word));
+        }
+    })
+}
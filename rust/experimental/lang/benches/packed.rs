@@ -0,0 +1,75 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of packing spans and unpacking them again.
+
+#![feature(test)]
+
+/// Run as:
+/// ```
+/// run nightly cargo bench --bench packed
+/// ```
+extern crate test;
+extern crate xi_core_lib;
+extern crate xi_lang;
+
+use std::mem;
+
+use test::Bencher;
+use xi_core_lib::plugins::rpc::ScopeSpan;
+use xi_lang::packed::PackedSpans;
+
+/// Spans as dense as code gets: a token every few bytes, some of them
+/// next to one with the same scope.
+fn spans() -> Vec<ScopeSpan> {
+    let mut spans = Vec::new();
+    let mut end = 0;
+    for i in 0..10_000 {
+        let start = end;
+        end = start + 1 + i % 7;
+        spans.push(ScopeSpan { start, end, scope_id: (i % 5 / 2) as u32 });
+    }
+    spans
+}
+
+#[bench]
+fn pack(b: &mut Bencher) {
+    let spans = spans();
+    let mut packed = PackedSpans::new();
+    b.bytes = (spans.len() * mem::size_of::<ScopeSpan>()) as u64;
+    b.iter(|| {
+        packed.repack(test::black_box(&spans), 0).unwrap();
+        test::black_box(&packed);
+    })
+}
+
+#[bench]
+fn unpack(b: &mut Bencher) {
+    let spans = spans();
+    let packed = PackedSpans::pack(&spans, 0).unwrap();
+    b.bytes = (spans.len() * mem::size_of::<ScopeSpan>()) as u64;
+    b.iter(|| test::black_box(packed.unpack(0)))
+}
+
+/// Packing and unpacking again, as spans kept packed are when sent.
+#[bench]
+fn pack_and_unpack(b: &mut Bencher) {
+    let spans = spans();
+    let mut packed = PackedSpans::new();
+    b.bytes = (spans.len() * mem::size_of::<ScopeSpan>()) as u64;
+    b.iter(|| {
+        packed.repack(test::black_box(&spans), 0).unwrap();
+        test::black_box(packed.unpack(0))
+    })
+}
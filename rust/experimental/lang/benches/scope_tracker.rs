@@ -0,0 +1,66 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of scope id lookup.
+
+#![feature(test)]
+
+/// Run as:
+/// ```
+/// run nightly cargo bench --bench scope_tracker
+/// ```
+extern crate test;
+extern crate xi_lang;
+
+use test::Bencher;
+use xi_lang::scope_tracker::ScopeTracker;
+
+const SCOPES: &[&[&str]] = &[
+    &["source.rust"],
+    &["source.rust", "string.quoted.double.rust"],
+    &["source.rust", "comment.line.double-slash.rust"],
+    &["source.rust", "keyword.operator.rust"],
+    &["source.rust", "entity.name.type.rust"],
+];
+
+fn tracker() -> ScopeTracker {
+    let mut tracker = ScopeTracker::new();
+    for scope in SCOPES {
+        tracker.lookup(scope);
+    }
+    tracker
+}
+
+/// The common case: every scope is already known, and is borrowed.
+#[bench]
+fn lookup_existing_borrowed(b: &mut Bencher) {
+    let mut tracker = tracker();
+    b.iter(|| {
+        for scope in SCOPES {
+            test::black_box(tracker.lookup(scope));
+        }
+    })
+}
+
+/// The previous calling convention, which built an owned scope per token.
+#[bench]
+fn lookup_existing_owned(b: &mut Bencher) {
+    let mut tracker = tracker();
+    b.iter(|| {
+        for scope in SCOPES {
+            let owned: Vec<String> = scope.iter().map(|s| s.to_string()).collect();
+            test::black_box(tracker.lookup(&owned));
+        }
+    })
+}
@@ -0,0 +1,3 @@
+fn f() {
+
+    "no newline at the end
\ No newline at end of file
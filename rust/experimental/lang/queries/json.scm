@@ -0,0 +1,10 @@
+; JSON highlights for the tree-sitter backend. Capture names are the ids
+; of the scope table for "json" in src/scope_map.rs; where patterns
+; capture the same node, the first wins.
+
+(pair key: (string) @key)
+(string) @string
+(escape_sequence) @escape
+(number) @number
+[(true) (false) (null)] @constant
+(comment) @comment
@@ -0,0 +1,72 @@
+; Rust highlights for the tree-sitter backend. Capture names are the ids
+; of the scope table for "rust" in src/scope_map.rs; where patterns
+; capture the same node, the first wins.
+
+(line_comment) @comment
+(block_comment) @comment
+
+(escape_sequence) @escape
+(string_literal) @string.double
+(raw_string_literal) @string.double
+(char_literal) @string.single
+
+(integer_literal) @number
+(float_literal) @number
+
+(primitive_type) @primitive-type
+
+[
+  "as"
+  "async"
+  "await"
+  "break"
+  "const"
+  "continue"
+  "default"
+  "dyn"
+  "else"
+  "enum"
+  "extern"
+  "fn"
+  "for"
+  "if"
+  "impl"
+  "in"
+  "let"
+  "loop"
+  "macro_rules!"
+  "match"
+  "mod"
+  "move"
+  "pub"
+  "ref"
+  "return"
+  "static"
+  "struct"
+  "trait"
+  "type"
+  "union"
+  "unsafe"
+  "use"
+  "where"
+  "while"
+] @keyword
+(crate) @keyword
+(super) @keyword
+(self) @keyword
+(mutable_specifier) @keyword
+
+[
+  "+"
+  "-"
+  "*"
+  "/"
+  "%"
+  "!"
+  "&&"
+  "||"
+  "=="
+  "!="
+  "<="
+  ">="
+] @operator
@@ -0,0 +1,247 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lexes a large document on a worker thread when it is opened, so that
+//! the main loop isn't kept busy for many idle slices. The worker lexes a
+//! snapshot of the text with a parser of its own, and the main loop takes
+//! in the lines it sends back, unless an edit has touched them since.
+
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+
+use xi_core_lib::plugins::rpc::ScopeSpan;
+use xi_rope::{Interval, RopeDelta};
+
+use crate::lexed::hash_line;
+use crate::outline::line_indent;
+use crate::parser::{LineFolding, Parser, Symbol, Tokens};
+use crate::scope_tracker::Scope;
+use crate::statestack::{State, StateMap};
+use crate::text::lines_with_endings;
+use crate::{ScopeId, STUCK_SCOPE};
+
+/// How many batches the worker may get ahead of the main loop.
+const QUEUE_LEN: usize = 4;
+
+/// A line of the snapshot, as the worker lexed it.
+pub struct Line {
+    /// Where the line is in the snapshot.
+    pub iv: Interval,
+    /// A hash of its text, as the lexed lines keep.
+    pub hash: u64,
+    pub entry: State,
+    pub exit: State,
+    /// What the parser said about the line, if it says anything at all.
+    pub folding: LineFolding,
+    pub symbol: Option<Symbol>,
+    pub indent: Option<usize>,
+    /// Its spans, in the batch's.
+    pub spans: Range<usize>,
+}
+
+/// Lines the worker lexed, in order.
+#[derive(Default)]
+pub struct Batch {
+    /// The scopes first used in this batch, which take the worker's next
+    /// scope ids in order.
+    pub scopes: Vec<Scope>,
+    pub lines: Vec<Line>,
+    /// The spans of the lines, each relative to the start of its line and
+    /// with the worker's scope ids.
+    pub spans: Vec<ScopeSpan>,
+    /// Bytes skipped because the parser made no progress on them.
+    pub stuck_bytes: usize,
+}
+
+pub enum Message {
+    Lines(Batch),
+    /// The worker is done, and hands back the parser the states of its
+    /// lines belong to.
+    Done(Box<dyn Parser>),
+}
+
+/// A worker lexing a snapshot of the document, and the edits made to the
+/// document since the snapshot was taken.
+pub struct BackgroundPass {
+    receiver: Receiver<Message>,
+    /// The part of the document each edit replaced, and the length of the
+    /// text it put there, in order.
+    edits: Vec<(Interval, usize)>,
+    /// Whether an edit was too large to describe, so that nothing in the
+    /// snapshot can be placed in the document any more.
+    lost: bool,
+    /// The tracker's id for each of the worker's scope ids.
+    pub scope_ids: Vec<ScopeId>,
+}
+
+impl BackgroundPass {
+    /// Starts lexing `text` with `parser` on a new thread, sending back
+    /// `batch_lines` lines at a time.
+    pub fn start(
+        text: String,
+        parser: Box<dyn Parser>,
+        batch_lines: usize,
+        tab_size: usize,
+    ) -> io::Result<BackgroundPass> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        thread::Builder::new()
+            .name("xi-lang background".to_owned())
+            .spawn(move || lex(&text, parser, batch_lines.max(1), tab_size, &sender))?;
+        Ok(BackgroundPass { receiver, edits: Vec::new(), lost: false, scope_ids: Vec::new() })
+    }
+
+    /// The next message from the worker, if it has sent one. The worker is
+    /// only disconnected without being done if its parser panicked.
+    pub fn try_recv(&self) -> Result<Message, TryRecvError> {
+        self.receiver.try_recv()
+    }
+
+    /// Notes an edit to the document; `None` is one too large to describe.
+    pub fn note_edit(&mut self, delta: Option<&RopeDelta>) {
+        match delta {
+            Some(delta) => self.edits.push(delta.summary()),
+            None => self.lost = true,
+        }
+    }
+
+    /// Where the snapshot's text at `iv` is now, if no edit has touched it
+    /// since. Text inserted right before or after it doesn't touch it,
+    /// though it may make it part of a longer line.
+    pub fn current(&self, iv: Interval) -> Option<Interval> {
+        if self.lost {
+            return None;
+        }
+        let (mut start, mut end) = (iv.start(), iv.end());
+        for &(edit, new_len) in &self.edits {
+            if end <= edit.start() {
+                continue;
+            }
+            if start < edit.end() {
+                return None;
+            }
+            start = start + new_len - edit.size();
+            end = end + new_len - edit.size();
+        }
+        Some(Interval::new(start, end))
+    }
+}
+
+/// Lexes `text` a line at a time, sending the lines back `batch_lines`
+/// at a time and then the parser. Gives up as soon as no one is listening.
+fn lex(
+    text: &str,
+    mut parser: Box<dyn Parser>,
+    batch_lines: usize,
+    tab_size: usize,
+    sender: &SyncSender<Message>,
+) {
+    let capabilities = parser.capabilities();
+    let outlined = capabilities.supports_folding || capabilities.reports_symbols;
+    let mut scope_ids = StateMap::new();
+    let mut stuck_ids = StateMap::new();
+    let mut next_id = 0;
+    let mut state = parser.initial_state();
+    let mut offset = 0;
+    let mut batch = Batch::default();
+    for line in lines_with_endings(text) {
+        let (entry, first) = (state, batch.spans.len());
+        let mut tokens = Tokens::new(0, state, std::usize::MAX);
+        while let Some(token) = tokens.next(&mut *parser, line) {
+            let ids = if token.stuck { &mut stuck_ids } else { &mut scope_ids };
+            let scope_id = match ids.get(token.state) {
+                Some(id) => id,
+                None => {
+                    let mut scope = parser.get_scope_for_state(token.state);
+                    if token.stuck {
+                        scope.push(STUCK_SCOPE.to_owned());
+                    }
+                    batch.scopes.push(scope);
+                    ids.insert(token.state, next_id);
+                    next_id += 1;
+                    next_id - 1
+                }
+            };
+            if token.stuck {
+                batch.stuck_bytes += token.end - token.start;
+            }
+            batch.spans.push(ScopeSpan { start: token.start, end: token.end, scope_id });
+        }
+        state = tokens.end().1;
+
+        let folding = if capabilities.supports_folding {
+            parser.line_folding(line, entry)
+        } else {
+            LineFolding::default()
+        };
+        let symbol =
+            if capabilities.reports_symbols { parser.line_symbol(line, entry) } else { None };
+        batch.lines.push(Line {
+            iv: Interval::new(offset, offset + line.len()),
+            hash: hash_line(line),
+            entry,
+            exit: state,
+            folding,
+            symbol,
+            indent: if outlined { line_indent(line, tab_size) } else { None },
+            spans: first..batch.spans.len(),
+        });
+        offset += line.len();
+
+        if batch.lines.len() >= batch_lines
+            && sender.send(Message::Lines(mem::replace(&mut batch, Batch::default()))).is_err()
+        {
+            return;
+        }
+    }
+    if !batch.lines.is_empty() && sender.send(Message::Lines(batch)).is_err() {
+        return;
+    }
+    let _ = sender.send(Message::Done(parser));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::Rope;
+
+    #[test]
+    fn only_untouched_text_is_placed() {
+        let (tx, receiver) = mpsc::sync_channel(1);
+        drop(tx);
+        let mut pass =
+            BackgroundPass { receiver, edits: Vec::new(), lost: false, scope_ids: Vec::new() };
+        let line = |start, end| Interval::new(start, end);
+        let edit = |start, end, new: &str, len| {
+            RopeDelta::simple_edit(Interval::new(start, end), Rope::from(new), len)
+        };
+        // three lines of ten bytes; two bytes typed at the start of the
+        // second, and the third shortened
+        pass.note_edit(Some(&edit(10, 10, "ab", 30)));
+        pass.note_edit(Some(&edit(25, 27, "", 32)));
+        assert_eq!(pass.current(line(0, 10)), Some(line(0, 10)));
+        assert_eq!(pass.current(line(10, 20)), Some(line(12, 22)));
+        assert_eq!(pass.current(line(20, 30)), None);
+
+        // typing inside a line touches it
+        pass.note_edit(Some(&edit(4, 4, "x", 30)));
+        assert_eq!(pass.current(line(0, 10)), None);
+        assert_eq!(pass.current(line(10, 20)), Some(line(13, 23)));
+
+        pass.note_edit(None);
+        assert_eq!(pass.current(line(10, 20)), None);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Holds back the spans flushed during an idle slice, to send them to core
+//! in as few `update_spans` calls as possible once the slice is over.
+
+use crate::view::HighlightView;
+use xi_core_lib::plugins::rpc::ScopeSpan;
+
+/// Spans for `start..start + len`, relative to `start`.
+struct Region {
+    start: usize,
+    len: usize,
+    spans: Vec<ScopeSpan>,
+}
+
+#[derive(Default)]
+pub struct SpanBatch {
+    regions: Vec<Region>,
+    open: bool,
+    /// The most spans, and bytes, a merged region may have; no more than
+    /// a single flush is allowed.
+    max_spans: usize,
+    max_bytes: usize,
+}
+
+impl SpanBatch {
+    pub fn new() -> SpanBatch {
+        SpanBatch::default()
+    }
+
+    /// Holds back updates until `finish`. Adjacent regions are merged as
+    /// long as the result has at most `max_spans` spans and `max_bytes`
+    /// bytes.
+    pub fn open(&mut self, max_spans: usize, max_bytes: usize) {
+        self.open = true;
+        self.max_spans = max_spans;
+        self.max_bytes = max_bytes;
+    }
+
+    /// Sends `spans` for `start..start + len` to core, or holds them back
+    /// if the batch is open. Returns the number of calls made.
+    pub fn update_spans<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        start: usize,
+        len: usize,
+        spans: &[ScopeSpan],
+    ) -> usize {
+        if !self.open {
+            view.update_spans(start, len, spans);
+            return 1;
+        }
+
+        // core replaces everything in a region's range, so earlier regions
+        // this one covers would only be overwritten
+        let end = start + len;
+        self.regions.retain(|region| region.start < start || region.start + region.len > end);
+        if let Some(last) = self.regions.last_mut() {
+            if last.start + last.len == start
+                && last.spans.len() + spans.len() <= self.max_spans
+                && last.len + len <= self.max_bytes
+            {
+                let shift = last.len;
+                last.spans.extend(spans.iter().map(|span| ScopeSpan {
+                    start: span.start + shift,
+                    end: span.end + shift,
+                    scope_id: span.scope_id,
+                }));
+                last.len += len;
+                return 0;
+            }
+        }
+        self.regions.push(Region { start, len, spans: spans.to_vec() });
+        0
+    }
+
+    /// Sends every region held back, in order, and stops holding them
+    /// back. Returns the number of calls made.
+    pub fn finish<V: HighlightView>(&mut self, view: &mut V) -> usize {
+        self.open = false;
+        let calls = self.regions.len();
+        for region in self.regions.drain(..) {
+            view.update_spans(region.start, region.len, &region.spans);
+        }
+        calls
+    }
+
+    /// Drops the regions held back, for when they are stale.
+    pub fn discard(&mut self) {
+        self.regions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::view::mock::MockView;
+
+    fn spans(ranges: &[(usize, usize)]) -> Vec<ScopeSpan> {
+        ranges.iter().map(|&(start, end)| ScopeSpan { start, end, scope_id: 0 }).collect()
+    }
+
+    /// A view of 100 bytes, which knows of the one scope the spans have.
+    fn view() -> MockView {
+        let mut view = MockView::new("Rust", &"x".repeat(100));
+        view.add_scopes(&[vec!["source.rust".to_owned()]]);
+        view
+    }
+
+    /// The range and span ranges of each update sent.
+    fn sent(view: &MockView) -> Vec<String> {
+        let ranges = |spans: &[ScopeSpan]| -> Vec<_> {
+            spans.iter().map(|span| span.start..span.end).collect()
+        };
+        view.updates
+            .iter()
+            .map(|u| format!("{:?} {:?}", u.start..u.start + u.len, ranges(&u.spans)))
+            .collect()
+    }
+
+    #[test]
+    fn adjacent_regions_are_merged() {
+        let mut view = view();
+        let mut batch = SpanBatch::new();
+        batch.open(3, 100);
+        assert_eq!(batch.update_spans(&mut view, 0, 10, &spans(&[(0, 4), (4, 10)])), 0);
+        batch.update_spans(&mut view, 10, 5, &spans(&[(0, 5)]));
+        // a gap, and then a region that would be too large to merge
+        batch.update_spans(&mut view, 20, 5, &spans(&[(0, 5)]));
+        batch.update_spans(&mut view, 25, 20, &spans(&[(0, 5), (5, 10), (10, 20)]));
+        assert!(view.updates.is_empty());
+
+        assert_eq!(batch.finish(&mut view), 3);
+        assert_eq!(
+            sent(&view),
+            vec!["0..15 [0..4, 4..10, 10..15]", "20..25 [0..5]", "25..45 [0..5, 5..10, 10..20]"]
+        );
+
+        // once finished, updates go straight out
+        assert_eq!(batch.update_spans(&mut view, 50, 5, &spans(&[(0, 5)])), 1);
+        assert_eq!(view.updates.len(), 4);
+    }
+
+    #[test]
+    fn covered_regions_are_dropped() {
+        let mut view = view();
+        let mut batch = SpanBatch::new();
+        batch.open(100, 100);
+        batch.update_spans(&mut view, 30, 10, &spans(&[(0, 10)]));
+        batch.update_spans(&mut view, 35, 10, &spans(&[(0, 10)]));
+        batch.update_spans(&mut view, 0, 40, &spans(&[(0, 40)]));
+        // partly covered, so still needed for 40..45
+        assert_eq!(batch.finish(&mut view), 2);
+        assert_eq!(sent(&view), vec!["35..45 [0..10]", "0..40 [0..40]"]);
+
+        batch.open(100, 100);
+        batch.update_spans(&mut view, 0, 10, &spans(&[(0, 10)]));
+        batch.discard();
+        assert_eq!(batch.finish(&mut view), 0);
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `xi-lang-tools bench`: highlights whole documents the way the plugin does,
+//! through a mock view, and reports how fast, along with how long the
+//! plugin takes to start. `benches/highlight.rs` times the same highlighting
+//! over the fixtures vendored with it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::registry::ParserRegistry;
+use crate::scope_tracker::Scope;
+use crate::text::split_once;
+use crate::view::mock::MockView;
+use crate::view::HighlightView;
+use crate::{LangPlugin, ViewState};
+use xi_core_lib::ConfigTable;
+
+/// Core hands back text a megabyte at a time.
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// The document opened at startup, small so that the time is the
+/// plugin's own.
+const STARTUP_TEXT: &str = "A scratch buffer,\nin plain text.\n";
+
+const USAGE: &str = "usage: xi-lang-tools bench [--runs N] [--trace FILE] LANGUAGE=PATH...";
+
+/// A document, and the language id to highlight it as.
+pub struct Fixture {
+    language: String,
+    path: String,
+    text: String,
+}
+
+impl Fixture {
+    /// The text of `path`, highlighted as `language`.
+    pub fn new(language: &str, path: &str, text: &str) -> Fixture {
+        Fixture { language: language.to_owned(), path: path.to_owned(), text: text.to_owned() }
+    }
+
+    /// Reads an argument like `Rust=src/main.rs`.
+    fn load(arg: &str) -> Result<Fixture, String> {
+        let (language, path) = split_once(arg, "=").ok_or_else(|| USAGE.to_owned())?;
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        Ok(Fixture::new(language, path, &text))
+    }
+}
+
+/// What core would show once a document is highlighted: the scope of
+/// each span, and the scopes announced, by id.
+#[derive(PartialEq)]
+pub struct Highlighted {
+    spans: Vec<(usize, usize, u32)>,
+    scopes: Vec<Scope>,
+}
+
+/// Runs the benchmark with the arguments after `bench`. Each fixture is
+/// highlighted `runs` times from scratch, and every run must come out the
+/// same, so that the benchmark is a smoke test too.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut runs = 5;
+    let mut trace = None;
+    let mut fixtures = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--runs" => {
+                runs = args.next().and_then(|n| n.parse().ok()).filter(|&n| n > 0).ok_or(USAGE)?
+            }
+            "--trace" => trace = Some(args.next().ok_or(USAGE)?),
+            _ => fixtures.push(Fixture::load(arg)?),
+        }
+    }
+    if fixtures.is_empty() {
+        return Err(USAGE.to_owned());
+    }
+    if trace.is_some() {
+        start_trace()?;
+    }
+
+    let startup = (0..runs).map(|_| startup()).min().unwrap_or_default();
+    println!("{:<16} {:>9.2} ms to open a plain text view", "startup", secs(startup) * 1e3);
+    for fixture in &fixtures {
+        let mut best = Duration::new(std::u64::MAX, 0);
+        let mut first = None;
+        for run in 1..=runs {
+            let (elapsed, highlighted) = highlight(fixture)?;
+            best = best.min(elapsed);
+            match first {
+                None => first = Some(highlighted),
+                Some(ref first) if *first != highlighted => {
+                    return Err(format!("{}: run {} highlighted differently", fixture.path, run));
+                }
+                Some(_) => (),
+            }
+        }
+        report(fixture, best);
+    }
+
+    match trace {
+        Some(path) => write_trace(path),
+        None => Ok(()),
+    }
+}
+
+/// Starts the plugin as `main` does and opens a plain text view, as core
+/// does first thing in a session, returning how long it took.
+pub fn startup() -> Duration {
+    let start = Instant::now();
+    let mut plugin = LangPlugin::new();
+    let mut view = MockView::new("Plain Text", STARTUP_TEXT);
+    let _view_state = plugin.open_view(&mut view, &ConfigTable::new());
+    start.elapsed()
+}
+
+/// Highlights the fixture from scratch, as when a document is opened,
+/// returning how long it took.
+pub fn highlight(fixture: &Fixture) -> Result<(Duration, Highlighted), String> {
+    let mut view = MockView::with_chunk_size(&fixture.language, &fixture.text, CHUNK_SIZE);
+    let mut view_state = ViewState::new(Arc::default());
+    let start = Instant::now();
+    view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+    while view.idle_scheduled {
+        view.idle_scheduled = false;
+        view_state.idle(&mut view);
+    }
+    let elapsed = start.elapsed();
+
+    if view.get_frontier().is_some() {
+        return Err(format!("{}: highlighting stopped before the end", fixture.path));
+    }
+    let spans = view.styles.iter().map(|(iv, &id)| (iv.start(), iv.end(), id)).collect();
+    Ok((elapsed, Highlighted { spans, scopes: view.scopes }))
+}
+
+/// `duration` in seconds.
+pub fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn report(fixture: &Fixture, best: Duration) {
+    let lines = fixture.text.lines().count();
+    let bytes = fixture.text.len();
+    let secs = secs(best);
+    println!(
+        "{:<16} {:>8} lines {:>9} bytes {:>9.2} ms {:>11.0} lines/s {:>8.2} MB/s   {}",
+        fixture.language,
+        lines,
+        bytes,
+        secs * 1e3,
+        lines as f64 / secs,
+        bytes as f64 / secs / 1e6,
+        fixture.path,
+    );
+}
+
+#[cfg(feature = "trace-bench")]
+fn start_trace() -> Result<(), String> {
+    xi_trace::enable_tracing();
+    Ok(())
+}
+
+#[cfg(not(feature = "trace-bench"))]
+fn start_trace() -> Result<(), String> {
+    Err("--trace needs xi-lang built with the trace-bench feature".to_owned())
+}
+
+/// Writes the samples traced during the runs, in the chrome tracing
+/// format that chrome://tracing and flamegraph tools load.
+#[cfg(feature = "trace-bench")]
+fn write_trace(path: &str) -> Result<(), String> {
+    let file = fs::File::create(path).map_err(|err| format!("{}: {}", path, err))?;
+    serde_json::to_writer(file, &xi_trace::samples_cloned_sorted())
+        .map_err(|err| format!("{}: {}", path, err))?;
+    println!("trace written to {}", path);
+    Ok(())
+}
+
+#[cfg(not(feature = "trace-bench"))]
+fn write_trace(_path: &str) -> Result<(), String> {
+    unreachable!("start_trace fails without the trace-bench feature")
+}
@@ -0,0 +1,44 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Developer tools for the plugin's highlighting, kept out of the plugin
+//! binary. Built with the `tools` feature.
+
+extern crate xi_lang;
+
+use std::{env, process};
+
+use xi_lang::{bench, coverage, export, highlight, testing};
+
+const USAGE: &str = "usage: xi-lang-tools (bench|highlight|export-html|coverage|replay) [args]";
+
+fn main() {
+    let tool = env::args().nth(1).unwrap_or_default();
+    let args: Vec<String> = env::args().skip(2).collect();
+    let result = match tool.as_str() {
+        "bench" => bench::run(&args),
+        "highlight" => highlight::run(&args),
+        "export-html" => export::run(&args),
+        "coverage" => coverage::run(&args),
+        "replay" => testing::run(&args),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("xi-lang-tools {}: {}", tool, err);
+        process::exit(1);
+    }
+}
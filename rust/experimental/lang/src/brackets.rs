@@ -0,0 +1,461 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Brackets, strings and comments, found by lexing the lines around an
+//! offset again: matching brackets, closing a pair as it is typed, and
+//! expanding a selection to the syntax around it.
+
+use std::ops::Range;
+
+use crate::parser::Parser;
+use crate::statestack::State;
+use crate::view::HighlightView;
+use crate::ViewState;
+
+impl ViewState {
+    /// The state `line_num` starts in, lexing down from the nearest state
+    /// known to be right.
+    pub fn state_at_line<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        line_num: usize,
+    ) -> Option<State> {
+        // states past the frontier may be stale
+        let known = view.get_frontier().map_or(line_num, |frontier| frontier.min(line_num));
+        let (mut prev_num, _, mut state) = self.state_before(view, known);
+        while prev_num < line_num {
+            let line = view.get_line(prev_num).ok()?;
+            state = self.state_after_line(line, state);
+            prev_num += 1;
+        }
+        Some(state)
+    }
+
+    /// The state at the end of `line`, lexed from `state` without making
+    /// any spans.
+    pub fn state_after_line(&self, line: &str, state: State) -> State {
+        self.walk_line(line, state, |_, _, _| ())
+    }
+
+    /// Lexes `line` from `state`, appending the brackets in code to
+    /// `brackets` with their offsets from `base`, and returns the state at
+    /// the end of the line.
+    pub fn line_brackets(
+        &self,
+        line: &str,
+        state: State,
+        base: usize,
+        brackets: &mut Vec<(usize, u8)>,
+    ) -> State {
+        self.walk_line(line, state, |parser, range, piece_state| {
+            if !parser.in_code(piece_state) {
+                return;
+            }
+            for (i, &b) in line.as_bytes()[range.clone()].iter().enumerate() {
+                if b"()[]{}".contains(&b) {
+                    brackets.push((base + range.start + i, b));
+                }
+            }
+        })
+    }
+
+    /// Lexes `line` from `state` like `state_after_line`, showing `f` the
+    /// range of each piece of it with the state it was lexed in.
+    fn walk_line<F>(&self, line: &str, mut state: State, mut f: F) -> State
+    where
+        F: FnMut(&dyn Parser, Range<usize>, State),
+    {
+        let mut parser = self.parser.borrow_mut();
+        let mut i = 0;
+        while i < line.len() {
+            let (prevlen, s0, len, s1) = parser.parse(&line[i..], state);
+            if prevlen == 0 && len == 0 && s1 == state {
+                let skip = line[i..].chars().next().map_or(1, char::len_utf8);
+                f(&**parser, i..i + skip, state);
+                i += skip;
+                continue;
+            }
+            f(&**parser, i..i + prevlen, state);
+            f(&**parser, i + prevlen..i + prevlen + len, s0);
+            i += prevlen + len;
+            state = s1;
+        }
+        state
+    }
+
+    /// The offset of the bracket matching the one at `offset`, looking at
+    /// most `bracket_search_lines` lines away. Brackets in strings and
+    /// comments match nothing, and are skipped over.
+    pub fn matching_bracket<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        offset: usize,
+    ) -> Option<usize> {
+        if !self.is_highlighting() {
+            return None;
+        }
+        let line_num = view.line_of_offset(offset).ok()?;
+        let state = self.state_at_line(view, line_num)?;
+        let line_start = view.offset_of_line(line_num).ok()?;
+        let line = view.get_line(line_num).ok()?;
+        let mut brackets = Vec::new();
+        let mut state = self.line_brackets(line, state, line_start, &mut brackets);
+        let target = brackets.iter().find(|&&(bracket, _)| bracket == offset)?.1;
+
+        if let Some(close) = closing_bracket(target) {
+            // the closers expected, innermost last
+            let mut expected = vec![close];
+            let mut line_num = line_num;
+            let last = line_num.saturating_add(self.bracket_search_lines);
+            brackets.retain(|&(bracket, _)| bracket > offset);
+            loop {
+                for &(bracket, b) in &brackets {
+                    if let Some(close) = closing_bracket(b) {
+                        expected.push(close);
+                    } else if expected.last() == Some(&b) {
+                        expected.pop();
+                        if expected.is_empty() {
+                            return Some(bracket);
+                        }
+                    }
+                }
+                line_num += 1;
+                if line_num > last {
+                    return None;
+                }
+                let line_start = view.offset_of_line(line_num).ok()?;
+                let line = match view.get_line(line_num) {
+                    Ok(line) if !line.is_empty() => line,
+                    _ => return None,
+                };
+                brackets.clear();
+                state = self.line_brackets(line, state, line_start, &mut brackets);
+            }
+        }
+
+        // the openers not closed yet before the target, innermost last
+        let first = line_num.saturating_sub(self.bracket_search_lines);
+        let mut state = self.state_at_line(view, first)?;
+        let mut open: Vec<(usize, u8)> = Vec::new();
+        for num in first..=line_num {
+            let line_start = view.offset_of_line(num).ok()?;
+            let line = view.get_line(num).ok()?;
+            brackets.clear();
+            state = self.line_brackets(line, state, line_start, &mut brackets);
+            for &(bracket, b) in &brackets {
+                let top = open.last().and_then(|&(_, last)| closing_bracket(last));
+                if bracket == offset {
+                    return open.last().filter(|_| top == Some(b)).map(|&(opener, _)| opener);
+                } else if closing_bracket(b).is_some() {
+                    open.push((bracket, b));
+                } else if top == Some(b) {
+                    open.pop();
+                }
+            }
+        }
+        None
+    }
+
+    /// The smallest region of syntax larger than the selection from
+    /// `start` to `end`: the inside of a string or comment, the whole of it,
+    /// the inside of a bracket pair, the pair with its brackets and, at the
+    /// top level, the whole document. Only the lines within
+    /// `bracket_search_lines` of the selection are looked at.
+    pub fn expand_selection<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        start: usize,
+        end: usize,
+    ) -> Option<(usize, usize)> {
+        if !self.is_highlighting() || start > end || end > view.get_buf_size() {
+            return None;
+        }
+        let first_line = view.line_of_offset(start).ok()?.saturating_sub(self.bracket_search_lines);
+        let last_line = view.line_of_offset(end).ok()? + self.bracket_search_lines;
+        let mut state = self.state_at_line(view, first_line)?;
+        let mut offset = view.offset_of_line(first_line).ok()?;
+
+        let mut brackets = Vec::new();
+        let mut literals: Vec<Literal> = Vec::new();
+        for line_num in first_line..=last_line {
+            let line = match view.get_line(line_num) {
+                Ok(line) if !line.is_empty() => line,
+                _ => break,
+            };
+            let text_len = line.trim_end_matches(&['\n', '\r'][..]).len();
+            let bytes = line.as_bytes();
+            state = self.walk_line(line, state, |parser, range, piece_state| {
+                if range.start >= range.end {
+                    return;
+                }
+                if parser.in_code(piece_state) {
+                    for (i, &b) in bytes[range.clone()].iter().enumerate() {
+                        if b"()[]{}".contains(&b) {
+                            brackets.push((offset + range.start + i, b));
+                        }
+                    }
+                    return;
+                }
+                // strings and comments that run on are one literal
+                let text_end = range.end.min(text_len).max(range.start);
+                let last = if text_end > range.start { Some(bytes[text_end - 1]) } else { None };
+                match literals.last_mut() {
+                    Some(literal) if literal.end == offset + range.start => {
+                        literal.end = offset + range.end;
+                        if let Some(last) = last {
+                            literal.text_end = offset + text_end;
+                            literal.last = last;
+                        }
+                    }
+                    _ => literals.push(Literal {
+                        start: offset + range.start,
+                        end: offset + range.end,
+                        text_end: offset + text_end,
+                        first: bytes[range.start],
+                        last: last.unwrap_or(bytes[range.start]),
+                    }),
+                }
+            });
+            offset += line.len();
+        }
+
+        let mut regions = vec![(0, view.get_buf_size())];
+        for literal in &literals {
+            let quoted = literal.text_end - literal.start >= 2
+                && literal.first == literal.last
+                && b"\"'`".contains(&literal.first);
+            if quoted {
+                regions.push((literal.start + 1, literal.text_end - 1));
+            }
+            regions.push((literal.start, literal.text_end));
+        }
+        // the openers not yet closed, with the closers they expect
+        let mut open = Vec::new();
+        for (offset, b) in brackets {
+            if let Some(close) = closing_bracket(b) {
+                open.push((offset, close));
+            } else if open.last().map(|&(_, close)| close) == Some(b) {
+                let (opener, _) = open.pop().unwrap();
+                regions.push((opener + 1, offset));
+                regions.push((opener, offset + 1));
+            }
+        }
+        regions
+            .into_iter()
+            .filter(|&(s, e)| s <= start && end <= e && e - s > end - start)
+            .min_by_key(|&(s, e)| e - s)
+            .or(Some((0, view.get_buf_size())))
+    }
+
+    /// The closer to insert after `typed`, when it is about to be typed at
+    /// `offset`. Nothing is closed inside strings and comments, or right
+    /// before a word.
+    pub fn autoclose<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        offset: usize,
+        typed: char,
+    ) -> Option<char> {
+        if !self.is_highlighting() {
+            return None;
+        }
+        let closer =
+            self.parser.borrow().autoclose_pairs().iter().find(|&&(opener, _)| opener == typed)?.1;
+        let line_num = view.line_of_offset(offset).ok()?;
+        let line_start = view.offset_of_line(line_num).ok()?;
+        let state = self.state_at_line(view, line_num)?;
+        // a line comment runs on into the newline, so one is always lexed
+        let line = view.get_line(line_num).ok()?.trim_end_matches(&['\n', '\r'][..]).to_owned();
+        let line = line + "\n";
+        let col = offset - line_start;
+        if !line.is_char_boundary(col) {
+            return None;
+        }
+
+        // the states of the characters either side of the offset
+        let (mut before, mut after) = (state, state);
+        self.walk_line(&line, state, |_, range, piece_state| {
+            if range.start < col && col <= range.end {
+                before = piece_state;
+            }
+            if range.start <= col && col < range.end {
+                after = piece_state;
+            }
+        });
+        let parser = self.parser.borrow();
+        if !parser.in_code(before) && !parser.in_code(after) {
+            return None;
+        }
+        let next = line[col..].chars().next();
+        if next.map_or(false, |c| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        if !parser.opens_pair_after(&line[..col], typed) {
+            return None;
+        }
+        Some(closer)
+    }
+}
+
+/// A run of strings and comments, as seen when expanding a selection.
+struct Literal {
+    start: usize,
+    /// The end of the run as lexed, which may take in a line ending.
+    end: usize,
+    /// The end of the run's text, leaving out a final line ending.
+    text_end: usize,
+    /// The first and last bytes of the run's text.
+    first: u8,
+    last: u8,
+}
+
+/// The bracket that closes `open`, if it is an opening bracket.
+pub fn closing_bracket(open: u8) -> Option<u8> {
+    match open {
+        b'(' => Some(b')'),
+        b'[' => Some(b']'),
+        b'{' => Some(b'}'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ParserRegistry;
+    use crate::view::mock::{run_idle, MockView};
+    use crate::BRACKET_SEARCH_LINES;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// The partner of the bracket at `offset`, once `text` is highlighted.
+    fn matching_bracket(text: &str, offset: usize, search_lines: usize) -> Option<usize> {
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.bracket_search_lines = search_lines;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        view_state.matching_bracket(&mut view, offset)
+    }
+
+    #[test]
+    fn brackets_in_strings_and_comments_are_skipped() {
+        let text = "fn f() { g(\")\", '(', a[0]) /* } */ }\n";
+        let pairs = &[
+            (text.find('{').unwrap(), text.rfind('}').unwrap()),
+            (text.find("g(").unwrap() + 1, text.find(") /*").unwrap()),
+            (text.find('[').unwrap(), text.find(']').unwrap()),
+        ];
+        for &(open, close) in pairs {
+            assert_eq!(matching_bracket(text, open, BRACKET_SEARCH_LINES), Some(close));
+            assert_eq!(matching_bracket(text, close, BRACKET_SEARCH_LINES), Some(open));
+        }
+        // a bracket in a string or a comment matches nothing
+        for bracket in &["\")", "'('", "} */"] {
+            let offset = text.find(bracket).unwrap();
+            let offset = offset + text[offset..].find(|c| "()[]{}".contains(c)).unwrap();
+            assert_eq!(matching_bracket(text, offset, BRACKET_SEARCH_LINES), None);
+        }
+        // nor does anything that isn't a bracket, or a bracket left open
+        assert_eq!(matching_bracket(text, 0, BRACKET_SEARCH_LINES), None);
+        assert_eq!(matching_bracket("fn f() {\n", 7, BRACKET_SEARCH_LINES), None);
+    }
+
+    #[test]
+    fn brackets_match_across_many_lines() {
+        let body = "    let s = \"{\"; // (\n".repeat(1000);
+        let text = format!("fn f() {{\n{}}}\n", body);
+        let open = 7;
+        let close = text.len() - 2;
+        assert_eq!(matching_bracket(&text, open, BRACKET_SEARCH_LINES), Some(close));
+        assert_eq!(matching_bracket(&text, close, BRACKET_SEARCH_LINES), Some(open));
+        // unless they are further apart than the search goes
+        assert_eq!(matching_bracket(&text, open, 100), None);
+        assert_eq!(matching_bracket(&text, close, 100), None);
+    }
+
+    /// The regions selected by expanding the selection from `start` to
+    /// `end` in `text` over and over, until it takes in the whole document.
+    fn expansions(language: &str, text: &str, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let mut view = MockView::new(language, text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let mut selection = (start, end);
+        let mut regions = Vec::new();
+        while selection != (0, text.len()) {
+            assert!(regions.len() < 20, "stuck at {:?}", selection);
+            selection = view_state.expand_selection(&mut view, selection.0, selection.1).unwrap();
+            regions.push(selection);
+        }
+        regions
+    }
+
+    #[test]
+    fn selections_expand_to_enclosing_syntax() {
+        let text = "fn f() {\n    g(a, \"hi there\");\n}\n";
+        let outward = vec![
+            // the string's contents, then the string
+            (19, 27),
+            (18, 28),
+            // the arguments, then the call's parentheses
+            (15, 28),
+            (14, 29),
+            // the function's body, then its block
+            (8, 31),
+            (7, 32),
+            (0, 33),
+        ];
+        assert_eq!(expansions("Rust", text, 20, 20), outward);
+        // from `a` into the string, across two arguments
+        assert_eq!(expansions("Rust", text, 15, 25), outward[2..].to_vec());
+
+        let text = "(a [b] c)\n";
+        let outward = vec![(4, 5), (3, 6), (1, 8), (0, 9), (0, 10)];
+        assert_eq!(expansions("Plain Text", text, 4, 4), outward);
+    }
+
+    /// The closer a frontend should insert after typing `typed` where
+    /// `text` has a `$`.
+    fn autoclose(text: &str, typed: char) -> Option<char> {
+        let offset = text.find('$').unwrap();
+        let text = text.replacen('$', "", 1);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        view_state.autoclose(&mut view, offset, typed)
+    }
+
+    #[test]
+    fn pairs_are_only_closed_in_code() {
+        let cases = &[
+            ("let s = $;\n", '"', Some('"')),
+            ("let s = \"ab$c\";\n", '"', None),
+            ("call$;\n", '(', Some(')')),
+            ("// call$\n", '(', None),
+            ("/* call$ */\n", '(', None),
+            ("let v = $x;\n", '(', None),
+            ("let f = $;\n", '|', None),
+            // char literals close, lifetimes don't
+            ("let c = $;\n", '\'', Some('\'')),
+            ("let b = b$;\n", '\'', Some('\'')),
+            ("fn f(x: &$T) {}\n", '\'', None),
+            ("fn f<'a>(x: &$) {}\n", '\'', None),
+        ];
+        for &(text, typed, expected) in cases {
+            assert_eq!(autoclose(text, typed), expected, "{:?} in {:?}", typed, text);
+        }
+    }
+}
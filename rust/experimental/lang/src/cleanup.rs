@@ -0,0 +1,159 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cleaning up whitespace on request: trailing whitespace, and the
+//! newlines the document ends with.
+
+use xi_rope::Interval;
+
+use crate::scope_map;
+use crate::view::{is_retryable, HighlightView};
+use crate::{send_as_undo_group, trace_lazily, ViewState};
+
+impl ViewState {
+    /// Strips trailing whitespace and ends the document with a single
+    /// newline, as far as the config asks for either, in one edit that core
+    /// can undo on its own. Whitespace in a string is content, and is kept.
+    pub fn cleanup<V: HighlightView>(&mut self, view: &mut V) {
+        if !self.trim_trailing_whitespace && !self.ensure_final_newline {
+            return;
+        }
+        match self.cleanup_edits(view) {
+            Ok(edits) => send_as_undo_group(view, edits, true),
+            Err(reason) => {
+                trace_lazily("can't clean up", &["experimental-lang"], || reason.to_owned())
+            }
+        }
+    }
+
+    /// The deletions of trailing whitespace, and the fixup of the final
+    /// newline, that a cleanup makes, in document order.
+    fn cleanup_edits<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+    ) -> Result<Vec<(Interval, String)>, &'static str> {
+        let keep_hard_breaks =
+            self.markdown_keep_hard_breaks && self.current_language.as_ref() == "Markdown";
+        let mut edits = Vec::new();
+        let mut state = self.state_at_line(view, 0).ok_or("couldn't lex the lines")?;
+        let mut in_string = false;
+        let mut offset = 0;
+        // where the text of the last line with any ends, its line ending,
+        // and the text after it
+        let mut last_text: Option<(usize, String)> = None;
+        let mut tail = String::new();
+        for line_num in 0.. {
+            let line = match view.get_line(line_num) {
+                Ok(line) if !line.is_empty() => line.to_owned(),
+                Ok(_) => break,
+                Err(err) if !is_retryable(&err) => break,
+                Err(_) => return Err("couldn't fetch the lines"),
+            };
+            let text = line.trim_end_matches(&['\n', '\r'][..]);
+            let text_state = self.state_after_line(text, state);
+            let scope = scope_map::translate(&self.parser.borrow().get_scope_for_state(text_state));
+            in_string = scope.iter().any(|name| name.starts_with("string"));
+
+            let trimmed = text.trim_end_matches(&[' ', '\t'][..]);
+            let trailing = &text[trimmed.len()..];
+            let hard_break = keep_hard_breaks
+                && !trimmed.trim().is_empty()
+                && trailing.len() >= 2
+                && !trailing.contains('\t');
+            if self.trim_trailing_whitespace && !trailing.is_empty() && !in_string && !hard_break {
+                edits.push((
+                    Interval::new(offset + trimmed.len(), offset + text.len()),
+                    String::new(),
+                ));
+            }
+            if trimmed.trim().is_empty() {
+                tail.push_str(&line);
+            } else {
+                last_text = Some((offset + text.len(), line[text.len()..].to_owned()));
+                tail = line[text.len()..].to_owned();
+            }
+            state = self.state_after_line(&line, state);
+            offset += line.len();
+        }
+
+        if self.ensure_final_newline && !in_string {
+            if let Some((text_end, ending)) = last_text {
+                // blank lines after the text go with the extra newlines
+                edits.retain(|(iv, _)| iv.start() < text_end);
+                let ending = if ending.is_empty() { "\n".to_owned() } else { ending };
+                if tail != ending {
+                    edits.push((Interval::new(text_end, offset), ending));
+                }
+            }
+        }
+        Ok(edits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ParserRegistry;
+    use crate::view::mock::{run_idle, MockView};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// `text` after a cleanup with the given settings, if it changed.
+    fn cleanup(language: &str, text: &str, trim: bool, newline: bool, keep_breaks: bool) -> String {
+        let mut view = MockView::new(language, text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.trim_trailing_whitespace = trim;
+        view_state.ensure_final_newline = newline;
+        view_state.markdown_keep_hard_breaks = keep_breaks;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        view_state.cleanup(&mut view);
+        assert!(view.edits.len() <= 1);
+        match view.edits.pop() {
+            Some(edit) => {
+                assert!(edit.new_undo_group);
+                edit.delta.apply(view.text()).to_string()
+            }
+            None => text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn cleanup_keeps_whitespace_in_strings() {
+        let text = "fn f() {   \n    let s = r\"a  \nb\";\t\n}\n\n  \n";
+        let cleaned = "fn f() {\n    let s = r\"a  \nb\";\n}\n";
+        assert_eq!(cleanup("Rust", text, true, true, false), cleaned);
+        assert_eq!(
+            cleanup("Rust", text, true, false, false),
+            "fn f() {\n    let s = r\"a  \nb\";\n}\n\n\n"
+        );
+        assert_eq!(
+            cleanup("Rust", text, false, true, false),
+            "fn f() {   \n    let s = r\"a  \nb\";\t\n}\n"
+        );
+        assert_eq!(cleanup("Rust", text, false, false, false), text);
+        assert_eq!(cleanup("Rust", "fn f() {}", false, true, false), "fn f() {}\n");
+        assert_eq!(cleanup("Rust", cleaned, true, true, false), cleaned);
+    }
+
+    #[test]
+    fn cleanup_can_keep_markdown_hard_breaks() {
+        let text = "one  \ntwo \n\nthree\t\n";
+        assert_eq!(cleanup("Markdown", text, true, true, true), "one  \ntwo\n\nthree\n");
+        assert_eq!(cleanup("Markdown", text, true, true, false), "one\ntwo\n\nthree\n");
+        // only in Markdown
+        assert_eq!(cleanup("Plain Text", text, true, true, true), "one\ntwo\n\nthree\n");
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Commenting lines out and back in, with the language's own comment
+//! syntax.
+
+use serde_json::json;
+use xi_rope::Interval;
+
+use crate::indent::leading_whitespace;
+use crate::view::HighlightView;
+use crate::{send_as_undo_group, trace_lazily, ViewState};
+
+impl ViewState {
+    /// Comments out the lines from `first_line` to `last_line`, or
+    /// uncomments them if they are all comments already, in one edit that
+    /// core can undo on its own.
+    pub fn toggle_comment<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        first_line: usize,
+        last_line: usize,
+    ) {
+        let edits = match self.comment_edits(view, first_line, last_line) {
+            Ok(edits) => edits,
+            Err(reason) => {
+                let payload =
+                    json!({ "first_line": first_line, "last_line": last_line, "reason": reason });
+                trace_lazily("can't toggle comment", &["experimental-lang"], || {
+                    payload.to_string()
+                });
+                return;
+            }
+        };
+        send_as_undo_group(view, edits, false);
+    }
+
+    /// The replacements that toggle the comment on the lines from
+    /// `first_line` to `last_line`, in document order. Languages without
+    /// line comments have the lines wrapped in a block comment instead.
+    fn comment_edits<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        first_line: usize,
+        last_line: usize,
+    ) -> Result<Vec<(Interval, String)>, &'static str> {
+        let last_line = last_line.min(view.get_num_lines().saturating_sub(1));
+        if first_line > last_line {
+            return Err("no such lines");
+        }
+        let mut lines = Vec::new();
+        for line_num in first_line..=last_line {
+            let offset = view.offset_of_line(line_num).map_err(|_| "couldn't fetch the lines")?;
+            let line = view.get_line(line_num).map_err(|_| "couldn't fetch the lines")?;
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            if !line.trim().is_empty() {
+                lines.push((offset, line.to_owned()));
+            }
+        }
+        if lines.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (line_comment, block_comment) = {
+            let parser = self.parser.borrow();
+            let block_comment = parser.block_comment();
+            (
+                parser.line_comment().map(str::to_owned),
+                block_comment.map(|(open, close)| (open.to_owned(), close.to_owned())),
+            )
+        };
+        match (line_comment, block_comment) {
+            (Some(token), _) => Ok(self.line_comment_edits(&lines, &token)),
+            (None, Some((open, close))) => {
+                // wrapping half of another block comment would unbalance it
+                let before =
+                    self.state_at_line(view, first_line).ok_or("couldn't lex the lines")?;
+                let after =
+                    self.state_at_line(view, last_line + 1).ok_or("couldn't lex the lines")?;
+                if before != after || !self.parser.borrow().in_code(before) {
+                    return Err("the lines hold an unbalanced block comment");
+                }
+                Ok(block_comment_edits(&lines, &open, &close))
+            }
+            (None, None) => Err("the language has no comments"),
+        }
+    }
+
+    /// Removes `token` from the start of `lines` if they all begin with it,
+    /// and otherwise inserts it at their least indentation.
+    fn line_comment_edits(
+        &self,
+        lines: &[(usize, String)],
+        token: &str,
+    ) -> Vec<(Interval, String)> {
+        let commented = |line: &str| line[leading_whitespace(line).len()..].starts_with(token);
+        if lines.iter().all(|(_, line)| commented(line)) {
+            return lines
+                .iter()
+                .map(|(offset, line)| {
+                    let start = leading_whitespace(line).len();
+                    let mut end = start + token.len();
+                    if line[end..].starts_with(' ') {
+                        end += 1;
+                    }
+                    (Interval::new(offset + start, offset + end), String::new())
+                })
+                .collect();
+        }
+        let width = lines
+            .iter()
+            .map(|(_, line)| self.indent_width(leading_whitespace(line)))
+            .min()
+            .unwrap_or(0);
+        lines
+            .iter()
+            .map(|(offset, line)| {
+                let mut at = 0;
+                for c in leading_whitespace(line).chars() {
+                    if self.indent_width(&line[..at]) >= width {
+                        break;
+                    }
+                    at += c.len_utf8();
+                }
+                (Interval::new(offset + at, offset + at), format!("{} ", token))
+            })
+            .collect()
+    }
+}
+
+/// Unwraps `lines` if they are a block comment already, and otherwise
+/// wraps them in one.
+fn block_comment_edits(
+    lines: &[(usize, String)],
+    open: &str,
+    close: &str,
+) -> Vec<(Interval, String)> {
+    let (first_offset, first) = &lines[0];
+    let (last_offset, last) = &lines[lines.len() - 1];
+    let start = first_offset + leading_whitespace(first).len();
+    let end = last_offset + last.trim_end().len();
+    let head = &first[leading_whitespace(first).len()..];
+    let tail = last.trim_end();
+    let wrapped = head.starts_with(open)
+        && tail.ends_with(close)
+        && (lines.len() > 1 || head.trim_end().len() >= open.len() + close.len());
+    if !wrapped {
+        return vec![
+            (Interval::new(start, start), format!("{} ", open)),
+            (Interval::new(end, end), format!(" {}", close)),
+        ];
+    }
+    let mut open_end = start + open.len();
+    if head[open.len()..].starts_with(' ') {
+        open_end += 1;
+    }
+    let mut close_start = end - close.len();
+    if tail[..tail.len() - close.len()].ends_with(' ') && close_start > open_end {
+        close_start -= 1;
+    }
+    vec![
+        (Interval::new(start, open_end), String::new()),
+        (Interval::new(close_start, end), String::new()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::rust::RustParser;
+    use crate::parser::Parser;
+    use crate::registry::ParserRegistry;
+    use crate::scope_tracker::Scope;
+    use crate::statestack::{self, State};
+    use crate::view::mock::{run_idle, MockView};
+    use crate::EDIT_AUTHOR;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// The text after toggling the comment on the lines from `first_line`
+    /// to `last_line`, if it was edited.
+    fn toggle_comment(
+        language: &str,
+        text: &str,
+        first_line: usize,
+        last_line: usize,
+    ) -> Option<String> {
+        let mut registry = ParserRegistry::default();
+        registry.register("Block Comments", || Box::new(BlockCommentParser(RustParser::new())));
+        let mut view = MockView::new(language, text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &registry, &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        view_state.toggle_comment(&mut view, first_line, last_line);
+        assert!(view.edits.len() <= 1);
+        view.edits.pop().map(|edit| {
+            assert_eq!(edit.author, EDIT_AUTHOR);
+            assert!(edit.new_undo_group);
+            edit.delta.apply(view.text()).to_string()
+        })
+    }
+
+    #[test]
+    fn comments_are_toggled_on_mixed_lines() {
+        let text = "fn main() {\n    // let x = 1;\n\n    let y = 2;\n}\n";
+        let commented = "fn main() {\n    // // let x = 1;\n\n    // let y = 2;\n}\n";
+        assert_eq!(toggle_comment("Rust", text, 1, 3), Some(commented.to_owned()));
+        assert_eq!(toggle_comment("Rust", commented, 1, 3), Some(text.to_owned()));
+        // blank lines alone are left as they are
+        assert_eq!(toggle_comment("Rust", text, 2, 2), None);
+        // past the end of the document
+        assert_eq!(toggle_comment("Rust", text, 10, 12), None);
+    }
+
+    #[test]
+    fn comments_go_in_at_the_least_indentation() {
+        let text = "\t\tlet x = 1;\n\n\tlet y = 2;\n";
+        let commented = "\t// \tlet x = 1;\n\n\t// let y = 2;\n";
+        assert_eq!(toggle_comment("Rust", text, 0, 2), Some(commented.to_owned()));
+        assert_eq!(toggle_comment("Rust", commented, 0, 2), Some(text.to_owned()));
+    }
+
+    #[test]
+    fn languages_without_line_comments_wrap_in_a_block() {
+        let text = "a {\n  color: red;\n}\n";
+        let wrapped = "/* a {\n  color: red;\n} */\n";
+        let toggled = toggle_comment("Block Comments", text, 0, 2);
+        assert_eq!(toggled, Some(wrapped.to_owned()));
+        let toggled = toggle_comment("Block Comments", wrapped, 0, 2);
+        assert_eq!(toggled, Some(text.to_owned()));
+        // wrapping the start of a comment but not its end
+        assert_eq!(toggle_comment("Block Comments", "/* a {\n  color: red;\n} */\n", 0, 1), None);
+        assert_eq!(toggle_comment("Plain Text", text, 0, 2), None);
+    }
+
+    /// Rust's lexing, with only block comments, as in CSS.
+    struct BlockCommentParser(RustParser);
+
+    impl Parser for BlockCommentParser {
+        fn name(&self) -> &'static str {
+            "Block Comments"
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            self.0.get_scope_for_state(state)
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            self.0.parse(text, state)
+        }
+
+        fn in_code(&self, state: State) -> bool {
+            self.0.in_code(state)
+        }
+
+        fn block_comment(&self) -> Option<(&str, &str)> {
+            self.0.block_comment()
+        }
+
+        fn stack_stats(&self) -> statestack::Stats {
+            self.0.stack_stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.0.shrink_to_fit()
+        }
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `xi-lang-tools coverage`: highlights a file as `xi-lang-tools highlight` does, and
+//! reports the text no token was found in, line by line, with how much
+//! text each scope was given. Every byte is in the document's own scope,
+//! like `source.rust`, so text in that scope alone is what the parser
+//! found nothing in; whitespace is left out, as there is nothing to color.
+//! It shows at once what a new parser is missing, and run over fixtures,
+//! gives a crude measure of a parser to track.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::highlight::{self, Highlighted};
+use crate::scope_tracker::Scope;
+use crate::text::lines_with_endings;
+
+const USAGE: &str = "usage: xi-lang-tools coverage [--language LANGUAGE] [--format text|json] \
+                     [--min-coverage RATIO] FILE";
+
+/// The longest a bar of the scope histogram gets.
+const BAR_WIDTH: usize = 30;
+
+/// How the report is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// A stretch of a line in no token's scope, trimmed of whitespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gap {
+    /// The line it is on, from 0.
+    pub line: usize,
+    /// Where it starts and ends in the line, in bytes.
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// How much of the text a scope was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeUse {
+    pub scope: Scope,
+    pub spans: usize,
+    /// Bytes of it that aren't whitespace.
+    pub bytes: usize,
+}
+
+/// How much of a file its parser found tokens in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    pub language: String,
+    /// The bytes that aren't whitespace, and how many of them are in a
+    /// token's scope.
+    pub bytes: usize,
+    pub scoped: usize,
+    pub gaps: Vec<Gap>,
+    /// The scopes used, the one given the most text first.
+    pub scopes: Vec<ScopeUse>,
+}
+
+impl Coverage {
+    /// The share of the text in a token's scope; all of it, if there is
+    /// no text.
+    pub fn ratio(&self) -> f64 {
+        if self.bytes == 0 {
+            1.0
+        } else {
+            self.scoped as f64 / self.bytes as f64
+        }
+    }
+
+    /// The document `xi-lang-tools coverage --format json` prints.
+    pub fn to_json(&self) -> Value {
+        let gaps: Vec<Value> = self
+            .gaps
+            .iter()
+            .map(|gap| {
+                json!({ "line": gap.line, "start": gap.start, "end": gap.end, "text": gap.text })
+            })
+            .collect();
+        let scopes: Vec<Value> = self
+            .scopes
+            .iter()
+            .map(|used| json!({ "scope": used.scope, "spans": used.spans, "bytes": used.bytes }))
+            .collect();
+        json!({
+            "language": self.language,
+            "bytes": self.bytes,
+            "scoped": self.scoped,
+            "coverage": self.ratio(),
+            "gaps": gaps,
+            "scopes": scopes,
+        })
+    }
+
+    /// The report for reading: the gaps of each line, numbered from 1 as
+    /// editors do, then a histogram of the scopes.
+    pub fn to_text(&self, path: &Path) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{}: {}, {:.1}% of {} bytes in a token",
+            path.display(),
+            self.language,
+            self.ratio() * 100.0,
+            self.bytes
+        )
+        .unwrap();
+        let mut gaps = self.gaps.iter().peekable();
+        while let Some(gap) = gaps.next() {
+            write!(out, "{:>6}: {:?}", gap.line + 1, gap.text).unwrap();
+            while let Some(next) = gaps.peek().filter(|next| next.line == gap.line) {
+                write!(out, ", {:?}", next.text).unwrap();
+                gaps.next();
+            }
+            writeln!(out).unwrap();
+        }
+        let most = self.scopes.first().map_or(0, |used| used.bytes);
+        for used in &self.scopes {
+            let bar = if used.bytes == 0 { 0 } else { (used.bytes * BAR_WIDTH + most - 1) / most };
+            writeln!(
+                out,
+                "{:>8} {:<width$} {}",
+                used.bytes,
+                "#".repeat(bar),
+                used.scope.join(" "),
+                width = BAR_WIDTH
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// What `highlighted` leaves out of any token.
+pub fn coverage(highlighted: &Highlighted) -> Coverage {
+    let lines: Vec<&str> = lines_with_endings(&highlighted.text).collect();
+    let mut uses = vec![(0, 0); highlighted.scopes.len()];
+    let mut bytes = 0;
+    let mut scoped = 0;
+    let mut gaps = Vec::new();
+    // the line, start and end of the gap so far
+    let mut open: Option<(usize, usize, usize)> = None;
+    for span in &highlighted.spans {
+        let text = &lines[span.line][span.start..span.end];
+        let text_bytes = text.bytes().filter(|b| !b.is_ascii_whitespace()).count();
+        let used = &mut uses[span.scope_id as usize];
+        used.0 += 1;
+        used.1 += text_bytes;
+        bytes += text_bytes;
+        if highlighted.scopes[span.scope_id as usize].len() > 1 {
+            scoped += text_bytes;
+            gaps.extend(open.take().and_then(|gap| trim_gap(&lines, gap)));
+            continue;
+        }
+        open = match open {
+            Some((line, start, end)) if line == span.line && end == span.start => {
+                Some((line, start, span.end))
+            }
+            _ => {
+                gaps.extend(open.and_then(|gap| trim_gap(&lines, gap)));
+                Some((span.line, span.start, span.end))
+            }
+        };
+    }
+    gaps.extend(open.and_then(|gap| trim_gap(&lines, gap)));
+
+    let mut scopes: Vec<ScopeUse> = uses
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, (spans, _))| spans > 0)
+        .map(|(id, (spans, bytes))| ScopeUse {
+            scope: highlighted.scopes[id].clone(),
+            spans,
+            bytes,
+        })
+        .collect();
+    scopes.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.scope.cmp(&b.scope)));
+    Coverage { language: highlighted.language.clone(), bytes, scoped, gaps, scopes }
+}
+
+/// The gap at `start..end` of `line`, trimmed of whitespace, unless it is
+/// all whitespace.
+fn trim_gap(lines: &[&str], (line, start, end): (usize, usize, usize)) -> Option<Gap> {
+    let text = &lines[line][start..end];
+    let trimmed = text.trim_start();
+    let start = start + text.len() - trimmed.len();
+    let trimmed = trimmed.trim_end();
+    if trimmed.is_empty() {
+        return None;
+    }
+    Some(Gap { line, start, end: start + trimmed.len(), text: trimmed.to_owned() })
+}
+
+/// Runs the command with the arguments after `coverage`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut language = None;
+    let mut format = Format::Text;
+    let mut min_coverage = None;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--language" => language = Some(args.next().ok_or(USAGE)?.as_str()),
+            "--format" => match args.next().map(String::as_str) {
+                Some("text") => format = Format::Text,
+                Some("json") => format = Format::Json,
+                Some(format) => return Err(format!("unknown format: {}", format)),
+                None => return Err(USAGE.to_owned()),
+            },
+            "--min-coverage" => {
+                let ratio = args.next().ok_or(USAGE)?;
+                match ratio.parse::<f64>() {
+                    Ok(ratio) if ratio >= 0.0 && ratio <= 1.0 => min_coverage = Some(ratio),
+                    _ => return Err(format!("not a ratio from 0 to 1: {}", ratio)),
+                }
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let path = Path::new(path.ok_or(USAGE)?);
+
+    let coverage = coverage(&highlight::highlight_file(path, language)?);
+    match format {
+        Format::Text => print!("{}", coverage.to_text(path)),
+        Format::Json => {
+            let json = serde_json::to_string_pretty(&coverage.to_json());
+            println!("{}", json.map_err(|err| err.to_string())?);
+        }
+    }
+    check_minimum(&coverage, min_coverage)
+}
+
+/// Fails if less of the text than `min_coverage` is in a token.
+fn check_minimum(coverage: &Coverage, min_coverage: Option<f64>) -> Result<(), String> {
+    match min_coverage {
+        Some(min) if coverage.ratio() < min => Err(format!(
+            "{:.1}% of the text is in a token, less than the {:.1}% asked for",
+            coverage.ratio() * 100.0,
+            min * 100.0
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ParserRegistry;
+
+    fn rust_coverage(text: &str) -> Coverage {
+        coverage(&highlight::highlight(&ParserRegistry::default(), "Rust", text).unwrap())
+    }
+
+    fn gap_texts(coverage: &Coverage) -> Vec<(usize, &str)> {
+        coverage.gaps.iter().map(|gap| (gap.line, gap.text.as_str())).collect()
+    }
+
+    #[test]
+    fn gaps_are_the_text_in_no_token() {
+        let text = "let answer = 42; // why\n\n  foo bar\n";
+        let coverage = rust_coverage(text);
+        assert_eq!(gap_texts(&coverage), vec![(0, "answer"), (0, ";"), (2, "foo bar")]);
+        let foo = &coverage.gaps[2];
+        assert_eq!((foo.start, foo.end), (2, 9));
+
+        // `let`, `=`, `42` and the comment
+        assert_eq!(coverage.bytes, 3 + 6 + 1 + 2 + 1 + 5 + 3 + 3);
+        assert_eq!(coverage.scoped, 3 + 1 + 2 + 5);
+        let root = &coverage.scopes.iter().find(|used| used.scope.len() == 1).unwrap();
+        assert_eq!(root.bytes, coverage.bytes - coverage.scoped);
+        let total: usize = coverage.scopes.iter().map(|used| used.bytes).sum();
+        assert_eq!(total, coverage.bytes);
+        assert!(coverage.scopes.windows(2).all(|pair| pair[0].bytes >= pair[1].bytes));
+    }
+
+    #[test]
+    fn fixtures_with_known_gaps_are_reported() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/rust/items.rs");
+        let coverage = coverage(&highlight::highlight_file(&path, None).unwrap());
+        // identifiers are left in the document's scope
+        let gaps = gap_texts(&coverage);
+        assert!(gaps.contains(&(15, "escaped")), "{:?}", gaps);
+        // a doc comment line is a token from end to end
+        assert!(gaps.iter().all(|&(line, _)| line != 0 && line != 4), "{:?}", gaps);
+        assert!(0.0 < coverage.ratio() && coverage.ratio() < 1.0);
+
+        let report = coverage.to_text(&path);
+        assert!(report.lines().next().unwrap().contains(": Rust, "), "{}", report);
+        assert!(report.contains("    16: \"escaped\""), "{}", report);
+        let json = coverage.to_json();
+        assert_eq!(json["gaps"].as_array().unwrap().len(), coverage.gaps.len());
+        assert_eq!(json["scoped"], coverage.scoped);
+    }
+
+    #[test]
+    fn a_minimum_can_be_asked_for() {
+        let coverage = rust_coverage("// all comment\n");
+        assert_eq!(coverage.scoped, coverage.bytes);
+        assert_eq!(check_minimum(&coverage, Some(1.0)), Ok(()));
+
+        let coverage = rust_coverage("half // way\n");
+        let err = check_minimum(&coverage, Some(0.8)).unwrap_err();
+        assert!(err.starts_with("55.6% of the text is in a token"), "{}", err);
+        assert_eq!(check_minimum(&coverage, None), Ok(()));
+
+        let args = |args: &[&str]| run(&args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>());
+        assert_eq!(args(&[]), Err(USAGE.to_owned()));
+        let err = args(&["--min-coverage", "80%", "a.rs"]).unwrap_err();
+        assert_eq!(err, "not a ratio from 0 to 1: 80%");
+    }
+}
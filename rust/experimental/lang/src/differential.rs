@@ -0,0 +1,268 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Differential tests against syntect. The files in
+//! `differential/corpus/` are highlighted both by the parsers here and by
+//! syntect's Sublime grammars, and every byte that isn't whitespace is put
+//! in a coarse class by its scopes: comment, string, keyword or other.
+//! Scope names differ too much between the two to compare, but the classes
+//! mostly agree, and a parser that loses track of where a string or a
+//! comment ends makes them disagree from there on.
+//!
+//! Loading the grammars is slow, so the test only runs when asked for:
+//!
+//! ```text
+//! cargo test -- --ignored differential --nocapture
+//! ```
+//!
+//! The corpus is files from elsewhere in xi-editor, under its license.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+
+use crate::highlight;
+use crate::palette::ScopeMap;
+use crate::registry::ParserRegistry;
+use crate::text::lines_with_endings;
+
+/// The share of bytes whose classes must agree in each file.
+const MIN_AGREEMENT: f64 = 0.85;
+
+/// How many of the runs of bytes the two disagree on are reported.
+const REPORTED_RUNS: usize = 12;
+
+/// The languages of the corpus, by extension: the name here, and the name
+/// of the syntect grammar.
+const LANGUAGES: &[(&str, &str, &str)] = &[("rs", "Rust", "Rust")];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Comment,
+    String,
+    Keyword,
+    Other,
+}
+
+const CLASSES: &[Class] = &[Class::Comment, Class::String, Class::Keyword, Class::Other];
+
+/// The class of a byte, and its scope, innermost last.
+type Classified = (Class, Vec<String>);
+
+fn classes() -> ScopeMap<Class> {
+    let mut classes = ScopeMap::new();
+    classes.insert("comment", Class::Comment);
+    classes.insert("string", Class::String);
+    classes.insert("keyword", Class::Keyword);
+    classes.insert("storage", Class::Keyword);
+    classes
+}
+
+fn classify(classes: &ScopeMap<Class>, scope: Vec<String>) -> Classified {
+    (classes.lookup(&scope).cloned().unwrap_or(Class::Other), scope)
+}
+
+fn corpus() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("differential/corpus");
+    let mut files: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    files.sort();
+    files
+}
+
+/// Each byte of `text` as the parsers here classify it.
+fn ours(classes: &ScopeMap<Class>, language: &str, text: &str) -> Vec<Classified> {
+    let highlighted = highlight::highlight(&ParserRegistry::default(), language, text).unwrap();
+    let mut bytes = vec![(Class::Other, Vec::new()); text.len()];
+    let mut line_start = 0;
+    let mut spans = highlighted.spans.iter().peekable();
+    for (line_num, line) in lines_with_endings(text).enumerate() {
+        while let Some(span) = spans.peek().filter(|span| span.line == line_num) {
+            let scope = highlighted.scopes[span.scope_id as usize].clone();
+            let classified = classify(classes, scope);
+            for byte in &mut bytes[line_start + span.start..line_start + span.end] {
+                *byte = classified.clone();
+            }
+            spans.next();
+        }
+        line_start += line.len();
+    }
+    bytes
+}
+
+/// Each byte of `text` as syntect classifies it with `syntax`.
+fn theirs(
+    classes: &ScopeMap<Class>,
+    syntax_set: &SyntaxSet,
+    syntax: &str,
+    text: &str,
+) -> Vec<Classified> {
+    let syntax = syntax_set.find_syntax_by_name(syntax).unwrap();
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut bytes = Vec::with_capacity(text.len());
+    for line in lines_with_endings(text) {
+        let mut ops = state.parse_line(line, syntax_set).into_iter();
+        let line_start = bytes.len();
+        loop {
+            let scope = stack.as_slice().iter().map(|scope| scope.build_string()).collect();
+            let classified = classify(classes, scope);
+            let next = ops.next();
+            let end = next.as_ref().map_or(line.len(), |&(cursor, _)| cursor);
+            bytes.resize(line_start + end, classified);
+            match next {
+                Some((_, op)) => stack.apply(&op),
+                None => break,
+            }
+        }
+    }
+    bytes
+}
+
+/// How well the classes of a file agree, and where they don't, readably.
+fn report(path: &Path, text: &str, ours: &[Classified], theirs: &[Classified]) -> (f64, String) {
+    let index = |class| CLASSES.iter().position(|&c| c == class).unwrap();
+    let mut counts = [[0usize; 4]; 4];
+    let mut runs = Vec::new();
+    let mut run_start = None;
+    for (i, c) in text.char_indices() {
+        // runs carry on over spaces, but not onto the next line
+        if c.is_whitespace() && c != '\n' {
+            continue;
+        }
+        let differ = c != '\n' && ours[i].0 != theirs[i].0;
+        if c != '\n' {
+            counts[index(ours[i].0)][index(theirs[i].0)] += c.len_utf8();
+        }
+        let same_run = run_start.map_or(false, |start: usize| {
+            differ && ours[start].0 == ours[i].0 && theirs[start].0 == theirs[i].0
+        });
+        if !same_run {
+            runs.extend(run_start.take().map(|start| (start, i)));
+            if differ {
+                run_start = Some(i);
+            }
+        }
+    }
+    runs.extend(run_start.map(|start| (start, text.len())));
+
+    let total: usize = counts.iter().flatten().sum();
+    let agreed: usize = (0..CLASSES.len()).map(|i| counts[i][i]).sum();
+    let agreement = if total == 0 { 1.0 } else { agreed as f64 / total as f64 };
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{}: {:.1}% of {} bytes agree, {} runs differ",
+        path.display(),
+        agreement * 100.0,
+        total,
+        runs.len()
+    )
+    .unwrap();
+    write!(out, "    {:<16}", "ours \\ syntect").unwrap();
+    for class in CLASSES {
+        write!(out, "{:>9}", format!("{:?}", class).to_lowercase()).unwrap();
+    }
+    writeln!(out).unwrap();
+    for (class, row) in CLASSES.iter().zip(&counts) {
+        write!(out, "    {:<16}", format!("{:?}", class).to_lowercase()).unwrap();
+        for count in row {
+            write!(out, "{:>9}", count).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    for &(start, end) in runs.iter().take(REPORTED_RUNS) {
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_num = text[..line_start].matches('\n').count() + 1;
+        let column = text[line_start..start].chars().count() + 1;
+        writeln!(out, "    {}:{}: {:?}", line_num, column, text[start..end].trim_end()).unwrap();
+        writeln!(out, "        ours:    {:?} {}", ours[start].0, ours[start].1.join(" ")).unwrap();
+        writeln!(out, "        syntect: {:?} {}", theirs[start].0, theirs[start].1.join(" "))
+            .unwrap();
+    }
+    if runs.len() > REPORTED_RUNS {
+        writeln!(out, "    and {} more runs", runs.len() - REPORTED_RUNS).unwrap();
+    }
+    (agreement, out)
+}
+
+#[test]
+#[ignore]
+fn classes_agree_with_syntect() {
+    let syntax_set = SyntaxSet::load_defaults_newlines();
+    let classes = classes();
+    let mut failures = Vec::new();
+    for path in corpus() {
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let &(_, language, syntax) = LANGUAGES
+            .iter()
+            .find(|&&(e, _, _)| e == ext)
+            .unwrap_or_else(|| panic!("{}: no language for the extension", path.display()));
+        let text = fs::read_to_string(&path).unwrap();
+        let ours = ours(&classes, language, &text);
+        let theirs = theirs(&classes, &syntax_set, syntax, &text);
+        let (agreement, report) = report(&path, &text, &ours, &theirs);
+        println!("{}", report);
+        if agreement < MIN_AGREEMENT {
+            failures.push(report);
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "fewer than {}% of bytes agree:\n{}",
+        MIN_AGREEMENT * 100.0,
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn reports_point_at_the_runs_that_differ() {
+    let text = "fn f() {}\n";
+    let classified = |class, name: &str| (class, vec!["source".to_owned(), name.to_owned()]);
+    let keyword = classified(Class::Keyword, "keyword.other");
+    let mut ours = vec![(Class::Other, vec!["source".to_owned()]); text.len()];
+    let mut theirs = ours.clone();
+    let (_, report_same) = report(Path::new("a.rs"), text, &ours, &theirs);
+    assert!(report_same.starts_with("a.rs: 100.0% of 7 bytes agree, 0 runs differ\n"));
+
+    ours[0] = keyword.clone();
+    ours[1] = keyword;
+    theirs[3] = classified(Class::Comment, "comment.block");
+    let (agreement, report) = report(Path::new("a.rs"), text, &ours, &theirs);
+    // of the 7 bytes that aren't whitespace, `fn` and `f` differ
+    assert!((agreement - 4.0 / 7.0).abs() < 1e-9, "{}", agreement);
+    let lines: Vec<_> = report.lines().collect();
+    assert_eq!(lines[0], "a.rs: 57.1% of 7 bytes agree, 2 runs differ");
+    assert_eq!(lines[6], "    1:1: \"fn\"");
+    assert_eq!(lines[7], "        ours:    Keyword source keyword.other");
+    assert_eq!(lines[8], "        syntect: Other source");
+    assert_eq!(lines[9], "    1:4: \"f\"");
+}
+
+#[test]
+fn scopes_pick_coarse_classes() {
+    let classes = classes();
+    let class = |names: &[&str]| {
+        let scope = names.iter().map(|&name| name.to_owned()).collect();
+        classify(&classes, scope).0
+    };
+    assert_eq!(class(&["source.rust", "comment.line.double-slash.rust"]), Class::Comment);
+    let escape = ["source.rust", "string.quoted.double.rust", "constant.character.escape.rust"];
+    assert_eq!(class(&escape), Class::String);
+    assert_eq!(class(&["source.rust", "storage.type.rust"]), Class::Keyword);
+    assert_eq!(class(&["source.rust", "meta.function.rust"]), Class::Other);
+    assert_eq!(class(&[]), Class::Other);
+}
@@ -0,0 +1,918 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `xi-lang-tools export-html`: highlights a file as `xi-lang-tools highlight` does
+//! and writes it out as a page of HTML. Each name of a span's scope is a
+//! `<span>` of its own, nested as the scope is, whose classes are the
+//! name and the prefixes of it a theme could have a rule for. The page's
+//! stylesheet has a rule for each of the theme's, which is the built-in
+//! palette, a palette file, or a TextMate `.tmTheme`.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::highlight::{self, Highlighted};
+use crate::palette::{Palette, ScopeMap};
+use crate::text::{lines_with_endings, strip_prefix};
+
+const USAGE: &str =
+    "usage: xi-lang-tools export-html [--language LANGUAGE] [--theme FILE] [--output FILE] FILE";
+
+/// The class of the `<pre>` the text is in, which the stylesheet's rules
+/// are limited to.
+const PRE_CLASS: &str = "xi-lang";
+
+/// How opaque dim text is.
+const DIM_OPACITY: &str = "0.6";
+
+/// The first 16 colors of the 256-color palette, as xterm shows them.
+const XTERM_COLORS: [&str; 16] = [
+    "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+/// Whether text is bold, italic or underlined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Font {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Font {
+    /// Reads a theme's `fontStyle`, as in `bold italic`. Styles a page
+    /// can't show are ignored.
+    fn parse(s: &str) -> Font {
+        let mut font = Font::default();
+        for word in s.split_whitespace() {
+            match word {
+                "bold" => font.bold = true,
+                "italic" => font.italic = true,
+                "underline" => font.underline = true,
+                _ => (),
+            }
+        }
+        font
+    }
+}
+
+/// How text is shown in a page. What is `None` is as it is in the text
+/// around.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CssStyle {
+    /// Colors as `#rrggbb`, or as the other hex forms CSS has.
+    pub color: Option<String>,
+    pub background: Option<String>,
+    pub font: Option<Font>,
+    pub dim: bool,
+}
+
+impl CssStyle {
+    /// The declarations of a rule for the style, as in `color: #cd0000;
+    /// font-weight: bold`.
+    fn declarations(&self) -> String {
+        let mut declarations = Vec::new();
+        if let Some(color) = &self.color {
+            declarations.push(format!("color: {}", color));
+        }
+        if let Some(background) = &self.background {
+            declarations.push(format!("background-color: {}", background));
+        }
+        if let Some(font) = self.font {
+            let weight = if font.bold { "bold" } else { "normal" };
+            let style = if font.italic { "italic" } else { "normal" };
+            let decoration = if font.underline { "underline" } else { "none" };
+            declarations.push(format!("font-weight: {}", weight));
+            declarations.push(format!("font-style: {}", style));
+            declarations.push(format!("text-decoration: {}", decoration));
+        }
+        if self.dim {
+            declarations.push(format!("opacity: {}", DIM_OPACITY));
+        }
+        declarations.join("; ")
+    }
+}
+
+/// The styles of scopes in a page.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Theme {
+    /// The colors of the page; `None` leaves them to the browser.
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    pub styles: ScopeMap<CssStyle>,
+}
+
+impl Theme {
+    /// The styles of a terminal palette, in the colors xterm has. A
+    /// terminal style sets every attribute, so the font of each is set
+    /// too.
+    pub fn from_palette(palette: &Palette) -> Theme {
+        let mut styles = ScopeMap::new();
+        for (prefix, style) in palette.styles().iter() {
+            let font = Font { bold: style.bold, italic: style.italic, underline: style.underline };
+            let style = CssStyle {
+                color: style.foreground.map(xterm_color),
+                background: None,
+                font: Some(font),
+                dim: style.dim,
+            };
+            styles.insert(prefix, style);
+        }
+        Theme { foreground: None, background: None, styles }
+    }
+
+    /// Reads a TextMate `.tmTheme`. Its settings without a scope are the
+    /// page's colors. A scope selector is taken as a rule for its
+    /// innermost scope, so `meta.tag string` is one for every `string`;
+    /// selectors with exclusions or groups in can't be, so are left out.
+    pub fn from_tmtheme(source: &str) -> Result<Theme, String> {
+        let plist = PlistReader { rest: source }.document()?;
+        let settings = match plist.get("settings") {
+            Some(Plist::Array(settings)) => settings,
+            _ => return Err("a theme must be a dict with an array of settings".to_owned()),
+        };
+        let mut theme = Theme::default();
+        for entry in settings {
+            let settings = match entry.get("settings") {
+                Some(settings @ Plist::Dict(_)) => settings,
+                _ => continue,
+            };
+            let color = |key: &str| match settings.get(key) {
+                Some(Plist::String(color)) => match css_color(color) {
+                    Some(color) => Ok(Some(color)),
+                    None => Err(format!("{}: not a color: {:?}", key, color)),
+                },
+                _ => Ok(None),
+            };
+            let selectors = match entry.get("scope") {
+                Some(Plist::String(selectors)) => selectors,
+                Some(_) => return Err("a scope must be a string".to_owned()),
+                None => {
+                    theme.foreground = color("foreground")?.or_else(|| theme.foreground.take());
+                    theme.background = color("background")?.or_else(|| theme.background.take());
+                    continue;
+                }
+            };
+            let font = match settings.get("fontStyle") {
+                Some(Plist::String(font)) => Some(Font::parse(font)),
+                _ => None,
+            };
+            let style = CssStyle {
+                color: color("foreground").map_err(|err| format!("{}: {}", selectors, err))?,
+                background: color("background").map_err(|err| format!("{}: {}", selectors, err))?,
+                font,
+                dim: false,
+            };
+            for prefix in selectors.split(',').filter_map(selector_prefix) {
+                theme.styles.insert(prefix, style.clone());
+            }
+        }
+        Ok(theme)
+    }
+
+    /// The stylesheet of a page. Rules for longer prefixes come after
+    /// those for shorter ones, so that, as in a theme, the longest rule
+    /// that applies to a name wins; and as the `<span>` of an inner name
+    /// is inside those of the outer ones, its rules win over theirs.
+    pub fn stylesheet(&self) -> String {
+        let mut out = String::new();
+        let page = CssStyle {
+            color: self.foreground.clone(),
+            background: self.background.clone(),
+            ..CssStyle::default()
+        };
+        if page != CssStyle::default() {
+            writeln!(out, "pre.{} {{ {}; }}", PRE_CLASS, page.declarations()).unwrap();
+        }
+        let mut rules: Vec<(&str, &CssStyle)> = self.styles.iter().collect();
+        rules.sort_by_key(|&(prefix, _)| prefix.len());
+        for (prefix, style) in rules {
+            let declarations = style.declarations();
+            if !declarations.is_empty() {
+                let class = css_identifier(&class_name(prefix));
+                writeln!(out, "pre.{} .{} {{ {}; }}", PRE_CLASS, class, declarations).unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// `highlighted` as a page titled `title`, styled by `theme`.
+pub fn to_html(highlighted: &Highlighted, theme: &Theme, title: &str) -> String {
+    let mut out = String::with_capacity(highlighted.text.len() * 4);
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>");
+    push_escaped(&mut out, title);
+    out.push_str("</title>\n<style>\n");
+    out.push_str(&theme.stylesheet());
+    out.push_str("</style>\n</head>\n<body>\n");
+    // a newline just after `<pre>` is dropped, which would be the text's
+    // own if it started with one
+    writeln!(out, "<pre class=\"{}\">", PRE_CLASS).unwrap();
+    push_spans(&mut out, highlighted);
+    out.push_str("</pre>\n</body>\n</html>\n");
+    out
+}
+
+/// Pushes the text, with each span in a `<span>` for each name of its
+/// scope. A span shares the `<span>`s of the names its scope starts with
+/// with the span before, if they are next to each other; line breaks,
+/// like text no span covers, are outside of every `<span>`.
+fn push_spans(out: &mut String, highlighted: &Highlighted) {
+    let mut spans = highlighted.spans.iter().peekable();
+    for (line_num, line) in lines_with_endings(&highlighted.text).enumerate() {
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        let mut open: &[String] = &[];
+        let mut offset = 0;
+        while let Some(span) = spans.peek().cloned().filter(|span| span.line == line_num) {
+            spans.next();
+            let start = span.start.max(offset).min(content.len());
+            let end = span.end.min(content.len());
+            if start >= end {
+                continue;
+            }
+            if start > offset {
+                set_scope(out, &mut open, &[]);
+                push_escaped(out, &content[offset..start]);
+            }
+            set_scope(out, &mut open, &highlighted.scopes[span.scope_id as usize]);
+            push_escaped(out, &content[start..end]);
+            offset = end;
+        }
+        set_scope(out, &mut open, &[]);
+        push_escaped(out, &line[offset..]);
+    }
+}
+
+/// Switches from the `<span>`s of the names of `open` to those of
+/// `scope`, closing only the ones of names `scope` doesn't start with.
+fn set_scope<'a>(out: &mut String, open: &mut &'a [String], scope: &'a [String]) {
+    let kept = open.iter().zip(scope).take_while(|(open, name)| open == name).count();
+    for _ in kept..open.len() {
+        out.push_str("</span>");
+    }
+    for name in &scope[kept..] {
+        out.push_str("<span class=\"");
+        push_escaped(out, &classes(name));
+        out.push_str("\">");
+    }
+    *open = scope;
+}
+
+/// The scope `name` as a class. A class can't have whitespace in, so any
+/// in a name is taken as `_`.
+fn class_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect()
+}
+
+/// The classes of the `<span>` of the scope `name`: one for each of its
+/// prefixes of whole dotted parts, as `string string.quoted
+/// string.quoted.double`, so that the rule for any of them applies.
+fn classes(name: &str) -> String {
+    let name = class_name(name);
+    let mut classes: Vec<&str> = name
+        .match_indices('.')
+        .map(|(i, _)| &name[..i])
+        .filter(|prefix| !prefix.is_empty())
+        .collect();
+    classes.push(&name);
+    classes.join(" ")
+}
+
+/// Pushes `text` with the characters that mean something in HTML
+/// escaped, so that it can go in text or in a quoted attribute.
+fn push_escaped(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+/// `name` as a CSS identifier, for a class selector. The dots of a scope
+/// name are escaped, as is anything else that isn't a letter, digit, `-`
+/// or `_`; what could close the `<style>` or be taken for whitespace is
+/// escaped by its code point, as is a digit an identifier can't start
+/// with.
+fn css_identifier(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 8);
+    for (i, c) in name.chars().enumerate() {
+        let leading_digit = c.is_ascii_digit() && (i == 0 || (i == 1 && name.starts_with('-')));
+        if (c.is_ascii_alphanumeric() && !leading_digit) || c == '-' || c == '_' || !c.is_ascii() {
+            out.push(c);
+        } else if c.is_ascii_punctuation() && !"<>&".contains(c) {
+            out.push('\\');
+            out.push(c);
+        } else {
+            write!(out, "\\{:x} ", c as u32).unwrap();
+        }
+    }
+    out
+}
+
+/// `color` as a CSS color, if it is one of the hex colors themes have:
+/// `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa`.
+fn css_color(color: &str) -> Option<String> {
+    let digits = strip_prefix(color.trim(), "#")?;
+    if [3, 4, 6, 8].contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(format!("#{}", digits.to_ascii_lowercase()))
+    } else {
+        None
+    }
+}
+
+/// A color of the 256-color palette as xterm shows it.
+fn xterm_color(color: u8) -> String {
+    match color {
+        0..=15 => XTERM_COLORS[color as usize].to_owned(),
+        16..=231 => {
+            let level = |n: u8| if n == 0 { 0 } else { 55 + 40 * n };
+            let n = color - 16;
+            format!("#{:02x}{:02x}{:02x}", level(n / 36), level(n / 6 % 6), level(n % 6))
+        }
+        _ => format!("#{0:02x}{0:02x}{0:02x}", 8 + 10 * (color - 232)),
+    }
+}
+
+/// The prefix of the scopes a theme's scope selector applies to: its
+/// innermost scope. Selectors with exclusions, groups or sides in are
+/// more than a prefix can say, so have none.
+fn selector_prefix(selector: &str) -> Option<&str> {
+    if selector.contains(|c: char| "()|&:".contains(c))
+        || selector.split_whitespace().any(|part| part.starts_with('-'))
+    {
+        return None;
+    }
+    selector.split_whitespace().last()
+}
+
+/// A value of a property list, which is what a `.tmTheme` is.
+#[derive(Debug, Clone, PartialEq)]
+enum Plist {
+    Dict(Vec<(String, Plist)>),
+    Array(Vec<Plist>),
+    String(String),
+    /// Numbers, dates, data and booleans, which themes have no use for.
+    Other,
+}
+
+impl Plist {
+    fn get(&self, key: &str) -> Option<&Plist> {
+        match self {
+            Plist::Dict(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the XML of a property list. Only as much XML as property lists
+/// use is understood: elements, whose attributes are ignored, text with
+/// entities, comments, and the declaration and doctype.
+struct PlistReader<'a> {
+    rest: &'a str,
+}
+
+impl<'a> PlistReader<'a> {
+    fn document(mut self) -> Result<Plist, String> {
+        let value = self.value()?;
+        self.skip_markup();
+        if self.rest.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.unexpected("the end"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Plist, String> {
+        let (name, empty) = self.start_tag()?;
+        if empty {
+            return Ok(match name {
+                "dict" => Plist::Dict(Vec::new()),
+                "array" => Plist::Array(Vec::new()),
+                "string" => Plist::String(String::new()),
+                _ => Plist::Other,
+            });
+        }
+        let value = match name {
+            "plist" => self.value()?,
+            "dict" => {
+                let mut entries = Vec::new();
+                while !self.at_end_tag() {
+                    match self.start_tag()? {
+                        ("key", false) => (),
+                        _ => return Err("a dict's entries must each start with a key".to_owned()),
+                    }
+                    let key = self.text()?;
+                    self.end_tag("key")?;
+                    entries.push((key, self.value()?));
+                }
+                Plist::Dict(entries)
+            }
+            "array" => {
+                let mut values = Vec::new();
+                while !self.at_end_tag() {
+                    values.push(self.value()?);
+                }
+                Plist::Array(values)
+            }
+            "string" => Plist::String(self.text()?),
+            _ => {
+                self.text()?;
+                Plist::Other
+            }
+        };
+        self.end_tag(name)?;
+        Ok(value)
+    }
+
+    /// Skips whitespace, comments, and the declaration and doctype.
+    fn skip_markup(&mut self) {
+        loop {
+            self.rest = self.rest.trim_start();
+            let end = if self.rest.starts_with("<!--") {
+                "-->"
+            } else if self.rest.starts_with("<?") {
+                "?>"
+            } else if self.rest.starts_with("<!") {
+                ">"
+            } else {
+                return;
+            };
+            self.rest = match self.rest.find(end) {
+                Some(i) => &self.rest[i + end.len()..],
+                None => "",
+            };
+        }
+    }
+
+    /// Reads a start tag, as its name and whether it ends the element
+    /// too, as `<true/>` does.
+    fn start_tag(&mut self) -> Result<(&'a str, bool), String> {
+        self.skip_markup();
+        if !self.rest.starts_with('<') || self.rest.starts_with("</") {
+            return Err(self.unexpected("a start tag"));
+        }
+        let end = self.rest.find('>').ok_or_else(|| self.unexpected("a tag's `>`"))?;
+        let tag = &self.rest[1..end];
+        self.rest = &self.rest[end + 1..];
+        let empty = tag.ends_with('/');
+        let name = tag.trim_end_matches('/').split_whitespace().next().unwrap_or("");
+        Ok((name, empty))
+    }
+
+    fn end_tag(&mut self, name: &str) -> Result<(), String> {
+        self.skip_markup();
+        let tag = format!("</{}>", name);
+        match strip_prefix(self.rest, &tag) {
+            Some(rest) => {
+                self.rest = rest;
+                Ok(())
+            }
+            None => Err(self.unexpected(&tag)),
+        }
+    }
+
+    fn at_end_tag(&mut self) -> bool {
+        self.skip_markup();
+        self.rest.starts_with("</")
+    }
+
+    /// Reads the text up to the next tag, with its entities replaced.
+    fn text(&mut self) -> Result<String, String> {
+        let end = self.rest.find('<').unwrap_or(self.rest.len());
+        let text = unescape_xml(&self.rest[..end])?;
+        self.rest = &self.rest[end..];
+        Ok(text)
+    }
+
+    fn unexpected(&self, expected: &str) -> String {
+        if self.rest.is_empty() {
+            return format!("expected {}, found the end", expected);
+        }
+        let found: String = self.rest.chars().take(20).collect();
+        format!("expected {}, found {:?}", expected, found)
+    }
+}
+
+/// `text` with its XML entities replaced by what they stand for.
+fn unescape_xml(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let len = rest[amp..].find(';').ok_or_else(|| format!("unended entity in {:?}", text))?;
+        let entity = &rest[amp + 1..amp + len];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => {
+                let code = if let Some(hex) = strip_prefix(entity, "#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(decimal) = strip_prefix(entity, "#") {
+                    decimal.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(std::char::from_u32)
+            }
+        };
+        out.push(c.ok_or_else(|| format!("unknown entity: &{};", entity))?);
+        rest = &rest[amp + len + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Reads the theme at `path`: a `.tmTheme`, or otherwise a palette file,
+/// applied over the built-in palette as `xi-lang-tools highlight --palette`
+/// does.
+fn read_theme(path: &Path) -> Result<Theme, String> {
+    let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let is_tmtheme =
+        path.extension().map_or(false, |ext| ext.to_string_lossy().eq_ignore_ascii_case("tmTheme"));
+    let theme = if is_tmtheme {
+        Theme::from_tmtheme(&source)
+    } else {
+        let mut palette = Palette::default();
+        palette.apply_toml(&source).map(|()| Theme::from_palette(&palette))
+    };
+    theme.map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// Runs the command with the arguments after `export-html`. The page is
+/// printed, unless `--output` names a file for it.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut language = None;
+    let mut theme = None;
+    let mut output = None;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--language" => language = Some(args.next().ok_or(USAGE)?.as_str()),
+            "--theme" => theme = Some(read_theme(Path::new(args.next().ok_or(USAGE)?))?),
+            "--output" => output = Some(args.next().ok_or(USAGE)?),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(Path::new(arg)),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+    let theme = theme.unwrap_or_else(|| Theme::from_palette(&Palette::default()));
+
+    let highlighted = highlight::highlight_file(path, language)?;
+    let title =
+        path.file_name().map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy());
+    let html = to_html(&highlighted, &theme, &title);
+    match output {
+        Some(output) => fs::write(output, html).map_err(|err| format!("{}: {}", output, err)),
+        None => {
+            print!("{}", html);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlight::LineSpan;
+    use std::env;
+    use std::path::PathBuf;
+
+    /// Tags that have no end tag.
+    const VOID_ELEMENTS: &[&str] = &["meta"];
+
+    /// The entities `push_escaped` writes.
+    const ENTITIES: &[&str] = &["&amp;", "&lt;", "&gt;", "&quot;", "&#39;"];
+
+    fn testdata(path: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(path)
+    }
+
+    fn scope(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    /// Text, or an attribute's value, in which nothing is markup but
+    /// entities `push_escaped` writes.
+    fn check_text(text: &str) -> Result<(), String> {
+        if text.contains(['<', '>', '"']) {
+            return Err(format!("markup in text: {:?}", text));
+        }
+        for (i, _) in text.match_indices('&') {
+            if !ENTITIES.iter().any(|entity| text[i..].starts_with(entity)) {
+                return Err(format!("unknown entity in {:?}", text));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `html`'s elements are closed, and in the order they
+    /// were opened, and that its text and attributes are escaped.
+    fn check_well_formed(html: &str) -> Result<(), String> {
+        let mut open = Vec::new();
+        let mut rest = html;
+        while !rest.is_empty() {
+            let next = rest.find('<').unwrap_or(rest.len());
+            check_text(&rest[..next])?;
+            rest = &rest[next..];
+            if rest.is_empty() {
+                break;
+            }
+            let end = rest.find('>').ok_or("a tag isn't closed")?;
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with('!') {
+                continue;
+            }
+            if tag.contains('<') || tag.matches('"').count() % 2 != 0 {
+                return Err(format!("bad tag: <{}>", tag));
+            }
+            for value in tag.split('"').skip(1).step_by(2) {
+                check_text(value)?;
+            }
+            let name = tag.trim_start_matches('/').split_whitespace().next().unwrap_or("");
+            if tag.starts_with('/') {
+                match open.pop() {
+                    Some(open) if open == name => (),
+                    open => return Err(format!("</{}> closes {:?}", name, open)),
+                }
+            } else if !VOID_ELEMENTS.contains(&name) {
+                open.push(name);
+            }
+        }
+        if open.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("never closed: {:?}", open))
+        }
+    }
+
+    /// What is in the page's `<pre>`.
+    fn pre(html: &str) -> &str {
+        let tag = "<pre class=\"xi-lang\">\n";
+        let start = html.find(tag).unwrap() + tag.len();
+        &html[start..html.rfind("</pre>").unwrap()]
+    }
+
+    #[test]
+    fn fixture_matches_its_snapshot() {
+        let highlighted = highlight::highlight_file(&testdata("rust/items.rs"), None).unwrap();
+        let html = to_html(&highlighted, &Theme::from_palette(&Palette::default()), "items.rs");
+        check_well_formed(&html).unwrap();
+
+        let snapshot = testdata("export/items.rs.html");
+        if env::var_os("UPDATE_SNAPSHOTS").map_or(false, |value| value == "1") {
+            fs::write(&snapshot, &html).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&snapshot).unwrap();
+        assert!(
+            html == expected,
+            "the page for rust/items.rs isn't {}; run with UPDATE_SNAPSHOTS=1 if that's meant",
+            snapshot.display()
+        );
+    }
+
+    #[test]
+    fn adjacent_spans_share_outer_scopes() {
+        let text = "x = \"a\\n\";\n\nz\n";
+        let scopes = vec![
+            scope(&["source.rust"]),
+            scope(&["source.rust", "string.quoted.double.rust"]),
+            scope(&["source.rust", "string.quoted.double.rust", "constant.character.escape"]),
+        ];
+        let span = |line, start, end, scope_id| LineSpan { line, start, end, scope_id };
+        let spans = vec![
+            span(0, 0, 4, 0),
+            span(0, 4, 6, 1),
+            span(0, 6, 8, 2),
+            span(0, 8, 9, 1),
+            span(0, 10, 11, 0),
+            span(2, 0, 2, 0),
+        ];
+        let highlighted =
+            Highlighted { text: text.to_owned(), language: "Rust".to_owned(), scopes, spans };
+        let html = to_html(&highlighted, &Theme::default(), "a.rs");
+        check_well_formed(&html).unwrap();
+        let source = "<span class=\"source source.rust\">";
+        let string = "<span class=\"string string.quoted string.quoted.double \
+                      string.quoted.double.rust\">";
+        let escape = "<span class=\"constant constant.character constant.character.escape\">";
+        let expected = format!(
+            "{source}x = {string}&quot;a{escape}\\n</span>&quot;</span></span>;\n\n\
+             {source}z</span>\n",
+            source = source,
+            string = string,
+            escape = escape
+        );
+        assert_eq!(pre(&html), expected);
+    }
+
+    #[test]
+    fn markup_in_text_and_scopes_is_escaped() {
+        let text = "<a href='x'>&amp;</a>\n";
+        let scopes = vec![scope(&["source.<b>\"x\"&'y'", "evil.</span><script> x"])];
+        let spans = vec![LineSpan { line: 0, start: 0, end: 21, scope_id: 0 }];
+        let highlighted =
+            Highlighted { text: text.to_owned(), language: "Test".to_owned(), scopes, spans };
+        let mut theme = Theme::default();
+        let red = CssStyle { color: Some("#cd0000".to_owned()), ..CssStyle::default() };
+        theme.styles.insert("evil.</span><script>", red);
+        let html = to_html(&highlighted, &theme, "</title><b>");
+        check_well_formed(&html).unwrap();
+        assert!(html.contains("<title>&lt;/title&gt;&lt;b&gt;</title>"), "{}", html);
+        assert!(html.contains("&lt;a href=&#39;x&#39;&gt;&amp;amp;&lt;/a&gt;"), "{}", html);
+        assert!(html.contains("class=\"source source.&lt;b&gt;&quot;x&quot;&amp;&#39;y&#39;\""));
+        // whitespace in a name would split it into classes
+        assert!(html.contains("class=\"evil evil.&lt;/span&gt;&lt;script&gt;_x\""), "{}", html);
+        let rule = "pre.xi-lang .evil\\.\\3c \\/span\\3e \\3c script\\3e  { color: #cd0000; }";
+        assert!(html.contains(rule), "{}", html);
+    }
+
+    #[test]
+    fn css_identifiers_are_escaped() {
+        assert_eq!(css_identifier("string.quoted"), "string\\.quoted");
+        assert_eq!(css_identifier("a_b-c9"), "a_b-c9");
+        assert_eq!(css_identifier("3d"), "\\33 d");
+        assert_eq!(css_identifier("-3d"), "-\\33 d");
+        assert_eq!(css_identifier("a b&c"), "a\\20 b\\26 c");
+        assert_eq!(css_identifier("naïve"), "naïve");
+    }
+
+    #[test]
+    fn stylesheets_put_longer_prefixes_last() {
+        let mut palette = Palette::default();
+        palette.apply_toml("\"string.quoted\" = \"plain\"\n").unwrap();
+        let css = Theme::from_palette(&palette).stylesheet();
+        let rule = |class: &str| css.find(&format!("pre.xi-lang .{} ", class)).unwrap();
+        assert!(rule("string") < rule("string\\.quoted"), "{}", css);
+        assert!(rule("constant") < rule("constant\\.character\\.escape"), "{}", css);
+        assert!(css.contains(
+            "pre.xi-lang .string\\.quoted { font-weight: normal; font-style: normal; \
+             text-decoration: none; }"
+        ));
+        assert!(css.contains("pre.xi-lang .comment { font-weight: normal; "), "{}", css);
+        assert!(css.contains("text-decoration: none; opacity: 0.6; }"), "{}", css);
+        assert!(css.contains("pre.xi-lang .invalid { color: #cd0000; font-weight: bold; "));
+        assert!(!css.contains("pre.xi-lang {"), "{}", css);
+    }
+
+    #[test]
+    fn palette_colors_are_xterm_colors() {
+        assert_eq!(xterm_color(1), "#cd0000");
+        assert_eq!(xterm_color(12), "#5c5cff");
+        assert_eq!(xterm_color(16), "#000000");
+        assert_eq!(xterm_color(208), "#ff8700");
+        assert_eq!(xterm_color(231), "#ffffff");
+        assert_eq!(xterm_color(244), "#808080");
+        assert_eq!(xterm_color(255), "#eeeeee");
+    }
+
+    #[test]
+    fn tmthemes_are_read() {
+        let source = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN"
+  "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>name</key>
+    <string>Test &amp; Theme</string>
+    <key>settings</key>
+    <array>
+        <dict>
+            <key>settings</key>
+            <dict>
+                <key>background</key>
+                <string>#1E1E1E</string>
+                <key>foreground</key>
+                <string>#D4D4D4</string>
+                <key>caret</key>
+                <string>#FFFFFF</string>
+            </dict>
+        </dict>
+        <!-- a comment, with <dict> in -->
+        <dict>
+            <key>name</key>
+            <string>Comments</string>
+            <key>scope</key>
+            <string>comment, punctuation.definition.comment</string>
+            <key>settings</key>
+            <dict>
+                <key>fontStyle</key>
+                <string>italic</string>
+                <key>foreground</key>
+                <string>#6A9955</string>
+            </dict>
+        </dict>
+        <dict>
+            <key>scope</key>
+            <string>meta.tag string.quoted, source - comment, (a | b)</string>
+            <key>settings</key>
+            <dict>
+                <key>foreground</key>
+                <string>#ce9178</string>
+            </dict>
+        </dict>
+        <dict>
+            <key>scope</key>
+            <string>invalid</string>
+            <key>settings</key>
+            <dict>
+                <key>background</key>
+                <string>#f00</string>
+                <key>fontStyle</key>
+                <string/>
+            </dict>
+        </dict>
+    </array>
+    <key>semanticHighlighting</key>
+    <true/>
+</dict>
+</plist>
+"#;
+        let theme = Theme::from_tmtheme(source).unwrap();
+        assert_eq!(theme.foreground, Some("#d4d4d4".to_owned()));
+        assert_eq!(theme.background, Some("#1e1e1e".to_owned()));
+        let style = |names: &[&str]| theme.styles.lookup(&scope(names)).cloned();
+        let comment = CssStyle {
+            color: Some("#6a9955".to_owned()),
+            font: Some(Font { italic: true, ..Font::default() }),
+            ..CssStyle::default()
+        };
+        assert_eq!(style(&["source.rust", "comment.line.rust"]), Some(comment.clone()));
+        assert_eq!(style(&["punctuation.definition.comment.rust"]), Some(comment));
+        let string = style(&["source.rust", "string.quoted.double.rust"]).unwrap();
+        assert_eq!(string.color, Some("#ce9178".to_owned()));
+        // the exclusion and the group are left out
+        assert_eq!(style(&["source.rust"]), None);
+        assert_eq!(style(&["a"]), None);
+        let invalid = CssStyle {
+            background: Some("#f00".to_owned()),
+            font: Some(Font::default()),
+            ..CssStyle::default()
+        };
+        assert_eq!(style(&["invalid.illegal"]), Some(invalid));
+
+        let css = theme.stylesheet();
+        assert!(css.starts_with("pre.xi-lang { color: #d4d4d4; background-color: #1e1e1e; }\n"));
+        assert!(css.contains("pre.xi-lang .string\\.quoted { color: #ce9178; }\n"), "{}", css);
+    }
+
+    #[test]
+    fn bad_tmthemes_say_why() {
+        let theme = |settings: &str| {
+            Theme::from_tmtheme(&format!(
+                "<plist><dict><key>settings</key><array>{}</array></dict></plist>",
+                settings
+            ))
+        };
+        assert!(theme("").unwrap().styles.iter().next().is_none());
+        let bad_color = "<dict><key>scope</key><string>string</string>\
+                         <key>settings</key><dict><key>foreground</key>\
+                         <string>red;}</string></dict></dict>";
+        assert_eq!(theme(bad_color).unwrap_err(), "string: foreground: not a color: \"red;}\"");
+        assert!(theme("<dict><string>x</string></dict>").is_err());
+        assert!(theme("<dict>").is_err());
+        assert!(theme("<string>&bogus;</string>").unwrap_err().contains("&bogus;"));
+        assert!(Theme::from_tmtheme("<plist><array/></plist>").is_err());
+        assert!(Theme::from_tmtheme("<plist><dict/></plist> trailing").is_err());
+        assert!(Theme::from_tmtheme("").is_err());
+    }
+
+    #[test]
+    fn xml_entities_are_replaced() {
+        assert_eq!(unescape_xml("a &lt;&amp;&gt; &#65;&#x42;&quot;&apos;").unwrap(), "a <&> AB\"'");
+        assert!(unescape_xml("a & b").is_err());
+        assert!(unescape_xml("&#xD800;").is_err());
+    }
+
+    #[test]
+    fn themes_are_read_by_their_extension() {
+        let err = read_theme(&testdata("rust/items.rs")).unwrap_err();
+        assert!(err.contains("items.rs"), "{}", err);
+        let args = |args: &[&str]| run(&args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>());
+        assert_eq!(args(&[]), Err(USAGE.to_owned()));
+        assert_eq!(args(&["--theme"]), Err(USAGE.to_owned()));
+        assert_eq!(args(&["a.rs", "b.rs"]), Err(USAGE.to_owned()));
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Randomized tests of every parser, of highlighting documents as they
+//! are edited, and of highlighting them in slices of any size. They run
+//! with the other tests on a fixed seed, so they fail the same way every
+//! time; to search further, run them with another seed and more
+//! iterations:
+//!
+//! ```text
+//! FUZZ_SEED=7 FUZZ_ITERATIONS=20000 cargo test --release fuzz
+//! ```
+//!
+//! The files in `fuzz/corpus/lines/` are also lexed, line by line, as
+//! arbitrary bytes read as UTF-8 lossily, and the ones in
+//! `fuzz/corpus/documents/` are edited at random on every run. A failure
+//! prints the input; add it to the corpus once it is fixed, so that it
+//! stays fixed.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use xi_rope::{Interval, Rope, RopeDelta};
+
+use crate::parser::Parser;
+use crate::registry::ParserRegistry;
+use crate::scope_tracker::Scope;
+use crate::slice::SliceSize;
+use crate::text::lines_with_endings;
+use crate::view::mock::{resolved_scopes, run_idle, MockView};
+use crate::{ViewState, MAX_LINE_PIECE, MAX_PENDING_BYTES, MAX_PENDING_SPANS};
+
+/// The languages fuzzed: every parser, and every overlay over a host.
+const LANGUAGES: &[&str] = &[
+    "Rust",
+    "Plain Text",
+    "Rust (Jinja)",
+    "Plain Text (Handlebars)",
+    "Rust (Literate)",
+    "Rust (Indented)",
+];
+
+/// Pieces of text that mean something to one parser or another, which
+/// random bytes would take a long time to come up with.
+const PIECES: &[&str] = &[
+    "fn",
+    "let",
+    " ",
+    "    ",
+    "\t",
+    "\r",
+    "\n",
+    "\n\n",
+    "x_1",
+    "0x1f",
+    "1.5e3",
+    "'a",
+    "'",
+    "'\\n'",
+    "\"",
+    "\\",
+    "\\\"",
+    "r#\"",
+    "\"#",
+    "/*",
+    "*/",
+    "//",
+    "///",
+    "{",
+    "}",
+    "{{",
+    "}}",
+    "{%",
+    "%}",
+    "{#",
+    "#}",
+    ">",
+    "> ",
+    "#[",
+    "]",
+    "(",
+    ")",
+    ";",
+    "é",
+    "λ",
+    "\u{1f600}",
+    "\u{2014}",
+    "\u{feff}",
+    "\u{b}",
+    "\0",
+];
+
+/// How many steps a parser may take per byte of a line before it is
+/// taken to be going round in circles.
+const MAX_STEPS_PER_BYTE: usize = 4;
+
+/// A linear congruential generator, as in Knuth's MMIX.
+struct Rng(u64);
+
+impl Rng {
+    /// A number below `bound`, which must not be 0.
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 =
+            self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 33) as usize % bound
+    }
+
+    /// Up to `max_pieces` pieces and random bytes, read as UTF-8 lossily.
+    fn text(&mut self, max_pieces: usize) -> String {
+        let mut bytes = Vec::new();
+        for _ in 0..self.below(max_pieces + 1) {
+            if self.below(4) == 0 {
+                bytes.push(self.below(256) as u8);
+            } else {
+                bytes.extend_from_slice(PIECES[self.below(PIECES.len())].as_bytes());
+            }
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// The files in `fuzz/corpus/<dir>`, read as UTF-8 lossily.
+fn corpus(dir: &str) -> Vec<(String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus").join(dir);
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| {
+            let text = String::from_utf8_lossy(&fs::read(&path).unwrap()).into_owned();
+            (path.display().to_string(), text)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn create(language: &str) -> Box<dyn Parser> {
+    ParserRegistry::default().create(language).unwrap()
+}
+
+/// Lexes `text` a line at a time with `parser`, checking every step.
+fn check_parser(parser: &mut dyn Parser, text: &str) -> Result<(), String> {
+    let mut state = parser.initial_state();
+    for line in lines_with_endings(text) {
+        let mut i = 0;
+        let mut steps = 0;
+        while i < line.len() {
+            let rest = &line[i..];
+            let (prevlen, s0, len, s1) = parser.parse(rest, state);
+            let end = prevlen.checked_add(len).filter(|&end| end <= rest.len());
+            let end =
+                end.ok_or_else(|| format!("{} + {} is past the end of {:?}", prevlen, len, rest))?;
+            if !rest.is_char_boundary(prevlen) || !rest.is_char_boundary(end) {
+                return Err(format!("{} + {} splits a character of {:?}", prevlen, len, rest));
+            }
+            // the scopes are asked for every token
+            parser.get_scope_for_state(state);
+            parser.get_scope_for_state(s0);
+            steps += 1;
+            if steps > (line.len() + 1) * MAX_STEPS_PER_BYTE {
+                return Err(format!("no progress at {:?}", rest));
+            }
+            if end == 0 && s1 == state {
+                // stuck; the plugin skips a character
+                i += rest.chars().next().map_or(1, char::len_utf8);
+            } else {
+                i += end;
+                state = s1;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn parsers_survive_arbitrary_lines() {
+    let mut rng = Rng(env_number("FUZZ_SEED", 1));
+    let iterations = env_number("FUZZ_ITERATIONS", 200);
+    let mut inputs = corpus("lines");
+    for i in 0..iterations {
+        inputs.push((format!("iteration {}", i), rng.text(64)));
+    }
+    for language in LANGUAGES {
+        // one parser for all the inputs, as the plugin shares one between
+        // documents
+        let mut parser = create(language);
+        for (name, text) in &inputs {
+            if let Err(err) = check_parser(&mut *parser, text) {
+                panic!("{}, {}: {}\ninput: {:?}", language, name, err, text);
+            }
+        }
+    }
+}
+
+fn open(language: &str, text: &str) -> (ViewState, MockView) {
+    let mut view = MockView::new(language, text);
+    let mut view_state = ViewState::new(Arc::default());
+    view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+    run_idle(&mut view_state, &mut view);
+    (view_state, view)
+}
+
+/// A random edit of whole lines of `text`: some lines, maybe none,
+/// replaced with random ones, maybe none.
+fn line_edit(rng: &mut Rng, text: &str) -> (Interval, String) {
+    let starts: Vec<usize> =
+        std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect();
+    let first = rng.below(starts.len());
+    let last = first + rng.below((starts.len() - first).min(3));
+    let start = starts[first];
+    let end = starts.get(last + 1).cloned().unwrap_or(text.len());
+    let lines: String = (0..rng.below(3)).map(|_| rng.text(8).replace('\n', "") + "\n").collect();
+    (Interval::new(start, end), lines)
+}
+
+/// Highlights `text` and edits it `edits` times, checking after each edit
+/// that the spans are what highlighting the new text from scratch gives.
+fn check_edits(rng: &mut Rng, language: &str, text: &str, edits: usize) -> Result<(), String> {
+    let (mut view_state, mut view) = open(language, text);
+    for _ in 0..edits {
+        let before = view.text().to_string();
+        let (iv, new) = line_edit(rng, &before);
+        if iv.is_empty() && new.is_empty() {
+            // core never sends an edit that changes nothing
+            continue;
+        }
+        let delta = RopeDelta::simple_edit(iv, Rope::from(new.as_str()), before.len());
+        view.apply_edit(&delta);
+        view_state.update(&mut view, Some(&delta), false);
+        run_idle(&mut view_state, &mut view);
+
+        let after = view.text().to_string();
+        let describe = || format!("replacing {:?} with {:?} in {:?}", iv, new, before);
+        for (iv, _) in view.styles.iter() {
+            if !after.is_char_boundary(iv.start()) || !after.is_char_boundary(iv.end()) {
+                return Err(format!("span {:?} splits a character, {}", iv, describe()));
+            }
+        }
+        let (_, scratch) = open(language, &after);
+        if resolved_scopes(&view) != resolved_scopes(&scratch) {
+            return Err(format!("not what highlighting from scratch gives, {}", describe()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn edits_match_highlighting_from_scratch() {
+    let mut rng = Rng(env_number("FUZZ_SEED", 1));
+    let iterations = env_number("FUZZ_ITERATIONS", 200) / 10;
+    let mut documents = corpus("documents");
+    for i in 0..iterations {
+        let lines: String = (0..rng.below(12)).map(|_| rng.text(12) + "\n").collect();
+        documents.push((format!("iteration {}", i), lines));
+    }
+    for language in LANGUAGES {
+        for (name, text) in &documents {
+            if let Err(err) = check_edits(&mut rng, language, text, 8) {
+                panic!("{}, {}: {}", language, name, err);
+            }
+        }
+    }
+}
+
+/// A way of cutting up the work of highlighting a document.
+#[derive(Debug, Clone, Copy)]
+struct Slicing {
+    /// Lines lexed an idle slice.
+    lines: usize,
+    /// How many spans, and how many bytes of them, are flushed at once.
+    spans: usize,
+    bytes: usize,
+    /// How much of a long line is lexed at a time.
+    piece: usize,
+}
+
+const WHOLE_FILE: Slicing = Slicing {
+    lines: std::usize::MAX,
+    spans: MAX_PENDING_SPANS,
+    bytes: MAX_PENDING_BYTES,
+    piece: MAX_LINE_PIECE,
+};
+
+/// The slicings compared with the whole file at once.
+const SLICINGS: &[Slicing] = &[
+    Slicing { lines: 1, ..WHOLE_FILE },
+    Slicing { lines: 7, ..WHOLE_FILE },
+    Slicing { lines: 50, ..WHOLE_FILE },
+    Slicing { lines: 1, spans: 1, bytes: 1, piece: MAX_LINE_PIECE },
+    Slicing { lines: 7, spans: 16, bytes: 40, piece: 16 },
+    Slicing { lines: 50, spans: 3, bytes: MAX_PENDING_BYTES, piece: 5 },
+    Slicing { piece: 1, ..WHOLE_FILE },
+];
+
+/// A random document: short lines, blank ones and the odd long one, and
+/// a newline at the end or not.
+fn document(rng: &mut Rng) -> String {
+    let lines: Vec<String> = (0..rng.below(20))
+        .map(|_| match rng.below(8) {
+            0 => String::new(),
+            1 => rng.text(200),
+            _ => rng.text(12),
+        })
+        .map(|line| line.replace('\n', ""))
+        .collect();
+    let mut text = lines.join("\n");
+    if rng.below(4) != 0 {
+        text.push('\n');
+    }
+    text
+}
+
+/// The scope at each byte once `text` is highlighted in `slicing`.
+fn highlight_sliced(language: &str, text: &str, slicing: Slicing) -> Vec<Option<Scope>> {
+    let mut view = MockView::new(language, text);
+    let mut view_state = ViewState::new(Arc::default());
+    // slices end by their lines alone
+    view_state.idle_budget = Duration::from_secs(60);
+    view_state.slice = SliceSize::fixed(slicing.lines);
+    view_state.max_pending_spans = slicing.spans;
+    view_state.max_pending_bytes = slicing.bytes;
+    view_state.max_line_piece = slicing.piece;
+    view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+    run_idle(&mut view_state, &mut view);
+    resolved_scopes(&view)
+}
+
+#[test]
+fn highlighting_is_independent_of_slicing() {
+    let mut rng = Rng(env_number("FUZZ_SEED", 1));
+    let iterations = env_number("FUZZ_ITERATIONS", 200) / 10;
+    let mut documents = corpus("documents");
+    for i in 0..iterations {
+        documents.push((format!("iteration {}", i), document(&mut rng)));
+    }
+    for language in LANGUAGES {
+        for (name, text) in &documents {
+            let whole = highlight_sliced(language, text, WHOLE_FILE);
+            if let Some(i) = whole.iter().position(Option::is_none) {
+                panic!("{}, {}: byte {} has no span\ninput: {:?}", language, name, i, text);
+            }
+            for &slicing in SLICINGS {
+                if highlight_sliced(language, text, slicing) != whole {
+                    panic!("{}, {}: {:?} differs\ninput: {:?}", language, name, slicing, text);
+                }
+            }
+        }
+    }
+}
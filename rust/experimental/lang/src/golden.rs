@@ -0,0 +1,279 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshot tests of the spans each language's parser gives the fixtures
+//! in `testdata/<lang>/`. Each fixture's spans are kept next to it in a
+//! `.expected` file, a span a line: its offset, its length, its scope
+//! and, for reading, its text. Run the tests with `UPDATE_SNAPSHOTS=1` to
+//! write the spans out again after a change meant to alter them.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use xi_rope::{Interval, Rope, RopeDelta};
+
+use crate::registry::ParserRegistry;
+use crate::slice::SliceSize;
+use crate::view::mock::{run_idle, MockView};
+use crate::ViewState;
+
+/// Each directory under `testdata/`, and the language its fixtures are
+/// highlighted as.
+pub(crate) const LANGUAGES: &[(&str, &str)] = &[
+    ("rust", "Rust"),
+    ("plaintext", "Plain Text"),
+    ("jinja", "Rust (Jinja)"),
+    ("literate", "Rust (Literate)"),
+    ("indented", "Rust (Indented)"),
+    ("ini", "INI"),
+];
+
+/// Directories under `testdata/` that hold something other than
+/// fixtures: the pages `export` snapshots, the sessions `tests/commands.rs`
+/// plays over the protocol, and the editing sessions `testing` replays.
+const OTHER_TESTDATA: &[&str] = &["export", "protocol", "sessions"];
+
+const EXPECTED_SUFFIX: &str = ".expected";
+
+/// A span as core ends up with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Span {
+    start: usize,
+    len: usize,
+    /// The scope stack, outermost first, separated by spaces.
+    scope: String,
+}
+
+fn testdata() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata")
+}
+
+/// The fixtures for the language in `dir`, in order.
+pub(crate) fn fixtures(dir: &str) -> Vec<PathBuf> {
+    let entries = fs::read_dir(testdata().join(dir)).unwrap();
+    let mut fixtures: Vec<_> = entries
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !path.to_string_lossy().ends_with(EXPECTED_SUFFIX))
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+fn expected_path(fixture: &Path) -> PathBuf {
+    let mut path = fixture.as_os_str().to_owned();
+    path.push(EXPECTED_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// The spans core has for the view. Empty spans color nothing, so are
+/// left out.
+fn spans(view: &MockView) -> Vec<Span> {
+    view.styles
+        .iter()
+        .filter(|(iv, _)| !iv.is_empty())
+        .map(|(iv, &id)| Span {
+            start: iv.start(),
+            len: iv.size(),
+            scope: view.scopes[id as usize].join(" "),
+        })
+        .collect()
+}
+
+/// Highlights `text` as the plugin would: a line an idle slice, so that
+/// every line is picked up from the state cache, and then again after a
+/// byte is typed at the start and taken back, so that the lines after it
+/// are lexed again until their states converge on the ones cached.
+/// Returns the spans from scratch and the spans after the edits.
+fn highlight(language: &str, text: &str) -> (Vec<Span>, Vec<Span>) {
+    let mut view = MockView::new(language, text);
+    let mut view_state = ViewState::new(Arc::default());
+    view_state.slice = SliceSize::fixed(1);
+    view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+    run_idle(&mut view_state, &mut view);
+    let from_scratch = spans(&view);
+
+    for &(iv, new) in &[(Interval::new(0, 0), "x"), (Interval::new(0, 1), "")] {
+        let delta = RopeDelta::simple_edit(iv, Rope::from(new), view.text().len());
+        view.apply_edit(&delta);
+        view_state.update(&mut view, Some(&delta), false);
+        run_idle(&mut view_state, &mut view);
+    }
+    (from_scratch, spans(&view))
+}
+
+/// The snapshot of `spans`, a span a line.
+fn format_spans(text: &str, spans: &[Span]) -> String {
+    let mut out = String::new();
+    for span in spans {
+        let source = &text[span.start..span.start + span.len];
+        writeln!(out, "{}\t{}\t{}\t{:?}", span.start, span.len, span.scope, source).unwrap();
+    }
+    out
+}
+
+/// The spans in a snapshot. The text of each is only there for reading,
+/// and is ignored.
+fn parse_spans(snapshot: &str) -> Result<Vec<Span>, String> {
+    let mut spans = Vec::new();
+    for (i, line) in snapshot.lines().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let number = |i: usize| fields.get(i).and_then(|field| field.parse().ok());
+        let span = match (number(0), number(1), fields.get(2).cloned()) {
+            (Some(start), Some(len), Some(scope)) => Span { start, len, scope: scope.to_owned() },
+            _ => return Err(format!("line {} of the snapshot is malformed: {:?}", i + 1, line)),
+        };
+        spans.push(span);
+    }
+    Ok(spans)
+}
+
+/// The scope at each byte of a document of `len` bytes.
+fn scope_at_each_byte(spans: &[Span], len: usize) -> Vec<Option<&str>> {
+    let mut scopes = vec![None; len];
+    for span in spans {
+        let end = (span.start + span.len).min(len);
+        for slot in scopes.iter_mut().take(end).skip(span.start) {
+            *slot = Some(span.scope.as_str());
+        }
+    }
+    scopes
+}
+
+/// Describes the first line where `actual` differs from `expected`, with
+/// the differing bytes of it marked and the scopes on both sides, or
+/// `None` if they don't differ.
+fn diff(path: &Path, text: &str, expected: &[Span], actual: &[Span]) -> Option<String> {
+    let first = match expected.iter().zip(actual).position(|(e, a)| e != a) {
+        Some(i) => i,
+        None if expected.len() == actual.len() => return None,
+        None => expected.len().min(actual.len()),
+    };
+    let offset = match (expected.get(first), actual.get(first)) {
+        (Some(e), Some(a)) => e.start.min(a.start),
+        (Some(span), None) | (None, Some(span)) => span.start,
+        (None, None) => unreachable!(),
+    };
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+    let line_num = text[..line_start].matches('\n').count() + 1;
+
+    // the bytes of the line whose scopes differ, or failing that, where
+    // the spans are only split differently, the first differing span
+    let expected_scopes = scope_at_each_byte(expected, text.len());
+    let actual_scopes = scope_at_each_byte(actual, text.len());
+    let differing: Vec<usize> =
+        (line_start..line_end).filter(|&i| expected_scopes[i] != actual_scopes[i]).collect();
+    let (start, end, at) = match (differing.first(), differing.last()) {
+        (Some(&start), Some(&end)) => (start, end + 1, start),
+        _ => {
+            let span = expected.get(first).or_else(|| actual.get(first)).unwrap();
+            let end = (span.start + span.len).min(line_end).max(offset);
+            (offset, end, offset)
+        }
+    };
+
+    let line = &text[line_start..line_end];
+    let padding: String =
+        text[line_start..start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let carets = "^".repeat(text[start..end].chars().count().max(1));
+    let expected_scope = expected_scopes.get(at).and_then(|&scope| scope).unwrap_or("-");
+    let actual_scope = actual_scopes.get(at).and_then(|&scope| scope).unwrap_or("-");
+    let (expected_span, actual_span) = (expected.get(first), actual.get(first));
+    let mut out = String::new();
+    writeln!(out, "{}:{}: spans differ", path.display(), line_num).unwrap();
+    writeln!(out, "    {}", line).unwrap();
+    writeln!(out, "    {}{}", padding, carets).unwrap();
+    writeln!(out, "    expected: {}", expected_scope).unwrap();
+    writeln!(out, "    actual:   {}", actual_scope).unwrap();
+    writeln!(out, "    span {}: {:?} became {:?}", first, expected_span, actual_span).unwrap();
+    Some(out)
+}
+
+#[test]
+fn languages_match_their_snapshots() {
+    let update = env::var_os("UPDATE_SNAPSHOTS").map_or(false, |value| value == "1");
+    let mut failures = Vec::new();
+    for &(dir, language) in LANGUAGES {
+        let fixtures = fixtures(dir);
+        assert!(!fixtures.is_empty(), "no fixtures in testdata/{}", dir);
+        for path in fixtures {
+            let text = fs::read_to_string(&path).unwrap();
+            let (spans, edited) = highlight(language, &text);
+            if let Some(diff) = diff(&path, &text, &spans, &edited) {
+                failures.push(format!("after an edit and its undo, not converged:\n{}", diff));
+            }
+
+            let expected_path = expected_path(&path);
+            if update {
+                fs::write(&expected_path, format_spans(&text, &spans)).unwrap();
+                continue;
+            }
+            let snapshot = match fs::read_to_string(&expected_path) {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    failures.push(format!(
+                        "{}: {}; run with UPDATE_SNAPSHOTS=1 to write it",
+                        expected_path.display(),
+                        err
+                    ));
+                    continue;
+                }
+            };
+            match parse_spans(&snapshot) {
+                Ok(expected) => failures.extend(diff(&path, &text, &expected, &spans)),
+                Err(err) => failures.push(format!("{}: {}", expected_path.display(), err)),
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn every_testdata_directory_has_a_language() {
+    for entry in fs::read_dir(testdata()).unwrap() {
+        let name = entry.unwrap().file_name();
+        let name = name.to_string_lossy();
+        assert!(
+            LANGUAGES.iter().any(|&(dir, _)| dir == name) || OTHER_TESTDATA.contains(&&*name),
+            "testdata/{} isn't in LANGUAGES, so its fixtures are never checked",
+            name
+        );
+    }
+}
+
+#[test]
+fn diffs_point_at_the_differing_bytes() {
+    let text = "fn a() {}\n\tlet b;\n";
+    let span = |start, len, scope: &str| Span { start, len, scope: scope.to_owned() };
+    let expected = vec![span(0, 2, "source.rust keyword"), span(11, 3, "source.rust keyword")];
+    assert_eq!(diff(Path::new("a.rs"), text, &expected, &expected), None);
+
+    let actual = vec![span(0, 2, "source.rust keyword"), span(12, 2, "source.rust keyword")];
+    let diff = diff(Path::new("a.rs"), text, &expected, &actual).unwrap();
+    let lines: Vec<_> = diff.lines().collect();
+    assert_eq!(lines[0], "a.rs:2: spans differ");
+    assert_eq!(lines[1], "    \tlet b;");
+    assert_eq!(lines[2], "    \t^");
+    assert_eq!(lines[3], "    expected: source.rust keyword");
+    assert_eq!(lines[4], "    actual:   -");
+
+    let parsed = parse_spans(&format_spans(text, &expected)).unwrap();
+    assert_eq!(parsed, expected);
+    assert!(parse_spans("0\tx\tsource.rust\n").is_err());
+}
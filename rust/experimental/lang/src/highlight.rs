@@ -0,0 +1,398 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `xi-lang-tools highlight`: highlights a file the way the plugin does, through
+//! a mock view, and prints the spans core would end up with, so that the
+//! highlighter can be run without core. The spans are printed as JSON,
+//! or as the semantic tokens of the Language Server Protocol, or the file
+//! is printed colored by them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::language::detect;
+use crate::palette::{Palette, Style, ANSI_RESET};
+use crate::registry::ParserRegistry;
+use crate::scope_tracker::Scope;
+use crate::semantic_tokens;
+use crate::text::lines_with_endings;
+use crate::view::mock::MockView;
+use crate::view::HighlightView;
+use crate::{ViewState, DETECTION_LINES};
+
+/// Core hands back text a megabyte at a time.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+const USAGE: &str = "usage: xi-lang-tools highlight [--language LANGUAGE] \
+                     [--format json|ansi|semantic-tokens] [--palette FILE] FILE";
+
+/// The language of a file with each extension, as core names them.
+const EXTENSIONS: &[(&str, &str)] =
+    &[("rs", "Rust"), ("lrs", "Rust (Literate)"), ("txt", "Plain Text")];
+
+/// The extensions of templates, whose host language is named by the
+/// extension before, as in `page.rs.j2`.
+const DIALECT_EXTENSIONS: &[(&str, &str)] =
+    &[("j2", "Jinja"), ("jinja", "Jinja"), ("hbs", "Handlebars")];
+
+/// A span of a highlighted file. Spans never cross lines, as every line
+/// is lexed on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineSpan {
+    /// The line it is on, from 0.
+    pub line: usize,
+    /// Where it starts and ends in the line, in bytes.
+    pub start: usize,
+    pub end: usize,
+    pub scope_id: u32,
+}
+
+/// How the highlighted file is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// The spans, for other tools.
+    Json,
+    /// The text, with the escapes that color it in a terminal.
+    Ansi,
+    /// The spans as a language server would send them.
+    SemanticTokens,
+}
+
+/// A file as core would show it once highlighted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Highlighted {
+    pub text: String,
+    /// The language id it was highlighted as.
+    pub language: String,
+    /// The scopes announced, by id.
+    pub scopes: Vec<Scope>,
+    /// The spans, in order. Empty spans color nothing, so are left out.
+    pub spans: Vec<LineSpan>,
+}
+
+impl Highlighted {
+    /// The document `xi-lang-tools highlight --format json` prints.
+    pub fn to_json(&self) -> Value {
+        let spans: Vec<Value> = self
+            .spans
+            .iter()
+            .map(|span| {
+                json!({
+                    "line": span.line,
+                    "start": span.start,
+                    "end": span.end,
+                    "scope": self.scopes[span.scope_id as usize],
+                })
+            })
+            .collect();
+        json!({ "language": self.language, "scopes": self.scopes, "spans": spans })
+    }
+
+    /// The text, colored by `palette`. Text of the same style is colored
+    /// as one, and every line ends with the colors reset, so that it can
+    /// be paged.
+    pub fn to_ansi(&self, palette: &Palette) -> String {
+        let mut out = String::with_capacity(self.text.len() * 2);
+        let mut spans = self.spans.iter().peekable();
+        for (line_num, line) in lines_with_endings(&self.text).enumerate() {
+            let content = line.trim_end_matches(&['\r', '\n'][..]);
+            let mut escape = None;
+            let mut offset = 0;
+            while let Some(span) = spans.peek().cloned().filter(|span| span.line == line_num) {
+                spans.next();
+                let start = span.start.max(offset).min(content.len());
+                let end = span.end.min(content.len());
+                if start >= end {
+                    continue;
+                }
+                if start > offset {
+                    set_escape(&mut out, &mut escape, None);
+                    out.push_str(&content[offset..start]);
+                }
+                let style = palette.style(&self.scopes[span.scope_id as usize]);
+                set_escape(&mut out, &mut escape, style.and_then(Style::ansi));
+                out.push_str(&content[start..end]);
+                offset = end;
+            }
+            set_escape(&mut out, &mut escape, None);
+            out.push_str(&line[offset..]);
+        }
+        out
+    }
+}
+
+/// Switches from the escape in effect, `current`, to `escape`.
+fn set_escape(out: &mut String, current: &mut Option<String>, escape: Option<String>) {
+    if *current == escape {
+        return;
+    }
+    if current.is_some() {
+        out.push_str(ANSI_RESET);
+    }
+    if let Some(escape) = &escape {
+        out.push_str(escape);
+    }
+    *current = escape;
+}
+
+/// Runs the command with the arguments after `highlight`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut language = None;
+    let mut format = Format::Json;
+    let mut palette = Palette::default();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--language" => language = Some(args.next().ok_or(USAGE)?.as_str()),
+            "--format" => match args.next().map(String::as_str) {
+                Some("json") => format = Format::Json,
+                Some("ansi") => format = Format::Ansi,
+                Some("semantic-tokens") => format = Format::SemanticTokens,
+                Some(format) => return Err(format!("unknown format: {}", format)),
+                None => return Err(USAGE.to_owned()),
+            },
+            "--palette" => {
+                let path = args.next().ok_or(USAGE)?;
+                let source =
+                    fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+                palette.apply_toml(&source).map_err(|err| format!("{}: {}", path, err))?;
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+
+    let highlighted = highlight_file(Path::new(path), language)?;
+    match format {
+        Format::Json => {
+            let json = serde_json::to_string_pretty(&highlighted.to_json());
+            println!("{}", json.map_err(|err| err.to_string())?);
+        }
+        Format::Ansi => print!("{}", highlighted.to_ansi(&palette)),
+        // the data has five numbers to a token, too many for a line each
+        Format::SemanticTokens => {
+            let json = serde_json::to_string(&semantic_tokens::export(&highlighted));
+            println!("{}", json.map_err(|err| err.to_string())?);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the file at `path` and highlights it as `language`, or as the
+/// language its extension or first line suggests.
+pub fn highlight_file(path: &Path, language: Option<&str>) -> Result<Highlighted, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let registry = ParserRegistry::default();
+    let language = match language {
+        Some(language) => language.to_owned(),
+        None => guess_language(&registry, path, &text).ok_or_else(|| {
+            format!(
+                "{}: can't tell what language this is; pass --language \"Plain Text\" \
+                 to highlight it as plain text",
+                path.display()
+            )
+        })?,
+    };
+    if registry.resolve(&language).is_none() {
+        return Err(format!("unknown language: {}", language));
+    }
+    highlight(&registry, &language, &text)
+}
+
+/// The language of a file, by its extension or, failing that, its first
+/// lines.
+fn guess_language(registry: &ParserRegistry, path: &Path, text: &str) -> Option<String> {
+    if let Some(ext) = extension(path) {
+        if let Some(language) = extension_language(registry, ext) {
+            return Some(language.to_owned());
+        }
+        if let Some(&(_, dialect)) = DIALECT_EXTENSIONS.iter().find(|&&(e, _)| e == ext) {
+            let host = path.file_stem().and_then(|stem| extension(Path::new(stem)));
+            let host = host.and_then(|ext| extension_language(registry, ext));
+            let host = host.unwrap_or("Plain Text");
+            return Some(format!("{} ({})", host, dialect));
+        }
+    }
+    let head: String = lines_with_endings(text).take(DETECTION_LINES).collect();
+    detect::detect(&head).map(str::to_owned)
+}
+
+fn extension(path: &Path) -> Option<&str> {
+    path.extension().and_then(|ext| ext.to_str())
+}
+
+/// The language of files with the extension `ext`: ours, or else that of
+/// the syntax that claims it.
+fn extension_language<'a>(registry: &'a ParserRegistry, ext: &str) -> Option<&'a str> {
+    let ours = EXTENSIONS.iter().find(|&&(e, _)| e == ext).map(|&(_, language)| language);
+    ours.or_else(|| registry.language_for_extension(ext))
+}
+
+/// Highlights `text` from scratch, as when a document is opened.
+pub fn highlight(
+    registry: &ParserRegistry,
+    language: &str,
+    text: &str,
+) -> Result<Highlighted, String> {
+    let mut view = MockView::with_chunk_size(language, text, CHUNK_SIZE);
+    let mut view_state = ViewState::new(Arc::default());
+    view_state.do_highlighting(&mut view, registry, &mut HashMap::new());
+    while view.idle_scheduled {
+        view.idle_scheduled = false;
+        view_state.idle(&mut view);
+    }
+    if view.get_frontier().is_some() {
+        return Err("highlighting stopped before the end".to_owned());
+    }
+
+    let line_starts: Vec<usize> = lines_with_endings(text)
+        .scan(0, |offset, line| {
+            let start = *offset;
+            *offset += line.len();
+            Some(start)
+        })
+        .collect();
+    let mut line = 0;
+    let mut spans = Vec::new();
+    for (iv, &scope_id) in view.styles.iter().filter(|(iv, _)| !iv.is_empty()) {
+        while line + 1 < line_starts.len() && line_starts[line + 1] <= iv.start() {
+            line += 1;
+        }
+        let line_start = line_starts.get(line).cloned().unwrap_or(0);
+        spans.push(LineSpan {
+            line,
+            start: iv.start() - line_start,
+            end: iv.end() - line_start,
+            scope_id,
+        });
+    }
+    Ok(Highlighted {
+        text: text.to_owned(),
+        language: language.to_owned(),
+        scopes: view.scopes,
+        spans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn testdata(path: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(path)
+    }
+
+    fn scope_of<'a>(highlighted: &'a Highlighted, span: &LineSpan) -> &'a Scope {
+        &highlighted.scopes[span.scope_id as usize]
+    }
+
+    #[test]
+    fn spans_are_by_line() {
+        let text = "fn main() {\n    // done\n}\n";
+        let highlighted = highlight(&ParserRegistry::default(), "Rust", text).unwrap();
+        let lines: Vec<_> = lines_with_endings(text).collect();
+        let mut covered = vec![0; lines.len()];
+        for span in &highlighted.spans {
+            assert!(span.start < span.end && span.end <= lines[span.line].len(), "{:?}", span);
+            covered[span.line] += span.end - span.start;
+        }
+        let lengths: Vec<_> = lines.iter().map(|line| line.len()).collect();
+        assert_eq!(covered, lengths);
+
+        let comment = highlighted.spans.iter().find(|span| span.line == 1 && span.start == 4);
+        let comment = scope_of(&highlighted, comment.unwrap());
+        assert!(comment.iter().any(|scope| scope.starts_with("comment")), "{:?}", comment);
+
+        let json = highlighted.to_json();
+        assert_eq!(json["language"], "Rust");
+        assert_eq!(json["spans"].as_array().unwrap().len(), highlighted.spans.len());
+        assert_eq!(json["spans"][0]["line"], 0);
+        assert_eq!(json["spans"][0]["scope"], json!(scope_of(&highlighted, &highlighted.spans[0])));
+    }
+
+    #[test]
+    fn ansi_output_colors_by_scope() {
+        let text = "let s = \"a\\tb\"; // done\n42\n";
+        let highlighted = highlight(&ParserRegistry::default(), "Rust", text).unwrap();
+        let mut expected = String::new();
+        expected.push_str("\x1b[35mlet\x1b[0m s \x1b[35m=\x1b[0m ");
+        expected.push_str("\x1b[32m\"a\x1b[0m\x1b[36m\\t\x1b[0m\x1b[32mb\"\x1b[0m; ");
+        expected.push_str("\x1b[2m// done\x1b[0m\n");
+        expected.push_str("\x1b[33m42\x1b[0m\n");
+        assert_eq!(highlighted.to_ansi(&Palette::default()), expected);
+
+        let mut palette = Palette::default();
+        palette.apply_toml("keyword = \"plain\"\nstring = \"bold 208\"\n").unwrap();
+        let recolored = highlighted.to_ansi(&palette);
+        assert!(recolored.starts_with("let s = \x1b[1;38;5;208m\"a\x1b[0m"), "{:?}", recolored);
+    }
+
+    #[test]
+    fn fixtures_highlight_as_their_extension_says() {
+        let rust = highlight_file(&testdata("rust/items.rs"), None).unwrap();
+        assert_eq!(rust.language, "Rust");
+        assert!(rust.scopes.iter().any(|scope| scope.iter().any(|s| s.starts_with("keyword"))));
+        let text = highlight_file(&testdata("plaintext/notes.txt"), None).unwrap();
+        assert_eq!(text.language, "Plain Text");
+        let literate = highlight_file(&testdata("literate/program.lrs"), None).unwrap();
+        assert_eq!(literate.language, "Rust (Literate)");
+
+        // the flag wins
+        let as_text = highlight_file(&testdata("rust/items.rs"), Some("Plain Text")).unwrap();
+        assert_eq!(as_text.language, "Plain Text");
+        assert_ne!(as_text.scopes, rust.scopes);
+    }
+
+    #[test]
+    fn languages_are_guessed() {
+        let registry = ParserRegistry::default();
+        let guess = |path: &str, text: &str| guess_language(&registry, Path::new(path), text);
+        assert_eq!(guess("a/b.rs", ""), Some("Rust".to_owned()));
+        assert_eq!(guess("page.rs.j2", ""), Some("Rust (Jinja)".to_owned()));
+        assert_eq!(guess("page.html.hbs", ""), Some("Plain Text (Handlebars)".to_owned()));
+        // the extensions syntaxes claim count too
+        assert_eq!(guess("settings.ini", ""), Some("INI".to_owned()));
+        assert_eq!(guess("settings.ini.j2", ""), Some("INI (Jinja)".to_owned()));
+        assert_eq!(
+            guess("script", "#!/usr/bin/env rust-script\nfn main() {}\n"),
+            Some("Rust".to_owned())
+        );
+        assert_eq!(guess("README", "Just some words.\n"), None);
+    }
+
+    #[test]
+    fn failures_say_what_went_wrong() {
+        let missing = highlight_file(&testdata("rust/missing.rs"), None).unwrap_err();
+        assert!(missing.contains("missing.rs"), "{}", missing);
+        let unknown = highlight_file(&testdata("rust/items.rs"), Some("Klingon")).unwrap_err();
+        assert_eq!(unknown, "unknown language: Klingon");
+        // detected, but not a language there is a parser for
+        let shebang = "#!/usr/bin/python3\nprint('hi')\n";
+        let guess = guess_language(&ParserRegistry::default(), Path::new("script"), shebang);
+        assert_eq!(guess, Some("Python".to_owned()));
+
+        let args = |args: &[&str]| run(&args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>());
+        assert_eq!(args(&[]), Err(USAGE.to_owned()));
+        assert_eq!(args(&["--language"]), Err(USAGE.to_owned()));
+        let path = testdata("rust/items.rs").to_string_lossy().into_owned();
+        assert_eq!(args(&["--format", "xml", &path]), Err("unknown format: xml".to_owned()));
+    }
+}
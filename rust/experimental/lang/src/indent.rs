@@ -0,0 +1,736 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Indentation: fixing it up as the user types, carrying comments on
+//! across a newline, reindenting lines on request, and the indent guide
+//! spans added as lines are lexed.
+
+use std::mem;
+
+use crate::scope_tracker::LookupResult;
+use serde_json::json;
+use xi_core_lib::plugins::rpc::ScopeSpan;
+use xi_rope::{DeltaBuilder, Interval, RopeDelta};
+
+use crate::brackets::closing_bracket;
+use crate::parser::IndentHint;
+use crate::statestack::State;
+use crate::view::HighlightView;
+use crate::{
+    send_as_undo_group, trace_lazily, ScopeId, TrackerLock, ViewState, EDIT_AUTHOR, EDIT_PRIORITY,
+};
+
+/// How far up to look for the brace a typed `}` closes.
+const MAX_BRACE_SCAN_LINES: usize = 1000;
+
+/// Appended, with the level, to the scope of whitespace where an indent
+/// guide goes.
+const INDENT_GUIDE_SCOPE: &str = "meta.indent-guide";
+
+impl ViewState {
+    /// Splits a one-character indent guide span off the spans of `line`,
+    /// from `first` on, at each indentation level its leading whitespace
+    /// reaches. Lines that start in a string or a comment are left alone,
+    /// as their whitespace may be content.
+    pub fn add_indent_guides(
+        &mut self,
+        line: &str,
+        state: State,
+        base: usize,
+        spans: &mut Vec<ScopeSpan>,
+        first: usize,
+        tracker: &mut TrackerLock,
+    ) {
+        if !self.parser.borrow().in_code(state) {
+            return;
+        }
+        let tab_size = self.tab_size.max(1);
+        let mut column = 0;
+        let mut guides = leading_whitespace(line)
+            .char_indices()
+            .filter_map(|(i, c)| {
+                let guide = if column % tab_size == 0 {
+                    Some((base + i, column / tab_size + 1))
+                } else {
+                    None
+                };
+                column += if c == '\t' { tab_size - column % tab_size } else { 1 };
+                guide
+            })
+            .peekable();
+        if guides.peek().is_none() {
+            return;
+        }
+
+        let mut line_spans = mem::replace(&mut self.line_spans, Vec::new());
+        line_spans.clear();
+        line_spans.extend(spans.drain(first..));
+        for &span in &line_spans {
+            let mut start = span.start;
+            while let Some(&(at, level)) = guides.peek() {
+                if at >= span.end {
+                    break;
+                }
+                guides.next();
+                if at > start {
+                    spans.push(ScopeSpan { start, end: at, scope_id: span.scope_id });
+                }
+                let scope_id = self.identifier_for_guide(span.scope_id, level, tracker);
+                spans.push(ScopeSpan { start: at, end: at + 1, scope_id });
+                start = at + 1;
+            }
+            if start < span.end || span.start == span.end {
+                spans.push(ScopeSpan { start, end: span.end, scope_id: span.scope_id });
+            }
+        }
+        self.line_spans = line_spans;
+    }
+
+    /// The scope id for an indent guide at `level` in text of `scope_id`.
+    fn identifier_for_guide(
+        &mut self,
+        scope_id: ScopeId,
+        level: usize,
+        tracker: &mut TrackerLock,
+    ) -> ScopeId {
+        if let Some(&id) = self.guide_scopes.get(&(scope_id, level)) {
+            return id;
+        }
+        let tracker = tracker.get();
+        let mut scope = tracker.scope_for_id(scope_id).cloned().unwrap_or_default();
+        scope.push(format!("{}.{}", INDENT_GUIDE_SCOPE, level));
+        let result = tracker.lookup(&scope);
+        if let LookupResult::New(_) = result {
+            self.check_scope_name(&scope);
+        }
+        self.guide_scopes.insert((scope_id, level), result.id());
+        result.id()
+    }
+
+    /// Fixes up the indentation around the user's edit, and carries on
+    /// comments across a newline, if the parser knows how. Called after
+    /// `update`, so the states above it are mostly cached.
+    pub fn indent_after_edit<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        delta: &RopeDelta,
+        edit_type: &str,
+    ) {
+        if !self.auto_indent {
+            return;
+        }
+        match edit_type {
+            "newline" => self.indent_new_lines(view, delta),
+            "insert" if self.capabilities().supports_indentation => {
+                self.dedent_closing_brace(view, delta)
+            }
+            _ => (),
+        }
+    }
+
+    /// Indents the lines a newline edit started, or starts them with the
+    /// leader of the comment they continue.
+    fn indent_new_lines<V: HighlightView>(&mut self, view: &mut V, delta: &RopeDelta) {
+        // comments are only carried on across a single newline typed
+        let plain_newline = delta.as_simple_insert().map_or(false, |text| {
+            let text = String::from(text);
+            text == "\n" || text == "\r\n"
+        });
+        let supports_indentation = self.capabilities().supports_indentation;
+        let mut builder = DeltaBuilder::new(view.get_buf_size());
+        for region in delta.iter_inserts() {
+            let line_num = match view.line_of_offset(region.new_offset + region.len) {
+                Ok(line_num) if line_num > 0 => line_num,
+                _ => continue,
+            };
+            let continuation =
+                if plain_newline { self.comment_continuation(view, line_num) } else { None };
+            let edits = match continuation {
+                Some(edits) => edits,
+                None if supports_indentation => {
+                    self.indentation_for_line(view, line_num).into_iter().collect()
+                }
+                None => Vec::new(),
+            };
+            for (iv, text) in edits {
+                builder.replace(iv, text.into());
+            }
+        }
+        if !builder.is_empty() {
+            view.edit(builder.build(), EDIT_PRIORITY, false, false, EDIT_AUTHOR);
+        }
+    }
+
+    /// Starts `line_num` with the leader of the comment the line above it
+    /// leaves off in. A newline typed after nothing but the leader ends the
+    /// comment instead, taking the leader off the line above.
+    fn comment_continuation<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        line_num: usize,
+    ) -> Option<Vec<(Interval, String)>> {
+        let prev_num = line_num.checked_sub(1)?;
+        let state = self.state_at_line(view, prev_num)?;
+        let prev = view.get_line(prev_num).ok()?.trim_end_matches(&['\n', '\r'][..]).to_owned();
+        let state = self.state_after_line(&prev, state);
+        let leader = self.parser.borrow().comment_continuation(state, &prev)?;
+
+        let start = view.offset_of_line(line_num).ok()?;
+        let line = view.get_line(line_num).ok()?;
+        let current = Interval::new(start, start + leading_whitespace(line).len());
+        if !prev.trim().is_empty() && prev.trim_end() == leader.trim_end() {
+            let prev_start = view.offset_of_line(prev_num).ok()?;
+            let indent = leading_whitespace(&prev).to_owned();
+            return Some(vec![
+                (Interval::new(prev_start, prev_start + prev.len()), String::new()),
+                (current, indent),
+            ]);
+        }
+        Some(vec![(current, leader)])
+    }
+
+    /// Lines a `}` typed alone on its line up with the line of the brace it
+    /// closes, unless the user chose the line's indentation themselves.
+    fn dedent_closing_brace<V: HighlightView>(&mut self, view: &mut V, delta: &RopeDelta) {
+        match delta.as_simple_insert() {
+            Some(text) if String::from(text) == "}" => (),
+            _ => return,
+        }
+        let line_num = match view.line_of_offset(delta.summary().0.start()) {
+            Ok(line_num) => line_num,
+            Err(_) => return,
+        };
+        if let Some((iv, indent)) = self.indentation_for_closing_brace(view, line_num) {
+            let mut builder = DeltaBuilder::new(view.get_buf_size());
+            builder.replace(iv, indent.into());
+            view.edit(builder.build(), EDIT_PRIORITY, false, false, EDIT_AUTHOR);
+        }
+    }
+
+    /// The leading whitespace of `line_num`, which holds only a closing
+    /// brace, and the indentation of the line that opened it.
+    fn indentation_for_closing_brace<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        line_num: usize,
+    ) -> Option<(Interval, String)> {
+        let state = self.state_at_line(view, line_num)?;
+        let line = view.get_line(line_num).ok()?;
+        let current = leading_whitespace(line).to_owned();
+        if line[current.len()..].trim_end() != "}" || !self.parser.borrow().in_code(state) {
+            return None;
+        }
+        // the plugin would have put the line somewhere else, so the user did
+        if self.suggested_indentation(view, line_num, IndentHint::None)? != current {
+            return None;
+        }
+
+        // the lines with a `{` still open, innermost last
+        let first = line_num.saturating_sub(MAX_BRACE_SCAN_LINES);
+        let mut state = self.state_at_line(view, first)?;
+        let mut open = Vec::new();
+        let mut brackets = Vec::new();
+        for num in first..line_num {
+            let line = view.get_line(num).ok()?;
+            brackets.clear();
+            state = self.line_brackets(line, state, 0, &mut brackets);
+            for &(_, b) in &brackets {
+                match b {
+                    b'{' => open.push(num),
+                    b'}' => {
+                        open.pop();
+                    }
+                    _ => (),
+                }
+            }
+        }
+        let opener = view.get_line(open.pop()?).ok()?;
+        let indent = leading_whitespace(opener).to_owned();
+
+        if indent == current {
+            return None;
+        }
+        let start = view.offset_of_line(line_num).ok()?;
+        Some((Interval::new(start, start + current.len()), indent))
+    }
+
+    /// The leading whitespace of `line_num` and what it should be instead,
+    /// according to the parser, if that is different.
+    fn indentation_for_line<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        line_num: usize,
+    ) -> Option<(Interval, String)> {
+        let state = self.state_at_line(view, line_num)?;
+        let line = view.get_line(line_num).ok()?;
+        let current = leading_whitespace(line).to_owned();
+        let hint = self.parser.borrow().indent_delta(line, state);
+        let indent = self.suggested_indentation(view, line_num, hint)?;
+
+        if indent == current {
+            return None;
+        }
+        let start = view.offset_of_line(line_num).ok()?;
+        Some((Interval::new(start, start + current.len()), indent))
+    }
+
+    /// The indentation for `line_num` given its hint, from the lines above.
+    /// `None` if the line's indentation isn't the plugin's to choose.
+    fn suggested_indentation<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        line_num: usize,
+        hint: IndentHint,
+    ) -> Option<String> {
+        // the nearest line above with something on it
+        let mut above = None;
+        for prev_num in (0..line_num).rev() {
+            let prev = view.get_line(prev_num).ok()?;
+            if !prev.trim().is_empty() {
+                above = Some((prev_num, prev.to_owned()));
+                break;
+            }
+        }
+        let (prev_indent, prev_text, prev_num) = match above {
+            Some((prev_num, prev)) => (leading_whitespace(&prev).to_owned(), prev, prev_num),
+            None => (String::new(), String::new(), 0),
+        };
+
+        let indent = match hint {
+            IndentHint::Verbatim => return None,
+            // the line above already has the hanging part if it continues
+            // the same construct
+            IndentHint::Hanging(prefix) if prev_text.trim_start().starts_with('*') => {
+                prev_indent + prefix.trim_start()
+            }
+            IndentHint::Hanging(prefix) => prev_indent + prefix,
+            IndentHint::None | IndentHint::Increase | IndentHint::DecreaseThisLine => {
+                let prev_state = self.state_at_line(view, prev_num)?;
+                let prev_hint = self.parser.borrow().indent_delta(&prev_text, prev_state);
+                let mut width = self.indent_width(&prev_indent);
+                if prev_hint == IndentHint::Increase {
+                    width += self.tab_size;
+                }
+                if hint == IndentHint::DecreaseThisLine {
+                    width = width.saturating_sub(self.tab_size);
+                }
+                self.indent_text(width)
+            }
+        };
+        Some(indent)
+    }
+
+    /// Recomputes the leading whitespace of the lines from `first_line` to
+    /// `last_line` from the brackets around them, in one edit that core can
+    /// undo on its own.
+    pub fn reindent<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        first_line: usize,
+        last_line: usize,
+    ) {
+        let edits = match self.reindent_edits(view, first_line, last_line) {
+            Ok(edits) => edits,
+            Err(reason) => {
+                let payload =
+                    json!({ "first_line": first_line, "last_line": last_line, "reason": reason });
+                trace_lazily("can't reindent", &["experimental-lang"], || payload.to_string());
+                return;
+            }
+        };
+        send_as_undo_group(view, edits, false);
+    }
+
+    /// The replacements of leading whitespace that reindent the lines from
+    /// `first_line` to `last_line`, in document order. Each line is
+    /// indented one level past the line that opened the innermost bracket
+    /// still open, or lined up with it if the line starts by closing it.
+    /// Lines in a string are left alone, and the lines of a block comment
+    /// move with the line that opened it.
+    fn reindent_edits<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        first_line: usize,
+        last_line: usize,
+    ) -> Result<Vec<(Interval, String)>, &'static str> {
+        if !self.capabilities().supports_indentation {
+            return Err("the parser doesn't know about indentation");
+        }
+        let last_line = last_line.min(view.get_num_lines().saturating_sub(1));
+        if first_line > last_line {
+            return Err("no such lines");
+        }
+
+        // The nearest line of code above sets the indentation to start
+        // from, and may leave brackets open.
+        let mut base = 0;
+        let mut open = Vec::new();
+        for prev_num in (0..first_line).rev() {
+            let state = self.state_at_line(view, prev_num).ok_or("couldn't lex the lines")?;
+            let prev = view.get_line(prev_num).map_err(|_| "couldn't fetch the lines")?.to_owned();
+            if prev.trim().is_empty() || !self.parser.borrow().in_code(state) {
+                continue;
+            }
+            base = self.indent_width(leading_whitespace(&prev));
+            let mut brackets = Vec::new();
+            self.line_brackets(&prev, state, 0, &mut brackets);
+            for (_, b) in brackets {
+                if closing_bracket(b).is_some() {
+                    open.push(base);
+                } else {
+                    open.pop();
+                }
+            }
+            break;
+        }
+
+        let mut state = self.state_at_line(view, first_line).ok_or("couldn't lex the lines")?;
+        // how far the line that opened the current block comment moved
+        let mut shift = 0isize;
+        // whether the line after the last is indented a level past it
+        // without opening a bracket, as after `=>`
+        let mut hang = false;
+        let mut edits = Vec::new();
+        for line_num in first_line..=last_line {
+            let start = view.offset_of_line(line_num).map_err(|_| "couldn't fetch the lines")?;
+            let line = view.get_line(line_num).map_err(|_| "couldn't fetch the lines")?.to_owned();
+            let current = leading_whitespace(&line).len();
+            let hint = self.parser.borrow().indent_delta(&line, state);
+            let mut brackets = Vec::new();
+            state = self.line_brackets(&line, state, 0, &mut brackets);
+
+            let old_width = self.indent_width(&line[..current]);
+            let code_line = match hint {
+                IndentHint::None | IndentHint::Increase | IndentHint::DecreaseThisLine => true,
+                IndentHint::Hanging(_) | IndentHint::Verbatim => false,
+            };
+            let width = match hint {
+                _ if line.trim().is_empty() => None,
+                IndentHint::Verbatim => None,
+                IndentHint::Hanging(_) => Some((old_width as isize + shift).max(0) as usize),
+                IndentHint::None | IndentHint::Increase | IndentHint::DecreaseThisLine => {
+                    let closes_first = brackets
+                        .first()
+                        .map_or(false, |&(i, b)| i == current && closing_bracket(b).is_none());
+                    let width = match (closes_first, open.last()) {
+                        (true, Some(&opener)) => opener,
+                        (true, None) => base.saturating_sub(self.tab_size),
+                        (false, Some(&opener)) => opener + self.tab_size,
+                        (false, None) => base,
+                    };
+                    let width = if hang && !closes_first { width + self.tab_size } else { width };
+                    shift = width as isize - old_width as isize;
+                    Some(width)
+                }
+            };
+            let new_width = width.unwrap_or(old_width);
+
+            // the brackets this line leaves open
+            let mut unclosed = 0usize;
+            for (_, b) in brackets {
+                if closing_bracket(b).is_some() {
+                    open.push(new_width);
+                    unclosed += 1;
+                } else {
+                    unclosed = unclosed.saturating_sub(1);
+                    if open.pop().is_none() {
+                        base = base.saturating_sub(self.tab_size);
+                    }
+                }
+            }
+            if code_line && width.is_some() {
+                hang = hint == IndentHint::Increase && unclosed == 0;
+            }
+
+            if let Some(width) = width {
+                let indent = self.indent_text(width);
+                if indent != line[..current] {
+                    edits.push((Interval::new(start, start + current), indent));
+                }
+            }
+        }
+        Ok(edits)
+    }
+
+    /// The width of `indent` in columns.
+    pub fn indent_width(&self, indent: &str) -> usize {
+        indent.chars().map(|c| if c == '\t' { self.tab_size } else { 1 }).sum()
+    }
+
+    /// Indentation `width` columns wide, in the view's style.
+    fn indent_text(&self, width: usize) -> String {
+        if self.translate_tabs_to_spaces {
+            " ".repeat(width)
+        } else {
+            let tab_size = self.tab_size.max(1);
+            "\t".repeat(width / tab_size) + &" ".repeat(width % tab_size)
+        }
+    }
+}
+
+/// The spaces and tabs `line` starts with.
+pub fn leading_whitespace(line: &str) -> &str {
+    let end = line.find(|c| c != ' ' && c != '\t').unwrap_or(line.len());
+    &line[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::registry::ParserRegistry;
+    use crate::scope_tracker::Scope;
+    use crate::statestack;
+    use crate::text::strip_prefix;
+    use crate::view::mock::{
+        apply_edit, highlight_from_scratch, resolved_scopes, run_idle, MockView,
+    };
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use xi_rope::Rope;
+
+    /// The text after the plugin's indentation edit in response to the user
+    /// typing `typed` at `offset`, if it made one.
+    fn indent_after_typing(
+        language: &str,
+        text: &str,
+        offset: usize,
+        typed: &str,
+    ) -> Option<String> {
+        let mut registry = ParserRegistry::default();
+        registry.register("Indent Oblivious", || Box::new(IndentObliviousParser));
+        let mut view = MockView::new(language, text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.translate_tabs_to_spaces = true;
+        view_state.do_highlighting(&mut view, &registry, &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        let delta =
+            RopeDelta::simple_edit(Interval::new(offset, offset), Rope::from(typed), text.len());
+        apply_edit(&mut view_state, &mut view, (offset, 0, typed));
+        let edit_type = if typed == "\n" { "newline" } else { "insert" };
+        view_state.indent_after_edit(&mut view, &delta, edit_type);
+        assert!(view.edits.len() <= 1);
+        view.edits.pop().map(|edit| {
+            assert_eq!(edit.author, EDIT_AUTHOR);
+            assert!(!edit.new_undo_group);
+            edit.delta.apply(view.text()).to_string()
+        })
+    }
+
+    #[test]
+    fn new_lines_are_indented() {
+        let cases = &[
+            ("fn main() {\n}\n", 11, Some("fn main() {\n    \n}\n")),
+            ("fn main() {}\n", 11, None),
+            ("    let x = 1;\n", 14, Some("    let x = 1;\n    \n")),
+            ("/* comment\n", 10, Some("/* comment\n * \n")),
+            ("let s = \"multi\n", 14, None),
+        ];
+        for &(text, offset, expected) in cases {
+            let indented = indent_after_typing("Rust", text, offset, "\n");
+            assert_eq!(indented, expected.map(str::to_owned), "{:?} at {}", text, offset);
+        }
+    }
+
+    #[test]
+    fn only_parsers_that_support_indentation_are_asked() {
+        let text = "fn main() {\n}\n";
+        assert_eq!(indent_after_typing("Indent Oblivious", text, 11, "\n"), None);
+        let text = "fn main() {\n    \n";
+        assert_eq!(indent_after_typing("Indent Oblivious", text, text.len() - 1, "}"), None);
+    }
+
+    #[test]
+    fn comments_are_carried_on_across_newlines() {
+        let cases = &[
+            ("    let x = 1; /* note\n", 22, Some("    let x = 1; /* note\n                * \n")),
+            ("/// Docs\nfn f() {}\n", 8, Some("/// Docs\n/// \nfn f() {}\n")),
+            ("    //! Crate docs\n", 18, Some("    //! Crate docs\n    //! \n")),
+            // splitting a doc comment carries the rest of it on
+            ("/// Docs go here\n", 8, Some("/// Docs\n/// go here\n")),
+            // neither doc comments nor comments
+            ("//// banner\n", 11, None),
+            ("let s = \"/* not a comment\n", 25, None),
+            // a newline after nothing but the leader ends the comment
+            ("    /// Docs\n    /// \n", 21, Some("    /// Docs\n\n    \n")),
+            ("    /* open\n     * \n", 19, Some("    /* open\n\n     \n")),
+        ];
+        for &(text, offset, expected) in cases {
+            let continued = indent_after_typing("Rust", text, offset, "\n");
+            assert_eq!(continued, expected.map(str::to_owned), "{:?} at {}", text, offset);
+        }
+    }
+
+    #[test]
+    fn closing_braces_line_up_with_their_opener() {
+        let cases = &[
+            ("fn main() {\n    if x {\n        \n", Some("fn main() {\n    if x {\n    }\n")),
+            ("fn main() {\n    \n", Some("fn main() {\n}\n")),
+            // a brace in a string or a comment opens nothing
+            (
+                "fn f() {\n    let s = \"{\"; // { too\n    \n",
+                Some("fn f() {\n    let s = \"{\"; // { too\n}\n"),
+            ),
+            ("fn f() {\n    g({ 1 });\n    \n", Some("fn f() {\n    g({ 1 });\n}\n")),
+            // already in place
+            ("fn main() {\n\n", None),
+            // typed inside a string or a comment
+            ("let s = \"{\n    \n", None),
+            ("/* {\n    \n", None),
+            // not alone on its line
+            ("fn main() {\n    x\n", None),
+            // indented by the user
+            ("fn main() {\n  \n", None),
+            // nothing to close
+            ("    \n", None),
+        ];
+        for &(text, expected) in cases {
+            let offset = text.len() - 1;
+            let dedented = indent_after_typing("Rust", text, offset, "}");
+            let expected = expected.map(|expected| expected.to_owned());
+            assert_eq!(dedented, expected, "{:?}", text);
+        }
+    }
+
+    /// `text` after reindenting the lines from `first_line` to `last_line`
+    /// as Rust, indenting with spaces unless `tabs` is set.
+    fn reindent(text: &str, first_line: usize, last_line: usize, tabs: bool) -> String {
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.translate_tabs_to_spaces = !tabs;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        view_state.reindent(&mut view, first_line, last_line);
+        assert!(view.edits.len() <= 1);
+        match view.edits.pop() {
+            Some(edit) => {
+                assert!(edit.new_undo_group);
+                edit.delta.apply(view.text()).to_string()
+            }
+            None => text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn reindenting_follows_the_brackets() {
+        let mangled = concat!(
+            "fn main() {\n",
+            "let s = \"keep\n",
+            "   this   \";\n",
+            "      if x {\n",
+            "  /* a comment\n",
+            "     keeps its shape */\n",
+            "foo(a,\n",
+            "b);\n",
+            "        }\n",
+            "  match x {\n",
+            " 1 =>\n",
+            "2,\n",
+            "}\n",
+            "  }\n",
+        );
+        let reindented = concat!(
+            "fn main() {\n",
+            "    let s = \"keep\n",
+            "   this   \";\n",
+            "    if x {\n",
+            "        /* a comment\n",
+            "           keeps its shape */\n",
+            "        foo(a,\n",
+            "            b);\n",
+            "    }\n",
+            "    match x {\n",
+            "        1 =>\n",
+            "            2,\n",
+            "    }\n",
+            "}\n",
+        );
+        assert_eq!(reindent(mangled, 0, 13, false), reindented);
+        // the string's text is content, so it is kept byte for byte
+        assert!(reindented.contains("\"keep\n   this   \""));
+        // already indented
+        assert_eq!(reindent(reindented, 0, 13, false), reindented);
+
+        assert_eq!(reindent("fn f() {\n  x\n}\n", 0, 2, true), "fn f() {\n\tx\n}\n");
+        // a range in the middle starts from the code above it
+        let text = "fn f() {\n    if x {\n  y\n    }\n}\n";
+        assert_eq!(reindent(text, 2, 2, false), "fn f() {\n    if x {\n        y\n    }\n}\n");
+    }
+
+    /// The offsets and levels of the indent guides in `text`, highlighted
+    /// as Rust with a tab size of 4.
+    fn indent_guides(text: &str) -> Vec<(usize, usize)> {
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.indent_guides = true;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        // guides only ever replace the scope of a whitespace character
+        let plain = highlight_from_scratch("Rust", text);
+        let mut guides = Vec::new();
+        for (offset, scope) in resolved_scopes(&view).into_iter().enumerate() {
+            let mut scope = scope.unwrap();
+            let level = scope.last().and_then(|name| strip_prefix(name, "meta.indent-guide."));
+            if let Some(level) = level.map(|level| level.parse().unwrap()) {
+                guides.push((offset, level));
+                scope.pop();
+            }
+            assert_eq!(Some(scope), plain[offset], "at {}", offset);
+        }
+        guides
+    }
+
+    #[test]
+    fn indent_guides_mark_each_level() {
+        let spaces = "fn f() {\n    if x {\n        y\n    }\n}\n";
+        assert_eq!(indent_guides(spaces), vec![(9, 1), (20, 1), (24, 2), (30, 1)]);
+        let tabs = "fn f() {\n\tif x {\n\t\ty\n\t}\n}\n";
+        assert_eq!(indent_guides(tabs), vec![(9, 1), (17, 1), (18, 2), (21, 1)]);
+        // a tab after spaces runs to the next tab stop
+        let mixed = "fn f() {\n  \tx;\n\t  y;\n}\n";
+        assert_eq!(indent_guides(mixed), vec![(9, 1), (15, 1), (16, 2)]);
+        // whitespace in a string is content
+        assert_eq!(indent_guides("let s = \"a\n    b\";\n"), vec![]);
+    }
+
+    /// A parser that would rather not be asked about indentation.
+    struct IndentObliviousParser;
+
+    impl Parser for IndentObliviousParser {
+        fn name(&self) -> &'static str {
+            "Indent Oblivious"
+        }
+
+        fn get_scope_for_state(&self, _state: State) -> Scope {
+            vec!["source.oblivious".to_owned()]
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            (0, state, text.len(), state)
+        }
+
+        fn indent_delta(&self, _line: &str, _state_before: State) -> IndentHint {
+            panic!("asked for an indent hint without supporting indentation")
+        }
+
+        fn stack_stats(&self) -> statestack::Stats {
+            statestack::Stats::default()
+        }
+
+        fn shrink_to_fit(&mut self) {}
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Embeddings configured by the user: regions of a host language, like a
+//! fenced block with a given info string, to highlight as another language.
+
+use serde_json::Value;
+
+use crate::text::split_once;
+
+/// A kind of region a host parser can report, with the name picking out
+/// which ones to inject into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    /// A fenced code block with this info string, like `mermaid`.
+    FenceInfo(String),
+    /// The body of an invocation of this macro, like `html!`.
+    Macro(String),
+}
+
+impl Pattern {
+    /// Reads a pattern like `fence-info:mermaid` or `macro:html!`.
+    pub fn parse(pattern: &str) -> Result<Pattern, String> {
+        let (kind, name) = match split_once(pattern, ":") {
+            Some((kind, name)) if !name.trim().is_empty() => (kind, name.trim()),
+            _ => return Err(format!("{:?} should look like \"kind:name\"", pattern)),
+        };
+        match kind {
+            "fence-info" => Ok(Pattern::FenceInfo(name.to_owned())),
+            // the `!` is optional
+            "macro" => Ok(Pattern::Macro(format!("{}!", name.trim_end_matches('!')))),
+            _ => Err(format!("unknown kind of pattern {:?}, expected fence-info or macro", kind)),
+        }
+    }
+
+    /// The name a host parser gives the region, in place of a language, in
+    /// the [`Embedding`](crate::parser::Embedding) it reports for it.
+    pub fn region(&self) -> String {
+        match self {
+            Pattern::FenceInfo(info) => format!("fence-info:{}", info),
+            Pattern::Macro(name) => format!("macro:{}", name),
+        }
+    }
+}
+
+/// One entry of the injection table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Injection {
+    /// The language id of the documents the region is in.
+    pub host: String,
+    pub pattern: Pattern,
+    /// The language id to highlight the region as.
+    pub language: String,
+}
+
+impl Injection {
+    fn from_entry(entry: &Value) -> Result<Injection, String> {
+        let field = |name: &str| match entry.get(name) {
+            Some(Value::String(value)) if !value.is_empty() => Ok(value.clone()),
+            Some(_) => Err(format!("{} should be a non-empty string in {}", name, entry)),
+            None => Err(format!("missing {} in {}", name, entry)),
+        };
+        if !entry.is_object() {
+            return Err(format!("{} should be a table", entry));
+        }
+        let pattern = Pattern::parse(&field("pattern")?)?;
+        Ok(Injection { host: field("host")?, pattern, language: field("language")? })
+    }
+}
+
+/// Reads the injection table from the config. Entries that aren't valid
+/// are left out, each with a reason why.
+pub fn parse_table(table: &Value) -> (Vec<Injection>, Vec<String>) {
+    let entries = match table.as_array() {
+        Some(entries) => entries.as_slice(),
+        None => return (Vec::new(), vec![format!("{} should be a list of entries", table)]),
+    };
+    let mut injections = Vec::new();
+    let mut problems = Vec::new();
+    for entry in entries {
+        match Injection::from_entry(entry) {
+            Ok(injection) => injections.push(injection),
+            Err(problem) => problems.push(problem),
+        }
+    }
+    (injections, problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn patterns_parse() {
+        assert_eq!(Pattern::parse("fence-info:mermaid"), Ok(Pattern::FenceInfo("mermaid".into())));
+        assert_eq!(Pattern::parse("macro:html!"), Ok(Pattern::Macro("html!".into())));
+        assert_eq!(Pattern::parse("macro:html").unwrap().region(), "macro:html!");
+        assert!(Pattern::parse("macro:").is_err());
+        assert!(Pattern::parse("mermaid").is_err());
+        assert!(Pattern::parse("attribute:onclick").is_err());
+    }
+
+    #[test]
+    fn bad_entries_are_left_out() {
+        let (injections, problems) = parse_table(&json!([
+            { "host": "Markdown", "pattern": "fence-info:mermaid", "language": "Plain Text" },
+            { "host": "Rust", "pattern": "macro:html!" },
+            { "host": "Rust", "pattern": "derive:Debug", "language": "Rust" },
+            { "host": 3, "pattern": "macro:sql!", "language": "SQL" },
+            "Rust",
+        ]));
+        assert_eq!(
+            injections,
+            vec![Injection {
+                host: "Markdown".into(),
+                pattern: Pattern::FenceInfo("mermaid".into()),
+                language: "Plain Text".into(),
+            }]
+        );
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].starts_with("missing language"), "{}", problems[0]);
+
+        let (injections, problems) = parse_table(&json!({ "host": "Rust" }));
+        assert!(injections.is_empty());
+        assert_eq!(problems.len(), 1);
+    }
+}
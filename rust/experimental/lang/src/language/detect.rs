@@ -0,0 +1,125 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Guesses the language of a document that core reports as plain text,
+//! usually a script without an extension, from its first lines.
+
+use crate::text::strip_prefix;
+
+/// Interpreters named by a shebang line, without any version suffix.
+const SHEBANG_INTERPRETERS: &[(&str, &str)] = &[
+    ("bash", "Shell Script"),
+    ("dash", "Shell Script"),
+    ("node", "JavaScript"),
+    ("perl", "Perl"),
+    ("python", "Python"),
+    ("ruby", "Ruby"),
+    ("run-cargo-script", "Rust"),
+    ("rust-script", "Rust"),
+    ("sh", "Shell Script"),
+    ("zsh", "Shell Script"),
+];
+
+/// Magic prefixes of the document. `None` means the document is not text
+/// worth highlighting, and no other rule should be tried.
+const MAGIC_PREFIXES: &[(&str, Option<&str>)] =
+    &[("%PDF", None), ("<?xml", Some("XML")), ("<!DOCTYPE html", Some("HTML"))];
+
+/// The language `head`, the beginning of a document, appears to be in.
+pub fn detect(head: &str) -> Option<&'static str> {
+    for &(prefix, language) in MAGIC_PREFIXES {
+        if head.starts_with(prefix) {
+            return language;
+        }
+    }
+
+    let first_line = head.lines().next().unwrap_or("");
+    if let Some(shebang) = strip_prefix(first_line, "#!") {
+        return shebang_interpreter(shebang).and_then(|interpreter| {
+            SHEBANG_INTERPRETERS
+                .iter()
+                .find(|&&(name, _)| name == interpreter)
+                .map(|&(_, language)| language)
+        });
+    }
+
+    if looks_like_json(head) {
+        return Some("JSON");
+    }
+    None
+}
+
+/// The interpreter a shebang runs, as in `python` for `/usr/bin/python3`
+/// or `/usr/bin/env -S python3.7 -u`.
+fn shebang_interpreter(shebang: &str) -> Option<&str> {
+    let mut words = shebang.split_whitespace();
+    let mut program = file_name(words.next()?);
+    if program == "env" {
+        program = file_name(words.find(|word| !word.starts_with('-'))?);
+    }
+    Some(program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'))
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// An object whose first key is a string; a bare `{` could start many
+/// other things.
+fn looks_like_json(head: &str) -> bool {
+    let head = head.trim_start();
+    if !head.starts_with('{') {
+        return false;
+    }
+    let rest = head[1..].trim_start();
+    rest.starts_with('"') || rest.starts_with('}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_corpus() {
+        let corpus: &[(&str, Option<&str>)] = &[
+            ("#!/usr/bin/env python3\n", Some("Python")),
+            ("#!/usr/bin/python2.7 -u\n", Some("Python")),
+            ("#! /bin/sh\nset -e\n", Some("Shell Script")),
+            ("#!/bin/bash\n", Some("Shell Script")),
+            ("#!/usr/bin/env -S node --harmony\n", Some("JavaScript")),
+            ("#!/usr/bin/env rust-script\nfn main() {}\n", Some("Rust")),
+            ("#!/usr/local/bin/ruby\n", Some("Ruby")),
+            ("#!/usr/bin/env awk -f\n", None),
+            ("#!\n", None),
+            ("<?xml version=\"1.0\"?>\n", Some("XML")),
+            ("<!DOCTYPE html>\n<html>\n", Some("HTML")),
+            ("%PDF-1.4\n%\u{e2}\u{e3}\n", None),
+            ("{\n  \"name\": \"xi\"\n}\n", Some("JSON")),
+            ("  {\"a\": 1}\n", Some("JSON")),
+            ("{}\n", Some("JSON")),
+            ("{\n  foo\n", None),
+            ("fn main() {}\n", None),
+            ("", None),
+        ];
+        for &(head, expected) in corpus {
+            assert_eq!(detect(head), expected, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn magic_prefixes_win_over_other_rules() {
+        // a PDF is never highlighted, whatever follows
+        assert_eq!(detect("%PDF-1.7 {\"a\": 1}"), None);
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prose files with code lines in them, highlighted by the code's parser.
+
+use crate::parser::{Capabilities, Parser};
+use crate::scope_tracker::Scope;
+use crate::statestack::{Context, State, Stats};
+use crate::text::strip_prefix;
+
+const PROSE_SCOPE: &str = "literate:prose";
+const MARKER_SCOPE: &str = "literate:marker";
+const CODE_SCOPE: &str = "literate:code";
+
+/// How code lines are told apart from prose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Lines starting with `>`, as in literate Haskell.
+    Bird,
+    /// Lines indented by four columns after a blank line, and the indented
+    /// lines after them, as in Markdown.
+    Indented,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum Layer {
+    /// The start of a line.
+    LineStart,
+    Prose,
+    /// The `>` of a code line, or the indentation of one.
+    Marker,
+    /// The rest of a code line.
+    Code,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct StateEl {
+    layer: Layer,
+    /// The state of the code's parser, which carries on from one code line
+    /// to the next.
+    code: State,
+    /// Whether an indented line goes on or starts a code block: it does
+    /// after a blank line or a code line, but not after prose.
+    indent_opens: bool,
+}
+
+/// Highlights the code lines of a prose file with the code's parser, with
+/// the prefix marking them left out of what that parser sees.
+pub struct LiterateParser {
+    style: Style,
+    code: Box<dyn Parser>,
+    ctx: Context<StateEl>,
+}
+
+impl LiterateParser {
+    pub fn new(style: Style, code: Box<dyn Parser>) -> LiterateParser {
+        LiterateParser { style, code, ctx: Context::new() }
+    }
+
+    fn element(&self, state: State) -> StateEl {
+        self.ctx.tos(state).unwrap_or(StateEl {
+            layer: Layer::LineStart,
+            code: self.code.initial_state(),
+            indent_opens: true,
+        })
+    }
+
+    fn state(&mut self, layer: Layer, code: State, indent_opens: bool) -> State {
+        self.ctx.push(State::default(), StateEl { layer, code, indent_opens })
+    }
+
+    /// The length of the marker `line` starts with if it is a code line.
+    fn marker_len(&self, line: &str, indent_opens: bool) -> Option<usize> {
+        match self.style {
+            Style::Bird => {
+                let rest = strip_prefix(line, ">")?;
+                Some(if rest.starts_with(' ') { 2 } else { 1 })
+            }
+            Style::Indented if indent_opens => {
+                let mut width = 0;
+                for (i, c) in line.char_indices() {
+                    width = match c {
+                        ' ' => width + 1,
+                        '\t' => width + 4 - width % 4,
+                        _ => return None,
+                    };
+                    if width >= 4 {
+                        return Some(i + 1);
+                    }
+                }
+                None
+            }
+            Style::Indented => None,
+        }
+    }
+}
+
+impl Parser for LiterateParser {
+    fn name(&self) -> &'static str {
+        "Literate"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { emits_sub_spans: true, ..Capabilities::default() }
+    }
+
+    fn get_scope_for_state(&self, state: State) -> Scope {
+        let element = self.element(state);
+        let mut scope = vec![PROSE_SCOPE.to_owned()];
+        match element.layer {
+            Layer::LineStart | Layer::Prose => (),
+            Layer::Marker => scope.push(MARKER_SCOPE.to_owned()),
+            Layer::Code => {
+                scope.push(CODE_SCOPE.to_owned());
+                scope.extend(self.code.get_scope_for_state(element.code));
+            }
+        }
+        scope
+    }
+
+    fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+        let StateEl { layer, code, indent_opens } = self.element(state);
+        // the code's parser doesn't see the newline
+        let body = text.trim_end_matches(&['\n', '\r'][..]);
+        match layer {
+            Layer::LineStart => {
+                if let Some(len) = self.marker_len(text, indent_opens) {
+                    let marker = self.state(Layer::Marker, code, true);
+                    return (0, marker, len, self.state(Layer::Code, code, true));
+                }
+                let blank = body.trim().is_empty();
+                let prose = self.state(Layer::Prose, code, false);
+                (0, prose, text.len(), self.state(Layer::LineStart, code, blank))
+            }
+            Layer::Code if !body.is_empty() => {
+                let (prevlen, s0, len, s1) = self.code.parse(body, code);
+                (prevlen, self.state(Layer::Code, s0, true), len, self.state(Layer::Code, s1, true))
+            }
+            // the end of a code line; lines in a `Prose` or `Marker` state
+            // don't get here
+            _ => (0, state, text.len(), self.state(Layer::LineStart, code, true)),
+        }
+    }
+
+    fn in_code(&self, state: State) -> bool {
+        let element = self.element(state);
+        element.layer == Layer::Code && self.code.in_code(element.code)
+    }
+
+    fn stack_stats(&self) -> Stats {
+        let (ours, code) = (self.ctx.stats(), self.code.stack_stats());
+        Stats {
+            states: ours.states + code.states,
+            elements: ours.elements + code.elements,
+            max_depth: ours.max_depth.max(code.max_depth),
+            estimated_bytes: ours.estimated_bytes + code.estimated_bytes,
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit();
+        self.code.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::rust::RustParser;
+    use crate::text::lines_with_endings;
+
+    /// The innermost scope of the first non-blank span of each line,
+    /// past any marker.
+    fn line_scopes(parser: &mut dyn Parser, text: &str) -> Vec<String> {
+        let mut state = parser.initial_state();
+        let mut scopes = Vec::new();
+        for line in lines_with_endings(text) {
+            let mut i = 0;
+            let mut first = None;
+            while i < line.len() {
+                let (prevlen, s0, len, s1) = parser.parse(&line[i..], state);
+                for (range, s) in
+                    vec![(i..i + prevlen, state), (i + prevlen..i + prevlen + len, s0)]
+                {
+                    let scope = parser.get_scope_for_state(s).pop().unwrap();
+                    if first.is_none() && !line[range].trim().is_empty() && scope != MARKER_SCOPE {
+                        first = Some(scope);
+                    }
+                }
+                i += prevlen + len;
+                state = s1;
+            }
+            scopes.push(first.unwrap_or_default());
+        }
+        scopes
+    }
+
+    #[test]
+    fn bird_tracks_are_code() {
+        let mut parser = LiterateParser::new(Style::Bird, Box::new(RustParser::new()));
+        let text = concat!(
+            "The answer, in a comment that\n",
+            "> /* spans\n",
+            "and goes on past\n",
+            ">prose */ fn answer() -> u32 { 42 }\n",
+        );
+        assert_eq!(
+            line_scopes(&mut parser, text),
+            vec![
+                PROSE_SCOPE,
+                "rust:comment",
+                PROSE_SCOPE,
+                // the code's state carries on over the prose
+                "rust:comment",
+            ]
+        );
+
+        let (_, marker, len, code) = parser.parse("> fn", parser.initial_state());
+        assert_eq!(len, 2);
+        assert_eq!(parser.get_scope_for_state(marker), vec![PROSE_SCOPE, MARKER_SCOPE]);
+        assert_eq!(parser.get_scope_for_state(code), vec![PROSE_SCOPE, CODE_SCOPE, "rust:source"]);
+    }
+
+    #[test]
+    fn indented_blocks_are_code_after_a_blank_line() {
+        let mut parser = LiterateParser::new(Style::Indented, Box::new(RustParser::new()));
+        let text = concat!(
+            "A paragraph whose next line\n",
+            "    is indented but goes on the paragraph.\n",
+            "\n",
+            "    fn main() {\n",
+            "\n",
+            "\tlet x = 1;\n",
+            "    }\n",
+            "Prose again, so\n",
+            "    this is prose.\n",
+        );
+        assert_eq!(
+            line_scopes(&mut parser, text),
+            vec![
+                PROSE_SCOPE,
+                PROSE_SCOPE,
+                "",
+                "rust:keyword",
+                "",
+                "rust:keyword",
+                "rust:source",
+                PROSE_SCOPE,
+                PROSE_SCOPE,
+            ]
+        );
+    }
+}
@@ -1,2 +1,10 @@
+pub mod detect;
+pub mod literate;
 pub mod plaintext;
 pub mod rust;
+pub mod sublime;
+pub mod template;
+#[cfg(test)]
+pub mod testlang;
+#[cfg(feature = "tree-sitter")]
+pub mod treesitter;
@@ -12,43 +12,54 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::parser::Parser;
-use crate::statestack::{Context, State};
-use crate::ScopeId;
+use crate::parser::{Capabilities, Parser};
+use crate::scope_tracker::Scope;
+use crate::statestack::{Context, State, Stats};
 
-const PLAINTEXT_SOURCE_SCOPE: &[&str] = &["source.plaintext"];
+const PLAINTEXT_SOURCE_SCOPE: &[&str] = &["plaintext:source"];
 
 pub struct PlaintextParser {
-    scope_offset: Option<u32>,
     ctx: Context<()>,
 }
 
 impl PlaintextParser {
     pub fn new() -> PlaintextParser {
-        PlaintextParser { scope_offset: None, ctx: Context::new() }
+        PlaintextParser { ctx: Context::new() }
+    }
+}
+
+impl Default for PlaintextParser {
+    fn default() -> PlaintextParser {
+        PlaintextParser::new()
     }
 }
 
 impl Parser for PlaintextParser {
-    fn has_offset(&mut self) -> bool {
-        self.scope_offset.is_some()
+    fn name(&self) -> &'static str {
+        "Plain Text"
     }
 
-    fn set_scope_offset(&mut self, offset: u32) {
-        if !self.has_offset() {
-            self.scope_offset = Some(offset)
-        }
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { wants_whole_line: true, ..Capabilities::default() }
     }
 
-    fn get_all_scopes(&self) -> Vec<Vec<String>> {
-        vec![PLAINTEXT_SOURCE_SCOPE.iter().map(|it| it.to_string()).collect()]
+    fn get_scope_for_state(&self, _state: State) -> Scope {
+        PLAINTEXT_SOURCE_SCOPE.iter().map(|it| it.to_string()).collect()
     }
 
-    fn get_scope_id_for_state(&self, _state: State) -> ScopeId {
-        self.scope_offset.unwrap_or_default()
+    fn scopes(&self) -> Vec<Scope> {
+        vec![self.get_scope_for_state(State::default())]
     }
 
     fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
-        (0, self.ctx.push(state, ()), text.as_bytes().len(), state)
+        (0, self.ctx.push(state, ()), text.len(), state)
+    }
+
+    fn stack_stats(&self) -> Stats {
+        self.ctx.stats()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit()
     }
 }
@@ -16,24 +16,28 @@
 
 use std::io::{stdin, Read};
 
-use crate::parser::Parser;
+use crate::parser::{
+    Capabilities, FoldHint, FoldKind, IndentHint, LineFolding, Parser, Symbol, SymbolKind,
+};
 use crate::peg::*;
-use crate::statestack::{Context, State};
-use crate::ScopeId;
+use crate::scope_tracker::Scope;
+use crate::statestack::{Context, State, Stats};
+use crate::text::{split_once, strip_prefix};
 
 /// See [this](https://github.com/sublimehq/Packages/blob/master/Rust/Rust.sublime-syntax)
-/// for reference.
+/// for reference. The names are sent as `scope_map`'s table for Rust has
+/// them.
 static ALL_SCOPES: &[&[&str]] = &[
-    &["source.rust"],
-    &["source.rust", "string.quoted.double.rust"],
-    &["source.rust", "string.quoted.single.rust"],
-    &["source.rust", "comment.line.double-slash.rust"],
-    &["source.rust", "constant.character.escape.rust"],
-    &["source.rust", "constant.numeric.decimal.rust"],
-    &["source.rust", "invalid.illegal.rust"],
-    &["source.rust", "keyword.operator.rust"],
-    &["source.rust", "keyword.operator.arithmetic.rust"],
-    &["source.rust", "entity.name.type.rust"],
+    &["rust:source"],
+    &["rust:source", "rust:string.double"],
+    &["rust:source", "rust:string.single"],
+    &["rust:source", "rust:comment"],
+    &["rust:source", "rust:escape"],
+    &["rust:source", "rust:number"],
+    &["rust:source", "rust:invalid"],
+    &["rust:source", "rust:keyword"],
+    &["rust:source", "rust:operator"],
+    &["rust:source", "rust:primitive-type"],
 ];
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -57,7 +61,8 @@ pub enum StateEl {
 }
 
 impl StateEl {
-    pub fn scope_id(&self) -> ScopeId {
+    /// Index of this element's scope in `ALL_SCOPES`.
+    pub fn scope_index(&self) -> usize {
         match self {
             StateEl::Source => 0,
             StateEl::StrQuote => 1,
@@ -73,68 +78,19 @@ impl StateEl {
     }
 }
 
-// sorted for easy binary searching
-const RUST_KEYWORDS: &[&[u8]] = &[
-    b"Self",
-    b"abstract",
-    b"alignof",
-    b"as",
-    b"become",
-    b"box",
-    b"break",
-    b"const",
-    b"continue",
-    b"crate",
-    b"default",
-    b"do",
-    b"else",
-    b"enum",
-    b"extern",
-    b"false",
-    b"final",
-    b"fn",
-    b"for",
-    b"if",
-    b"impl",
-    b"in",
-    b"let",
-    b"loop",
-    b"macro",
-    b"match",
-    b"mod",
-    b"move",
-    b"mut",
-    b"offsetof",
-    b"override",
-    b"priv",
-    b"proc",
-    b"pub",
-    b"pure",
-    b"ref",
-    b"return",
-    b"self",
-    b"sizeof",
-    b"static",
-    b"struct",
-    b"super",
-    b"trait",
-    b"true",
-    b"type",
-    b"typeof",
-    b"union",
-    b"unsafe",
-    b"unsized",
-    b"use",
-    b"virtual",
-    b"where",
-    b"while",
-    b"yield",
+// sorted, as KeywordSet wants
+const RUST_KEYWORDS: &[&str] = &[
+    "Self", "abstract", "alignof", "as", "become", "box", "break", "const", "continue", "crate",
+    "default", "do", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+    "let", "loop", "macro", "match", "mod", "move", "mut", "offsetof", "override", "priv", "proc",
+    "pub", "pure", "ref", "return", "self", "sizeof", "static", "struct", "super", "trait", "true",
+    "type", "typeof", "union", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
 ];
 
-// sorted for easy binary searching
-const RUST_PRIM_TYPES: &[&[u8]] = &[
-    b"bool", b"char", b"f32", b"f64", b"i128", b"i16", b"i32", b"i64", b"i8", b"isize", b"str",
-    b"u128", b"u16", b"u32", b"u64", b"u8", b"usize",
+// sorted, as KeywordSet wants
+const RUST_PRIM_TYPES: &[&str] = &[
+    "bool", "char", "f32", "f64", "i128", "i16", "i32", "i64", "i8", "isize", "str", "u128", "u16",
+    "u32", "u64", "u8", "usize",
 ];
 
 const RUST_OPERATORS: &[&[u8]] = &[
@@ -144,13 +100,18 @@ const RUST_OPERATORS: &[&[u8]] = &[
 ];
 
 pub struct RustParser {
-    scope_offset: Option<u32>,
     ctx: Context<StateEl>,
+    /// Keywords and primitive types, with the element of each.
+    words: KeywordSet<StateEl>,
 }
 
 impl RustParser {
     pub fn new() -> RustParser {
-        RustParser { scope_offset: None, ctx: Context::new() }
+        let words = KeywordSet::new(&[
+            (RUST_KEYWORDS, StateEl::Keyword),
+            (RUST_PRIM_TYPES, StateEl::PrimType),
+        ]);
+        RustParser { ctx: Context::new(), words }
     }
 
     fn quoted_str(&mut self, t: &[u8], state: State) -> (usize, State, usize, State) {
@@ -174,32 +135,34 @@ impl RustParser {
     }
 }
 
+impl Default for RustParser {
+    fn default() -> RustParser {
+        RustParser::new()
+    }
+}
+
 impl Parser for RustParser {
-    fn has_offset(&mut self) -> bool {
-        self.scope_offset.is_some()
+    fn name(&self) -> &'static str {
+        "Rust"
     }
 
-    fn set_scope_offset(&mut self, offset: u32) {
-        if !self.has_offset() {
-            self.scope_offset = Some(offset)
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            supports_indentation: true,
+            supports_folding: true,
+            reports_symbols: true,
+            emits_sub_spans: true,
+            ..Capabilities::default()
         }
     }
 
-    fn get_all_scopes(&self) -> Vec<Vec<String>> {
-        ALL_SCOPES
-            .iter()
-            .map(|stack| stack.iter().map(|s| s.to_string()).collect::<Vec<_>>())
-            .collect()
+    fn get_scope_for_state(&self, state: State) -> Scope {
+        let index = self.ctx.tos(state).map(|element| element.scope_index()).unwrap_or(0);
+        ALL_SCOPES[index].iter().map(|s| s.to_string()).collect()
     }
 
-    fn get_scope_id_for_state(&self, state: State) -> ScopeId {
-        let offset = self.scope_offset.unwrap_or_default();
-
-        if let Some(element) = self.ctx.tos(state) {
-            element.scope_id() + offset
-        } else {
-            offset
-        }
+    fn scopes(&self) -> Vec<Scope> {
+        ALL_SCOPES.iter().map(|stack| stack.iter().map(|s| s.to_string()).collect()).collect()
     }
 
     fn parse(&mut self, text: &str, mut state: State) -> (usize, State, usize, State) {
@@ -222,11 +185,16 @@ impl Parser for RustParser {
         let mut i = 0;
         while i < t.len() {
             let b = t[i];
+            if !TOKEN_START.contains(b) {
+                debug_assert!(!starts_token(&t[i..]), "{:?} starts a token", &text[i..]);
+                i += 1;
+                continue;
+            }
             if let Some(len) = "/*".p(&t[i..]) {
                 state = self.ctx.push(state, StateEl::Comment);
                 return (i, state, len, state);
             } else if "//".p(&t[i..]).is_some() {
-                return (i, self.ctx.push(state, StateEl::Comment), t.len(), state);
+                return (i, self.ctx.push(state, StateEl::Comment), t.len() - i, state);
             } else if let Some(len) = numeric_literal.p(&t[i..]) {
                 return (i, self.ctx.push(state, StateEl::NumericLiteral), len, state);
             } else if b == b'"' {
@@ -234,17 +202,14 @@ impl Parser for RustParser {
                 return (i, state, 1, state);
             } else if let Some(len) = char_literal.p(&t[i..]) {
                 return (i, self.ctx.push(state, StateEl::CharQuote), len, state);
-            } else if let Some(len) = OneOf(RUST_OPERATORS).p(&t[i..]) {
+            } else if let Some(len) = operator.p(&t[i..]) {
                 return (i, self.ctx.push(state, StateEl::Operator), len, state);
             } else if let Some(len) = ident.p(&t[i..]) {
-                if RUST_KEYWORDS.binary_search(&&t[i..i + len]).is_ok() {
-                    return (i, self.ctx.push(state, StateEl::Keyword), len, state);
-                } else if RUST_PRIM_TYPES.binary_search(&&t[i..i + len]).is_ok() {
-                    return (i, self.ctx.push(state, StateEl::PrimType), len, state);
-                } else {
-                    i += len;
-                    continue;
+                if let Some(el) = self.words.get(&t[i..i + len]) {
+                    return (i, self.ctx.push(state, el), len, state);
                 }
+                i += len;
+                continue;
             } else if let Some(len) = whitespace.p(&t[i..]) {
                 return (i, self.ctx.push(state, StateEl::Source), len, state);
             }
@@ -254,32 +219,160 @@ impl Parser for RustParser {
 
         (0, self.ctx.push(state, StateEl::Source), t.len(), state)
     }
-}
 
-fn is_digit(c: u8) -> bool {
-    c >= b'0' && c <= b'9'
-}
+    fn indent_delta(&self, line: &str, state_before: State) -> IndentHint {
+        let code = line.trim();
+        match self.ctx.tos(state_before) {
+            // line up with the `*` of the line above, if it has one
+            Some(StateEl::Comment) if code.starts_with('*') => return IndentHint::Hanging(" "),
+            Some(StateEl::Comment) => return IndentHint::Hanging(" * "),
+            Some(StateEl::StrQuote) => return IndentHint::Verbatim,
+            _ => (),
+        }
+        // A line like `} else {` increases the indentation of the lines
+        // after it; its own is left to the closing brace being typed.
+        if code.ends_with('{') || code.ends_with('(') || code.ends_with("=>") {
+            IndentHint::Increase
+        } else if code.starts_with('}') || code.starts_with(')') {
+            IndentHint::DecreaseThisLine
+        } else {
+            IndentHint::None
+        }
+    }
 
-fn is_hex_digit(c: u8) -> bool {
-    (c >= b'0' && c <= b'9') || (c >= b'a' && c <= b'f') || (c >= b'A' && c <= b'F')
-}
+    fn autoclose_pairs(&self) -> &[(char, char)] {
+        // `|` is left out, as closures' parameters are short
+        &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')]
+    }
 
-// Note: will have to rework this if we want to support non-ASCII identifiers
-fn is_ident_start(c: u8) -> bool {
-    (c >= b'A' && c <= b'Z') || (c >= b'a' && c <= b'z') || c == b'_'
-}
+    fn opens_pair_after(&self, prefix: &str, opener: char) -> bool {
+        if opener != '\'' {
+            return true;
+        }
+        // a quote after `&` or `<`, or on the end of a word, starts a
+        // lifetime or is a typo; `b'` starts a byte literal
+        let mut before = prefix.bytes().rev();
+        match before.next() {
+            Some(b'&') | Some(b'<') => false,
+            Some(b'b') => !before.next().map_or(false, |c| IDENT_CONTINUE.contains(c)),
+            Some(c) => !IDENT_CONTINUE.contains(c),
+            None => true,
+        }
+    }
+
+    fn comment_continuation(&self, state: State, line: &str) -> Option<String> {
+        let code = line.trim_start();
+        let indent = &line[..line.len() - code.len()];
+        match self.ctx.tos(state) {
+            // still in a block comment: line up under its opening `/*`, or
+            // with the `*` of this line
+            Some(StateEl::Comment) => {
+                if let Some(open) = line.rfind("/*") {
+                    let align: String =
+                        line[..open].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                    Some(align + " * ")
+                } else if code.starts_with('*') {
+                    Some(format!("{}* ", indent))
+                } else {
+                    None
+                }
+            }
+            // `////` and on is an ordinary comment
+            _ if self.in_code(state) && !code.starts_with("////") => ["///", "//!"]
+                .iter()
+                .find(|leader| code.starts_with(*leader))
+                .map(|leader| format!("{}{} ", indent, leader)),
+            _ => None,
+        }
+    }
+
+    fn in_code(&self, state: State) -> bool {
+        match self.ctx.tos(state) {
+            Some(StateEl::Comment) | Some(StateEl::StrQuote) | Some(StateEl::CharQuote) => false,
+            Some(StateEl::CharConst) | Some(StateEl::Invalid) => false,
+            _ => true,
+        }
+    }
+
+    fn line_comment(&self) -> Option<&str> {
+        Some("//")
+    }
 
-fn is_ident_continue(c: u8) -> bool {
-    is_ident_start(c) || is_digit(c)
+    fn block_comment(&self) -> Option<(&str, &str)> {
+        Some(("/*", "*/"))
+    }
+
+    fn line_folding(&self, line: &str, state_before: State) -> LineFolding {
+        let kind = match self.ctx.tos(state_before) {
+            Some(StateEl::Comment) => FoldKind::Comment,
+            _ => FoldKind::Region,
+        };
+        let code = line.trim_start();
+        let hint = if !self.in_code(state_before) {
+            None
+        } else if code.starts_with("use ") || code.starts_with("pub use ") {
+            Some(FoldHint::Run(FoldKind::Imports))
+        } else if code.starts_with("#[cfg") {
+            Some(FoldHint::Item(FoldKind::Region))
+        } else {
+            None
+        };
+        let ends_item = self.in_code(state_before) && code.trim_end().ends_with(&[';', '}'][..]);
+        LineFolding { depth: self.ctx.depth(state_before), kind, hint, ends_item }
+    }
+
+    fn line_symbol(&self, line: &str, state_before: State) -> Option<Symbol> {
+        if self.in_code(state_before) {
+            declaration(line.trim_start())
+        } else {
+            None
+        }
+    }
+
+    fn stack_stats(&self) -> Stats {
+        self.ctx.stats()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit()
+    }
 }
 
+// The byte classes are written out as bits, as a const fn can't loop over
+// bytes; `byte_classes_have_their_bytes` builds each from its bytes.
+
+/// `0` to `9`.
+const DIGIT: ByteClass = ByteClass::from_bits([0x03ff_0000_0000_0000, 0, 0, 0]);
+/// Digits and `a` to `f`, either case.
+const HEX_DIGIT: ByteClass =
+    ByteClass::from_bits([0x03ff_0000_0000_0000, 0x0000_007e_0000_007e, 0, 0]);
+// Note: will have to rework this if we want to support non-ASCII identifiers
+/// `_` and ASCII letters.
+const IDENT_START: ByteClass = ByteClass::from_bits([0, 0x07ff_fffe_87ff_fffe, 0, 0]);
+/// `IDENT_START` and digits.
+const IDENT_CONTINUE: ByteClass =
+    ByteClass::from_bits([0x03ff_0000_0000_0000, 0x07ff_fffe_87ff_fffe, 0, 0]);
+// Reference: https://en.cppreference.com/w/cpp/string/byte/isspace
+// 0x0B -> \v
+// 0x0C -> \f
+const WHITESPACE: ByteClass = ByteClass::from_bits([0x0000_0001_0000_3e00, 0, 0, 0]);
+/// The first bytes of `RUST_OPERATORS`.
+const OPERATOR_START: ByteClass =
+    ByteClass::from_bits([0x7000_ec62_0000_0000, 0x1000_0000_4000_0000, 0, 0]);
+/// Every byte a token, comment or literal can start with: `IDENT_CONTINUE`,
+/// `WHITESPACE`, `OPERATOR_START` and quotes. The parser skips any other
+/// byte, including every byte of a non-ASCII character, without trying to
+/// match anything there.
+const TOKEN_START: ByteClass =
+    ByteClass::from_bits([0x73ff_ece7_0000_3e00, 0x17ff_fffe_c7ff_fffe, 0, 0]);
+
 fn ident(s: &[u8]) -> Option<usize> {
-    (OneByte(is_ident_start), ZeroOrMore(OneByte(is_ident_continue))).p(s)
+    IDENT_START.p(s).map(|len| len + IDENT_CONTINUE.run(&s[len..]))
 }
 
 // sequence of decimal digits with optional separator
 fn raw_numeric(s: &[u8]) -> Option<usize> {
-    (OneByte(is_digit), ZeroOrMore(Alt(b'_', OneByte(is_digit)))).p(s)
+    (DIGIT, ZeroOrMore(Alt(b'_', DIGIT))).p(s)
 }
 
 fn int_suffix(s: &[u8]) -> Option<usize> {
@@ -301,7 +394,7 @@ fn positive_nondecimal(s: &[u8]) -> Option<usize> {
     (
         b'0',
         Alt3(
-            (b'x', OneOrMoreWithSep(OneByte(is_hex_digit), b'_')),
+            (b'x', OneOrMoreWithSep(HEX_DIGIT, b'_')),
             (b'o', OneOrMoreWithSep(Inclusive(b'0'..b'7'), b'_')),
             (b'b', OneOrMoreWithSep(Alt(b'0', b'1'), b'_')),
         ),
@@ -316,7 +409,7 @@ fn positive_decimal(s: &[u8]) -> Option<usize> {
         Alt(
             int_suffix,
             (
-                Optional((b'.', FailIf(OneByte(is_ident_start)), Optional(raw_numeric))),
+                Optional((b'.', FailIf(IDENT_START), Optional(raw_numeric))),
                 Optional((Alt(b'e', b'E'), Optional(Alt(b'+', b'-')), raw_numeric)),
                 Optional(Alt("f32", "f64")),
             ),
@@ -334,8 +427,8 @@ fn escape(s: &[u8]) -> Option<usize> {
         b'\\',
         Alt3(
             OneOf(b"\\\'\"0nrt"),
-            (b'x', Repeat(OneByte(is_hex_digit), 2)),
-            ("u{", Repeat(OneByte(is_hex_digit), 1..7), b'}'),
+            (b'x', Repeat(HEX_DIGIT, 2)),
+            ("u{", Repeat(HEX_DIGIT, 1..7), b'}'),
         ),
     )
         .p(s)
@@ -345,12 +438,29 @@ fn char_literal(s: &[u8]) -> Option<usize> {
     (b'\'', Alt(OneChar(|c| c != '\\' && c != '\''), escape), b'\'').p(s)
 }
 
+fn operator(s: &[u8]) -> Option<usize> {
+    if !OPERATOR_START.contains(*s.first()?) {
+        return None;
+    }
+    OneOf(RUST_OPERATORS).p(s)
+}
+
 // Parser for an arbitrary number of whitespace characters
-// Reference: https://en.cppreference.com/w/cpp/string/byte/isspace
 fn whitespace(s: &[u8]) -> Option<usize> {
-    // 0x0B -> \v
-    // 0x0C -> \f
-    (OneOrMore(OneOf(&[b' ', b'\t', b'\n', b'\r', 0x0B, 0x0C]))).p(s)
+    Some(WHITESPACE.run(s)).filter(|&len| len > 0)
+}
+
+/// Whether anything the parser looks for in code matches at the start of
+/// `s`; the slow path `TOKEN_START` stands in for.
+fn starts_token(s: &[u8]) -> bool {
+    "/*".p(s).is_some()
+        || "//".p(s).is_some()
+        || numeric_literal.p(s).is_some()
+        || s.first() == Some(&b'"')
+        || char_literal.p(s).is_some()
+        || OneOf(RUST_OPERATORS).p(s).is_some()
+        || ident.p(s).is_some()
+        || whitespace.p(s).is_some()
 }
 
 // A simple stdio based harness for testing.
@@ -375,9 +485,94 @@ pub fn test() {
     }
 }
 
+/// The declaration `code` starts with, past any visibility and qualifiers.
+fn declaration(mut code: &str) -> Option<Symbol> {
+    loop {
+        let word = leading_ident(code);
+        let after = code[word.len()..].trim_start();
+        let kind = match word {
+            // `pub(crate)` and the like
+            "pub" => {
+                code = match strip_prefix(after, "(") {
+                    Some(restriction) => split_once(restriction, ")")?.1.trim_start(),
+                    None => after,
+                };
+                continue;
+            }
+            // with an ABI, as in `extern "C" fn`
+            "extern" => {
+                code = match strip_prefix(after, "\"") {
+                    Some(abi) => split_once(abi, "\"")?.1.trim_start(),
+                    None => after,
+                };
+                continue;
+            }
+            "const" if ["fn", "unsafe", "extern"].contains(&leading_ident(after)) => {
+                code = after;
+                continue;
+            }
+            "async" | "default" | "unsafe" => {
+                code = after;
+                continue;
+            }
+            "fn" => SymbolKind::Function,
+            "struct" | "union" => SymbolKind::Struct,
+            "enum" => SymbolKind::Enum,
+            "trait" => SymbolKind::Trait,
+            "mod" => SymbolKind::Module,
+            "const" | "static" => SymbolKind::Constant,
+            "impl" => {
+                // named for what it implements, as in `Display for Point`
+                let header = skip_generics(after);
+                let end = [header.find('{'), header.find(" where")].iter().flatten().min().cloned();
+                let name = header[..end.unwrap_or(header.len())].trim();
+                if name.is_empty() {
+                    return None;
+                }
+                return Some(Symbol { kind: SymbolKind::Impl, name: name.to_owned() });
+            }
+            _ => return None,
+        };
+        let after = strip_prefix(after, "mut ").unwrap_or(after);
+        let name = leading_ident(after);
+        if name.is_empty() {
+            return None;
+        }
+        return Some(Symbol { kind, name: name.to_owned() });
+    }
+}
+
+/// The identifier `text` starts with, or the empty string.
+fn leading_ident(text: &str) -> &str {
+    let end = text.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(text.len());
+    &text[..end]
+}
+
+/// `text` past the generic parameters it starts with, if it does.
+fn skip_generics(text: &str) -> &str {
+    if !text.starts_with('<') {
+        return text;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => {
+                depth -= 1;
+                if depth == 0 {
+                    return text[i + 1..].trim_start();
+                }
+            }
+            _ => (),
+        }
+    }
+    ""
+}
+
 #[cfg(test)]
 mod tests {
-    use super::numeric_literal;
+    use super::*;
+    use crate::text::lines_with_endings;
 
     #[test]
     fn numeric_literals() {
@@ -392,4 +587,189 @@ mod tests {
         assert_eq!(Some(4), numeric_literal(b"0xff"));
         assert_eq!(Some(4), numeric_literal(b"0o6789"));
     }
+
+    /// The indent hint for each line of `text`.
+    fn indent_hints(text: &str) -> Vec<IndentHint> {
+        let mut parser = RustParser::new();
+        let mut state = State::default();
+        let mut hints = Vec::new();
+        for line in lines_with_endings(text) {
+            hints.push(parser.indent_delta(line, state));
+            let mut i = 0;
+            while i < line.len() {
+                let (prevlen, _, len, s1) = parser.parse(&line[i..], state);
+                i += prevlen + len;
+                state = s1;
+            }
+        }
+        hints
+    }
+
+    #[test]
+    fn declarations() {
+        let symbol = |kind, name: &str| Some(Symbol { kind, name: name.to_owned() });
+        let cases = &[
+            ("fn main() {", symbol(SymbolKind::Function, "main")),
+            ("pub(crate) async unsafe fn run<T>(t: T)", symbol(SymbolKind::Function, "run")),
+            ("pub const fn new() -> Self {", symbol(SymbolKind::Function, "new")),
+            ("extern \"C\" fn callback()", symbol(SymbolKind::Function, "callback")),
+            ("pub struct Point {", symbol(SymbolKind::Struct, "Point")),
+            ("enum Kind { A, B }", symbol(SymbolKind::Enum, "Kind")),
+            ("pub trait Parser {", symbol(SymbolKind::Trait, "Parser")),
+            ("mod tests {", symbol(SymbolKind::Module, "tests")),
+            ("const MAX: usize = 1;", symbol(SymbolKind::Constant, "MAX")),
+            ("static mut COUNT: u32 = 0;", symbol(SymbolKind::Constant, "COUNT")),
+            ("impl Point {", symbol(SymbolKind::Impl, "Point")),
+            (
+                "impl<T: Clone> fmt::Display for Wrapper<T> where T: Debug {",
+                symbol(SymbolKind::Impl, "fmt::Display for Wrapper<T>"),
+            ),
+            ("let x = 1;", None),
+            ("extern crate serde;", None),
+            ("pub use foo::bar;", None),
+            ("impl", None),
+        ];
+        for (code, expected) in cases {
+            assert_eq!(declaration(code), *expected, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn lines_get_indent_hints() {
+        use IndentHint::*;
+        let cases: &[(&str, &[IndentHint])] = &[
+            ("fn main() {\n    42\n}\n", &[Increase, None, DecreaseThisLine]),
+            ("foo(\n    a,\n)\n", &[Increase, None, DecreaseThisLine]),
+            ("match x {\n    1 =>\n        2,\n", &[Increase, Increase, None]),
+            ("} else {\n", &[Increase]),
+            (
+                "/* open\nmore\n * star\n */\nfn f()\n",
+                &[None, Hanging(" * "), Hanging(" "), Hanging(" "), None],
+            ),
+            ("let s = \"multi\n  line {\n\";\n", &[None, Verbatim, Verbatim]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(indent_hints(text), *expected, "{:?}", text);
+        }
+    }
+
+    /// Lines of random code, punctuation and non-ASCII characters, the
+    /// same on every run.
+    fn random_lines(count: usize) -> Vec<String> {
+        const PIECES: &[&str] = &[
+            "fn",
+            " ",
+            "\t",
+            "x_1",
+            "u8",
+            "0x1f",
+            "1.5e3",
+            "-2",
+            "'a'",
+            "'\\n'",
+            "'é'",
+            "\"",
+            "\\",
+            "/*",
+            "*/",
+            "//",
+            "->",
+            "..",
+            "é",
+            "λ",
+            "\u{2014}",
+            "\u{1f600}",
+            "(",
+            ")",
+            "{",
+            ";",
+            "#",
+            "@",
+            "$",
+            "`",
+            "~",
+            "?",
+            ":",
+            ",",
+            "\u{b}",
+        ];
+        // a linear congruential generator, as in Knuth's MMIX
+        let mut seed = 1u64;
+        let mut next = |bound: usize| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) as usize % bound
+        };
+        (0..count).map(|_| (0..next(24)).map(|_| PIECES[next(PIECES.len())]).collect()).collect()
+    }
+
+    #[test]
+    fn fast_paths_agree_with_slow_ones() {
+        let slow_ident = (
+            OneByte(|c: u8| c.is_ascii_alphabetic() || c == b'_'),
+            ZeroOrMore(OneByte(|c: u8| c.is_ascii_alphanumeric() || c == b'_')),
+        );
+        let slow_whitespace = OneOrMore(OneOf(&[b' ', b'\t', b'\n', b'\r', 0x0B, 0x0C]));
+        for line in random_lines(500) {
+            let t = line.as_bytes();
+            for i in 0..t.len() {
+                assert_eq!(ident(&t[i..]), slow_ident.p(&t[i..]), "{:?} at {}", line, i);
+                assert_eq!(whitespace(&t[i..]), slow_whitespace.p(&t[i..]), "{:?} at {}", line, i);
+                assert_eq!(operator(&t[i..]), OneOf(RUST_OPERATORS).p(&t[i..]));
+                if !TOKEN_START.contains(t[i]) {
+                    assert!(!starts_token(&t[i..]), "{:?} at {}", line, i);
+                }
+            }
+
+            // lexed with the debug assertions on
+            let mut parser = RustParser::new();
+            let mut state = State::default();
+            let mut i = 0;
+            while i < line.len() {
+                let (prevlen, _, len, s1) = parser.parse(&line[i..], state);
+                assert!(prevlen + len > 0, "stuck on {:?}", &line[i..]);
+                i += prevlen + len;
+                state = s1;
+            }
+        }
+    }
+
+    #[test]
+    fn byte_classes_have_their_bytes() {
+        let digit = ByteClass::new(b"").with_range(b'0', b'9');
+        assert_eq!(DIGIT, digit);
+        assert_eq!(HEX_DIGIT, digit.with_range(b'a', b'f').with_range(b'A', b'F'));
+        let ident_start = ByteClass::new(b"_").with_range(b'A', b'Z').with_range(b'a', b'z');
+        assert_eq!(IDENT_START, ident_start);
+        assert_eq!(IDENT_CONTINUE, ident_start.union(digit));
+        let whitespace = ByteClass::new(&[b' ', b'\t', b'\n', b'\r', 0x0B, 0x0C]);
+        assert_eq!(WHITESPACE, whitespace);
+        let operator_start = RUST_OPERATORS.iter().fold(ByteClass::new(b""), |c, op| c.with(op[0]));
+        assert_eq!(OPERATOR_START, operator_start);
+        let quotes = ByteClass::new(b"\"'");
+        assert_eq!(
+            TOKEN_START,
+            IDENT_CONTINUE.union(whitespace).union(operator_start).union(quotes)
+        );
+    }
+
+    #[test]
+    fn skipped_bytes_start_nothing() {
+        for b in (0..=std::u8::MAX).filter(|&b| !TOKEN_START.contains(b)) {
+            for &rest in &[&b""[..], b"*", b"/", b"0", b"x", b"=", b"'"] {
+                let s = [&[b][..], rest].concat();
+                assert!(!starts_token(&s), "{:?}", s);
+            }
+        }
+    }
+
+    #[test]
+    fn line_comments_end_with_the_line() {
+        let mut parser = RustParser::new();
+        for &(text, prevlen) in &[("// c\n", 0), ("x// c\n", 1), ("xé// c", 3)] {
+            let (start, _, len, _) = parser.parse(text, State::default());
+            assert_eq!((start, start + len), (prevlen, text.len()), "{:?}", text);
+        }
+    }
 }
@@ -0,0 +1,1012 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A parser that interprets a Sublime Text `.sublime-syntax` definition,
+//! for the languages nobody has written a parser for here. The subset
+//! read is contexts, with `match`, `push`, `pop`, `set`, `meta_scope`,
+//! `meta_content_scope`, `captures`, `include` and the `prototype`, and
+//! `variables` in regexes. The stack of contexts is the parser's state
+//! stack, with `main` at the bottom as the empty stack.
+//!
+//! Anything else a syntax uses, like branch points or embedding with an
+//! escape, is reported when it's loaded, and the match it's on is kept
+//! without it, so a syntax highlights as well as it can rather than not
+//! at all. Scope names that aren't well formed are reported and left out.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use fancy_regex::Regex;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::parser::{Capabilities, Parser};
+use crate::scope_tracker::Scope;
+use crate::scope_validator::ScopeValidator;
+use crate::statestack::{Context, State, Stats};
+
+/// The extension of the files syntaxes are read from.
+pub const EXTENSION: &str = "sublime-syntax";
+
+/// The context matching starts in, as the empty stack.
+const MAIN: ContextId = 0;
+
+/// How deep the stack of contexts may get, so that a syntax that pushes
+/// without consuming anything can't grow it forever; pushes past it are
+/// left undone.
+const MAX_DEPTH: usize = 128;
+
+/// How deeply variables may refer to other variables.
+const MAX_EXPANSIONS: usize = 16;
+
+/// The keys of a match, other than those read, that change what it does.
+/// A match with one is kept, as if it didn't have it.
+const UNSUPPORTED_MATCH_KEYS: &[&str] =
+    &["branch_point", "branch", "fail", "embed", "escape", "embed_scope", "with_prototype"];
+
+type ContextId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    None,
+    Push(Vec<ContextId>),
+    /// Replaces the innermost context with these.
+    Set(Vec<ContextId>),
+    /// Pops this many contexts.
+    Pop(usize),
+}
+
+/// Where each group of a match is, if it took part, the whole match
+/// first.
+type Groups = Vec<Option<(usize, usize)>>;
+
+struct Pattern {
+    regex: Regex,
+    scope: Scope,
+    /// The scope of each capture group that has one, by group.
+    captures: Vec<(usize, Scope)>,
+    action: Action,
+}
+
+#[derive(Default)]
+struct SyntaxContext {
+    /// The scope of all the text in the context, including the match that
+    /// pushed it and the one that popped it.
+    meta_scope: Scope,
+    /// The scope of the text in the context between those matches.
+    meta_content_scope: Scope,
+    /// The patterns tried in it, in order, its includes' among them.
+    patterns: Vec<usize>,
+}
+
+/// A language as a `.sublime-syntax` file defines it.
+pub struct Syntax {
+    pub name: String,
+    pub file_extensions: Vec<String>,
+    /// The scope of the whole document, like `source.python`.
+    scope: String,
+    /// The contexts, `main` first.
+    contexts: Vec<SyntaxContext>,
+    patterns: Vec<Pattern>,
+}
+
+impl Syntax {
+    /// Reads the syntax in the file at `path`. Whatever was read but not
+    /// understood is returned along with it.
+    pub fn load(path: &Path) -> Result<(Syntax, Vec<String>), String> {
+        let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let stem = path.file_stem().map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+        Syntax::read(&source, &stem)
+    }
+
+    /// Reads a syntax from `source`, named `default_name` if it doesn't
+    /// say what it's called.
+    pub fn read(source: &str, default_name: &str) -> Result<(Syntax, Vec<String>), String> {
+        let docs = YamlLoader::load_from_str(source).map_err(|err| err.to_string())?;
+        let doc = docs.first().ok_or("the file is empty")?;
+        let scope = doc["scope"].as_str().ok_or("no scope for the document")?;
+        let name = doc["name"].as_str().unwrap_or(default_name).to_owned();
+        let file_extensions = doc["file_extensions"]
+            .as_vec()
+            .map(|exts| exts.iter().filter_map(Yaml::as_str).map(str::to_owned).collect())
+            .unwrap_or_default();
+        let definitions: HashMap<&str, &Yaml> = doc["contexts"]
+            .as_hash()
+            .ok_or("no contexts")?
+            .iter()
+            .filter_map(|(name, items)| Some((name.as_str()?, items)))
+            .collect();
+        if !definitions.contains_key("main") {
+            return Err("no main context".to_owned());
+        }
+
+        let mut builder = Builder {
+            definitions,
+            variables: HashMap::new(),
+            validator: ScopeValidator::new(),
+            ids: HashMap::new(),
+            flattened: HashMap::new(),
+            including: Vec::new(),
+            prototype: Vec::new(),
+            contexts: Vec::new(),
+            patterns: Vec::new(),
+            problems: Vec::new(),
+        };
+        builder.read_variables(&doc["variables"]);
+        // main's id is taken first, so that it is MAIN even if the
+        // prototype pushes contexts of its own
+        builder.contexts.push(SyntaxContext::default());
+        builder.ids.insert("main".to_owned(), MAIN);
+        if builder.definitions.contains_key("prototype") {
+            builder.prototype = builder.flatten("prototype");
+        }
+        builder.build_context(MAIN, "main");
+        let syntax = Syntax {
+            name,
+            file_extensions,
+            scope: scope.to_owned(),
+            contexts: builder.contexts,
+            patterns: builder.patterns,
+        };
+        Ok((syntax, builder.problems))
+    }
+}
+
+/// Reads every syntax in `dir` and the directories in it, in order of
+/// their paths. What couldn't be read, and what was read but not
+/// understood, is returned along with them.
+pub fn load_dir(dir: &Path) -> (Vec<Syntax>, Vec<String>) {
+    let mut paths = Vec::new();
+    let mut problems = Vec::new();
+    find_syntaxes(dir, &mut paths, &mut problems);
+    paths.sort();
+    let mut syntaxes = Vec::new();
+    for path in paths {
+        match Syntax::load(&path) {
+            Ok((syntax, found)) => {
+                syntaxes.push(syntax);
+                problems.extend(found.into_iter().map(|p| format!("{}: {}", path.display(), p)));
+            }
+            Err(err) => problems.push(format!("{}: {}", path.display(), err)),
+        }
+    }
+    (syntaxes, problems)
+}
+
+fn find_syntaxes(dir: &Path, paths: &mut Vec<PathBuf>, problems: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            problems.push(format!("{}: {}", dir.display(), err));
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            find_syntaxes(&path, paths, problems);
+        } else if path.extension().map_or(false, |ext| ext == EXTENSION) {
+            paths.push(path);
+        }
+    }
+}
+
+/// Turns a syntax's contexts into patterns and context ids as they are
+/// met from `main`, so that contexts nothing reaches are left out.
+struct Builder<'a> {
+    definitions: HashMap<&'a str, &'a Yaml>,
+    variables: HashMap<String, String>,
+    validator: ScopeValidator,
+    /// The ids of the named contexts given one so far.
+    ids: HashMap<String, ContextId>,
+    /// The patterns of each named context, with those of its includes.
+    flattened: HashMap<String, Vec<usize>>,
+    /// The contexts being flattened, for catching an include of one
+    /// inside itself.
+    including: Vec<String>,
+    /// The patterns tried first in every context that doesn't opt out.
+    prototype: Vec<usize>,
+    contexts: Vec<SyntaxContext>,
+    patterns: Vec<Pattern>,
+    problems: Vec<String>,
+}
+
+impl<'a> Builder<'a> {
+    fn read_variables(&mut self, variables: &Yaml) {
+        for (name, value) in variables.as_hash().into_iter().flatten() {
+            if let (Some(name), Some(value)) = (name.as_str(), value.as_str()) {
+                self.variables.insert(name.to_owned(), value.to_owned());
+            }
+        }
+    }
+
+    /// The id of the context named `name`, built the first time it's met.
+    fn named_context(&mut self, name: &str) -> Option<ContextId> {
+        if let Some(&id) = self.ids.get(name) {
+            return Some(id);
+        }
+        if !self.definitions.contains_key(name) {
+            self.problems.push(format!("no context named {}", name));
+            return None;
+        }
+        let id = self.contexts.len();
+        self.contexts.push(SyntaxContext::default());
+        self.ids.insert(name.to_owned(), id);
+        self.build_context(id, name);
+        Some(id)
+    }
+
+    /// Fills in the context `id`, the one named `name`.
+    fn build_context(&mut self, id: ContextId, name: &str) {
+        let items = self.definitions[name];
+        let patterns = self.flatten(name);
+        self.fill_context(id, items, patterns, name != "prototype");
+    }
+
+    /// A context with no name, written where it is pushed.
+    fn anonymous_context(&mut self, items: &Yaml, within: &str) -> ContextId {
+        let id = self.contexts.len();
+        self.contexts.push(SyntaxContext::default());
+        let patterns = self.patterns_of(items, within);
+        self.fill_context(id, items, patterns, true);
+        id
+    }
+
+    fn fill_context(&mut self, id: ContextId, items: &Yaml, own: Vec<usize>, prototype: bool) {
+        let mut context = SyntaxContext::default();
+        let mut with_prototype = prototype;
+        for item in items.as_vec().map_or(&[][..], Vec::as_slice) {
+            if let Some(scope) = item["meta_scope"].as_str() {
+                context.meta_scope = self.scope(scope);
+            }
+            if let Some(scope) = item["meta_content_scope"].as_str() {
+                context.meta_content_scope = self.scope(scope);
+            }
+            if let Some(include) = item["meta_include_prototype"].as_bool() {
+                with_prototype &= include;
+            }
+            if !item["clear_scopes"].is_badvalue() {
+                self.problems.push("clear_scopes isn't supported; the scopes are kept".to_owned());
+            }
+        }
+        if with_prototype {
+            context.patterns.extend(&self.prototype);
+        }
+        context.patterns.extend(own);
+        self.contexts[id] = context;
+    }
+
+    /// The patterns of the context named `name`, with those of the
+    /// contexts it includes in their place.
+    fn flatten(&mut self, name: &str) -> Vec<usize> {
+        if let Some(patterns) = self.flattened.get(name) {
+            return patterns.clone();
+        }
+        if self.including.iter().any(|n| n == name) {
+            self.problems.push(format!("{} includes itself", name));
+            return Vec::new();
+        }
+        let items = match self.definitions.get(name) {
+            Some(&items) => items,
+            None => {
+                self.problems.push(format!("no context named {}", name));
+                return Vec::new();
+            }
+        };
+        self.including.push(name.to_owned());
+        let patterns = self.patterns_of(items, name);
+        self.including.pop();
+        self.flattened.insert(name.to_owned(), patterns.clone());
+        patterns
+    }
+
+    fn patterns_of(&mut self, items: &Yaml, within: &str) -> Vec<usize> {
+        let items = match items.as_vec() {
+            Some(items) => items,
+            None => {
+                self.problems.push(format!("{} should be a list", within));
+                return Vec::new();
+            }
+        };
+        let mut patterns = Vec::new();
+        for item in items {
+            if let Some(include) = item["include"].as_str() {
+                if include.starts_with("scope:") || include.contains('/') {
+                    self.problems.push(format!(
+                        "{}: including another syntax, as with {}, isn't supported",
+                        within, include
+                    ));
+                } else {
+                    patterns.extend(self.flatten(include));
+                }
+            } else if !item["match"].is_badvalue() {
+                patterns.extend(self.pattern(item, within));
+            }
+        }
+        patterns
+    }
+
+    fn pattern(&mut self, item: &Yaml, within: &str) -> Option<usize> {
+        let source = item["match"].as_str().unwrap_or_default();
+        let regex = expand(source, &self.variables, 0).and_then(|source| {
+            // `^` and `$` are the line's, as in Sublime Text
+            Regex::new(&format!("(?m){}", source)).map_err(|err| format!("{:?}", err))
+        });
+        let regex = match regex {
+            Ok(regex) => regex,
+            Err(err) => {
+                self.problems.push(format!("{}: can't match {:?}: {}", within, source, err));
+                return None;
+            }
+        };
+        for key in UNSUPPORTED_MATCH_KEYS.iter().filter(|&&key| !item[key].is_badvalue()) {
+            self.problems.push(format!(
+                "{}: {} isn't supported; {:?} is matched without it",
+                within, key, source
+            ));
+        }
+        let scope = item["scope"].as_str().map_or_else(Vec::new, |scope| self.scope(scope));
+        let mut captures = Vec::new();
+        for (group, scope) in item["captures"].as_hash().into_iter().flatten() {
+            let group = match group {
+                Yaml::Integer(group) => *group as usize,
+                Yaml::String(group) => group.parse().unwrap_or(0),
+                _ => 0,
+            };
+            if let (true, Some(scope)) = (group > 0, scope.as_str()) {
+                captures.push((group, self.scope(scope)));
+            }
+        }
+        captures.sort_by_key(|&(group, _)| group);
+        let action = if !item["push"].is_badvalue() {
+            Action::Push(self.targets(&item["push"], within))
+        } else if !item["set"].is_badvalue() {
+            Action::Set(self.targets(&item["set"], within))
+        } else {
+            match &item["pop"] {
+                Yaml::Boolean(true) => Action::Pop(1),
+                Yaml::Integer(n) if *n > 0 => Action::Pop(*n as usize),
+                _ => Action::None,
+            }
+        };
+        self.patterns.push(Pattern { regex, scope, captures, action });
+        Some(self.patterns.len() - 1)
+    }
+
+    /// The contexts a match pushes or sets: a name, a list of names, or a
+    /// context written in place, or a list of those.
+    fn targets(&mut self, target: &Yaml, within: &str) -> Vec<ContextId> {
+        match target {
+            Yaml::String(name) => self.named_context(name).into_iter().collect(),
+            Yaml::Array(items) if items.iter().all(|item| item.as_hash().is_some()) => {
+                vec![self.anonymous_context(target, within)]
+            }
+            Yaml::Array(targets) => targets
+                .iter()
+                .filter_map(|target| match target {
+                    Yaml::String(name) => self.named_context(name),
+                    Yaml::Array(_) => Some(self.anonymous_context(target, within)),
+                    _ => None,
+                })
+                .collect(),
+            _ => {
+                self.problems.push(format!("{}: can't push {:?}", within, target));
+                Vec::new()
+            }
+        }
+    }
+
+    /// The names in `names`, separated by spaces, leaving out any that
+    /// aren't well formed.
+    fn scope(&mut self, names: &str) -> Scope {
+        let mut scope = Vec::new();
+        for name in names.split_whitespace() {
+            match self.validator.validate_name(name) {
+                Ok(()) => scope.push(name.to_owned()),
+                Err(err) => self.problems.push(format!("{}; it's left out", err)),
+            }
+        }
+        scope
+    }
+}
+
+/// `regex` with each `{{variable}}` in it replaced by its value, and the
+/// variables in that by theirs.
+fn expand(
+    regex: &str,
+    variables: &HashMap<String, String>,
+    depth: usize,
+) -> Result<String, String> {
+    if depth > MAX_EXPANSIONS {
+        return Err("variables refer to each other too deeply".to_owned());
+    }
+    let mut out = String::with_capacity(regex.len());
+    let mut rest = regex;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 2..end];
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            // a repetition, as in `a{{2}`
+            out.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let value = variables.get(name).ok_or_else(|| format!("no variable named {}", name))?;
+        out.push_str(&rest[..start]);
+        out.push_str(&expand(value, variables, depth + 1)?);
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum StateEl {
+    /// A context pushed over `main`.
+    Context(ContextId),
+    /// The text of a match, scoped by the contexts under it and its
+    /// scope in `scopes`. It's bare if it's the text that pushed or popped
+    /// the innermost context, which has that context's meta scope but not
+    /// its meta content scope.
+    Match { scope: usize, bare: bool },
+    /// The rest of a match split up by its captures, handed out a piece a
+    /// call: the length and scope of each piece, and the stack they are
+    /// matches over.
+    Pieces { base: State, bare: bool, rest: Vec<(usize, usize)> },
+    /// Marks a state in the middle of a line, where `^` doesn't match.
+    MidLine,
+}
+
+pub struct SublimeParser {
+    syntax: Arc<Syntax>,
+    ctx: Context<StateEl>,
+    /// The scopes of the matches and their pieces.
+    scopes: Vec<Scope>,
+    scope_ids: HashMap<Scope, usize>,
+    /// The line the last call at the start of one was given, for matching
+    /// the rest of it in, so that lookbehinds see the text before.
+    line: String,
+}
+
+impl SublimeParser {
+    pub fn new(syntax: Arc<Syntax>) -> SublimeParser {
+        SublimeParser {
+            syntax,
+            ctx: Context::new(),
+            scopes: Vec::new(),
+            scope_ids: HashMap::new(),
+            line: String::new(),
+        }
+    }
+
+    fn scope_id(&mut self, scope: Scope) -> usize {
+        if let Some(&id) = self.scope_ids.get(&scope) {
+            return id;
+        }
+        self.scopes.push(scope.clone());
+        self.scope_ids.insert(scope, self.scopes.len() - 1);
+        self.scopes.len() - 1
+    }
+
+    /// The contexts on the stack of `state`, from `main` out.
+    fn contexts(&self, mut state: State) -> Vec<ContextId> {
+        let mut contexts = Vec::new();
+        while let Some(el) = self.ctx.tos(state) {
+            if let StateEl::Context(id) = el {
+                contexts.push(id);
+            }
+            state = self.ctx.pop(state).unwrap();
+        }
+        contexts.push(MAIN);
+        contexts.reverse();
+        contexts
+    }
+
+    fn innermost(&self, state: State) -> ContextId {
+        match self.ctx.tos(state) {
+            Some(StateEl::Context(id)) => id,
+            _ => MAIN,
+        }
+    }
+
+    /// `state` with `n` contexts popped, down to `main`.
+    fn pop_contexts(&self, mut state: State, n: usize) -> State {
+        for _ in 0..n {
+            state = self.ctx.pop(state).unwrap_or(state);
+        }
+        state
+    }
+
+    fn push_contexts(&mut self, mut state: State, ids: &[ContextId]) -> State {
+        for &id in ids {
+            state = self.ctx.push(state, StateEl::Context(id));
+        }
+        state
+    }
+
+    /// The stack the text of a match of `action` in `state` is over, whether
+    /// it's bare, and the stack after it.
+    fn apply(&mut self, action: &Action, state: State) -> (State, bool, State) {
+        let after = match action {
+            Action::None => return (state, false, state),
+            Action::Push(ids) => self.push_contexts(state, ids),
+            Action::Set(ids) => {
+                let popped = self.pop_contexts(state, 1);
+                self.push_contexts(popped, ids)
+            }
+            Action::Pop(n) => return (state, true, self.pop_contexts(state, *n)),
+        };
+        if self.ctx.depth(after) > MAX_DEPTH {
+            return (state, false, state);
+        }
+        (after, true, after)
+    }
+
+    /// The pieces the captures of `pattern` split its match at `groups`
+    /// into, as lengths and scopes.
+    fn pieces(&mut self, pattern: usize, groups: &[Option<(usize, usize)>]) -> Vec<(usize, usize)> {
+        let syntax = self.syntax.clone();
+        let pattern = &syntax.patterns[pattern];
+        let (start, end) = groups[0].unwrap();
+        let captured = |group: usize| groups.get(group).and_then(|&bounds| bounds);
+        let mut bounds = vec![start, end];
+        for &(group, _) in &pattern.captures {
+            if let Some((s, e)) = captured(group) {
+                bounds.push(s.max(start).min(end));
+                bounds.push(e.max(start).min(end));
+            }
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut pieces: Vec<(usize, usize)> = Vec::new();
+        for window in bounds.windows(2) {
+            let (s, e) = (window[0], window[1]);
+            let mut scope = pattern.scope.clone();
+            for (group, names) in &pattern.captures {
+                if captured(*group).map_or(false, |(cs, ce)| cs <= s && e <= ce && cs < ce) {
+                    scope.extend(names.iter().cloned());
+                }
+            }
+            let id = self.scope_id(scope);
+            match pieces.last_mut() {
+                Some(last) if last.1 == id => last.0 += e - s,
+                _ => pieces.push((e - s, id)),
+            }
+        }
+        pieces
+    }
+
+    /// The earliest match in the line from `offset` of a pattern of
+    /// `context`, as the pattern and the positions of its groups. Of two
+    /// at the same place the first pattern wins. Empty matches that don't
+    /// change the stack would go on matching forever, so are passed over.
+    fn find_match(&self, context: ContextId, offset: usize) -> Option<(usize, Groups)> {
+        let mut best: Option<(usize, Groups)> = None;
+        for &index in &self.syntax.contexts[context].patterns {
+            let pattern = &self.syntax.patterns[index];
+            // a regex that gives up, as on too much backtracking, doesn't
+            // match
+            let captures = match pattern.regex.captures_from_pos(&self.line, offset) {
+                Ok(Some(captures)) => captures,
+                _ => continue,
+            };
+            let whole = captures.get(0).unwrap();
+            let (start, end) = (whole.start(), whole.end());
+            if start == end && pattern.action == Action::None {
+                continue;
+            }
+            let start = start - offset;
+            if best.as_ref().map_or(false, |(_, groups)| groups[0].unwrap().0 <= start) {
+                continue;
+            }
+            let groups = (0..captures.len())
+                .map(|i| captures.get(i).map(|m| (m.start() - offset, m.end() - offset)))
+                .collect();
+            best = Some((index, groups));
+        }
+        best
+    }
+
+    /// `state`, marked as being in the middle of a line if `mid_line`.
+    fn at(&mut self, state: State, mid_line: bool) -> State {
+        if mid_line {
+            self.ctx.push(state, StateEl::MidLine)
+        } else {
+            state
+        }
+    }
+}
+
+impl Parser for SublimeParser {
+    fn name(&self) -> &'static str {
+        "Sublime"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { emits_sub_spans: true, ..Capabilities::default() }
+    }
+
+    fn get_scope_for_state(&self, mut state: State) -> Scope {
+        let mut matched = None;
+        loop {
+            match self.ctx.tos(state) {
+                Some(StateEl::MidLine) | Some(StateEl::Pieces { .. }) => (),
+                Some(StateEl::Match { scope, bare }) => matched = Some((scope, bare)),
+                _ => break,
+            }
+            state = self.ctx.pop(state).unwrap();
+        }
+        let bare = matched.map_or(false, |(_, bare)| bare);
+        let contexts = self.contexts(state);
+        let mut scope = vec![self.syntax.scope.clone()];
+        for (i, &id) in contexts.iter().enumerate() {
+            let context = &self.syntax.contexts[id];
+            scope.extend(context.meta_scope.iter().cloned());
+            if !(bare && i + 1 == contexts.len()) {
+                scope.extend(context.meta_content_scope.iter().cloned());
+            }
+        }
+        if let Some((id, _)) = matched {
+            scope.extend(self.scopes[id].iter().cloned());
+        }
+        scope
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        vec![self.get_scope_for_state(State::default())]
+    }
+
+    fn parse(&mut self, text: &str, given: State) -> (usize, State, usize, State) {
+        let (state, mid_line) = match self.ctx.tos(given) {
+            Some(StateEl::MidLine) => (self.ctx.pop(given).unwrap(), true),
+            _ => (given, false),
+        };
+        let offset = if !mid_line {
+            self.line.clear();
+            self.line.push_str(text);
+            0
+        } else if self.line.ends_with(text) {
+            self.line.len() - text.len()
+        } else {
+            // the start of the line was given to another parser; all
+            // that's known is that this isn't it
+            self.line.clear();
+            self.line.push('\0');
+            self.line.push_str(text);
+            1
+        };
+
+        if let Some(StateEl::Pieces { base, bare, rest }) = self.ctx.tos(state) {
+            let after = self.ctx.pop(state).unwrap();
+            let (len, scope) = rest[0];
+            let len = len.min(text.len());
+            let s0 = self.ctx.push(base, StateEl::Match { scope, bare });
+            let s1 = if rest.len() > 1 {
+                self.ctx.push(after, StateEl::Pieces { base, bare, rest: rest[1..].to_vec() })
+            } else {
+                after
+            };
+            return (0, s0, len, self.at(s1, len < text.len()));
+        }
+
+        let (index, groups) = match self.find_match(self.innermost(state), offset) {
+            Some(found) => found,
+            None => return (0, given, text.len(), state),
+        };
+        let syntax = self.syntax.clone();
+        let (base, bare, after) = self.apply(&syntax.patterns[index].action, state);
+        let start = groups[0].unwrap().0;
+        let mut pieces = self.pieces(index, &groups);
+        if pieces.is_empty() {
+            // an empty match, which only changes the stack
+            let scope = self.scope_id(syntax.patterns[index].scope.clone());
+            pieces.push((0, scope));
+        }
+        let (len, scope) = pieces[0];
+        let s0 = self.ctx.push(base, StateEl::Match { scope, bare });
+        let s1 = if pieces.len() > 1 {
+            self.ctx.push(after, StateEl::Pieces { base, bare, rest: pieces[1..].to_vec() })
+        } else {
+            after
+        };
+        (start, s0, len, self.at(s1, start + len < text.len()))
+    }
+
+    fn in_code(&self, state: State) -> bool {
+        let scope = self.get_scope_for_state(state);
+        !scope.iter().any(|name| name.starts_with("comment") || name.starts_with("string"))
+    }
+
+    fn stack_stats(&self) -> Stats {
+        self.ctx.stats()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::lines_with_endings;
+
+    fn syntax(source: &str) -> (Arc<Syntax>, Vec<String>) {
+        let (syntax, problems) = Syntax::read(source, "Test").unwrap();
+        (Arc::new(syntax), problems)
+    }
+
+    /// Each token `parse` makes of `text` a line at a time, as its text
+    /// and its scope past the document's, joined by spaces.
+    fn tokens(syntax: &Arc<Syntax>, text: &str) -> Vec<(String, String)> {
+        let mut parser = SublimeParser::new(syntax.clone());
+        let mut state = parser.initial_state();
+        let mut tokens = Vec::new();
+        for line in lines_with_endings(text) {
+            let mut i = 0;
+            while i < line.len() {
+                let (prevlen, s0, len, s1) = parser.parse(&line[i..], state);
+                assert!(prevlen + len > 0 || s1 != state, "stuck at {:?}", &line[i..]);
+                for &(from, to, scoped) in
+                    &[(i, i + prevlen, state), (i + prevlen, i + prevlen + len, s0)]
+                {
+                    if from < to {
+                        let scope = parser.get_scope_for_state(scoped)[1..].join(" ");
+                        tokens.push((line[from..to].to_owned(), scope));
+                    }
+                }
+                i += prevlen + len;
+                state = s1;
+            }
+        }
+        tokens
+    }
+
+    fn token<'a>(tokens: &'a [(String, String)], text: &str) -> &'a str {
+        let found = tokens.iter().find(|(t, _)| t == text);
+        &found.unwrap_or_else(|| panic!("no token {:?} in {:?}", text, tokens)).1
+    }
+
+    const QUOTES: &str = r#"
+name: Quotes
+scope: source.quotes
+file_extensions: [qt]
+variables:
+  ident: '[a-z]+'
+  call: '({{ident}})\('
+contexts:
+  main:
+    - match: '"'
+      scope: punctuation.definition.string.begin.quotes
+      push: string
+    - match: '{{call}}'
+      captures:
+        1: entity.name.function.quotes
+    - match: '^#'
+      push:
+        - meta_scope: comment.line.quotes
+        - match: '$\n?'
+          pop: true
+  string:
+    - meta_scope: string.quoted.double.quotes
+    - meta_content_scope: meta.content.quotes
+    - include: escapes
+    - match: '"'
+      scope: punctuation.definition.string.end.quotes
+      pop: true
+  escapes:
+    - match: '\\.'
+      scope: constant.character.escape.quotes
+"#;
+
+    #[test]
+    fn contexts_scope_their_text() {
+        let (quotes, problems) = syntax(QUOTES);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(quotes.name, "Quotes");
+        assert_eq!(quotes.file_extensions, vec!["qt"]);
+
+        let tokens = tokens(&quotes, "say(\"a\\\"b\") # not\n# comment\n");
+        // the quotes have the string's meta scope, but not its content's
+        let open = "string.quoted.double.quotes punctuation.definition.string.begin.quotes";
+        assert_eq!(token(&tokens, "\""), open);
+        assert_eq!(token(&tokens, "a"), "string.quoted.double.quotes meta.content.quotes");
+        assert_eq!(
+            token(&tokens, "\\\""),
+            "string.quoted.double.quotes meta.content.quotes constant.character.escape.quotes"
+        );
+        assert_eq!(token(&tokens, "b"), "string.quoted.double.quotes meta.content.quotes");
+        assert_eq!(token(&tokens, "say"), "entity.name.function.quotes");
+        assert_eq!(token(&tokens, "("), "");
+        // `^` only matches at the start of a line
+        assert_eq!(token(&tokens, ") # not\n"), "");
+        assert_eq!(token(&tokens, "#"), "comment.line.quotes");
+        assert_eq!(token(&tokens, " comment"), "comment.line.quotes");
+        assert_eq!(token(&tokens, "\n"), "comment.line.quotes");
+    }
+
+    #[test]
+    fn set_replaces_the_innermost_context() {
+        let (syntax, problems) = syntax(
+            r#"
+scope: source.set
+contexts:
+  main:
+    - match: 'let'
+      scope: keyword.other.set
+      push: [value, name]
+  name:
+    - meta_scope: meta.name.set
+    - match: '\w+'
+      scope: variable.other.set
+    - match: '='
+      scope: keyword.operator.set
+      set: expression
+  expression:
+    - match: '\d+'
+      scope: constant.numeric.set
+  value:
+    - match: ';'
+      pop: true
+"#,
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+        let tokens = tokens(&syntax, "let x = 1;\n2\n");
+        assert_eq!(token(&tokens, "let"), "meta.name.set keyword.other.set");
+        assert_eq!(token(&tokens, "x"), "meta.name.set variable.other.set");
+        assert_eq!(token(&tokens, "="), "keyword.operator.set");
+        assert_eq!(token(&tokens, "1"), "constant.numeric.set");
+        // expression never pops, so the `;` is only text in it
+        assert_eq!(token(&tokens, "2"), "constant.numeric.set");
+    }
+
+    #[test]
+    fn the_prototype_is_tried_first_in_each_context() {
+        let (syntax, problems) = syntax(
+            r#"
+scope: source.proto
+contexts:
+  prototype:
+    - match: '#'
+      push:
+        - meta_scope: comment.line.proto
+        - match: '(?=\n)'
+          pop: true
+  main:
+    - match: '\w+'
+      scope: variable.other.proto
+      push: value
+  value:
+    - match: '\w+'
+      scope: string.unquoted.proto
+    - match: '\n'
+      pop: true
+"#,
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+        let tokens = tokens(&syntax, "key value# note\nnext\n");
+        assert_eq!(token(&tokens, "key"), "variable.other.proto");
+        assert_eq!(token(&tokens, "value"), "string.unquoted.proto");
+        assert_eq!(token(&tokens, " note"), "comment.line.proto");
+        assert_eq!(token(&tokens, "next"), "variable.other.proto");
+    }
+
+    #[test]
+    fn lines_end_on_the_stack_alone() {
+        let (quotes, _) = syntax(QUOTES);
+        let mut parser = SublimeParser::new(quotes);
+        let line = "f(\"a\n";
+        let mut state = State::default();
+        let mut i = 0;
+        while i < line.len() {
+            let (prevlen, _, len, s1) = parser.parse(&line[i..], state);
+            i += prevlen + len;
+            state = s1;
+        }
+        assert_eq!(parser.contexts(state).len(), 2);
+        assert_eq!(parser.ctx.depth(state), 1);
+        assert!(!parser.in_code(state));
+    }
+
+    #[test]
+    fn unsupported_features_are_reported() {
+        let (syntax, problems) = syntax(
+            r#"
+scope: source.odd
+contexts:
+  main:
+    - include: scope:source.other
+    - include: main
+    - match: '<'
+      scope: punctuation.odd
+      branch_point: tag
+      branch: [tag]
+    - match: '(?<bad'
+    - match: 'Bad'
+      scope: Keyword.odd
+    - match: '{{nothing}}'
+  tag:
+    - match: '>'
+      pop: true
+"#,
+        );
+        let expected: &[&str] = &[
+            "including another syntax",
+            "includes itself",
+            "branch_point isn't supported",
+            "branch isn't supported",
+            "can't match \"(?<bad\"",
+            "\"Keyword.odd\" contains invalid character",
+            "no variable named nothing",
+        ];
+        assert_eq!(problems.len(), expected.len(), "{:?}", problems);
+        for (problem, expected) in problems.iter().zip(expected) {
+            assert!(problem.contains(expected), "{:?} should say {:?}", problem, expected);
+        }
+        // what could be read still highlights
+        let tokens = tokens(&syntax, "<Bad>\n");
+        assert_eq!(token(&tokens, "<"), "punctuation.odd");
+        assert_eq!(token(&tokens, "Bad"), "");
+    }
+
+    #[test]
+    fn syntaxes_without_main_or_scope_are_refused() {
+        assert!(Syntax::read("scope: source.x\ncontexts: {}\n", "X").is_err());
+        assert!(Syntax::read("contexts:\n  main: []\n", "X").is_err());
+        assert!(Syntax::read("[unclosed\n", "X").is_err());
+        let (syntax, _) = Syntax::read("scope: source.x\ncontexts:\n  main: []\n", "X").unwrap();
+        assert_eq!(syntax.name, "X");
+    }
+
+    #[test]
+    fn variables_expand_inside_each_other() {
+        let variables: HashMap<String, String> =
+            [("digit", "[0-9]"), ("number", "{{digit}}+"), ("loop", "{{loop}}")]
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect();
+        assert_eq!(expand("-?{{number}}", &variables, 0), Ok("-?[0-9]+".to_owned()));
+        assert_eq!(expand("a{{2}", &variables, 0), Ok("a{{2}".to_owned()));
+        assert!(expand("{{loop}}", &variables, 0).is_err());
+    }
+
+    #[test]
+    fn pushes_without_progress_stop() {
+        let (syntax, _) = syntax(
+            r#"
+scope: source.deep
+contexts:
+  main:
+    - match: '(?=x)'
+      push: main
+"#,
+        );
+        // the pushes stop at the limit, and then the line is stuck
+        let mut parser = SublimeParser::new(syntax);
+        let mut state = State::default();
+        for _ in 0..MAX_DEPTH {
+            let (prevlen, _, len, s1) = parser.parse("x\n", state);
+            assert_eq!((prevlen, len), (0, 0));
+            assert_ne!(s1, state);
+            state = s1;
+        }
+        let (prevlen, _, len, s1) = parser.parse("x\n", state);
+        assert_eq!((prevlen, len, s1), (0, 0, state));
+    }
+}
@@ -0,0 +1,463 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Jinja and Handlebars style template tags, layered over a host language.
+
+use crate::parser::{Capabilities, Parser};
+use crate::peg::KeywordSet;
+use crate::scope_tracker::Scope;
+use crate::statestack::{Context, State, Stats};
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum TagKind {
+    /// `{{ ... }}`
+    Expression,
+    /// `{% ... %}`
+    Statement,
+    /// `{# ... #}`
+    Comment,
+}
+
+impl TagKind {
+    fn closer(self) -> &'static str {
+        match self {
+            TagKind::Expression => "}}",
+            TagKind::Statement => "%}",
+            TagKind::Comment => "#}",
+        }
+    }
+
+    fn scope(self) -> &'static str {
+        match self {
+            TagKind::Expression => "template:expression",
+            TagKind::Statement => "template:statement",
+            TagKind::Comment => "template:comment",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum StateEl {
+    /// The bottom of every stack: the host parser's state, which resumes
+    /// where it left off after a tag.
+    Host(State),
+    Tag(TagKind),
+    Delimiter,
+    /// A string, which may run on to the next line; holds its quote.
+    Str(u8),
+    Keyword,
+    /// After a `|`, where the next identifier names a filter.
+    Pipe,
+    Filter,
+    Variable,
+    Number,
+    Operator,
+}
+
+impl StateEl {
+    fn scope(&self) -> Option<&'static str> {
+        match self {
+            StateEl::Host(_) | StateEl::Tag(_) | StateEl::Pipe => None,
+            StateEl::Delimiter => Some("template:delimiter"),
+            StateEl::Str(b'\'') => Some("template:string.single"),
+            StateEl::Str(_) => Some("template:string.double"),
+            StateEl::Keyword => Some("template:keyword"),
+            StateEl::Filter => Some("template:filter"),
+            StateEl::Variable => Some("template:variable"),
+            StateEl::Number => Some("template:number"),
+            StateEl::Operator => Some("template:operator"),
+        }
+    }
+}
+
+// sorted, as KeywordSet wants; words starting with `end` are keywords
+// too, for custom blocks
+const TEMPLATE_KEYWORDS: &[&str] = &[
+    "and",
+    "as",
+    "block",
+    "call",
+    "elif",
+    "else",
+    "extends",
+    "filter",
+    "for",
+    "from",
+    "if",
+    "import",
+    "in",
+    "include",
+    "is",
+    "macro",
+    "not",
+    "or",
+    "raw",
+    "recursive",
+    "set",
+    "with",
+];
+
+const TEMPLATE_OPERATORS: &[&str] =
+    &["==", "!=", "<=", ">=", "<", ">", "=", "+", "-", "*", "/", "%", "~", "!"];
+
+/// Highlights the `{{ ... }}`, `{% ... %}` and `{# ... #}` tags of a
+/// template, and hands everything outside them to the host parser. The
+/// host state is kept at the bottom of the stack through a tag, so a tag
+/// in the middle of, say, an attribute value doesn't end the value.
+pub struct TemplateParser {
+    host: Box<dyn Parser>,
+    ctx: Context<StateEl>,
+    keywords: KeywordSet<StateEl>,
+}
+
+impl TemplateParser {
+    pub fn new(host: Box<dyn Parser>) -> TemplateParser {
+        let keywords = KeywordSet::new(&[(TEMPLATE_KEYWORDS, StateEl::Keyword)]);
+        TemplateParser { host, ctx: Context::new(), keywords }
+    }
+
+    /// The host state under `state`, and the state of the tag it is in, if
+    /// any.
+    fn layers(&self, state: State) -> (State, Option<(State, TagKind)>) {
+        let mut tag = None;
+        let mut s = state;
+        loop {
+            match self.ctx.tos(s) {
+                None => return (self.host.initial_state(), tag),
+                Some(StateEl::Host(host)) => return (host, tag),
+                Some(StateEl::Tag(kind)) if tag.is_none() => tag = Some((s, kind)),
+                _ => (),
+            }
+            s = self.ctx.pop(s).unwrap();
+        }
+    }
+
+    fn host_state(&mut self, host: State) -> State {
+        self.ctx.push(State::default(), StateEl::Host(host))
+    }
+
+    fn host_text(&mut self, text: &str, host: State) -> (usize, State, usize, State) {
+        let base = self.host_state(host);
+        if let Some((at, kind, len)) = find_opener(text) {
+            if at == 0 {
+                let tag = self.ctx.push(base, StateEl::Tag(kind));
+                return (0, self.ctx.push(tag, StateEl::Delimiter), len, tag);
+            }
+            return self.host_parse(&text[..at], host);
+        }
+        self.host_parse(text, host)
+    }
+
+    fn host_parse(&mut self, text: &str, host: State) -> (usize, State, usize, State) {
+        let (prevlen, s0, len, s1) = self.host.parse(text, host);
+        (prevlen, self.host_state(s0), len, self.host_state(s1))
+    }
+
+    fn comment(&mut self, text: &str, tag: State) -> (usize, State, usize, State) {
+        match text.find("#}") {
+            Some(at) => {
+                let (at, len) = if text[..at].ends_with('-') { (at - 1, 3) } else { (at, 2) };
+                let delimiter = self.ctx.push(tag, StateEl::Delimiter);
+                (at, delimiter, len, self.ctx.pop(tag).unwrap())
+            }
+            None => (0, tag, text.len(), tag),
+        }
+    }
+
+    fn tag_code(&mut self, text: &str, state: State, tag: State, kind: TagKind) -> Lexed {
+        let after_pipe = self.ctx.tos(state) == Some(StateEl::Pipe);
+        let i = text.len() - text.trim_start().len();
+        let rest = &text[i..];
+        let token = |el, len| (i, Some(el), len, tag);
+        let first = match rest.bytes().next() {
+            Some(b) => b,
+            None => return (i, None, 0, state),
+        };
+
+        if let Some(len) = closer_len(rest, kind) {
+            return (i, Some(StateEl::Delimiter), len, self.ctx.pop(tag).unwrap());
+        }
+        if first == b'"' || first == b'\'' {
+            return match string_end(&rest[1..], first) {
+                Some(end) => token(StateEl::Str(first), end + 2),
+                None => {
+                    let string = self.ctx.push(tag, StateEl::Str(first));
+                    (i, Some(StateEl::Str(first)), rest.len(), string)
+                }
+            };
+        }
+        if first.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+            return token(StateEl::Number, len);
+        }
+        // Handlebars block helpers, like `#each` and `/each`
+        let (sigil, word) = match first {
+            b'#' | b'/' if kind == TagKind::Expression => (1, &rest[1..]),
+            _ => (0, rest),
+        };
+        let len = word.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(word.len());
+        if len > 0 && !word.as_bytes()[0].is_ascii_digit() {
+            let name = &word[..len];
+            let el = if after_pipe {
+                StateEl::Filter
+            } else if sigil > 0
+                || self.keywords.contains(name.as_bytes())
+                || name.starts_with("end")
+            {
+                StateEl::Keyword
+            } else {
+                StateEl::Variable
+            };
+            return token(el, sigil + len);
+        }
+        if first == b'|' && !rest.starts_with("||") {
+            return (i, Some(StateEl::Operator), 1, self.ctx.push(tag, StateEl::Pipe));
+        }
+        if let Some(op) = TEMPLATE_OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            return token(StateEl::Operator, op.len());
+        }
+        let len = rest.chars().next().map_or(1, char::len_utf8);
+        (i, None, len, tag)
+    }
+}
+
+/// What `TemplateParser::tag_code` found: the whitespace before a token,
+/// the token's element if it has its own scope, its length, and the state
+/// after it.
+type Lexed = (usize, Option<StateEl>, usize, State);
+
+/// The first tag opener in `text`, as its offset, kind and length.
+fn find_opener(text: &str) -> Option<(usize, TagKind, usize)> {
+    let mut from = 0;
+    while let Some(found) = text[from..].find('{') {
+        let at = from + found;
+        let kind = match text.as_bytes().get(at + 1) {
+            Some(b'{') => TagKind::Expression,
+            Some(b'%') => TagKind::Statement,
+            Some(b'#') => TagKind::Comment,
+            _ => {
+                from = at + 1;
+                continue;
+            }
+        };
+        // whitespace control, as in `{%-`
+        let trim = match text.as_bytes().get(at + 2) {
+            Some(b'-') | Some(b'~') => true,
+            _ => false,
+        };
+        return Some((at, kind, 2 + trim as usize));
+    }
+    None
+}
+
+/// The length of the tag closer `text` starts with, if it does.
+fn closer_len(text: &str, kind: TagKind) -> Option<usize> {
+    let closer = kind.closer();
+    let trim = text.starts_with('-') || text.starts_with('~');
+    if text[trim as usize..].starts_with(closer) {
+        Some(trim as usize + closer.len())
+    } else {
+        None
+    }
+}
+
+/// The offset of the quote ending a string in `text`, if it is there.
+fn string_end(text: &str, quote: u8) -> Option<usize> {
+    let t = text.as_bytes();
+    let mut i = 0;
+    while i < t.len() {
+        if t[i] == b'\\' {
+            i += 2;
+            continue;
+        } else if t[i] == quote {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+impl Parser for TemplateParser {
+    fn name(&self) -> &'static str {
+        "Template"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { emits_sub_spans: true, ..Capabilities::default() }
+    }
+
+    fn get_scope_for_state(&self, state: State) -> Scope {
+        let (host, tag) = self.layers(state);
+        let mut scope = self.host.get_scope_for_state(host);
+        if let Some((_, kind)) = tag {
+            scope.push(kind.scope().to_owned());
+            if let Some(name) = self.ctx.tos(state).as_ref().and_then(StateEl::scope) {
+                scope.push(name.to_owned());
+            }
+        }
+        scope
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        self.host.scopes()
+    }
+
+    fn scope_prefixes(&self) -> &[&str] {
+        self.host.scope_prefixes()
+    }
+
+    fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+        let (host, tag) = self.layers(state);
+        let (tag, kind) = match tag {
+            Some(tag) => tag,
+            None => return self.host_text(text, host),
+        };
+        if kind == TagKind::Comment {
+            return self.comment(text, tag);
+        }
+        if let Some(StateEl::Str(quote)) = self.ctx.tos(state) {
+            return match string_end(text, quote) {
+                Some(end) => (0, state, end + 1, tag),
+                None => (0, state, text.len(), state),
+            };
+        }
+        let (prevlen, el, len, next) = self.tag_code(text, state, tag, kind);
+        let token = match el {
+            Some(el) => self.ctx.push(tag, el),
+            None => tag,
+        };
+        (prevlen, token, len, next)
+    }
+
+    fn in_code(&self, state: State) -> bool {
+        match self.layers(state) {
+            (host, None) => self.host.in_code(host),
+            (_, Some((_, TagKind::Comment))) => false,
+            (_, Some(_)) => match self.ctx.tos(state) {
+                Some(StateEl::Str(_)) => false,
+                _ => true,
+            },
+        }
+    }
+
+    fn stack_stats(&self) -> Stats {
+        let (ours, host) = (self.ctx.stats(), self.host.stack_stats());
+        Stats {
+            states: ours.states + host.states,
+            elements: ours.elements + host.elements,
+            max_depth: ours.max_depth.max(host.max_depth),
+            estimated_bytes: ours.estimated_bytes + host.estimated_bytes,
+        }
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit();
+        self.host.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::{plaintext::PlaintextParser, rust::RustParser};
+    use crate::text::lines_with_endings;
+
+    /// Lexes `text` a line at a time, returning each span's text with the
+    /// innermost scope name of its state.
+    fn lex(parser: &mut dyn Parser, text: &str) -> Vec<(String, String)> {
+        let mut spans = Vec::new();
+        let mut state = parser.initial_state();
+        for line in lines_with_endings(text) {
+            let mut i = 0;
+            while i < line.len() {
+                let (prevlen, s0, len, s1) = parser.parse(&line[i..], state);
+                assert!(prevlen + len > 0 || s1 != state, "stuck at {:?}", &line[i..]);
+                for (range, s) in
+                    vec![(i..i + prevlen, state), (i + prevlen..i + prevlen + len, s0)]
+                {
+                    if range.start < range.end && !line[range.clone()].trim().is_empty() {
+                        let scope = parser.get_scope_for_state(s).pop().unwrap();
+                        spans.push((line[range].trim().to_owned(), scope));
+                    }
+                }
+                i += prevlen + len;
+                state = s1;
+            }
+        }
+        spans
+    }
+
+    fn scope_of<'a>(spans: &'a [(String, String)], text: &str) -> Vec<&'a str> {
+        spans.iter().filter(|(t, _)| t == text).map(|(_, scope)| scope.as_str()).collect()
+    }
+
+    #[test]
+    fn tags_are_highlighted_over_the_host() {
+        let mut parser = TemplateParser::new(Box::new(PlaintextParser::new()));
+        let text = concat!(
+            "<ul>\n",
+            "{% for item in items if item.visible %}\n",
+            "  <li class=\"{{ item.kind | lower }} {% if loop.first %}lead{% endif %}\">\n",
+            "    {{ item.name|title }} {# the name,\n",
+            "    in title case #}\n",
+            "  </li>\n",
+            "{%- endfor %}\n",
+            "</ul>\n",
+        );
+        let spans = lex(&mut parser, text);
+
+        assert_eq!(scope_of(&spans, "for"), vec!["template:keyword"]);
+        assert_eq!(scope_of(&spans, "endfor"), vec!["template:keyword"]);
+        assert_eq!(scope_of(&spans, "endif"), vec!["template:keyword"]);
+        assert_eq!(scope_of(&spans, "lower"), vec!["template:filter"]);
+        assert_eq!(scope_of(&spans, "title"), vec!["template:filter"]);
+        assert_eq!(scope_of(&spans, "items"), vec!["template:variable"]);
+        assert_eq!(scope_of(&spans, "{%-"), vec!["template:delimiter"]);
+        assert_eq!(scope_of(&spans, "in title case"), vec!["template:comment"]);
+        assert_eq!(scope_of(&spans, "lead"), vec!["plaintext:source"]);
+        assert_eq!(scope_of(&spans, "</li>"), vec!["plaintext:source"]);
+    }
+
+    #[test]
+    fn the_host_resumes_where_it_left_off() {
+        let mut parser = TemplateParser::new(Box::new(RustParser::new()));
+        let text = concat!(
+            "let greeting = \"Hello, {{ user.name | default(\"you\") }}!\";\n",
+            "let n = {{\n",
+            "  count }};\n",
+        );
+        let spans = lex(&mut parser, text);
+
+        // the host string goes on after the tag, and the tag's own
+        // string doesn't end it
+        assert_eq!(scope_of(&spans, "!\""), vec!["rust:string.double"]);
+        assert_eq!(scope_of(&spans, "\"you\""), vec!["template:string.double"]);
+        assert_eq!(scope_of(&spans, "default"), vec!["template:filter"]);
+        assert_eq!(
+            parser.get_scope_for_state(parser.initial_state()),
+            vec!["rust:source".to_owned()]
+        );
+        // a tag split across lines
+        assert_eq!(scope_of(&spans, "count"), vec!["template:variable"]);
+    }
+
+    #[test]
+    fn handlebars_block_helpers_are_keywords() {
+        let mut parser = TemplateParser::new(Box::new(PlaintextParser::new()));
+        let spans = lex(&mut parser, "{{#each people}}<b>{{this}}</b>{{/each}}\n");
+        assert_eq!(scope_of(&spans, "#each"), vec!["template:keyword"]);
+        assert_eq!(scope_of(&spans, "/each"), vec!["template:keyword"]);
+        assert_eq!(scope_of(&spans, "<b>"), vec!["plaintext:source"]);
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A parser for the plumbing tests, registered as "Test" in test builds.
+//! Its grammar is made up of the awkward things a parser can do, each with
+//! a character of its own, so that a test can say exactly how much of
+//! them it wants, and doesn't change as the real grammars do:
+//!
+//! - `.` is a token of its own, and takes exactly one byte a call, so a
+//!   line of dots has a span a byte;
+//! - `(` pushes a state and `)` pops one, so a line of N `(` pushes N
+//!   states, and each depth is a scope of its own, with a name a level;
+//! - `!` is a token whose scope has `HUGE_SCOPE_LEN` names;
+//! - `%` starts a tally of every byte after it, to the end of the
+//!   document, kept in the state, so that an edit below it changes the
+//!   state of every line after and lexing never converges;
+//! - on `@` and `é` no progress is made, as with a grammar with a bug;
+//! - anything else is lexed up to the next of these in one token.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::parser::Parser;
+use crate::scope_tracker::Scope;
+use crate::statestack::{Context, State, Stats};
+
+const ROOT_SCOPE: &str = "testlang:source";
+const DOT_SCOPE: &str = "testlang:dot";
+const GROUP_SCOPE: &str = "testlang:group";
+const HUGE_SCOPE: &str = "testlang:huge";
+const TALLY_SCOPE: &str = "testlang:tally";
+
+/// How many names the scope of a `!` has, past those of where it is.
+pub const HUGE_SCOPE_LEN: usize = 1000;
+
+/// The characters no progress is made on.
+const STUCK: &[char] = &['@', 'é'];
+
+/// The characters that end a run of other text.
+const SPECIAL: &[char] = &['.', '(', ')', '!', '%', '@', 'é'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum StateEl {
+    Dot,
+    Group,
+    Huge,
+    /// A hash of the text since the `%`.
+    Tally(u64),
+}
+
+pub struct TestParser {
+    ctx: Context<StateEl>,
+}
+
+impl TestParser {
+    pub fn new() -> TestParser {
+        TestParser { ctx: Context::new() }
+    }
+
+    /// The elements of `state`'s stack, outermost first.
+    fn elements(&self, mut state: State) -> Vec<StateEl> {
+        let mut elements = Vec::new();
+        while let Some(el) = self.ctx.tos(state) {
+            elements.push(el);
+            state = self.ctx.pop(state).unwrap();
+        }
+        elements.reverse();
+        elements
+    }
+}
+
+fn tally(sum: u64, text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (sum, text).hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Default for TestParser {
+    fn default() -> TestParser {
+        TestParser::new()
+    }
+}
+
+impl Parser for TestParser {
+    fn name(&self) -> &'static str {
+        "Test"
+    }
+
+    fn get_scope_for_state(&self, state: State) -> Scope {
+        let mut scope = vec![ROOT_SCOPE.to_owned()];
+        for el in self.elements(state) {
+            match el {
+                StateEl::Dot => scope.push(DOT_SCOPE.to_owned()),
+                StateEl::Group => scope.push(GROUP_SCOPE.to_owned()),
+                StateEl::Huge => scope.extend((0..HUGE_SCOPE_LEN).map(|_| HUGE_SCOPE.to_owned())),
+                StateEl::Tally(_) => scope.push(TALLY_SCOPE.to_owned()),
+            }
+        }
+        scope
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        vec![vec![ROOT_SCOPE.to_owned()], vec![ROOT_SCOPE.to_owned(), DOT_SCOPE.to_owned()]]
+    }
+
+    fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+        if let Some(StateEl::Tally(sum)) = self.ctx.tos(state) {
+            let outer = self.ctx.pop(state).unwrap();
+            let next = self.ctx.push(outer, StateEl::Tally(tally(sum, text)));
+            return (0, state, text.len(), next);
+        }
+        match text.chars().next() {
+            Some(c) if STUCK.contains(&c) => (0, state, 0, state),
+            Some('.') => (0, self.ctx.push(state, StateEl::Dot), 1, state),
+            Some('(') => {
+                let inner = self.ctx.push(state, StateEl::Group);
+                (0, inner, 1, inner)
+            }
+            Some(')') => {
+                let outer = match self.ctx.tos(state) {
+                    Some(StateEl::Group) => self.ctx.pop(state).unwrap(),
+                    _ => state,
+                };
+                (0, state, 1, outer)
+            }
+            Some('!') => (0, self.ctx.push(state, StateEl::Huge), 1, state),
+            Some('%') => {
+                let tally = self.ctx.push(state, StateEl::Tally(0));
+                (0, tally, 1, tally)
+            }
+            _ => {
+                let len = text.find(SPECIAL).unwrap_or(text.len());
+                (0, state, len, state)
+            }
+        }
+    }
+
+    fn stack_stats(&self) -> Stats {
+        self.ctx.stats()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each call `parse` makes over `line` from `state`, as the text it
+    /// took and its scope's innermost name, and the state at the end.
+    fn calls<'a>(
+        parser: &mut TestParser,
+        line: &'a str,
+        mut state: State,
+    ) -> (Vec<(&'a str, String)>, State) {
+        let mut calls = Vec::new();
+        let mut i = 0;
+        while i < line.len() {
+            let (prevlen, s0, len, s1) = parser.parse(&line[i..], state);
+            assert_eq!(prevlen, 0);
+            if len == 0 && s1 == state {
+                // stuck; the plumbing skips a character
+                let len = line[i..].chars().next().unwrap().len_utf8();
+                calls.push((&line[i..i + len], "stuck".to_owned()));
+                i += len;
+                continue;
+            }
+            let name = parser.get_scope_for_state(s0).pop().unwrap();
+            calls.push((&line[i..i + len], name));
+            i += len;
+            state = s1;
+        }
+        (calls, state)
+    }
+
+    #[test]
+    fn each_character_does_what_it_says() {
+        let mut parser = TestParser::new();
+        let (result, end) = calls(&mut parser, "ab..(c)@é!d\n", State::default());
+        let texts: Vec<&str> = result.iter().map(|&(text, _)| text).collect();
+        assert_eq!(texts, vec!["ab", ".", ".", "(", "c", ")", "@", "é", "!", "d\n"]);
+        assert_eq!(result[1].1, DOT_SCOPE);
+        assert_eq!(result[4].1, GROUP_SCOPE);
+        assert_eq!(result[6].1, "stuck");
+        assert_eq!(result[8].1, HUGE_SCOPE);
+        assert_eq!(end, State::default());
+
+        let (_, state, _, _) = parser.parse("!", State::default());
+        assert_eq!(parser.get_scope_for_state(state).len(), 1 + HUGE_SCOPE_LEN);
+    }
+
+    #[test]
+    fn groups_push_a_state_each() {
+        let mut parser = TestParser::new();
+        let (_, end) = calls(&mut parser, "(((\n", State::default());
+        assert_eq!(parser.stack_stats().max_depth, 3);
+        assert_eq!(parser.get_scope_for_state(end).len(), 4);
+        let (_, end) = calls(&mut parser, "))))\n", end);
+        assert_eq!(end, State::default());
+    }
+
+    #[test]
+    fn tallies_never_converge() {
+        let mut parser = TestParser::new();
+        let lines = |parser: &mut TestParser, first: &str| {
+            let (_, mut state) = calls(parser, first, State::default());
+            let mut states = Vec::new();
+            for _ in 0..3 {
+                state = calls(parser, "same\n", state).1;
+                states.push(state);
+            }
+            states
+        };
+        let before = lines(&mut parser, "%a\n");
+        let after = lines(&mut parser, "%b\n");
+        assert!(before.iter().zip(&after).all(|(a, b)| a != b));
+        assert_eq!(lines(&mut parser, "%a\n"), before);
+        assert_eq!(parser.get_scope_for_state(before[2]).pop().unwrap(), TALLY_SCOPE);
+    }
+}
@@ -0,0 +1,627 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A parser that highlights with a tree-sitter grammar compiled in, built
+//! with the `tree-sitter` feature. Rather than lexing lines, it keeps a
+//! tree of the whole document, parsed again as edits come in, and the
+//! captures of the grammar's highlight query are its spans.
+//!
+//! The states it hands out are positions in the document, so that the
+//! main pass still decides which lines to lex. The state at a line's
+//! start names the line and the last edit that changed it, and lines keep
+//! their names when lines above them come and go: lexing after an edit
+//! goes on through the lines the tree changed in, and stops at the first
+//! one it didn't. A change the tree has above the edit, which is rare, is
+//! only seen once those lines are lexed again.
+//!
+//! As the document is edited, states naming places that are gone pile up.
+//! Parsing the document from scratch starts the states over, which an
+//! edit does once there are more of them than the document needs.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+
+use tree_sitter::{InputEdit, Language, Point, Query, QueryCursor, Tree};
+
+use crate::parser::{Capabilities, Parser};
+use crate::scope_map;
+use crate::scope_tracker::Scope;
+use crate::statestack::{Context, State, Stats};
+use xi_rope::RopeDelta;
+
+/// A grammar, with the query that picks out what to highlight. The
+/// query's capture names are the scope names, after the language.
+#[derive(Clone, Copy)]
+pub struct Grammar {
+    /// The language the scope names start with, as in `rust:keyword`.
+    pub scope_language: &'static str,
+    pub language: fn() -> Language,
+    pub highlights: &'static str,
+}
+
+pub const RUST: Grammar = Grammar {
+    scope_language: "rust",
+    language: tree_sitter_rust::language,
+    highlights: include_str!("../../queries/rust.scm"),
+};
+
+pub const JSON: Grammar = Grammar {
+    scope_language: "json",
+    language: tree_sitter_json::language,
+    highlights: include_str!("../../queries/json.scm"),
+};
+
+/// The scope of text no capture covers.
+const ROOT: usize = 0;
+
+/// How many states beyond twice those the document needs there may be
+/// before an edit starts them over.
+const SPARE_STATES: usize = 1 << 16;
+
+/// A line of the document, by the name states give it.
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    start: usize,
+    anchor: usize,
+    /// The version of the document the line last changed in.
+    stamp: u64,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+enum StateEl {
+    /// The start of a line, other than the first.
+    LineStart { anchor: usize, stamp: u64 },
+    /// A place part-way through a line.
+    Within { anchor: usize, col: usize },
+    /// The scope a span is in.
+    Scoped(usize),
+}
+
+pub struct TreeSitterParser {
+    parser: tree_sitter::Parser,
+    query: Query,
+    /// The scope name of each of the query's captures.
+    capture_names: Vec<String>,
+    tree: Option<Tree>,
+    text: String,
+    lines: Vec<Line>,
+    /// The line each anchor names.
+    anchors: HashMap<usize, usize>,
+    next_anchor: usize,
+    version: u64,
+    /// Where each span starts, and its scope, covering the whole text.
+    spans: Vec<(usize, usize)>,
+    scopes: Vec<Scope>,
+    scope_ids: HashMap<Scope, usize>,
+    ctx: Context<StateEl>,
+}
+
+impl TreeSitterParser {
+    pub fn new(grammar: Grammar) -> TreeSitterParser {
+        let language = (grammar.language)();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(language).expect("grammar built for another tree-sitter");
+        let query = Query::new(language, grammar.highlights).expect("bad highlight query");
+        let capture_names = query
+            .capture_names()
+            .iter()
+            .map(|name| format!("{}{}{}", grammar.scope_language, scope_map::SEPARATOR, name))
+            .collect();
+        let root = vec![format!("{}{}source", grammar.scope_language, scope_map::SEPARATOR)];
+        let mut scope_ids = HashMap::new();
+        scope_ids.insert(root.clone(), ROOT);
+        TreeSitterParser {
+            parser,
+            query,
+            capture_names,
+            tree: None,
+            text: String::new(),
+            lines: vec![Line { start: 0, anchor: 0, stamp: 0 }],
+            anchors: Some((0, 0)).into_iter().collect(),
+            next_anchor: 1,
+            version: 0,
+            spans: vec![(0, ROOT)],
+            scopes: vec![root],
+            scope_ids,
+            ctx: Context::new(),
+        }
+    }
+
+    fn line_of(&self, offset: usize) -> usize {
+        let after = |start: usize| if start <= offset { Ordering::Less } else { Ordering::Greater };
+        let next = self.lines.binary_search_by(|line| after(line.start)).unwrap_or_else(|i| i);
+        next.saturating_sub(1)
+    }
+
+    fn point(&self, offset: usize) -> Point {
+        let line = self.line_of(offset);
+        Point { row: line, column: offset - self.lines[line].start }
+    }
+
+    fn new_line(&mut self, start: usize) -> Line {
+        self.next_anchor += 1;
+        Line { start, anchor: self.next_anchor - 1, stamp: self.version }
+    }
+
+    /// Finds the lines of the text after an edit that replaced `start` to
+    /// `old_end` with what is now up to `new_end`. The lines that start
+    /// outside the edit keep their names, and those in it are new.
+    fn reline(&mut self, start: usize, old_end: usize, new_end: usize) {
+        let old = std::mem::replace(&mut self.lines, Vec::new());
+        let starts = line_starts(&self.text);
+        let mut lines = Vec::with_capacity(starts.len());
+        for line_start in starts {
+            let old_start = if line_start <= start {
+                Some(line_start)
+            } else if line_start >= new_end && line_start - new_end + old_end > start {
+                Some(line_start - new_end + old_end)
+            } else {
+                None
+            };
+            let line = match old_start.and_then(|s| old.binary_search_by_key(&s, |l| l.start).ok())
+            {
+                Some(i) => Line { start: line_start, ..old[i] },
+                None => self.new_line(line_start),
+            };
+            lines.push(line);
+        }
+        self.lines = lines;
+        self.index_anchors();
+    }
+
+    fn index_anchors(&mut self) {
+        self.anchors = self.lines.iter().enumerate().map(|(i, line)| (line.anchor, i)).collect();
+    }
+
+    /// Marks the lines from `first` to `last` as changed in this version.
+    fn stamp(&mut self, first: usize, last: usize) {
+        let version = self.version;
+        let last = last.min(self.lines.len() - 1);
+        for line in &mut self.lines[first.min(last)..=last] {
+            line.stamp = version;
+        }
+    }
+
+    /// The place in the text a state is at, if it is one.
+    fn offset(&self, state: State) -> Option<usize> {
+        let (line, col) = match self.ctx.tos(state) {
+            None => (0, 0),
+            Some(StateEl::LineStart { anchor, .. }) => (*self.anchors.get(&anchor)?, 0),
+            Some(StateEl::Within { anchor, col }) => (*self.anchors.get(&anchor)?, col),
+            Some(StateEl::Scoped(_)) => return None,
+        };
+        Some(self.lines[line].start + col).filter(|&offset| offset <= self.text.len())
+    }
+
+    /// The state at `offset` in the text.
+    fn state_at(&mut self, offset: usize) -> State {
+        let line = self.line_of(offset);
+        let Line { start, anchor, stamp } = self.lines[line];
+        let el = if offset > start {
+            StateEl::Within { anchor, col: offset - start }
+        } else if line > 0 {
+            StateEl::LineStart { anchor, stamp }
+        } else {
+            return State::default();
+        };
+        self.ctx.push(State::default(), el)
+    }
+
+    /// The index in `spans` of the span `offset` is in.
+    fn span_at(&self, offset: usize) -> usize {
+        let after = |start: usize| if start <= offset { Ordering::Less } else { Ordering::Greater };
+        let next = self.spans.binary_search_by(|&(start, _)| after(start)).unwrap_or_else(|i| i);
+        next.saturating_sub(1)
+    }
+
+    fn scope_index(&self, state: State) -> usize {
+        match self.ctx.tos(state) {
+            Some(StateEl::Scoped(scope)) => scope,
+            _ => match self.offset(state) {
+                Some(offset) => self.spans[self.span_at(offset)].1,
+                None => ROOT,
+            },
+        }
+    }
+
+    /// The scope of a capture inside `parent`.
+    fn child_scope(&mut self, parent: usize, capture: usize) -> usize {
+        let mut scope = self.scopes[parent].clone();
+        scope.push(self.capture_names[capture].clone());
+        if let Some(&id) = self.scope_ids.get(&scope) {
+            return id;
+        }
+        self.scopes.push(scope.clone());
+        self.scope_ids.insert(scope, self.scopes.len() - 1);
+        self.scopes.len() - 1
+    }
+
+    /// Runs the highlight query over the tree, nesting each capture's
+    /// scope inside the scopes of the captures around it.
+    fn gather_spans(&mut self) {
+        let mut captures = Vec::new();
+        if let Some(ref tree) = self.tree {
+            let mut cursor = QueryCursor::new();
+            let matches = cursor.captures(&self.query, tree.root_node(), self.text.as_bytes());
+            for (found, i) in matches {
+                let capture = found.captures[i];
+                let (start, end) = (capture.node.start_byte(), capture.node.end_byte());
+                if start < end {
+                    captures.push((start, end, found.pattern_index, capture.index as usize));
+                }
+            }
+        }
+        // outer captures first, and of those of one node, the first pattern's
+        captures.sort_by_key(|&(start, end, pattern, _)| (start, Reverse(end), pattern));
+
+        let mut spans = Vec::new();
+        // the captures around the one at hand, by start, end and scope
+        let mut stack = vec![(0, self.text.len(), ROOT)];
+        let mut pos = 0;
+        for (start, end, _, capture) in captures {
+            while stack.len() > 1 && stack[stack.len() - 1].1 <= start {
+                let (_, end, scope) = stack.pop().unwrap();
+                push_span(&mut spans, pos, end, scope);
+                pos = end;
+            }
+            let (outer_start, outer_end, outer) = stack[stack.len() - 1];
+            if stack.len() > 1 && (outer_start, outer_end) == (start, end) {
+                continue;
+            }
+            push_span(&mut spans, pos, start, outer);
+            pos = start;
+            let scope = self.child_scope(outer, capture);
+            stack.push((start, end.min(outer_end), scope));
+        }
+        while let Some((_, end, scope)) = stack.pop() {
+            push_span(&mut spans, pos, end, scope);
+            pos = end;
+        }
+        if spans.is_empty() {
+            spans.push((0, ROOT));
+        }
+        self.spans = spans;
+    }
+}
+
+fn line_starts(text: &str) -> Vec<usize> {
+    Some(0).into_iter().chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect()
+}
+
+/// Adds a span from `start` to `end`, unless it's empty, merging it with
+/// the span before if that has the same scope.
+fn push_span(spans: &mut Vec<(usize, usize)>, start: usize, end: usize, scope: usize) {
+    if start < end && spans.last().map(|&(_, last)| last) != Some(scope) {
+        spans.push((start, scope));
+    }
+}
+
+impl Parser for TreeSitterParser {
+    fn name(&self) -> &'static str {
+        "Tree-sitter"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { parses_documents: true, ..Capabilities::default() }
+    }
+
+    fn get_scope_for_state(&self, state: State) -> Scope {
+        self.scopes[self.scope_index(state)].clone()
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        vec![self.scopes[ROOT].clone()]
+    }
+
+    fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+        if let Some(offset) = self.offset(state).filter(|&offset| offset < self.text.len()) {
+            let span = self.span_at(offset);
+            let end = self.spans.get(span + 1).map_or(self.text.len(), |&(start, _)| start);
+            let len = (end - offset).min(text.len());
+            if len > 0 && self.text.as_bytes()[offset..offset + len] == text.as_bytes()[..len] {
+                let s0 = self.ctx.push(State::default(), StateEl::Scoped(self.spans[span].1));
+                return (0, s0, len, self.state_at(offset + len));
+            }
+        }
+        // not the text we have, until we're told of the edit
+        let root = self.ctx.push(State::default(), StateEl::Scoped(ROOT));
+        (0, root, text.len(), state)
+    }
+
+    fn parse_document(&mut self, text: &str) {
+        self.text.clear();
+        self.text.push_str(text);
+        self.version += 1;
+        self.ctx = Context::new();
+        self.next_anchor = 0;
+        let lines = line_starts(text).into_iter().map(|start| self.new_line(start)).collect();
+        self.lines = lines;
+        self.index_anchors();
+        self.tree = self.parser.parse(text, None);
+        self.gather_spans();
+    }
+
+    fn apply_delta(&mut self, delta: &RopeDelta, text: &str) -> bool {
+        let (iv, new_len) = delta.summary();
+        let needed = 2 * (self.spans.len() + self.lines.len()) + SPARE_STATES;
+        let mut old_tree = match self.tree.take() {
+            Some(tree)
+                if delta.base_len == self.text.len()
+                    && delta.new_document_len() == text.len()
+                    && self.ctx.len() <= needed =>
+            {
+                tree
+            }
+            _ => {
+                self.parse_document(text);
+                return false;
+            }
+        };
+        let (start, old_end, new_end) = (iv.start(), iv.end(), iv.start() + new_len);
+        let start_position = self.point(start);
+        let old_end_position = self.point(old_end);
+        self.text.clear();
+        self.text.push_str(text);
+        self.version += 1;
+        self.reline(start, old_end, new_end);
+        old_tree.edit(&InputEdit {
+            start_byte: start,
+            old_end_byte: old_end,
+            new_end_byte: new_end,
+            start_position,
+            old_end_position,
+            new_end_position: self.point(new_end),
+        });
+        self.tree = self.parser.parse(text, Some(&old_tree));
+
+        // every line from the first changed to the last, so that lexing
+        // doesn't stop between them
+        let (mut first, mut last) = (self.line_of(start), self.line_of(new_end));
+        if let Some(ref tree) = self.tree {
+            for range in old_tree.changed_ranges(tree) {
+                first = first.min(self.line_of(range.start_byte));
+                last = last.max(self.line_of(range.end_byte));
+            }
+        }
+        self.stamp(first, last);
+        self.gather_spans();
+        true
+    }
+
+    fn in_code(&self, state: State) -> bool {
+        !self.scopes[self.scope_index(state)].iter().any(|name| {
+            scope_map::translate_name(name)
+                .ok()
+                .map_or(false, |name| name.starts_with("comment") || name.starts_with("string"))
+        })
+    }
+
+    fn stack_stats(&self) -> Stats {
+        self.ctx.stats()
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit();
+        self.scopes.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden;
+    use crate::highlight::{self, Highlighted};
+    use crate::registry::ParserRegistry;
+    use crate::text::lines_with_endings;
+    use std::fs;
+    use xi_rope::{DeltaBuilder, Interval, Rope};
+
+    /// How much of a fixture the two Rust parsers must agree on.
+    const AGREEMENT: f64 = 0.85;
+
+    fn parsed(grammar: Grammar, text: &str) -> TreeSitterParser {
+        let mut parser = TreeSitterParser::new(grammar);
+        parser.parse_document(text);
+        parser
+    }
+
+    /// The pieces `line`, which starts in `state`, is lexed into, each with
+    /// the innermost name of its scope.
+    fn lex_line(
+        parser: &mut TreeSitterParser,
+        line: &str,
+        mut state: State,
+    ) -> Vec<(String, String)> {
+        let mut pieces = Vec::new();
+        let mut rest = line;
+        while !rest.is_empty() {
+            let (_, s0, len, s1) = parser.parse(rest, state);
+            let scope = parser.get_scope_for_state(s0);
+            pieces.push((rest[..len].to_owned(), scope.last().unwrap().clone()));
+            rest = &rest[len..];
+            state = s1;
+        }
+        pieces
+    }
+
+    fn line_states(parser: &mut TreeSitterParser) -> Vec<State> {
+        let starts: Vec<usize> = parser.lines.iter().map(|line| line.start).collect();
+        starts.into_iter().map(|start| parser.state_at(start)).collect()
+    }
+
+    fn edit(parser: &mut TreeSitterParser, start: usize, end: usize, new: &str) {
+        let mut builder = DeltaBuilder::new(parser.text.len());
+        builder.replace(Interval::new(start, end), Rope::from(new));
+        let mut text = parser.text.clone();
+        text.replace_range(start..end, new);
+        parser.apply_delta(&builder.build(), &text);
+    }
+
+    #[test]
+    fn captures_are_spans() {
+        let mut parser = parsed(JSON, "{\"a\": [1, true]}\n");
+        let pieces = lex_line(&mut parser, "{\"a\": [1, true]}\n", State::default());
+        let pieces: Vec<(&str, &str)> =
+            pieces.iter().map(|(text, scope)| (text.as_str(), scope.as_str())).collect();
+        assert_eq!(
+            pieces,
+            vec![
+                ("{", "json:source"),
+                ("\"a\"", "json:key"),
+                (": [", "json:source"),
+                ("1", "json:number"),
+                (", ", "json:source"),
+                ("true", "json:constant"),
+                ("]}\n", "json:source"),
+            ]
+        );
+        assert!(parser.in_code(State::default()));
+    }
+
+    #[test]
+    fn captures_nest() {
+        let mut parser = parsed(RUST, "let s = \"a\\tb\";\n");
+        let pieces = lex_line(&mut parser, "let s = \"a\\tb\";\n", State::default());
+        assert_eq!(pieces[0], ("let".to_owned(), "rust:keyword".to_owned()));
+        let escape = parser.state_at(10);
+        let (_, s0, len, _) = parser.parse("\\tb\";\n", escape);
+        assert_eq!(len, 2);
+        assert_eq!(
+            parser.get_scope_for_state(s0),
+            vec!["rust:source", "rust:string.double", "rust:escape"]
+        );
+        assert!(!parser.in_code(s0));
+    }
+
+    #[test]
+    fn text_it_was_not_given_is_lexed_whole() {
+        let mut parser = parsed(RUST, "let a = 1;\n");
+        let (_, s0, len, s1) = parser.parse("fn b() {}\n", State::default());
+        assert_eq!(len, "fn b() {}\n".len());
+        assert_eq!(parser.get_scope_for_state(s0), vec!["rust:source"]);
+        assert_eq!(s1, State::default());
+    }
+
+    #[test]
+    fn edits_change_only_the_states_of_lines_they_change() {
+        let text = "fn a() {}\nfn b() {}\nfn c() {} // */\n";
+        let mut parser = parsed(RUST, text);
+        let before = line_states(&mut parser);
+
+        // within a line
+        edit(&mut parser, 13, 14, "bb");
+        let after = line_states(&mut parser);
+        assert_eq!(after.len(), before.len());
+        assert_eq!(after[0], before[0]);
+        assert_ne!(after[1], before[1]);
+        assert_eq!(&after[2..], &before[2..]);
+
+        // a line added above keeps the states of the lines below
+        let before = after;
+        edit(&mut parser, 0, 0, "use x;\n");
+        let after = line_states(&mut parser);
+        assert_eq!(after.len(), before.len() + 1);
+        assert_eq!(&after[2..], &before[1..]);
+        assert_eq!(lex_line(&mut parser, "fn c() {} // */\n", after[3])[0].1, "rust:keyword");
+
+        // and a comment opened changes every line it runs over, to the
+        // `*/` it now ends at
+        let before = after;
+        edit(&mut parser, 7, 7, "/*");
+        let after = line_states(&mut parser);
+        assert!(after[2..4].iter().zip(&before[2..4]).all(|(a, b)| a != b));
+        assert_eq!(&after[4..], &before[4..]);
+        let line = lex_line(&mut parser, "fn c() {} // */\n", after[3]);
+        assert_eq!(line[0], ("fn c() {} // */".to_owned(), "rust:comment".to_owned()));
+    }
+
+    #[test]
+    fn edits_it_cannot_follow_parse_it_all_again() {
+        let mut parser = parsed(RUST, "let a = 1;\n");
+        let mut builder = DeltaBuilder::new(3);
+        builder.replace(Interval::new(0, 0), Rope::from("x"));
+        assert!(!parser.apply_delta(&builder.build(), "// all new\n"));
+        assert_eq!(parser.text, "// all new\n");
+        let pieces = lex_line(&mut parser, "// all new\n", State::default());
+        assert_eq!(pieces[0], ("// all new".to_owned(), "rust:comment".to_owned()));
+    }
+
+    #[test]
+    fn states_start_over_once_they_pile_up() {
+        let text = "fn a() {}\nfn b() {}\n";
+        let mut parser = parsed(RUST, text);
+        line_states(&mut parser);
+        // an edit keeps them
+        let mut builder = DeltaBuilder::new(text.len());
+        builder.replace(Interval::new(3, 4), Rope::from("aa"));
+        assert!(parser.apply_delta(&builder.build(), "fn aa() {}\nfn b() {}\n"));
+
+        for anchor in 0..2 * SPARE_STATES {
+            parser.ctx.push(State::default(), StateEl::Within { anchor, col: 0 });
+        }
+        let mut builder = DeltaBuilder::new(parser.text.len());
+        builder.replace(Interval::new(3, 5), Rope::from("a"));
+        assert!(!parser.apply_delta(&builder.build(), text));
+        assert!(parser.ctx.is_empty());
+        let state = parser.state_at(10);
+        let pieces = lex_line(&mut parser, "fn b() {}\n", state);
+        assert_eq!(pieces[0], ("fn".to_owned(), "rust:keyword".to_owned()));
+    }
+
+    /// What each byte of a highlighted file is, where it isn't whitespace.
+    fn kinds(highlighted: &Highlighted) -> Vec<(usize, &'static str)> {
+        let mut kinds = Vec::new();
+        let mut line_start = 0;
+        let lines: Vec<usize> = lines_with_endings(&highlighted.text)
+            .map(|line| {
+                line_start += line.len();
+                line_start - line.len()
+            })
+            .collect();
+        for span in &highlighted.spans {
+            let scope = &highlighted.scopes[span.scope_id as usize];
+            let kind = match scope
+                .iter()
+                .rev()
+                .find(|name| name.starts_with("comment") || name.starts_with("string"))
+            {
+                Some(name) if name.starts_with("comment") => "comment",
+                Some(_) => "string",
+                None => "code",
+            };
+            let start = lines[span.line];
+            for offset in start + span.start..start + span.end {
+                if !highlighted.text.as_bytes()[offset].is_ascii_whitespace() {
+                    kinds.push((offset, kind));
+                }
+            }
+        }
+        kinds
+    }
+
+    #[test]
+    fn rust_fixtures_mostly_agree_with_the_native_parser() {
+        let registry = ParserRegistry::default();
+        for path in golden::fixtures("rust") {
+            let text = fs::read_to_string(&path).unwrap();
+            let native = highlight::highlight(&registry, "Rust", &text).unwrap();
+            let tree = highlight::highlight(&registry, "Tree-sitter Rust", &text).unwrap();
+            let (native, tree) = (kinds(&native), kinds(&tree));
+            let native: HashMap<usize, &str> = native.into_iter().collect();
+            let agreed = tree.iter().filter(|(offset, kind)| native.get(offset) == Some(kind));
+            let agreed = agreed.count() as f64 / native.len().max(1) as f64;
+            assert!(agreed >= AGREEMENT, "{}: only {:.0}% agree", path.display(), agreed * 100.0);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Which lines core already has the spans for, so that the main pass can
+//! step over a line it would only lex the same way again.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use xi_rope::Interval;
+
+use crate::line_map::LineMap;
+use crate::statestack::State;
+
+/// How a line was last lexed by the main pass.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LexedLine {
+    entry: State,
+    exit: State,
+    /// A hash of the line's text, line ending included.
+    hash: u64,
+}
+
+/// The lines the main pass lexed, kept by offset as the outline is.
+/// Unlike the state cache, nothing here is evicted, so a line whose text
+/// and entry state are as they were can be skipped anywhere in the
+/// document.
+pub type LexedLines = LineMap<LexedLine>;
+
+impl LexedLines {
+    /// Notes that `line`, at `offset`, was lexed from `entry` to `exit`,
+    /// and its spans sent.
+    pub fn record_line(&mut self, offset: usize, line: &str, entry: State, exit: State) {
+        let iv = Interval::new(offset, offset + line.len());
+        self.record_hashed(iv, hash_line(line), entry, exit);
+    }
+
+    /// Notes that the line at `iv`, whose text has `hash`, was lexed from
+    /// `entry` to `exit`, and its spans sent, for when the text is gone.
+    pub fn record_hashed(&mut self, iv: Interval, hash: u64, entry: State, exit: State) {
+        self.record(iv, LexedLine { entry, exit, hash });
+    }
+
+    /// The state `line`, at `offset`, ends in if it was last lexed from
+    /// `entry` with the same text, which means core has its spans.
+    pub fn unchanged(&self, offset: usize, line: &str, entry: State) -> Option<State> {
+        self.get(Interval::new(offset, offset + line.len()))
+            .filter(|lexed| lexed.entry == entry && lexed.hash == hash_line(line))
+            .map(|lexed| lexed.exit)
+    }
+}
+
+/// The hash the lines are recognized by.
+pub fn hash_line(line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statestack::Context;
+    use xi_rope::{Rope, RopeDelta};
+
+    #[test]
+    fn only_untouched_lines_are_unchanged() {
+        let text = "let a = 1;\nlet b = 2;\nlet c = 3;\n";
+        let mut ctx = Context::new();
+        let mut push = |el: u32| ctx.push(State::default(), el);
+        let (a, b, c, d) = (push(1), push(2), push(3), push(4));
+        let mut lexed = LexedLines::new(text.len());
+        lexed.record_line(0, "let a = 1;\n", a, b);
+        lexed.record_line(11, "let b = 2;\n", b, c);
+        lexed.record_line(22, "let c = 3;\n", c, d);
+        assert_eq!(lexed.unchanged(11, "let b = 2;\n", b), Some(c));
+        assert_eq!(lexed.unchanged(11, "let b = 2;\n", a), None);
+        assert_eq!(lexed.unchanged(11, "let b = 3;\n", b), None);
+
+        // typing into the first line, and taking it out again
+        let edit = |start, end, new: &str, len| {
+            RopeDelta::simple_edit(Interval::new(start, end), Rope::from(new), len)
+        };
+        lexed.apply_shape(&edit(4, 4, "x", text.len()));
+        lexed.apply_shape(&edit(4, 5, "", text.len() + 1));
+        assert_eq!(lexed.doc_len(), text.len());
+        assert_eq!(lexed.unchanged(0, "let a = 1;\n", a), None);
+        assert_eq!(lexed.unchanged(11, "let b = 2;\n", b), Some(c));
+
+        // a line pasted over with the same text has no spans in core
+        lexed.apply_shape(&edit(11, 22, "let b = 2;\n", text.len()));
+        assert_eq!(lexed.unchanged(11, "let b = 2;\n", b), None);
+        assert_eq!(lexed.unchanged(22, "let c = 3;\n", c), Some(d));
+
+        lexed.forget(Interval::new(20, 40));
+        assert_eq!(lexed.unchanged(22, "let c = 3;\n", c), None);
+    }
+}
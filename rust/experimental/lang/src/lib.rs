@@ -12,7 +12,4370 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Library export for benchmarking and testing purposes.
+//! A language syntax coloring and indentation plugin for xi-editor.
+//!
+//! The plugin binary only runs a [`LangPlugin`]. The developer tools, like
+//! `xi-lang-tools bench`, are built with the `tools` feature.
 
-// At the moment, we only export the peg module; this may expand.
+extern crate serde_json;
+extern crate xi_core_lib;
+extern crate xi_plugin_lib;
+extern crate xi_rope;
+extern crate xi_trace;
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    mem,
+    path::Path,
+    rc::Rc,
+    sync::{mpsc::TryRecvError, Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::background::{BackgroundPass, Batch, Message};
+use crate::batch::SpanBatch;
+use crate::language::{detect, plaintext::PlaintextParser};
+use crate::lexed::LexedLines;
+use crate::outline::Outline;
+use crate::parser::{Capabilities, Parser, Token, Tokens};
+use crate::registry::{ParserRegistry, CONFIG_SECTION};
+use crate::sent::SentSpans;
+use crate::slice::SliceSize;
+use crate::span_validator::SpanReporter;
+use crate::statestack::{State, StateMap};
+use crate::view::{is_retryable, HighlightView};
+use serde_json::{json, Value};
+use xi_core_lib::{plugins::rpc::ScopeSpan, ConfigTable, LanguageId, ViewId};
+use xi_plugin_lib::{Plugin, StateCache, View};
+use xi_rope::{DeltaBuilder, Interval, RopeDelta};
+use xi_trace::{trace, trace_block, trace_payload, CategoriesT};
+
+mod background;
+mod batch;
+#[cfg(any(test, feature = "tools"))]
+pub mod bench;
+mod brackets;
+mod cleanup;
+mod comment;
+#[cfg(any(test, feature = "tools"))]
+pub mod coverage;
+#[cfg(test)]
+mod differential;
+#[cfg(any(test, feature = "tools"))]
+pub mod export;
+#[cfg(test)]
+mod fuzz;
+#[cfg(test)]
+mod golden;
+#[cfg(any(test, feature = "tools"))]
+pub mod highlight;
+mod indent;
+mod injection;
+pub mod language;
+mod lexed;
+mod line_map;
+mod outline;
+pub mod packed;
+#[cfg(any(test, feature = "tools"))]
+mod palette;
+mod parser;
 pub mod peg;
+mod registry;
+#[cfg(test)]
+mod scope_audit;
+mod scope_map;
+pub mod scope_tracker;
+pub mod scope_validator;
+#[cfg(any(test, feature = "tools"))]
+mod semantic_tokens;
+mod sent;
+mod slice;
+mod span_validator;
+pub mod statestack;
+#[cfg(any(test, feature = "tools"))]
+pub mod testing;
+mod text;
+#[cfg(test)]
+mod timing;
+mod view;
+
+use crate::packed::PackedSpans;
+use crate::scope_tracker::{LookupResult, Scope, ScopeTracker, DEFAULT_SOFT_CAP};
+use crate::scope_validator::ScopeValidator;
+
+/// How long one idle callback may spend highlighting before yielding.
+const IDLE_BUDGET_MS: u64 = 5;
+/// The lines highlighted per idle callback unless configured otherwise,
+/// however cheap they are.
+const MAX_LINES_PER_IDLE: usize = slice::MAX_LINES;
+/// How far above the visible region the priority pass may start, to begin
+/// from a cached state rather than a guess.
+const PRIORITY_LOOKBEHIND: usize = 100;
+/// How far below the main pass the visible region may start and still be
+/// highlighted straight away, when the client reports it.
+const CATCH_UP_LINES: usize = 500;
+/// How many times in a row fetching a line may fail before we give up on
+/// the rest of the document.
+const MAX_FETCH_RETRIES: usize = 5;
+/// How many lines language detection looks at.
+const DETECTION_LINES: usize = 2;
+/// How many spans may be pending before they are flushed at the end of
+/// the current line.
+const MAX_PENDING_SPANS: usize = 4096;
+/// How much lexed text may be pending before its spans are flushed at the
+/// end of the current line.
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+/// How much of a line the main pass lexes at once. A longer line, like
+/// that of a minified file, is lexed a piece at a time, so that an idle
+/// slice can end in the middle of it.
+const MAX_LINE_PIECE: usize = 64 * 1024;
+/// The status item highlighting progress is shown in.
+const PROGRESS_STATUS_KEY: &str = "experimental_lang.progress";
+/// How many idle batches pass between checks on whether to report progress.
+const PROGRESS_BATCHES: usize = 8;
+/// The least time between progress reports. Highlighting that finishes
+/// sooner is never reported at all.
+const PROGRESS_INTERVAL_MS: u64 = 500;
+/// The core config keys indentation follows.
+const TAB_SIZE_KEY: &str = "tab_size";
+const TRANSLATE_TABS_TO_SPACES_KEY: &str = "translate_tabs_to_spaces";
+const AUTO_INDENT_KEY: &str = "auto_indent";
+/// A number of lines per idle callback, or `"auto"` to adapt it to how
+/// long lines take to lex.
+const LINES_PER_SLICE_KEY: &str = "lines_per_slice";
+const CATCH_UP_LINES_KEY: &str = "catch_up_lines";
+/// The config key for how many milliseconds an idle callback may spend.
+const IDLE_BUDGET_KEY: &str = "idle_budget_ms";
+const BRACKET_SEARCH_LINES_KEY: &str = "bracket_search_lines";
+/// The config key for marking indentation levels with spans.
+const INDENT_GUIDES_KEY: &str = "indent_guides";
+/// The config keys for what `experimental_lang.cleanup` does.
+const TRIM_TRAILING_WHITESPACE_KEY: &str = "trim_trailing_whitespace";
+const ENSURE_FINAL_NEWLINE_KEY: &str = "ensure_final_newline";
+const MARKDOWN_KEEP_HARD_BREAKS_KEY: &str = "markdown_keep_hard_breaks";
+/// The config key for tracing every span flushed, rather than how many
+/// and where, at `VERBOSE_TRACE_CATEGORY`.
+const VERBOSE_TRACE_KEY: &str = "verbose_trace";
+const VERBOSE_TRACE_CATEGORY: &str = "experimental-lang-verbose";
+/// The config key for sending scopes with the parsers' own names for
+/// them, rather than the TextMate names `scope_map` gives them.
+const RAW_SCOPE_NAMES_KEY: &str = "raw_scope_names";
+/// The config key that turns highlighting off for a view.
+const SYNTAX_HIGHLIGHTING_KEY: &str = "syntax_highlighting";
+/// The config key for the size past which a document isn't highlighted.
+const MAX_HIGHLIGHT_BYTES_KEY: &str = "max_highlight_bytes";
+const MAX_HIGHLIGHT_BYTES: usize = 8 * 1024 * 1024;
+/// The most lines the outline and the lexed lines are kept for. Each costs
+/// a span per line, which for a large log is far more than the highlighting
+/// itself, so past this folds, symbols and skipping unchanged lines are
+/// given up.
+const MAX_RECORDED_LINES: usize = 100_000;
+/// The config key for how many scopes are given ids of their own before
+/// new ones all share one; like the registry's settings it goes for every
+/// view.
+const SCOPE_SOFT_CAP_KEY: &str = "scope_soft_cap";
+
+/// Documents with at least this many lines are lexed on a worker thread
+/// when opened; off unless set.
+const BACKGROUND_HIGHLIGHT_LINES_KEY: &str = "background_highlight_lines";
+/// How many lines the worker sends back at a time.
+const BACKGROUND_BATCH_LINES: usize = 1000;
+/// How long to leave the worker be when it has nothing for us.
+const BACKGROUND_POLL_INTERVAL_MS: u64 = 10;
+/// How many lines away the partner of a bracket may be found.
+const BRACKET_SEARCH_LINES: usize = 10_000;
+/// How many lines one debug dump lexes, counting those it lexes from the
+/// nearest cached state before its first line.
+const MAX_DUMP_LINES: usize = 1000;
+/// The priority of the plugin's edits.
+const EDIT_PRIORITY: u64 = 100;
+/// The author of the plugin's edits, as core reports them back.
+const EDIT_AUTHOR: &str = "experimental-lang";
+/// Appended to the scope of a character skipped because the parser made no
+/// progress on it.
+const STUCK_SCOPE: &str = "invalid.illegal.stuck";
+/// The least time between reports of a parser making no progress.
+const STUCK_REPORT_INTERVAL_MS: u64 = 1000;
+/// How long typing must pause before the main pass walks on past the
+/// lines around the edit.
+const TYPING_DEBOUNCE_MS: u64 = 100;
+/// How many lines are highlighted straight after a keystroke: the edited
+/// line, and the next one if the edit changed the state it starts in.
+const EAGER_LINES: usize = 2;
+/// How many lines from the edit are highlighted before `update` returns,
+/// so that the edited line never shows stale colors.
+const SYNC_LINES: usize = 3;
+
+type ScopeId = u32;
+
+/// Whether an edit is the user typing, as opposed to a paste, an undo or
+/// a plugin's edit, none of which are likely to be followed by another
+/// edit to the same lines.
+fn is_typing(edit_type: &str, author: &str) -> bool {
+    author == "core" && ["insert", "newline", "delete"].contains(&edit_type)
+}
+
+/// Traces the payload `payload` makes, only making it when tracing is on;
+/// most payloads are formatted, and most of the time no one is tracing.
+fn trace_lazily<C, F>(name: &'static str, categories: C, payload: F)
+where
+    C: Into<CategoriesT>,
+    F: FnOnce() -> String,
+{
+    if xi_trace::is_enabled() {
+        trace_payload(name, categories, payload());
+    }
+}
+
+/// Answers the custom command `method` with `result`, which also goes
+/// into the trace as `name`.
+fn answer<V: HighlightView>(view: &mut V, method: &str, name: &'static str, result: Value) {
+    trace_lazily(name, &["experimental-lang"], || result.to_string());
+    view.respond_to_command(method, &result);
+}
+
+/// Traces the flush of `spans` for `region`: how many there are, or with
+/// `verbose`, every one of them.
+fn trace_spans(name: &'static str, region: Interval, spans: &[ScopeSpan], verbose: bool) {
+    if verbose {
+        trace_lazily(name, &[VERBOSE_TRACE_CATEGORY], || format!("{:?}: {:?}", region, spans));
+    } else {
+        trace_lazily(name, &["experimental-lang"], || {
+            format!("{} spans for {}..{}", spans.len(), region.start(), region.end())
+        });
+    }
+}
+
+/// A parser shared by every view in its language. Parsers keep no
+/// per-document state, only the context their states are interned in, and
+/// the main loop is single-threaded, so views take turns borrowing it. A
+/// view lexed on a worker thread keeps the parser made for the worker to
+/// itself instead, as the states it sent back are in that one's context,
+/// and so does a view whose parser parses whole documents.
+type SharedParser = Rc<RefCell<Box<dyn Parser>>>;
+
+/// The parsers in use, keyed by the registered language they handle.
+type ParserCache = HashMap<LanguageId, SharedParser>;
+
+fn share(parser: Box<dyn Parser>) -> SharedParser {
+    Rc::new(RefCell::new(parser))
+}
+
+/// The shared scope tracker, locked the first time a line needs it and
+/// then held until the line is lexed, rather than once for every scope.
+struct TrackerLock<'a> {
+    tracker: &'a Mutex<ScopeTracker>,
+    guard: Option<MutexGuard<'a, ScopeTracker>>,
+}
+
+impl<'a> TrackerLock<'a> {
+    fn new(tracker: &'a Mutex<ScopeTracker>) -> TrackerLock<'a> {
+        TrackerLock { tracker, guard: None }
+    }
+
+    fn get(&mut self) -> &mut ScopeTracker {
+        let tracker = self.tracker;
+        self.guard.get_or_insert_with(|| tracker.lock().unwrap())
+    }
+}
+
+/// Guesses the language of a plain text view from its first lines, if it
+/// is one we have a parser for.
+fn detect_language<V: HighlightView>(
+    view: &mut V,
+    registry: &ParserRegistry,
+) -> Option<LanguageId> {
+    let mut head = String::new();
+    for line_num in 0..DETECTION_LINES {
+        match view.get_line(line_num) {
+            Ok(line) => head.push_str(line),
+            Err(_) => break,
+        }
+    }
+    detect::detect(&head)
+        .filter(|&language| registry.resolve(language).map_or(false, |l| l != "Plain Text"))
+        .map(LanguageId::from)
+}
+
+pub struct LangPlugin {
+    /// Core opens a new buffer for every view, and plugin-lib refuses a
+    /// buffer with more than one, so each view's state is also the whole
+    /// document's. Two views of one file are separate buffers, edited
+    /// independently, and share nothing but their parser and scope ids.
+    view_states: HashMap<ViewId, ViewState>,
+    /// Shared by all views, so that a scope has the same id everywhere.
+    scope_tracker: Arc<Mutex<ScopeTracker>>,
+    registry: ParserRegistry,
+    parsers: ParserCache,
+    /// Set through `background_highlight_lines`, which like the registry's
+    /// settings goes for every view; 0 when off.
+    background_lines: usize,
+    /// The scope tracker's soft cap, kept for when it is replaced.
+    scope_soft_cap: usize,
+}
+
+impl LangPlugin {
+    pub fn new() -> LangPlugin {
+        LangPlugin {
+            view_states: HashMap::new(),
+            scope_tracker: Arc::default(),
+            registry: ParserRegistry::default(),
+            parsers: HashMap::new(),
+            background_lines: 0,
+            scope_soft_cap: DEFAULT_SOFT_CAP,
+        }
+    }
+
+    /// Starts highlighting a view core has just opened, with the config of
+    /// its buffer. Only the parser for the view's language is made, if no
+    /// other view has made it already.
+    fn open_view<V: HighlightView>(&mut self, view: &mut V, config: &ConfigTable) -> ViewState {
+        self.apply_config(config);
+        let mut view_state = ViewState::new(self.scope_tracker.clone());
+        view_state.read_config(config);
+        if let Some(enabled) = config.get(SYNTAX_HIGHLIGHTING_KEY).and_then(Value::as_bool) {
+            view_state.enabled = enabled;
+        }
+
+        view_state.do_highlighting(view, &self.registry, &mut self.parsers);
+        if self.background_lines > 0 && view.get_num_lines() >= self.background_lines {
+            view_state.start_background(view, &self.registry);
+        }
+        view_state
+    }
+
+    /// Takes in the settings that go for every view: those of the registry,
+    /// and when to highlight on a worker.
+    fn apply_config(&mut self, changes: &ConfigTable) {
+        let generation = self.registry.generation();
+        self.registry.apply_config(changes);
+        if self.registry.generation() != generation {
+            // the parsers made so far have the old injections or syntaxes;
+            // views swap theirs as they notice
+            self.parsers.clear();
+        }
+        let section = changes.get(CONFIG_SECTION);
+        let background_lines = section.and_then(|s| s.get(BACKGROUND_HIGHLIGHT_LINES_KEY));
+        if let Some(lines) = background_lines.and_then(Value::as_u64) {
+            self.background_lines = lines as usize;
+        }
+        let soft_cap = section.and_then(|s| s.get(SCOPE_SOFT_CAP_KEY));
+        if let Some(soft_cap) = soft_cap.and_then(Value::as_u64) {
+            self.scope_soft_cap = soft_cap as usize;
+            self.scope_tracker.lock().unwrap().set_soft_cap(self.scope_soft_cap);
+        }
+    }
+
+    /// Forgets a closed view, along with any parser or scope that no other
+    /// view still needs.
+    fn close_view(&mut self, view_id: ViewId) {
+        if let Some(view_state) = self.view_states.remove(&view_id) {
+            view_state.close();
+        }
+        self.parsers.retain(|_, parser| Rc::strong_count(parser) > 1);
+        // Scope ids must stay stable while any view may refer to them.
+        if self.view_states.is_empty() {
+            *self.scope_tracker.lock().unwrap() = ScopeTracker::with_soft_cap(self.scope_soft_cap);
+        }
+    }
+}
+
+impl Default for LangPlugin {
+    fn default() -> LangPlugin {
+        LangPlugin::new()
+    }
+}
+
+impl Plugin for LangPlugin {
+    type Cache = StateCache<State>;
+
+    fn update(
+        &mut self,
+        view: &mut View<Self::Cache>,
+        delta: Option<&RopeDelta>,
+        edit_type: String,
+        author: String,
+    ) {
+        // By now the state cache has applied the delta itself, so it knows
+        // which lines need lexing again.
+        let view_id = view.get_id();
+        if let Some(view_state) = self.view_states.get_mut(&view_id) {
+            view_state.update(view, delta, is_typing(&edit_type, &author));
+            if let (Some(delta), "core") = (delta, author.as_str()) {
+                view_state.indent_after_edit(view, delta, &edit_type);
+            }
+        }
+    }
+
+    fn did_save(&mut self, view: &mut View<Self::Cache>, _old_path: Option<&Path>) {
+        let view_id = view.get_id();
+        if let Some(view_state) = self.view_states.get_mut(&view_id) {
+            view_state.did_save(view, &self.registry, &mut self.parsers);
+        }
+    }
+
+    fn did_close(&mut self, view: &View<Self::Cache>) {
+        self.close_view(view.get_id());
+    }
+
+    fn new_view(&mut self, view: &mut View<Self::Cache>) {
+        let view_id = view.get_id();
+        let config = view.get_config_table().clone();
+        let view_state = self.open_view(view, &config);
+        self.view_states.insert(view_id, view_state);
+    }
+
+    fn config_changed(&mut self, view: &mut View<Self::Cache>, changes: &ConfigTable) {
+        self.apply_config(changes);
+        let view_id = view.get_id();
+        if let Some(view_state) = self.view_states.get_mut(&view_id) {
+            view_state.config_changed(view, changes, &self.registry, &mut self.parsers);
+        }
+    }
+
+    fn language_changed(
+        &mut self,
+        view: &mut View<<Self as Plugin>::Cache>,
+        _old_lang: LanguageId,
+    ) {
+        let view_id = view.get_id();
+        if let Some(view_state) = self.view_states.get_mut(&view_id) {
+            view_state.do_highlighting(view, &self.registry, &mut self.parsers);
+        }
+    }
+
+    fn custom_command(&mut self, view: &mut View<Self::Cache>, method: &str, params: Value) {
+        let view_id = view.get_id();
+        let view_state = match self.view_states.get_mut(&view_id) {
+            Some(view_state) => view_state,
+            None => {
+                trace_lazily("command for unknown view", &["experimental-lang"], || {
+                    format!("{} on {}", method, view_id)
+                });
+                return;
+            }
+        };
+
+        match method {
+            // an escape hatch for when highlighting goes wrong, from
+            // `first_line` on if given
+            "experimental_lang.rehighlight" => match params["first_line"].as_u64() {
+                Some(line_num) => view_state.invalidate_from(view, line_num as usize),
+                None => {
+                    // core's spans may be what went wrong, so all are sent
+                    view_state.sent = SentSpans::new(view_state.recorded_len(view));
+                    view_state.do_highlighting(view, &self.registry, &mut self.parsers)
+                }
+            },
+            // for when core has dropped the spans, as after reloading the view
+            "experimental_lang.resend_spans" => view_state.resend_spans(view),
+            // The dump and the stats also go into the trace, where
+            // `collect_trace` picks them up.
+            "experimental_lang.debug_dump" => {
+                let line = |key: &str| params[key].as_u64().map(|n| n as usize);
+                let first = line("first_line").unwrap_or(0);
+                let result = view_state.debug_dump(view, first, line("last_line"));
+                answer(view, method, "debug dump", result);
+            }
+            "experimental_lang.matching_bracket" => match params["offset"].as_u64() {
+                Some(offset) => {
+                    let partner = view_state.matching_bracket(view, offset as usize);
+                    let result = json!({ "offset": offset, "match": partner });
+                    answer(view, method, "matching bracket", result);
+                }
+                None => trace_lazily("invalid matching_bracket", &["experimental-lang"], || {
+                    params.to_string()
+                }),
+            },
+            "experimental_lang.toggle_comment" => {
+                let line = |key: &str| params[key].as_u64().map(|n| n as usize);
+                match (line("first_line"), line("last_line")) {
+                    (Some(first), Some(last)) if first <= last => {
+                        view_state.toggle_comment(view, first, last)
+                    }
+                    _ => trace_lazily("invalid toggle_comment", &["experimental-lang"], || {
+                        params.to_string()
+                    }),
+                }
+            }
+            "experimental_lang.should_autoclose" => {
+                let typed = params["character"].as_str().and_then(|s| {
+                    let mut chars = s.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Some(c),
+                        _ => None,
+                    }
+                });
+                match (params["offset"].as_u64(), typed) {
+                    (Some(offset), Some(c)) => {
+                        let closer = view_state.autoclose(view, offset as usize, c);
+                        let result = json!({
+                            "offset": offset,
+                            "character": c.to_string(),
+                            "autoclose": closer.is_some(),
+                            "closer": closer.map(|c| c.to_string()),
+                        });
+                        answer(view, method, "should autoclose", result);
+                    }
+                    _ => trace_lazily("invalid should_autoclose", &["experimental-lang"], || {
+                        params.to_string()
+                    }),
+                }
+            }
+            "experimental_lang.expand_selection" => {
+                match (params["start"].as_u64(), params["end"].as_u64()) {
+                    (Some(start), Some(end)) => {
+                        let expanded =
+                            view_state.expand_selection(view, start as usize, end as usize);
+                        let expanded =
+                            expanded.map(|(start, end)| json!({ "start": start, "end": end }));
+                        let result = json!({ "start": start, "end": end, "expanded": expanded });
+                        answer(view, method, "expanded selection", result);
+                    }
+                    _ => trace_lazily("invalid expand_selection", &["experimental-lang"], || {
+                        params.to_string()
+                    }),
+                }
+            }
+            "experimental_lang.reindent" => {
+                let line = |key: &str| params[key].as_u64().map(|n| n as usize);
+                match (line("first_line"), line("last_line")) {
+                    (Some(first), Some(last)) if first <= last => {
+                        view_state.reindent(view, first, last)
+                    }
+                    _ => trace_lazily("invalid reindent", &["experimental-lang"], || {
+                        params.to_string()
+                    }),
+                }
+            }
+            // There is no hook before a save, so this is for the client to
+            // send first.
+            "experimental_lang.cleanup" => view_state.cleanup(view),
+            "experimental_lang.folds" => {
+                let result = view_state.folds(view);
+                answer(view, method, "folds", result);
+            }
+            "experimental_lang.symbols" => {
+                let result = view_state.symbols(view);
+                answer(view, method, "symbols", result);
+            }
+            "experimental_lang.stats" => {
+                let result = view_state.stats(view);
+                answer(view, method, "stats", result);
+            }
+            // A protocol extension: core doesn't tell plugins where the view
+            // is scrolled to, so a client that wants the visible lines
+            // highlighted first sends them itself, through `plugin_rpc`:
+            //
+            //     plugin_rpc {"view_id": "view-id-1", "receiver": "xi-lang-plugin",
+            //                 "notification": {"method": "set_visible_region",
+            //                     "params": {"first_line": 300, "last_line": 340}}}
+            //
+            // and again as it scrolls. Without it, the document is highlighted
+            // from the top down.
+            "set_visible_region" => {
+                let line = |key: &str| params[key].as_u64().map(|n| n as usize);
+                match (line("first_line"), line("last_line")) {
+                    (Some(first), Some(last)) if first <= last => {
+                        view_state.set_visible_region(view, first, last)
+                    }
+                    _ => trace_lazily("invalid visible region", &["experimental-lang"], || {
+                        params.to_string()
+                    }),
+                }
+            }
+            _ => trace_lazily("unknown command", &["experimental-lang"], || method.to_owned()),
+        }
+    }
+
+    fn idle(&mut self, view: &mut View<Self::Cache>) {
+        let view_id = view.get_id();
+
+        if let Some(view_state) = self.view_states.get_mut(&view_id) {
+            view_state.idle(view);
+        }
+    }
+}
+
+/// Highlights the lines the user can see ahead of the main top-down pass.
+/// Its states are not known to be right, so they never go into the state
+/// cache; the main pass lexes these lines again when it gets to them, and
+/// its spans replace the ones sent from here.
+struct PriorityPass {
+    line_num: usize,
+    last_line: usize,
+    offset: usize,
+    state: State,
+    /// Whether the pass started from a guess, rather than from a cached
+    /// state the main pass has already confirmed.
+    speculative: bool,
+    spans_start: usize,
+    spans: Vec<ScopeSpan>,
+    /// The generation of highlighting the pass belongs to.
+    generation: usize,
+}
+
+/// A line the main pass has lexed part of, which it carries on with from
+/// `lexed` bytes in, in `state`, rather than from its start.
+struct PartialLine {
+    line_num: usize,
+    lexed: usize,
+    state: State,
+}
+
+/// How far the main pass has got through the document.
+struct Progress {
+    /// When progress was last reported, or when the pass started.
+    last_report: Instant,
+    batches: usize,
+    /// Whether the status item is showing.
+    shown: bool,
+}
+
+/// Running totals for `experimental_lang.stats`. Updating them is kept to
+/// plain increments, as they are always on.
+#[derive(Default)]
+struct Counters {
+    /// Lines lexed by the main pass, counting lines lexed again after edits.
+    lines_lexed: usize,
+    /// Lines the main pass stepped over, as they were unchanged.
+    lines_skipped: usize,
+    /// Flushes not sent, as core already had the same spans.
+    flushes_unchanged: usize,
+    /// Lines lexed on the worker thread, and of those, the ones an edit
+    /// had touched by the time they were taken in.
+    background_lines: usize,
+    background_lines_discarded: usize,
+    /// Time spent in the main pass's idle batches, taken once a batch.
+    parse_time: Duration,
+    update_spans_calls: usize,
+    add_scopes_calls: usize,
+    /// Bytes skipped because the parser made no progress on them.
+    stuck_bytes: usize,
+    /// How many spans sent by the main pass had each scope, by scope id.
+    scope_uses: Vec<usize>,
+}
+
+struct ViewState {
+    current_language: LanguageId,
+    parser: SharedParser,
+    /// Whether a parser that parses whole documents has been given this
+    /// one, and every edit to it since.
+    document_synced: bool,
+    /// The offset the main pass has lexed up to.
+    offset: usize,
+    initial_state: State,
+    spans_start: usize,
+    /// Spans lexed since the last flush, exactly covering `spans_start` to
+    /// `offset` and relative to `spans_start`. Each is flushed exactly
+    /// once: before the main pass jumps to another offset, before an edit
+    /// moves it, and at the end of every idle batch, including the last.
+    spans: Vec<ScopeSpan>,
+    /// The line at the frontier, when the main pass stopped part-way
+    /// through it. Its spans so far are pending or flushed like any other.
+    partial_line: Option<PartialLine>,
+    /// Scratch space for the spans of a line being split at indent guides,
+    /// kept so that it isn't allocated again for every line.
+    line_spans: Vec<ScopeSpan>,
+    /// Spans flushed during an idle slice, sent together at its end.
+    batch: SpanBatch,
+    scope_tracker: Arc<Mutex<ScopeTracker>>,
+    /// How many of the tracker's scopes have been sent to this view. Core
+    /// keeps a scope table per view, so each view must be sent every scope
+    /// in id order, including those only other views use.
+    announced_scopes: usize,
+    /// The scope id of each state the parser has produced, so that a
+    /// state's scope is built and hashed once rather than for every token.
+    /// The tracker never reuses ids, and is only replaced once every view
+    /// is closed, so these only go stale with the parser.
+    state_scopes: StateMap<ScopeId>,
+    /// The same for characters skipped in each state.
+    stuck_scopes: StateMap<ScopeId>,
+    /// The scope id of each indent guide, by the scope it is in and level.
+    guide_scopes: HashMap<(ScopeId, usize), ScopeId>,
+    scope_validator: ScopeValidator,
+    span_reporter: SpanReporter,
+    idle_budget: Duration,
+    slice: SliceSize,
+    catch_up_lines: usize,
+    bracket_search_lines: usize,
+    max_pending_spans: usize,
+    max_pending_bytes: usize,
+    max_line_piece: usize,
+    progress_interval: Duration,
+    progress: Progress,
+    /// The language guessed from the text of a Plain Text view, which is
+    /// highlighted as such without core's knowledge.
+    detected_language: Option<LanguageId>,
+    /// The registered language whose parser is in use; `None` when the
+    /// language is unsupported and highlighted as plain text.
+    parser_language: Option<String>,
+    /// The `ParserRegistry::generation` the parser was made in.
+    registry_generation: u64,
+    /// The first and last visible lines, if the client has told us.
+    visible_region: Option<(usize, usize)>,
+    priority: Option<PriorityPass>,
+    /// Consecutive failures to fetch the line at the frontier.
+    fetch_failures: usize,
+    /// While typing, the time after which the main pass may walk on past
+    /// the lines around the edit.
+    typing_until: Option<Instant>,
+    /// How many more lines the main pass may highlight before then.
+    eager_lines: usize,
+    typing_debounce: Duration,
+    /// The unit of indentation.
+    tab_size: usize,
+    translate_tabs_to_spaces: bool,
+    /// Whether new lines are indented for the user.
+    auto_indent: bool,
+    /// Whether the leading whitespace of lines is marked with indent guide
+    /// spans.
+    indent_guides: bool,
+    /// What a cleanup fixes up.
+    trim_trailing_whitespace: bool,
+    ensure_final_newline: bool,
+    /// Whether a cleanup keeps the trailing spaces of a Markdown line
+    /// break.
+    markdown_keep_hard_breaks: bool,
+    /// Whether flushes are traced span by span.
+    verbose_trace: bool,
+    /// Whether scopes are sent as the parser names them.
+    raw_scope_names: bool,
+    /// What the parser said about folding and declarations on each line
+    /// the main pass lexed. Empty, with the lexed lines, while the
+    /// document is past `MAX_RECORDED_LINES`.
+    outline: Outline,
+    /// The lines the main pass lexed whose spans core still has.
+    lexed: LexedLines,
+    /// What core was last sent for each line. Like the lexed lines, it is
+    /// empty past `MAX_RECORDED_LINES`, but a rehighlight keeps it.
+    sent: SentSpans,
+    /// A digest of each line, or piece of one, in `spans`, by offset, to
+    /// be checked against `sent` when they are flushed.
+    pending_digests: Vec<(Interval, u64)>,
+    /// The spans a digest is being taken of, packed, kept for the memory.
+    digest_spans: PackedSpans,
+    /// The worker lexing the document, if it was handed one when opened.
+    /// The main pass waits for it, but the priority pass doesn't.
+    background: Option<BackgroundPass>,
+    background_batch_lines: usize,
+    /// Whether this view is highlighted at all.
+    enabled: bool,
+    max_highlight_bytes: usize,
+    /// Whether the document was past `max_highlight_bytes`, and so left
+    /// alone, when last checked.
+    too_large: bool,
+    /// Whether core may be showing colors from spans sent for this view.
+    styled: bool,
+    /// Bumped whenever highlighting starts again from scratch. Work begun
+    /// in an earlier generation is dropped rather than flushed, as its
+    /// spans may refer to another parser's scopes.
+    generation: usize,
+    counters: Counters,
+    /// When the parser was last reported stuck, and how many bytes it has
+    /// been stuck on since.
+    stuck_report: Option<Instant>,
+    stuck_unreported: usize,
+}
+
+impl ViewState {
+    fn new(scope_tracker: Arc<Mutex<ScopeTracker>>) -> ViewState {
+        ViewState {
+            current_language: LanguageId::from("Plain Text"),
+            parser: share(Box::new(PlaintextParser::new())),
+            document_synced: false,
+            offset: 0,
+            initial_state: State::default(),
+            spans_start: 0,
+            spans: Vec::new(),
+            partial_line: None,
+            line_spans: Vec::new(),
+            batch: SpanBatch::new(),
+            scope_tracker,
+            announced_scopes: 0,
+            state_scopes: StateMap::new(),
+            stuck_scopes: StateMap::new(),
+            guide_scopes: HashMap::new(),
+            scope_validator: ScopeValidator::new(),
+            span_reporter: SpanReporter::new(),
+            idle_budget: Duration::from_millis(IDLE_BUDGET_MS),
+            slice: SliceSize::fixed(MAX_LINES_PER_IDLE),
+            catch_up_lines: CATCH_UP_LINES,
+            bracket_search_lines: BRACKET_SEARCH_LINES,
+            max_pending_spans: MAX_PENDING_SPANS,
+            max_pending_bytes: MAX_PENDING_BYTES,
+            max_line_piece: MAX_LINE_PIECE,
+            progress_interval: Duration::from_millis(PROGRESS_INTERVAL_MS),
+            progress: Progress { last_report: Instant::now(), batches: 0, shown: false },
+            detected_language: None,
+            parser_language: None,
+            registry_generation: 0,
+            visible_region: None,
+            priority: None,
+            fetch_failures: 0,
+            typing_until: None,
+            eager_lines: 0,
+            typing_debounce: Duration::from_millis(TYPING_DEBOUNCE_MS),
+            tab_size: 4,
+            translate_tabs_to_spaces: false,
+            auto_indent: true,
+            indent_guides: false,
+            trim_trailing_whitespace: false,
+            ensure_final_newline: false,
+            markdown_keep_hard_breaks: false,
+            verbose_trace: false,
+            raw_scope_names: false,
+            outline: Outline::new(0),
+            lexed: LexedLines::new(0),
+            sent: SentSpans::new(0),
+            pending_digests: Vec::new(),
+            digest_spans: PackedSpans::new(),
+            background: None,
+            background_batch_lines: BACKGROUND_BATCH_LINES,
+            enabled: true,
+            max_highlight_bytes: MAX_HIGHLIGHT_BYTES,
+            too_large: false,
+            styled: false,
+            generation: 0,
+            counters: Counters::default(),
+            stuck_report: None,
+            stuck_unreported: 0,
+        }
+    }
+
+    /// Tears down the view's highlighting once core has closed it. Core has
+    /// already dropped the view, so spans not yet flushed are discarded
+    /// rather than sent.
+    fn close(self) {
+        let pending = self.spans.len() + self.priority.as_ref().map_or(0, |pass| pass.spans.len());
+        trace_lazily("view closed", &["experimental-lang"], || {
+            format!("{}, {} pending spans discarded", self.current_language.as_ref(), pending)
+        });
+    }
+
+    /// Makes sure the parser matches the view's language, then highlights
+    /// the whole document.
+    fn do_highlighting<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        registry: &ParserRegistry,
+        parsers: &mut ParserCache,
+    ) {
+        self.reset_parser_if_language_changed(view, registry, parsers);
+        self.rehighlight_all(view);
+    }
+
+    /// Swaps in the parser for the view's language, or the one detected
+    /// from its text, if that isn't the parser in use. Returns whether it
+    /// was swapped; if so, the cached states are meaningless and the
+    /// document must be highlighted again from the start.
+    fn reset_parser_if_language_changed<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        registry: &ParserRegistry,
+        parsers: &mut ParserCache,
+    ) -> bool {
+        let language_id = view.get_language_id().clone();
+        let detected = if language_id.as_ref() == "Plain Text" {
+            detect_language(view, registry)
+        } else {
+            None
+        };
+        let wanted = detected.as_ref().unwrap_or(&language_id);
+        let parser_language = registry.resolve(wanted.as_ref()).map(str::to_owned);
+
+        let mut changed = false;
+        if language_id != self.current_language
+            || detected != self.detected_language
+            || parser_language != self.parser_language
+            || registry.generation() != self.registry_generation
+        {
+            if parser_language.is_none() {
+                trace_lazily("unsupported language", &["experimental-lang"], || {
+                    format!("language id: {}", language_id.as_ref())
+                });
+            }
+            // `create` resolves `wanted` to `parser_language` again, so the
+            // cache only ever holds a language's own parser
+            let key = LanguageId::from(
+                parser_language.as_ref().map(String::as_str).unwrap_or("Plain Text"),
+            );
+            let parser = match parsers.get(&key) {
+                Some(parser) => parser.clone(),
+                None => {
+                    let parser = share(
+                        registry
+                            .create(wanted.as_ref())
+                            .unwrap_or_else(|| Box::new(PlaintextParser::new())),
+                    );
+                    // one that parses the document is this view's alone
+                    if !parser.borrow().capabilities().parses_documents {
+                        parsers.insert(key, parser.clone());
+                    }
+                    parser
+                }
+            };
+
+            self.set_parser(language_id, parser);
+            trace_lazily("parser installed", &["experimental-lang"], || {
+                format!("{} parser for {}", self.parser_name(), self.current_language.as_ref())
+            });
+            self.detected_language = detected;
+            self.parser_language = parser_language;
+            self.registry_generation = registry.generation();
+
+            // Core keeps showing the old parser's colors until each line is
+            // highlighted again, so clear them all now.
+            self.clear_styles(view);
+            changed = true;
+        }
+
+        self.register_parser_scopes(view);
+        changed
+    }
+
+    /// Saving doesn't change the text, but saving under a new name can
+    /// change the language.
+    fn did_save<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        registry: &ParserRegistry,
+        parsers: &mut ParserCache,
+    ) {
+        if view.get_language_id() != &self.current_language {
+            self.do_highlighting(view, registry, parsers);
+        }
+    }
+
+    /// Picks up the settings in a change to the view's config, and
+    /// highlights the document again only if one of them changes the
+    /// scopes sent; otherwise every cached state is kept. The registry has
+    /// already taken in the change, or one reported to another view, and
+    /// may now route the view's language to another parser.
+    fn config_changed<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        changes: &ConfigTable,
+        registry: &ParserRegistry,
+        parsers: &mut ParserCache,
+    ) {
+        // the registry is shared, so the change that rerouted the view's
+        // language, or changed its injections, may have been another view's
+        let rerouted = registry.generation() != self.registry_generation
+            || self.parser_rerouted(view, registry);
+        let mut rehighlight = self.read_config(changes)
+            || rerouted && self.reset_parser_if_language_changed(view, registry, parsers);
+        if changes.contains_key(MAX_HIGHLIGHT_BYTES_KEY) {
+            rehighlight |= self.too_large != self.exceeds_limit(view);
+        }
+
+        match changes.get(SYNTAX_HIGHLIGHTING_KEY).and_then(Value::as_bool) {
+            Some(true) if !self.enabled => self.enable(view, registry, parsers),
+            Some(false) if self.enabled => self.disable(view),
+            _ if rehighlight => self.rehighlight_all(view),
+            _ => (),
+        }
+    }
+
+    /// Takes in the view's settings in `changes`, returning whether one of
+    /// them changes the scopes sent.
+    fn read_config(&mut self, changes: &ConfigTable) -> bool {
+        // indent guides depend on the tab size
+        let mut guides_changed = false;
+        if let Some(tab_size) = changes.get(TAB_SIZE_KEY).and_then(Value::as_u64) {
+            guides_changed |= self.indent_guides && self.tab_size != tab_size as usize;
+            self.tab_size = tab_size as usize;
+        }
+        if let Some(translate) = changes.get(TRANSLATE_TABS_TO_SPACES_KEY).and_then(Value::as_bool)
+        {
+            self.translate_tabs_to_spaces = translate;
+        }
+        if let Some(auto_indent) = changes.get(AUTO_INDENT_KEY).and_then(Value::as_bool) {
+            self.auto_indent = auto_indent;
+        }
+
+        match changes.get(CONFIG_SECTION).and_then(|section| section.get(LINES_PER_SLICE_KEY)) {
+            Some(Value::String(mode)) if mode == "auto" => {
+                self.slice = SliceSize::adaptive(Duration::from_millis(slice::TARGET_LATENCY_MS))
+            }
+            Some(lines) if lines.is_u64() => {
+                self.slice = SliceSize::fixed(lines.as_u64().unwrap() as usize)
+            }
+            Some(other) => trace_lazily("invalid lines_per_slice", &["experimental-lang"], || {
+                other.to_string()
+            }),
+            None => (),
+        }
+        let catch_up_lines = changes.get(CONFIG_SECTION).and_then(|s| s.get(CATCH_UP_LINES_KEY));
+        if let Some(lines) = catch_up_lines.and_then(Value::as_u64) {
+            self.catch_up_lines = lines as usize;
+        }
+        let budget = changes.get(CONFIG_SECTION).and_then(|s| s.get(IDLE_BUDGET_KEY));
+        if let Some(millis) = budget.and_then(Value::as_u64) {
+            self.idle_budget = Duration::from_millis(millis);
+        }
+        let search_lines =
+            changes.get(CONFIG_SECTION).and_then(|s| s.get(BRACKET_SEARCH_LINES_KEY));
+        if let Some(lines) = search_lines.and_then(Value::as_u64) {
+            self.bracket_search_lines = lines as usize;
+        }
+        let section_flag = |key: &str| changes.get(CONFIG_SECTION).and_then(|s| s.get(key));
+        if let Some(trim) = section_flag(TRIM_TRAILING_WHITESPACE_KEY).and_then(Value::as_bool) {
+            self.trim_trailing_whitespace = trim;
+        }
+        if let Some(newline) = section_flag(ENSURE_FINAL_NEWLINE_KEY).and_then(Value::as_bool) {
+            self.ensure_final_newline = newline;
+        }
+        if let Some(keep) = section_flag(MARKDOWN_KEEP_HARD_BREAKS_KEY).and_then(Value::as_bool) {
+            self.markdown_keep_hard_breaks = keep;
+        }
+        if let Some(verbose) = section_flag(VERBOSE_TRACE_KEY).and_then(Value::as_bool) {
+            self.verbose_trace = verbose;
+        }
+        let mut names_changed = false;
+        if let Some(raw) = section_flag(RAW_SCOPE_NAMES_KEY).and_then(Value::as_bool) {
+            names_changed = self.raw_scope_names != raw;
+            self.raw_scope_names = raw;
+        }
+        if names_changed {
+            // the states' scopes go by other names now
+            self.state_scopes.clear();
+            self.stuck_scopes.clear();
+            self.guide_scopes.clear();
+        }
+        let guides = changes.get(CONFIG_SECTION).and_then(|s| s.get(INDENT_GUIDES_KEY));
+        if let Some(guides) = guides.and_then(Value::as_bool) {
+            guides_changed |= self.indent_guides != guides;
+            self.indent_guides = guides;
+        }
+        if let Some(max) = changes.get(MAX_HIGHLIGHT_BYTES_KEY).and_then(Value::as_u64) {
+            self.max_highlight_bytes = max as usize;
+        }
+        guides_changed || names_changed
+    }
+
+    /// Whether the registry now routes the language the view is
+    /// highlighted as to a parser other than the one in use.
+    fn parser_rerouted<V: HighlightView>(&self, view: &V, registry: &ParserRegistry) -> bool {
+        let language_id = view.get_language_id();
+        let wanted = match self.detected_language {
+            Some(ref detected) if language_id.as_ref() == "Plain Text" => detected,
+            _ => language_id,
+        };
+        registry.resolve(wanted.as_ref()) != self.parser_language.as_ref().map(String::as_str)
+    }
+
+    fn enable<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        registry: &ParserRegistry,
+        parsers: &mut ParserCache,
+    ) {
+        self.enabled = true;
+        self.do_highlighting(view, registry, parsers);
+    }
+
+    /// Stops highlighting the view, and removes the colors already sent.
+    fn disable<V: HighlightView>(&mut self, view: &mut V) {
+        self.enabled = false;
+        self.stop_highlighting(view);
+    }
+
+    fn is_highlighting(&self) -> bool {
+        self.enabled && !self.too_large
+    }
+
+    fn exceeds_limit<V: HighlightView>(&self, view: &V) -> bool {
+        view.get_buf_size() > self.max_highlight_bytes
+    }
+
+    /// Notes whether the document has outgrown `max_highlight_bytes`,
+    /// stopping highlighting as it does. Returns whether it is too large.
+    fn check_size<V: HighlightView>(&mut self, view: &mut V) -> bool {
+        let too_large = self.exceeds_limit(view);
+        if too_large && !self.too_large {
+            trace_lazily("document too large to highlight", &["experimental-lang"], || {
+                format!("{} bytes, limit {}", view.get_buf_size(), self.max_highlight_bytes)
+            });
+            self.too_large = true;
+            self.stop_highlighting(view);
+        }
+        self.too_large = too_large;
+        too_large
+    }
+
+    /// Drops all highlighting work and cached states, and the colors
+    /// already sent.
+    fn stop_highlighting<V: HighlightView>(&mut self, view: &mut V) {
+        self.generation += 1;
+        self.priority = None;
+        self.background = None;
+        self.spans.clear();
+        self.pending_digests.clear();
+        self.partial_line = None;
+        self.batch.discard();
+        self.offset = 0;
+        self.spans_start = 0;
+        self.lexed = LexedLines::new(self.recorded_len(view));
+        view.clear_cache();
+        self.clear_styles(view);
+        self.finish_progress(view);
+    }
+
+    fn clear_styles<V: HighlightView>(&mut self, view: &mut V) {
+        self.sent = SentSpans::new(self.recorded_len(view));
+        if self.styled {
+            view.update_spans(0, view.get_buf_size(), &[]);
+            self.styled = false;
+        }
+    }
+
+    /// Discards every cached state and starts again from the first line.
+    /// Spans not yet flushed are dropped; the restart lexes their lines
+    /// again.
+    fn rehighlight_all<V: HighlightView>(&mut self, view: &mut V) {
+        if !self.enabled || self.check_size(view) {
+            return;
+        }
+        self.sync_document(view, None);
+        self.generation += 1;
+        self.background = None;
+        self.offset = 0;
+        self.spans_start = 0;
+        self.initial_state = self.parser.borrow().initial_state();
+        self.spans.clear();
+        self.pending_digests.clear();
+        self.partial_line = None;
+        self.batch.discard();
+        self.reset_line_records(view);
+        // what core has is still worth knowing, if it is for this text
+        if self.sent.doc_len() != self.recorded_len(view) {
+            self.sent = SentSpans::new(self.recorded_len(view));
+        }
+        view.clear_cache();
+        self.restart_progress();
+        self.start_priority_pass(view);
+        view.schedule_idle();
+    }
+
+    /// Forgets everything recorded about the lines of the document.
+    fn reset_line_records<V: HighlightView>(&mut self, view: &V) {
+        let len = self.recorded_len(view);
+        self.outline = Outline::new(len);
+        self.lexed = LexedLines::new(len);
+    }
+
+    /// The length to keep line records for: the document's, or nothing at
+    /// all, which records no line, when it has too many lines.
+    fn recorded_len<V: HighlightView>(&self, view: &V) -> usize {
+        if view.get_num_lines() > MAX_RECORDED_LINES {
+            0
+        } else {
+            view.get_buf_size()
+        }
+    }
+
+    /// Discards the cached states of the lines after `line_num`, and
+    /// highlights again from there, keeping the states above.
+    fn invalidate_from<V: HighlightView>(&mut self, view: &mut V, line_num: usize) {
+        if !self.is_highlighting() {
+            return;
+        }
+        self.priority = None;
+        if let Ok(offset) = view.offset_of_line(line_num) {
+            // the lines are sent again, whatever core has
+            self.lexed.forget(Interval::new(offset, view.get_buf_size()));
+            self.sent.forget(Interval::new(offset, view.get_buf_size()));
+        }
+        view.invalidate_from(line_num);
+        self.resume_from_frontier(view);
+        self.restart_progress();
+        self.start_priority_pass(view);
+        view.schedule_idle();
+    }
+
+    /// Points the main pass at the frontier, starting from the nearest
+    /// state cached above it, after the frontier has moved back. Spans
+    /// already lexed are flushed first, as they are still right. A line
+    /// left part-way through is lexed again from its start.
+    fn resume_from_frontier<V: HighlightView>(&mut self, view: &mut V) {
+        self.partial_line = None;
+        if let Some(line_num) = view.get_frontier() {
+            let (_, offset, state) = self.state_before(view, line_num);
+            self.flush_spans(view);
+            self.offset = offset;
+            self.spans_start = offset;
+            self.initial_state = state;
+        }
+    }
+
+    /// Sends the spans of the whole document again, for when core has
+    /// dropped the ones already sent. The main pass starts over, lexing
+    /// across idle slices with the visible region first, rather than all
+    /// at once.
+    fn resend_spans<V: HighlightView>(&mut self, view: &mut V) {
+        self.invalidate_from(view, 0);
+    }
+
+    /// Resumes highlighting after an edit. The state cache has already
+    /// dropped the states the edit invalidated and moved the frontier back
+    /// to the first line it touched; from there, the convergence check in
+    /// `highlight_one_line` stops as soon as a line ends in the state it
+    /// had before the edit.
+    ///
+    /// While the user is `typing`, the lines below are likely to change
+    /// again before we reach them, so only the lines around the edit are
+    /// highlighted until typing pauses. The plugin only hears about time
+    /// passing through `idle`, so a timer calls it once the pause is long
+    /// enough.
+    fn update<V: HighlightView>(&mut self, view: &mut V, delta: Option<&RopeDelta>, typing: bool) {
+        match delta {
+            // an edit that takes the document past the limit, or back under
+            // it, has to start the records over
+            Some(delta)
+                if delta.base_len == self.outline.doc_len()
+                    && delta.base_len == self.lexed.doc_len()
+                    && self.recorded_len(view) == view.get_buf_size() =>
+            {
+                self.outline.apply_shape(delta);
+                self.lexed.apply_shape(delta);
+            }
+            _ => self.reset_line_records(view),
+        }
+        match delta {
+            Some(delta)
+                if delta.base_len == self.sent.doc_len()
+                    && self.recorded_len(view) == view.get_buf_size() =>
+            {
+                self.sent.apply_shape(delta)
+            }
+            _ => self.sent = SentSpans::new(self.recorded_len(view)),
+        }
+        if let Some(ref mut pass) = self.background {
+            pass.note_edit(delta);
+        }
+        if delta.is_none() || !self.is_highlighting() {
+            // an edit the parser isn't told of means parsing it all again
+            self.document_synced = false;
+        }
+        if !self.enabled {
+            return;
+        }
+        if typing {
+            self.typing_until = Some(Instant::now() + self.typing_debounce);
+            self.eager_lines = EAGER_LINES;
+        } else {
+            self.typing_until = None;
+        }
+        if self.too_large {
+            // the edit may have brought it back under the limit
+            if !self.exceeds_limit(view) {
+                self.rehighlight_all(view);
+            }
+            return;
+        }
+        if self.check_size(view) {
+            return;
+        }
+        self.sync_document(view, delta);
+        self.resume_from_frontier(view);
+        self.highlight_edited_lines(view);
+        self.restart_progress();
+        // the edit may have moved the visible lines
+        self.start_priority_pass(view);
+        view.schedule_idle();
+    }
+
+    /// Highlights the first few lines from the frontier straight away,
+    /// within the idle budget, and flushes them. While typing these count
+    /// against the eager lines, which they usually use up. The main pass
+    /// picks up from where this stops, so nothing is sent twice.
+    fn highlight_edited_lines<V: HighlightView>(&mut self, view: &mut V) {
+        let typing = self.typing_until.is_some();
+        let max_lines = if typing { self.eager_lines.min(SYNC_LINES) } else { SYNC_LINES };
+        let start = Instant::now();
+        let mut lines = 0;
+        while lines < max_lines && self.highlight_one_line(view) {
+            lines += 1;
+            if view.request_is_pending() || start.elapsed() >= self.idle_budget {
+                break;
+            }
+        }
+        if typing {
+            self.eager_lines -= lines;
+        }
+        self.flush_spans(view);
+    }
+
+    /// Notes the lines the client reports visible, and highlights them
+    /// ahead of the main pass.
+    fn set_visible_region<V: HighlightView>(&mut self, view: &mut V, first: usize, last: usize) {
+        self.visible_region = Some((first, last));
+        if self.is_highlighting() {
+            self.catch_up(view, first, last);
+            self.start_priority_pass(view);
+            view.schedule_idle();
+        }
+    }
+
+    /// Runs the main pass on down to `last` straight away, if it is within
+    /// `catch_up_lines` of `first`, so that a region scrolled to just past
+    /// it is highlighted without waiting for idle callbacks. Gives up when
+    /// the idle budget is spent, leaving the rest to the priority pass.
+    fn catch_up<V: HighlightView>(&mut self, view: &mut V, first: usize, last: usize) {
+        let behind = |view: &V| view.get_frontier().map_or(false, |frontier| frontier <= last);
+        let near = view
+            .get_frontier()
+            .map_or(false, |frontier| first.saturating_sub(frontier) <= self.catch_up_lines);
+        if !near || !behind(view) {
+            return;
+        }
+
+        let start = Instant::now();
+        while behind(view) && self.highlight_one_line(view) {
+            if view.request_is_pending() || start.elapsed() >= self.idle_budget {
+                break;
+            }
+        }
+        self.flush_spans(view);
+    }
+
+    /// Starts highlighting the visible region, unless the main pass has
+    /// already reached it.
+    fn start_priority_pass<V: HighlightView>(&mut self, view: &mut V) {
+        // the last pass's span buffer, unless it was dropped
+        let mut spans = self.priority.take().map_or_else(Vec::new, |pass| pass.spans);
+        spans.clear();
+        let (first, last_line) = match self.visible_region {
+            Some(region) => region,
+            None => return,
+        };
+        let frontier = match view.get_frontier() {
+            Some(frontier) if frontier < first => frontier,
+            _ => return,
+        };
+
+        // States past the main frontier may be stale, but are still a
+        // better guess than starting from scratch.
+        let (line_num, offset, state) = self.state_before(view, first);
+        let (line_num, offset, state, speculative) = if first - line_num <= PRIORITY_LOOKBEHIND {
+            (line_num, offset, state, line_num > frontier)
+        } else {
+            match view.offset_of_line(first) {
+                Ok(offset) => (first, offset, self.parser.borrow().initial_state(), true),
+                Err(_) => return,
+            }
+        };
+
+        self.priority = Some(PriorityPass {
+            line_num,
+            last_line,
+            offset,
+            state,
+            speculative,
+            spans_start: offset,
+            spans,
+            generation: self.generation,
+        });
+    }
+
+    /// Highlights the next line of the priority pass, if there is one.
+    /// Returns false once the pass has nothing more to do.
+    fn priority_one_line<V: HighlightView>(&mut self, view: &mut V) -> bool {
+        let mut pass = match self.priority.take() {
+            Some(pass) if pass.generation == self.generation => pass,
+            _ => return false,
+        };
+
+        let caught_up = match view.get_frontier() {
+            Some(frontier) => frontier >= pass.line_num,
+            None => true,
+        };
+        let line = match view.get_line(pass.line_num) {
+            Ok(line) if !caught_up && pass.line_num <= pass.last_line && !line.is_empty() => line,
+            _ => {
+                self.priority = Some(pass);
+                self.flush_priority_spans(view);
+                self.priority = None;
+                return false;
+            }
+        };
+
+        let base = pass.offset - pass.spans_start;
+        pass.state = self.lex_line(line, pass.state, base, &mut pass.spans);
+        pass.offset += line.len();
+        pass.line_num += 1;
+        let flush = pass.spans.len() >= self.max_pending_spans
+            || pass.offset - pass.spans_start >= self.max_pending_bytes;
+        self.priority = Some(pass);
+        if flush {
+            self.flush_priority_spans(view);
+        }
+        true
+    }
+
+    fn flush_priority_spans<V: HighlightView>(&mut self, view: &mut V) {
+        self.announce_scopes(view);
+        let parser = self.parser_name();
+        if let Some(ref mut pass) = self.priority {
+            if pass.generation != self.generation {
+                self.priority = None;
+                return;
+            }
+            if pass.spans_start != pass.offset {
+                let name = if pass.speculative {
+                    "flushing speculative spans"
+                } else {
+                    "flushing priority spans"
+                };
+                let region = Interval::new(pass.spans_start, pass.offset);
+                trace_spans(name, region, &pass.spans, self.verbose_trace);
+                let len = pass.offset - pass.spans_start;
+                let known_scopes = self.announced_scopes;
+                self.span_reporter.check(parser, pass.spans_start, len, &pass.spans, known_scopes);
+                self.counters.update_spans_calls +=
+                    self.batch.update_spans(view, pass.spans_start, len, &pass.spans);
+                // the main pass lexes these lines again, even if unchanged,
+                // and sends them again, as these spans may be wrong
+                self.lexed.forget(Interval::new(pass.spans_start, pass.offset));
+                self.sent.forget(Interval::new(pass.spans_start, pass.offset));
+                self.styled = true;
+                pass.spans.clear();
+            }
+            pass.spans_start = pass.offset;
+        }
+    }
+
+    /// Swaps in the parser for a new language. States produced by the old
+    /// parser are meaningless to the new one, so nothing derived from them
+    /// may survive the swap.
+    fn set_parser(&mut self, language_id: LanguageId, parser: SharedParser) {
+        self.initial_state = parser.borrow().initial_state();
+        self.current_language = language_id;
+        self.parser = parser;
+        self.document_synced = false;
+        self.partial_line = None;
+        self.state_scopes.clear();
+        self.stuck_scopes.clear();
+        self.guide_scopes.clear();
+        self.scope_validator = ScopeValidator::new();
+        for prefix in self.parser.borrow().scope_prefixes() {
+            self.scope_validator.add_prefix(*prefix);
+        }
+    }
+
+    fn parser_name(&self) -> &'static str {
+        self.parser.borrow().name()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.parser.borrow().capabilities()
+    }
+
+    /// Brings a parser that parses whole documents up to date with the
+    /// document: `delta` is the edit made since it was last told, if one
+    /// was. Without one, or if the parser hasn't been given the document,
+    /// it parses the document from scratch. Other parsers are left alone.
+    fn sync_document<V: HighlightView>(&mut self, view: &mut V, delta: Option<&RopeDelta>) {
+        if !self.capabilities().parses_documents || (self.document_synced && delta.is_none()) {
+            return;
+        }
+        let text = match view.get_document() {
+            Ok(text) => text,
+            Err(err) => {
+                // tried again when idle
+                trace_lazily("snapshot failed", &["experimental-lang"], || format!("{:?}", err));
+                self.document_synced = false;
+                return;
+            }
+        };
+        let kept = match delta {
+            Some(delta) if self.document_synced => {
+                self.parser.borrow_mut().apply_delta(delta, &text)
+            }
+            _ => {
+                self.parser.borrow_mut().parse_document(&text);
+                false
+            }
+        };
+        self.document_synced = true;
+        if !kept {
+            self.forget_states(view);
+        }
+    }
+
+    /// Drops everything derived from the parser's states, once they may
+    /// mean something else, and starts the main pass again from the top.
+    /// Unlike a parser swap, the scopes stay.
+    fn forget_states<V: HighlightView>(&mut self, view: &mut V) {
+        self.offset = 0;
+        self.spans_start = 0;
+        self.initial_state = self.parser.borrow().initial_state();
+        self.spans.clear();
+        self.pending_digests.clear();
+        self.partial_line = None;
+        self.batch.discard();
+        self.state_scopes.clear();
+        self.stuck_scopes.clear();
+        self.lexed = LexedLines::new(self.recorded_len(view));
+        view.clear_cache();
+        self.start_priority_pass(view);
+    }
+
+    /// Announces the parser's whole scope vocabulary in one `add_scopes`
+    /// call, so that lookups while highlighting almost never find new scopes.
+    fn register_parser_scopes<V: HighlightView>(&mut self, view: &mut V) {
+        let scopes = self.parser.borrow().scopes();
+        let scopes: Vec<Scope> = scopes.into_iter().map(|scope| self.sent_scope(scope)).collect();
+        for scope in &scopes {
+            self.check_scope_name(scope);
+        }
+        self.scope_tracker.lock().unwrap().register_all(&scopes);
+        self.announce_scopes(view);
+    }
+
+    /// `scope`, as the parser names it, as it is sent to core.
+    fn sent_scope(&self, scope: Scope) -> Scope {
+        if self.raw_scope_names {
+            scope
+        } else {
+            scope_map::translate(&scope)
+        }
+    }
+
+    /// Reports a scope that breaks naming conventions: fatally in debug
+    /// builds, so that parser typos are caught by tests. The parsers' own
+    /// names follow conventions of their own, so aren't checked.
+    fn check_scope_name(&self, scope: &Scope) {
+        if !scope_validator::ENABLED || self.raw_scope_names {
+            return;
+        }
+        if let Err(err) = self.scope_validator.validate(scope) {
+            let message = format!("{} parser: {}", self.parser_name(), err);
+            if cfg!(debug_assertions) {
+                panic!("{}", message);
+            }
+            trace_lazily("invalid scope name", &["experimental-lang"], || message);
+        }
+    }
+
+    /// Sends this view every scope issued since it was last brought up to
+    /// date, whichever view caused it to be issued.
+    fn announce_scopes<V: HighlightView>(&mut self, view: &mut V) {
+        let tracker = self.scope_tracker.lock().unwrap();
+        let missing = tracker.scopes_since(self.announced_scopes);
+        if !missing.is_empty() {
+            view.add_scopes(missing);
+            self.counters.add_scopes_calls += 1;
+            self.announced_scopes = tracker.len();
+        }
+    }
+
+    /// Highlights lines until the idle budget is spent, rescheduling
+    /// itself until done.
+    fn idle<V: HighlightView>(&mut self, view: &mut V) {
+        if !self.is_highlighting() {
+            return;
+        }
+        if self.background.is_some() {
+            self.background_idle(view);
+            return;
+        }
+        self.sync_document(view, None);
+        let start = Instant::now();
+        let mut lines = 0;
+        let mut done = false;
+        self.batch.open(self.max_pending_spans, self.max_pending_bytes);
+        let mut deferred_until = None;
+        while lines < self.slice.lines() {
+            if let Some(until) = self.typing_until {
+                if Instant::now() >= until {
+                    self.typing_until = None;
+                } else if self.eager_lines == 0 {
+                    deferred_until = Some(until);
+                    break;
+                }
+            }
+            let typing = self.typing_until.is_some();
+
+            // the visible region comes first, unless the user is typing
+            if (typing || !self.priority_one_line(view)) && !self.highlight_one_line(view) {
+                // a failed fetch leaves the frontier open, to retry later
+                done = view.get_frontier().is_none();
+                break;
+            }
+            lines += 1;
+            if typing {
+                self.eager_lines -= 1;
+            }
+
+            if view.request_is_pending() {
+                trace("yielding for request", &["experimental-lang"]);
+                break;
+            }
+            if start.elapsed() >= self.idle_budget {
+                break;
+            }
+        }
+        let elapsed = start.elapsed();
+        self.slice.record(lines, elapsed);
+        self.counters.parse_time += elapsed;
+
+        self.flush_priority_spans(view);
+        self.flush_spans(view);
+        self.counters.update_spans_calls += self.batch.finish(view);
+        trace_lazily("idle batch", &["experimental-lang"], || {
+            format!("{} lines in {:?}, generation {}", lines, elapsed, self.generation)
+        });
+        if done {
+            self.finish_progress(view);
+            self.finish_highlighting();
+        } else if let Some(until) = deferred_until {
+            view.schedule_timer(until);
+        } else {
+            self.report_progress(view);
+            view.schedule_idle();
+        }
+    }
+
+    /// Hands the document to a worker thread to lex, with a parser of its
+    /// own, while the main pass waits. Only a document whose lines are
+    /// recorded qualifies, as that is how the main pass steps over the
+    /// lines the worker lexed; indent guides are only added here.
+    fn start_background<V: HighlightView>(&mut self, view: &mut V, registry: &ParserRegistry) {
+        if !self.is_highlighting()
+            || self.indent_guides
+            || self.parser_language.is_none()
+            || self.recorded_len(view) == 0
+        {
+            return;
+        }
+        let language = self.detected_language.as_ref().unwrap_or(&self.current_language);
+        let mut parser = match registry.create(language.as_ref()) {
+            Some(parser) => parser,
+            None => return,
+        };
+        let text = match view.get_document() {
+            Ok(text) => text,
+            Err(err) => {
+                trace_lazily("snapshot failed", &["experimental-lang"], || format!("{:?}", err));
+                return;
+            }
+        };
+        if parser.capabilities().parses_documents {
+            parser.parse_document(&text);
+        }
+        let lines = view.get_num_lines();
+        match BackgroundPass::start(text, parser, self.background_batch_lines, self.tab_size) {
+            Ok(pass) => {
+                trace_lazily("background pass started", &["experimental-lang"], || {
+                    format!("{} lines of {}", lines, language.as_ref())
+                });
+                self.background = Some(pass);
+            }
+            Err(err) => {
+                trace_lazily("background pass failed to start", &["experimental-lang"], || {
+                    err.to_string()
+                })
+            }
+        }
+    }
+
+    /// Takes in the lines the worker has lexed, a slice's worth at most,
+    /// and highlights the visible region ahead in the rest of the budget.
+    /// Once the worker is done, the main pass starts with its parser.
+    fn background_idle<V: HighlightView>(&mut self, view: &mut V) {
+        let start = Instant::now();
+        let mut lines = 0;
+        let mut received = false;
+        let (mut finished, mut failed) = (None, false);
+        self.batch.open(self.max_pending_spans, self.max_pending_bytes);
+        while lines < self.slice.lines() && start.elapsed() < self.idle_budget {
+            let message = match self.background {
+                Some(ref pass) => pass.try_recv(),
+                None => break,
+            };
+            match message {
+                Ok(Message::Lines(batch)) => {
+                    lines += batch.lines.len();
+                    received = true;
+                    self.take_in_lines(view, batch);
+                }
+                Ok(Message::Done(parser)) => {
+                    finished = Some(parser);
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        while lines < self.slice.lines()
+            && start.elapsed() < self.idle_budget
+            && self.priority_one_line(view)
+        {
+            lines += 1;
+        }
+
+        self.flush_priority_spans(view);
+        self.flush_spans(view);
+        self.counters.update_spans_calls += self.batch.finish(view);
+        if let Some(parser) = finished {
+            self.finish_background(view, parser);
+        } else if failed {
+            // the worker's parser panicked; this one may fare no better, but
+            // it gets to try, and what was taken in is in the other's states
+            trace("background pass failed", &["experimental-lang"]);
+            self.background = None;
+            self.rehighlight_all(view);
+        } else if received {
+            view.schedule_idle();
+        } else {
+            view.schedule_timer(
+                Instant::now() + Duration::from_millis(BACKGROUND_POLL_INTERVAL_MS),
+            );
+        }
+    }
+
+    /// Sends the spans of the lines in `batch` no edit has touched since
+    /// the snapshot, where they are now, and records them as the main pass
+    /// would have. The main pass lexes the rest.
+    fn take_in_lines<V: HighlightView>(&mut self, view: &mut V, mut batch: Batch) {
+        let mut pass = match self.background.take() {
+            Some(pass) => pass,
+            None => return,
+        };
+        for scope in mem::replace(&mut batch.scopes, Vec::new()) {
+            let scope = self.sent_scope(scope);
+            let result = self.scope_tracker.lock().unwrap().lookup(&scope);
+            if let LookupResult::New(_) = result {
+                self.check_scope_name(&scope);
+            }
+            pass.scope_ids.push(result.id());
+        }
+        let capabilities = self.capabilities();
+        let outlined = capabilities.supports_folding || capabilities.reports_symbols;
+        self.counters.background_lines += batch.lines.len();
+        self.counters.stuck_bytes += batch.stuck_bytes;
+
+        for line in batch.lines {
+            let iv = match pass.current(line.iv) {
+                Some(iv) => iv,
+                None => {
+                    self.counters.background_lines_discarded += 1;
+                    continue;
+                }
+            };
+            if iv.start() != self.offset {
+                self.flush_spans(view);
+                self.offset = iv.start();
+                self.spans_start = iv.start();
+            }
+            let base = self.offset - self.spans_start;
+            let first = self.spans.len();
+            self.spans.extend(batch.spans[line.spans].iter().map(|span| ScopeSpan {
+                start: base + span.start,
+                end: base + span.end,
+                scope_id: pass.scope_ids[span.scope_id as usize],
+            }));
+            self.note_digest(first, iv.size());
+            self.offset = iv.end();
+            self.lexed.record_hashed(iv, line.hash, line.entry, line.exit);
+            if outlined {
+                self.outline.record_indented(iv, line.folding, line.symbol, line.indent);
+            }
+            self.flush_if_full(view);
+        }
+        self.background = Some(pass);
+    }
+
+    /// Swaps in the worker's parser, which the states of the lines taken
+    /// in belong to, and starts the main pass from the top. It steps over
+    /// the lines taken in, lexing only those edited since the snapshot and
+    /// the lines below them that the edits affect.
+    fn finish_background<V: HighlightView>(&mut self, view: &mut V, parser: Box<dyn Parser>) {
+        trace_lazily("background pass done", &["experimental-lang"], || {
+            format!(
+                "{} lines, {} discarded",
+                self.counters.background_lines, self.counters.background_lines_discarded
+            )
+        });
+        self.background = None;
+        self.generation += 1;
+        self.priority = None;
+        let language = self.current_language.clone();
+        self.set_parser(language, share(parser));
+        // the worker's parse is of the snapshot, from before any edits
+        self.sync_document(view, None);
+        self.register_parser_scopes(view);
+        self.offset = 0;
+        self.spans_start = 0;
+        view.clear_cache();
+        self.restart_progress();
+        self.start_priority_pass(view);
+        view.schedule_idle();
+    }
+
+    fn restart_progress(&mut self) {
+        self.progress.last_report = Instant::now();
+        self.progress.batches = 0;
+    }
+
+    /// Shows how much of the document has been highlighted, every few
+    /// batches and at most once per `progress_interval`.
+    fn report_progress<V: HighlightView>(&mut self, view: &mut V) {
+        self.progress.batches += 1;
+        if self.progress.batches % PROGRESS_BATCHES != 0
+            || self.progress.last_report.elapsed() < self.progress_interval
+        {
+            return;
+        }
+        self.progress.last_report = Instant::now();
+
+        let percent = self.offset * 100 / view.get_buf_size().max(1);
+        let status = format!("highlighting {}%", percent);
+        trace_lazily("highlighting progress", &["experimental-lang"], || status.clone());
+        if self.progress.shown {
+            view.update_status_item(PROGRESS_STATUS_KEY, &status);
+        } else {
+            view.add_status_item(PROGRESS_STATUS_KEY, &status, "left");
+            self.progress.shown = true;
+        }
+    }
+
+    fn finish_progress<V: HighlightView>(&mut self, view: &mut V) {
+        trace_lazily("highlighting done", &["experimental-lang"], || {
+            format!("generation {}", self.generation)
+        });
+        if self.progress.shown {
+            view.remove_status_item(PROGRESS_STATUS_KEY);
+            self.progress.shown = false;
+        }
+    }
+
+    /// The nearest cached state at or before `line_num`, with its line
+    /// and offset. The cache never holds the state for the first line, and
+    /// reports the default state instead; the parser knows better.
+    fn state_before<V: HighlightView>(&self, view: &V, line_num: usize) -> (usize, usize, State) {
+        match view.get_prev(line_num) {
+            (0, _, _) => (0, 0, self.parser.borrow().initial_state()),
+            prev => prev,
+        }
+    }
+
+    fn highlight_one_line<V: HighlightView>(&mut self, view: &mut V) -> bool {
+        if self.background.is_some() {
+            // the worker's lines come first
+            return false;
+        }
+        if let Some(line_num) = view.get_frontier() {
+            let (line_num, offset, state) = self.state_before(view, line_num);
+            self.initial_state = state;
+            // carrying on with a line, if the main pass is still where it
+            // left off in it
+            let resumed = self.partial_line.take().filter(|partial| {
+                partial.line_num == line_num && offset + partial.lexed == self.offset
+            });
+
+            if offset != self.offset && resumed.is_none() {
+                self.flush_spans(view);
+                self.offset = offset;
+                self.spans_start = offset;
+            }
+
+            let new_frontier = match view.get_line(line_num) {
+                // Only the last line of a document should be empty, but if
+                // some other line is, its state carries on to the next.
+                Ok("") => match view.get_line(line_num + 1) {
+                    Ok(_) => Some((self.initial_state, line_num + 1)),
+                    Err(_) => None,
+                },
+                Ok(line) => {
+                    self.fetch_failures = 0;
+                    let (from, from_state) = resumed.map_or((0, state), |p| (p.lexed, p.state));
+                    let unchanged = match from {
+                        0 => self.lexed.unchanged(offset, line, state),
+                        _ => None,
+                    };
+                    let new_state = match unchanged {
+                        // Core still has its spans, and the outline what the
+                        // parser said about it. Leaving `self.offset` behind
+                        // flushes the spans above before the next line.
+                        Some(exit) => {
+                            self.counters.lines_skipped += 1;
+                            exit
+                        }
+                        None => {
+                            if from == 0 {
+                                self.record_outline(line);
+                            }
+                            let first = self.spans.len();
+                            let (end, exit) = self.compute_syntax(line, from, from_state);
+                            self.note_digest(first, end - from);
+                            self.offset += end - from;
+                            if end < line.len() {
+                                // the rest waits for the next call
+                                self.partial_line =
+                                    Some(PartialLine { line_num, lexed: end, state: exit });
+                                self.flush_if_full(view);
+                                return true;
+                            }
+                            self.lexed.record_line(offset, line, state, exit);
+                            exit
+                        }
+                    };
+
+                    if line.as_bytes().last() == Some(&b'\n') {
+                        Some((new_state, line_num + 1))
+                    } else {
+                        None
+                    }
+                }
+                Err(ref err) if is_retryable(err) && self.fetch_failures < MAX_FETCH_RETRIES => {
+                    self.fetch_failures += 1;
+                    trace_lazily("fetch failed", &["experimental-lang"], || {
+                        format!("line {}: {:?}", line_num, err)
+                    });
+                    return false;
+                }
+                Err(_) => None,
+            };
+
+            self.flush_if_full(view);
+
+            let mut converged = false;
+            if let Some((ref new_state, new_line_num)) = new_frontier {
+                if let Some(old_state) = view.get(new_line_num) {
+                    converged = old_state == new_state;
+                }
+            }
+
+            if !converged {
+                if let Some((new_state, new_line_num)) = new_frontier {
+                    view.set(new_line_num, new_state);
+                    view.update_frontier(new_line_num);
+                    return true;
+                }
+            }
+
+            view.close_frontier();
+        }
+
+        false
+    }
+
+    /// Called once the frontier is exhausted; releases memory we no longer
+    /// need and reports how large the state context has grown.
+    fn finish_highlighting(&mut self) {
+        let mut parser = self.parser.borrow_mut();
+        parser.shrink_to_fit();
+        let stats = parser.stack_stats();
+        trace_lazily("statestack stats", &["experimental-lang"], || {
+            format!(
+                "{} parser states: {}, elements: {}, max depth: {}, bytes: {}, pending spans: {}",
+                parser.name(),
+                stats.states,
+                stats.elements,
+                stats.max_depth,
+                stats.estimated_bytes,
+                self.spans.len()
+            )
+        });
+    }
+
+    /// Lexes the next piece of `line` for the main pass, from `from` bytes
+    /// in, where the parser is in `state`. Returns where the piece ends,
+    /// which is the end of the line unless it is long, and the state there.
+    fn compute_syntax(&mut self, line: &str, from: usize, state: State) -> (usize, State) {
+        let mut spans = mem::replace(&mut self.spans, Vec::new());
+        let base = self.offset - self.spans_start;
+        let max_bytes = self.max_line_piece;
+        let (end, state) = self.lex_line_from(line, from, state, base, max_bytes, &mut spans);
+        self.spans = spans;
+        if end == line.len() {
+            self.counters.lines_lexed += 1;
+        }
+        (end, state)
+    }
+
+    /// Notes a digest of the pending spans from `first` on, which cover the
+    /// `len` bytes just lexed from `offset`.
+    fn note_digest(&mut self, first: usize, len: usize) {
+        let base = self.offset - self.spans_start;
+        match self.digest_spans.repack(&self.spans[first..], base) {
+            Ok(()) => {
+                let digest = sent::digest(&self.digest_spans, len);
+                self.pending_digests.push((Interval::new(self.offset, self.offset + len), digest));
+            }
+            // with a line missing, the digests never cover a region
+            Err(_) => self.pending_digests.clear(),
+        }
+    }
+
+    /// Lexes `line` starting in `state`, appending its spans to `spans`
+    /// relative to `base`, and returns the state at the end of the line.
+    fn lex_line(
+        &mut self,
+        line: &str,
+        state: State,
+        base: usize,
+        spans: &mut Vec<ScopeSpan>,
+    ) -> State {
+        self.lex_line_from(line, 0, state, base, std::usize::MAX, spans).1
+    }
+
+    /// Lexes `line` from `from` bytes in, where the parser is in `state`,
+    /// until at least `max_bytes` are lexed or the line ends, appending the
+    /// spans to `spans` with `from` at `base`.
+    /// Returns where it stopped, always between tokens, and the state
+    /// there. Indent guides are only added to the piece the line starts
+    /// with.
+    fn lex_line_from(
+        &mut self,
+        line: &str,
+        from: usize,
+        state: State,
+        base: usize,
+        max_bytes: usize,
+        spans: &mut Vec<ScopeSpan>,
+    ) -> (usize, State) {
+        let _guard = trace_block("ExperimentalLang::compute_syntax", &["experimental-lang"]);
+
+        let first = spans.len();
+        let scope_tracker = Arc::clone(&self.scope_tracker);
+        let mut tracker = TrackerLock::new(&scope_tracker);
+        let mut tokens = Tokens::new(from, state, max_bytes);
+        loop {
+            // the parser is only borrowed for the step, as finding a scope
+            // borrows it too
+            let token = tokens.next(&mut **self.parser.borrow_mut(), line);
+            let Token { start, end, state, stuck } = match token {
+                Some(token) => token,
+                None => break,
+            };
+            let scope_id = if stuck {
+                self.identifier_for_stuck_state(state, &mut tracker)
+            } else {
+                self.identifier_for_state(state, &mut tracker)
+            };
+            spans.push(ScopeSpan { start: base + start - from, end: base + end - from, scope_id });
+            if stuck {
+                self.report_stuck(&line[start..end]);
+            }
+        }
+
+        if self.indent_guides && from == 0 {
+            self.add_indent_guides(line, state, base, spans, first, &mut tracker);
+        }
+        tokens.end()
+    }
+
+    /// The scope id for text parsed in `state`. Scopes seen for the first
+    /// time are sent to core with the next flush.
+    fn identifier_for_state(&mut self, state: State, tracker: &mut TrackerLock) -> ScopeId {
+        if let Some(id) = self.state_scopes.get(state) {
+            return id;
+        }
+        let scope = self.sent_scope(self.parser.borrow().get_scope_for_state(state));
+        let result = tracker.get().lookup(&scope);
+        if let LookupResult::New(_) = result {
+            self.check_scope_name(&scope);
+        }
+        self.state_scopes.insert(state, result.id());
+        result.id()
+    }
+
+    /// The scope id for a character skipped in `state`: the state's own
+    /// scope, marked invalid.
+    fn identifier_for_stuck_state(&mut self, state: State, tracker: &mut TrackerLock) -> ScopeId {
+        if let Some(id) = self.stuck_scopes.get(state) {
+            return id;
+        }
+        let mut scope = self.sent_scope(self.parser.borrow().get_scope_for_state(state));
+        scope.push(STUCK_SCOPE.to_owned());
+        let id = tracker.get().lookup(&scope).id();
+        self.stuck_scopes.insert(state, id);
+        id
+    }
+
+    /// Notes that the parser made no progress on `text`, reporting it at
+    /// most once per `STUCK_REPORT_INTERVAL_MS` so that a broken grammar
+    /// doesn't flood the trace.
+    fn report_stuck(&mut self, text: &str) {
+        self.counters.stuck_bytes += text.len();
+        self.stuck_unreported += text.len();
+        if self
+            .stuck_report
+            .map_or(false, |last| last.elapsed() < Duration::from_millis(STUCK_REPORT_INTERVAL_MS))
+        {
+            return;
+        }
+        self.stuck_report = Some(Instant::now());
+        trace_lazily("parser made no progress", &["experimental-lang"], || {
+            format!(
+                "{} parser stuck on {:?}, {} bytes skipped",
+                self.parser_name(),
+                text,
+                self.stuck_unreported
+            )
+        });
+        self.stuck_unreported = 0;
+    }
+
+    /// The scope table, and the spans of lines `first` to `last` as this
+    /// plugin would send them, as JSON. The spans are lexed again from the
+    /// nearest cached state rather than kept, so lines past the frontier
+    /// may not match what core has. Each line's spans are packed relative
+    /// to its offset, as gaps, lengths and runs of scope ids. At most
+    /// `MAX_DUMP_LINES` are lexed, as this happens in one call; `truncated`
+    /// is set if the dump stops short of `last`, or of the end.
+    fn debug_dump<V: HighlightView>(
+        &mut self,
+        view: &mut V,
+        first: usize,
+        last: Option<usize>,
+    ) -> Value {
+        let mut lines = Vec::new();
+        let (mut line_num, mut offset, mut state) = self.state_before(view, first);
+        let stop = line_num + MAX_DUMP_LINES;
+        let mut truncated = false;
+        while last.map_or(true, |last| line_num <= last) {
+            if line_num == stop {
+                truncated = view.get_line(line_num).ok().map_or(false, |line| !line.is_empty());
+                break;
+            }
+            let line = match view.get_line(line_num) {
+                Ok(line) if !line.is_empty() => line,
+                _ => break,
+            };
+            let mut spans = Vec::new();
+            state = self.lex_line(line, state, offset, &mut spans);
+            if line_num >= first {
+                let spans = match PackedSpans::pack(&spans, offset) {
+                    Ok(packed) => json!({
+                        "gaps": packed.gaps(),
+                        "lengths": packed.lengths(),
+                        "scopes": packed.runs(),
+                    }),
+                    Err(err) => json!(err.to_string()),
+                };
+                lines.push(json!({ "line": line_num, "offset": offset, "spans": spans }));
+            }
+            offset += line.len();
+            line_num += 1;
+        }
+
+        let tracker = self.scope_tracker.lock().unwrap();
+        json!({
+            "language": self.current_language,
+            "parser": self.parser_name(),
+            "tab_size": self.tab_size,
+            "translate_tabs_to_spaces": self.translate_tabs_to_spaces,
+            "frontier": view.get_frontier(),
+            "scopes": tracker.scopes_since(0),
+            "lines": lines,
+            "truncated": truncated,
+        })
+    }
+
+    /// How far highlighting has got and what it has cost so far, as JSON.
+    fn stats<V: HighlightView>(&self, view: &V) -> Value {
+        let num_lines = view.get_num_lines();
+        let stack = self.parser.borrow().stack_stats();
+        let tracker = self.scope_tracker.lock().unwrap();
+        let scopes: Vec<Value> = (self.counters.scope_uses.iter().enumerate())
+            .filter(|&(_, &uses)| uses > 0)
+            .map(|(id, uses)| json!({ "scope": tracker.scope_for_id(id as ScopeId), "uses": uses }))
+            .collect();
+        let capabilities = self.capabilities();
+        let span_size = mem::size_of::<ScopeSpan>();
+        let pairs: Vec<Value> = (self.parser.borrow().autoclose_pairs().iter())
+            .map(|&(opener, closer)| json!([opener.to_string(), closer.to_string()]))
+            .collect();
+        json!({
+            "language": self.current_language,
+            "parser": self.parser_name(),
+            "capabilities": {
+                "supports_indentation": capabilities.supports_indentation,
+                "supports_folding": capabilities.supports_folding,
+                "reports_symbols": capabilities.reports_symbols,
+                "emits_sub_spans": capabilities.emits_sub_spans,
+                "wants_whole_line": capabilities.wants_whole_line,
+            },
+            "autoclose_pairs": pairs,
+            "lines_highlighted": view.get_frontier().unwrap_or(num_lines),
+            "total_lines": num_lines,
+            "lines_lexed": self.counters.lines_lexed,
+            "lines_skipped": self.counters.lines_skipped,
+            "background_lines": self.counters.background_lines,
+            "background_lines_discarded": self.counters.background_lines_discarded,
+            "flushes_unchanged": self.counters.flushes_unchanged,
+            "lines_per_slice": self.slice.lines(),
+            "parse_time_us": micros(self.counters.parse_time),
+            "update_spans_calls": self.counters.update_spans_calls,
+            "add_scopes_calls": self.counters.add_scopes_calls,
+            "stuck_bytes": self.counters.stuck_bytes,
+            "scopes": scopes,
+            "scope_table_size": tracker.len(),
+            "statestack": {
+                "states": stack.states,
+                "elements": stack.elements,
+                "max_depth": stack.max_depth,
+                "estimated_bytes": stack.estimated_bytes,
+            },
+            // rough estimates of the heap memory this view holds, in bytes
+            "memory": {
+                "outline_bytes": self.outline.estimated_bytes(),
+                "lexed_lines_bytes": self.lexed.estimated_bytes(),
+                "sent_spans_bytes": self.sent.estimated_bytes(),
+                "state_scopes_bytes": self.state_scopes.estimated_bytes()
+                    + self.stuck_scopes.estimated_bytes(),
+                "pending_spans_bytes":
+                    (self.spans.capacity() + self.line_spans.capacity()) * span_size
+                        + self.digest_spans.estimated_bytes(),
+                "lines_recorded": self.recorded_len(view) == view.get_buf_size(),
+            },
+        })
+    }
+
+    /// Whether core already has the pending spans for `region`: the lines
+    /// lexed cover all of it, and each was last sent the same way.
+    fn core_has_pending_spans(&self, region: Interval) -> bool {
+        let digests = &self.pending_digests;
+        match (digests.first(), digests.last()) {
+            (Some(&(first, _)), Some(&(last, _)))
+                if first.start() == region.start() && last.end() == region.end() =>
+            {
+                digests.iter().all(|&(iv, digest)| self.sent.get(iv) == Some(digest))
+            }
+            _ => false,
+        }
+    }
+
+    /// Flushes the pending spans if there are enough of them. The main
+    /// pass is between lines, or where it left a long line off, so the
+    /// region flushed never ends in the middle of a token.
+    fn flush_if_full<V: HighlightView>(&mut self, view: &mut V) {
+        if self.spans.len() >= self.max_pending_spans
+            || self.offset - self.spans_start >= self.max_pending_bytes
+        {
+            self.flush_spans(view);
+        }
+    }
+
+    fn flush_spans<V: HighlightView>(&mut self, view: &mut V) {
+        // scopes must be known to core before any span refers to them
+        self.announce_scopes(view);
+
+        debug_assert_eq!(
+            self.spans.last().map_or(0, |span| span.end),
+            self.offset - self.spans_start,
+            "pending spans don't cover the lexed text"
+        );
+        if self.spans_start != self.offset {
+            let region = Interval::new(self.spans_start, self.offset);
+            trace_spans("flushing spans", region, &self.spans, self.verbose_trace);
+            if self.core_has_pending_spans(region) {
+                self.counters.flushes_unchanged += 1;
+            } else {
+                let parser = self.parser_name();
+                let known_scopes = self.announced_scopes;
+                let (start, len) = (region.start(), region.size());
+                self.span_reporter.check(parser, start, len, &self.spans, known_scopes);
+                self.counters.update_spans_calls +=
+                    self.batch.update_spans(view, start, len, &self.spans);
+                let scope_uses = &mut self.counters.scope_uses;
+                for span in &self.spans {
+                    let id = span.scope_id as usize;
+                    if id >= scope_uses.len() {
+                        scope_uses.resize(id + 1, 0);
+                    }
+                    scope_uses[id] += 1;
+                }
+                self.styled = true;
+                self.sent.forget(region);
+                for &(iv, digest) in &self.pending_digests {
+                    self.sent.record(iv, digest);
+                }
+            }
+            self.spans.clear();
+        }
+        self.pending_digests.clear();
+
+        self.spans_start = self.offset;
+    }
+}
+
+/// Sends `edits`, if there are any, to core as one edit in a new undo
+/// group. With `after_cursor`, text inserted at the cursor goes after it.
+fn send_as_undo_group<V: HighlightView>(
+    view: &mut V,
+    edits: Vec<(Interval, String)>,
+    after_cursor: bool,
+) {
+    if edits.is_empty() {
+        return;
+    }
+    let mut builder = DeltaBuilder::new(view.get_buf_size());
+    for (iv, text) in edits {
+        builder.replace(iv, text.into());
+    }
+    view.edit(builder.build(), EDIT_PRIORITY, after_cursor, true, EDIT_AUTHOR);
+}
+
+/// `duration` in microseconds.
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::rust::RustParser;
+    use crate::language::testlang::{TestParser, HUGE_SCOPE_LEN};
+    use crate::scope_tracker::OVERFLOW_SCOPE;
+    use crate::view::mock::{
+        apply_edit, highlight_from_scratch, highlight_from_scratch_with, resolved_scopes, run_idle,
+        MockView, SpanUpdate,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use xi_rope::{Interval, Rope};
+
+    const RUST_TEXT: &str = "fn main() {\n    \"string\" // comment\n}\n";
+
+    /// Asserts that every span sent to `view` refers to a scope that had
+    /// already been announced to it, and that the view's scope table agrees
+    /// with the tracker.
+    fn assert_scopes_consistent(view: &MockView, tracker: &Mutex<ScopeTracker>) {
+        let tracker = tracker.lock().unwrap();
+        let issued: Vec<Scope> = tracker.iter().map(|(scope, _)| scope.clone()).collect();
+        assert!(issued.starts_with(&view.scopes), "view scopes are not a prefix of the tracker");
+        for update in &view.updates {
+            for span in &update.spans {
+                assert!(
+                    (span.scope_id as usize) < update.known_scopes,
+                    "scope {} used before it was announced",
+                    span.scope_id
+                );
+            }
+        }
+    }
+
+    fn span_scopes(view: &MockView) -> Vec<Scope> {
+        view.updates
+            .iter()
+            .flat_map(|update| update.spans.iter())
+            .map(|span| view.scopes[span.scope_id as usize].clone())
+            .collect()
+    }
+
+    #[test]
+    fn edits_match_a_full_rehighlight() {
+        let text = "fn main() {\n    let x = 1;\n    // comment\n    \"string\"\n}\n";
+        let edits: &[(usize, usize, &str)] = &[
+            (0, 0, "/*"),
+            (14, 0, "*/"),
+            (5, 3, ""),
+            (20, 0, "\n\n"),
+            (0, 2, ""),
+            (30, 0, "\"open string\n"),
+            (10, 15, ""),
+            (40, 0, "'c' 42 + 7 u8\n"),
+            (1000, 0, "\n// trailing"),
+            (3, 1, "x"),
+            (12, 40, "/* nested /* comment */\n still open\n"),
+            (0, 1000, "fn f() {}\n"),
+        ];
+
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", text));
+
+        for &edit in edits {
+            apply_edit(&mut view_state, &mut view, edit);
+            let text = view.text().to_string();
+            assert_eq!(
+                resolved_scopes(&view),
+                highlight_from_scratch("Rust", &text),
+                "after {:?}, text {:?}",
+                edit,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn edit_relexes_only_affected_lines() {
+        let text: String = (0..20).map(|i| format!("let x{} = {};\n", i, i)).collect();
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        let line_start = text.find("let x10").unwrap();
+        let updates_before = view.updates.len();
+        let cache_clears = view.cache_clears;
+        apply_edit(&mut view_state, &mut view, (line_start + 4, 0, "y"));
+
+        assert_eq!(view.cache_clears, cache_clears);
+        let relexed: Vec<_> = view.updates[updates_before..].iter().collect();
+        assert_eq!(relexed.len(), 1);
+        assert_eq!(relexed[0].start, line_start);
+        assert_eq!(relexed[0].len, "let yx10 = 10;\n".len());
+    }
+
+    #[test]
+    fn config_changes_keep_cached_states() {
+        let text = "/* a\n comment */ fn main() {}\n".repeat(20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        let mut registry = ParserRegistry::default();
+        let mut parsers = HashMap::new();
+        view_state.do_highlighting(&mut view, &registry, &mut parsers);
+        run_idle(&mut view_state, &mut view);
+        let states: Vec<_> = (1..40).map(|line_num| view.get(line_num).cloned()).collect();
+        assert!(states.iter().all(Option::is_some));
+
+        let changes = json!({
+            "tab_size": 2,
+            "experimental_lang": { "language_overrides": { "Svelte": "Rust" } },
+        });
+        let changes = changes.as_object().unwrap();
+        assert!(registry.apply_config(changes));
+        view.idle_scheduled = false;
+        view_state.config_changed(&mut view, changes, &registry, &mut parsers);
+
+        assert_eq!(view.cache_clears, 1);
+        assert_eq!(view.get_frontier(), None);
+        assert!(!view.idle_scheduled);
+        let kept: Vec<_> = (1..40).map(|line_num| view.get(line_num).cloned()).collect();
+        assert_eq!(kept, states);
+    }
+
+    #[test]
+    fn invalidation_keeps_the_states_above() {
+        let text = "/* a\n comment */ fn main() {}\n".repeat(20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let states: Vec<_> = (1..=10).map(|line_num| view.get(line_num).cloned()).collect();
+
+        view_state.invalidate_from(&mut view, 10);
+        assert_eq!(view.cache_clears, 1);
+        assert_eq!(view.get_frontier(), Some(10));
+        assert_eq!(view.get(11), None);
+        let kept: Vec<_> = (1..=10).map(|line_num| view.get(line_num).cloned()).collect();
+        assert_eq!(kept, states);
+
+        let updates_before = view.updates.len();
+        run_idle(&mut view_state, &mut view);
+        let line_start = view.offset_of_line(10).unwrap();
+        assert_covered_once(&view, updates_before, line_start, text.len() - line_start);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn typed_characters_are_highlighted_at_once() {
+        let text = "let s = \"hello\";\n".repeat(20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        let line_start = view.offset_of_line(5).unwrap();
+        let offset = text[line_start..].find("hello").unwrap() + line_start;
+        let delta =
+            RopeDelta::simple_edit(Interval::new(offset, offset), Rope::from("x"), text.len());
+        view.apply_edit(&delta);
+        let updates_before = view.updates.len();
+        view_state.update(&mut view, Some(&delta), true);
+
+        assert_eq!(view.updates.len(), updates_before + 1);
+        let update = &view.updates[updates_before];
+        assert_eq!((update.start, update.len), (line_start, "let s = \"xhello\";\n".len()));
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+
+        // and idle has nothing left to send
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.updates.len(), updates_before + 1);
+    }
+
+    #[test]
+    fn save_keeps_highlighting() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        let cache_clears = view.cache_clears;
+        let updates = view.updates.len();
+        view.idle_scheduled = false;
+        view_state.did_save(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        assert_eq!(view.cache_clears, cache_clears);
+        assert_eq!(view.updates.len(), updates);
+        assert!(!view.idle_scheduled);
+
+        // saving as a file of another language rehighlights it
+        view.set_language("Plain Text");
+        view_state.did_save(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.cache_clears, cache_clears + 1);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Plain Text", RUST_TEXT));
+    }
+
+    /// A parser that takes a fixed time per line.
+    struct SlowParser(PlaintextParser, Duration);
+
+    impl Parser for SlowParser {
+        fn name(&self) -> &'static str {
+            "Slow"
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            self.0.get_scope_for_state(state)
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            std::thread::sleep(self.1);
+            self.0.parse(text, state)
+        }
+
+        fn stack_stats(&self) -> statestack::Stats {
+            self.0.stack_stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.0.shrink_to_fit()
+        }
+    }
+
+    #[test]
+    fn dropped_spans_are_resent_across_idle_slices() {
+        let text = "/* a\n comment */ fn main() {}\n".repeat(50);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.max_pending_bytes = 200;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        view_state.idle_budget = Duration::from_secs(0);
+        for _ in 0..30 {
+            view_state.idle(&mut view);
+        }
+        assert!(view.get_frontier().is_some());
+
+        view.drop_styles();
+        let updates_before = view.updates.len();
+        let (cache_clears, generation) = (view.cache_clears, view_state.generation);
+        view_state.resend_spans(&mut view);
+        // nothing is lexed until the next idle slice, which starts over
+        assert_eq!(view.updates.len(), updates_before);
+        assert_eq!(view.cache_clears, cache_clears);
+        assert_eq!(view_state.generation, generation);
+        assert_eq!(view.get_frontier(), Some(0));
+        assert!(view.idle_scheduled);
+
+        run_idle(&mut view_state, &mut view);
+        assert_covered_once(&view, updates_before, 0, text.len());
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn slow_lines_yield_after_budget() {
+        let text = "line\n".repeat(50);
+        let mut view = MockView::new("Plain Text", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        let slow = SlowParser(PlaintextParser::new(), Duration::from_millis(2));
+        view_state.set_parser(LanguageId::from("Plain Text"), share(Box::new(slow)));
+
+        view.idle_scheduled = false;
+        view_state.idle(&mut view);
+        assert!(view.idle_scheduled);
+        let frontier = view.get_frontier().unwrap();
+        assert!(frontier > 0 && frontier < 10, "highlighted {} lines", frontier);
+
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.get_frontier(), None);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Plain Text", &text));
+    }
+
+    #[test]
+    fn slice_size_follows_the_config() {
+        let text = "line\n".repeat(200);
+        let mut view = MockView::new("Plain Text", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        let registry = ParserRegistry::default();
+        let mut parsers = HashMap::new();
+        view_state.do_highlighting(&mut view, &registry, &mut parsers);
+        let mut set_slice = |view_state: &mut ViewState, view: &mut MockView, value: Value| {
+            let changes = json!({ "experimental_lang": { "lines_per_slice": value } });
+            view_state.config_changed(view, changes.as_object().unwrap(), &registry, &mut parsers);
+        };
+
+        set_slice(&mut view_state, &mut view, json!(3));
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.idle(&mut view);
+        assert_eq!(view.get_frontier(), Some(3));
+
+        set_slice(&mut view_state, &mut view, json!("auto"));
+        view_state.idle_budget = Duration::from_millis(IDLE_BUDGET_MS);
+        let slow = SlowParser(PlaintextParser::new(), Duration::from_millis(1));
+        view_state.set_parser(LanguageId::from("Plain Text"), share(Box::new(slow)));
+        for _ in 0..6 {
+            view_state.idle(&mut view);
+        }
+        let lines = view_state.slice.lines();
+        assert!(lines < 100, "{} lines per slice", lines);
+
+        // a bad value leaves the slice alone
+        set_slice(&mut view_state, &mut view, json!("fast"));
+        assert_eq!(view_state.slice.lines(), lines);
+        assert_eq!(view_state.stats(&view)["lines_per_slice"], lines);
+    }
+
+    #[test]
+    fn idle_budget_follows_the_config() {
+        let mut view_state = ViewState::new(Arc::default());
+        let changes = json!({ "experimental_lang": { "idle_budget_ms": 20 } });
+        view_state.read_config(changes.as_object().unwrap());
+        assert_eq!(view_state.idle_budget, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn cheap_lines_are_capped_per_idle() {
+        let text = "x\n".repeat(MAX_LINES_PER_IDLE * 2);
+        let mut view = MockView::new("Test", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+
+        view_state.idle(&mut view);
+        assert_eq!(view.get_frontier(), Some(MAX_LINES_PER_IDLE));
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.get_frontier(), None);
+    }
+
+    fn scope_at(view: &MockView, offset: usize) -> Option<Scope> {
+        resolved_scopes(view)[offset].clone()
+    }
+
+    #[test]
+    fn visible_region_is_highlighted_first() {
+        let text = "let x = 1;\n".repeat(MAX_LINES_PER_IDLE * 3);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+
+        let first_visible = MAX_LINES_PER_IDLE * 2;
+        view_state.set_visible_region(&mut view, first_visible, first_visible + 10);
+        view_state.idle(&mut view);
+        assert!(view.get_frontier().unwrap() < first_visible);
+        let visible_offset = view.offset_of_line(first_visible + 5).unwrap();
+        assert!(scope_at(&view, visible_offset).is_some());
+
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn nearby_region_is_highlighted_at_once() {
+        let text = "let x = 1;\n".repeat(1000);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+
+        view_state.set_visible_region(&mut view, 300, 320);
+        assert_eq!(view.get_frontier(), Some(321));
+        assert!(view_state.priority.is_none());
+        let visible_offset = view.offset_of_line(310).unwrap();
+        assert!(scope_at(&view, visible_offset).is_some());
+
+        // out of time, the rest is left to the priority pass
+        view_state.idle_budget = Duration::from_secs(0);
+        view_state.set_visible_region(&mut view, 700, 720);
+        assert_eq!(view.get_frontier(), Some(322));
+        assert!(view_state.priority.is_some());
+
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn distant_region_is_deferred() {
+        let text = "let x = 1;\n".repeat(60_000);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        view.idle_scheduled = false;
+
+        view_state.set_visible_region(&mut view, 50_000, 50_020);
+        assert!(view.updates.is_empty());
+        assert_eq!(view.get_frontier(), Some(0));
+        assert!(view_state.priority.is_some());
+        assert!(view.idle_scheduled);
+    }
+
+    #[test]
+    fn speculative_spans_are_corrected() {
+        // everything after the opening line is in a comment, which a pass
+        // starting at the visible region cannot know
+        let text = format!("/*\n{}", "let x = 1;\n".repeat(MAX_LINES_PER_IDLE * 3));
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+
+        let first_visible = MAX_LINES_PER_IDLE * 2;
+        view_state.set_visible_region(&mut view, first_visible, first_visible + 10);
+        assert!(view_state.priority.as_ref().unwrap().speculative);
+        view_state.idle(&mut view);
+        let visible_offset = view.offset_of_line(first_visible + 5).unwrap();
+        let guessed = scope_at(&view, visible_offset);
+
+        run_idle(&mut view_state, &mut view);
+        let expected = highlight_from_scratch("Rust", &text);
+        assert_ne!(guessed, expected[visible_offset]);
+        assert_eq!(resolved_scopes(&view), expected);
+    }
+
+    #[test]
+    fn scripts_are_detected_until_core_knows_better() {
+        let text = "#!/usr/bin/env rust-script\nfn main() {}\n";
+        let mut view = MockView::new("Plain Text", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view_state.detected_language, Some(LanguageId::from("Rust")));
+        assert_eq!(view.get_language_id().as_ref(), "Plain Text");
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", text));
+
+        // a real language change wins, even to a language we can't highlight
+        view.set_language("Shell Script");
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view_state.detected_language, None);
+        let plain = highlight_from_scratch("Shell Script", text);
+        assert!(
+            plain
+                .iter()
+                .all(|scope| scope
+                    == &Some(scope_map::translate(&PlaintextParser::new().scopes()[0])))
+        );
+        assert_eq!(resolved_scopes(&view), plain);
+    }
+
+    #[test]
+    fn overrides_pick_the_parser() {
+        let mut registry = ParserRegistry::default();
+        let mut view = MockView::new("Svelte", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &registry, &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Plain Text", RUST_TEXT));
+
+        let overrides = serde_json::json!({
+            "experimental_lang": { "language_overrides": { "Svelte": "Rust" } }
+        });
+        assert!(registry.apply_config(overrides.as_object().unwrap()));
+        view_state.do_highlighting(&mut view, &registry, &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", RUST_TEXT));
+    }
+
+    #[test]
+    fn overrides_reroute_every_view() {
+        let mut registry = ParserRegistry::default();
+        let mut parsers = HashMap::new();
+        let scope_tracker = Arc::default();
+        let mut views: Vec<_> = (0..2).map(|_| MockView::new("Svelte", RUST_TEXT)).collect();
+        let mut view_states: Vec<_> =
+            (0..2).map(|_| ViewState::new(Arc::clone(&scope_tracker))).collect();
+        for (view_state, view) in view_states.iter_mut().zip(&mut views) {
+            view_state.do_highlighting(view, &registry, &mut parsers);
+            run_idle(view_state, view);
+        }
+
+        // core reports the change to each view, but only the first changes
+        // the registry
+        let changes = json!({
+            "experimental_lang": { "language_overrides": { "Svelte": "Rust" } }
+        });
+        let changes = changes.as_object().unwrap();
+        for (view_state, view) in view_states.iter_mut().zip(&mut views) {
+            registry.apply_config(changes);
+            view_state.config_changed(view, changes, &registry, &mut parsers);
+            run_idle(view_state, view);
+            assert_eq!(resolved_scopes(view), highlight_from_scratch("Rust", RUST_TEXT));
+        }
+    }
+
+    #[test]
+    fn state_flows_through_empty_lines() {
+        let text = "/* start\n\n\n\nend */ fn\n";
+        let mut view = MockView::new("Rust", text);
+        view.blank_lines_empty = true;
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        assert_eq!(view.get_frontier(), None);
+        let in_comment = *view.get(1).unwrap();
+        assert_ne!(in_comment, State::default());
+        for line_num in 2..=4 {
+            assert_eq!(view.get(line_num), Some(&in_comment), "line {}", line_num);
+        }
+        assert_eq!(view.get(5), Some(&State::default()));
+    }
+
+    /// Asserts that the updates since `first_update` cover `len` bytes from
+    /// `start` once each, in order.
+    fn assert_covered_once(view: &MockView, first_update: usize, start: usize, len: usize) {
+        let mut offset = start;
+        for update in &view.updates[first_update..] {
+            assert_eq!(update.start, offset, "gap or overlap at {}", offset);
+            offset += update.len;
+        }
+        assert_eq!(offset, start + len);
+    }
+
+    #[test]
+    fn single_line_without_newline_is_highlighted() {
+        let text = "fn main() { 42 }";
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        assert_covered_once(&view, 0, 0, text.len());
+        assert!(resolved_scopes(&view).iter().all(Option::is_some));
+        assert!(view_state.spans.is_empty());
+    }
+
+    #[test]
+    fn unterminated_comment_on_last_line_is_highlighted() {
+        let text = "fn f() {}\n/* open\nstill open";
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_covered_once(&view, 0, 0, text.len());
+        let scopes = resolved_scopes(&view);
+        let comment = scopes[text.find("/*").unwrap()].clone();
+        assert!(comment.as_ref().unwrap().iter().any(|name| name.starts_with("comment")));
+        assert_eq!(scopes[text.len() - 1], comment);
+
+        // relex just the last line, as the first line of a batch after a
+        // jump in offset
+        let last_line = text.rfind('\n').unwrap() + 1;
+        let updates_before = view.updates.len();
+        apply_edit(&mut view_state, &mut view, (text.len(), 0, "!"));
+        let text = view.text().to_string();
+        assert_covered_once(&view, updates_before, last_line, text.len() - last_line);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn failed_fetches_are_retried() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        view.failing_fetches = 1;
+
+        view.idle_scheduled = false;
+        view_state.idle(&mut view);
+        assert_eq!(view.get_frontier(), Some(0));
+        assert!(view.idle_scheduled);
+        assert!(view.updates.is_empty());
+
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", RUST_TEXT));
+    }
+
+    #[test]
+    fn persistent_fetch_failures_stop_highlighting() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        view.failing_fetches = std::usize::MAX;
+
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.get_frontier(), None);
+        assert!(view.updates.is_empty());
+    }
+
+    /// Highlights the first line as a commit subject, and the rest as its
+    /// body, so that its initial state is not the default one.
+    struct CommitParser {
+        ctx: statestack::Context<()>,
+        subject: State,
+    }
+
+    impl CommitParser {
+        fn new() -> CommitParser {
+            let mut ctx = statestack::Context::new();
+            let subject = ctx.push(State::default(), ());
+            CommitParser { ctx, subject }
+        }
+    }
+
+    impl Parser for CommitParser {
+        fn name(&self) -> &'static str {
+            "Git Commit"
+        }
+
+        fn initial_state(&self) -> State {
+            self.subject
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            let mut scope = vec!["text.git-commit".to_owned()];
+            if state == self.subject {
+                scope.push("markup.heading.subject.git-commit".to_owned());
+            }
+            scope
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            (0, state, text.len(), State::default())
+        }
+
+        fn stack_stats(&self) -> statestack::Stats {
+            self.ctx.stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.ctx.shrink_to_fit()
+        }
+    }
+
+    #[test]
+    fn parsers_choose_their_initial_state() {
+        let mut registry = ParserRegistry::default();
+        registry.register("Git Commit", || Box::new(CommitParser::new()));
+        let subject =
+            vec!["text.git-commit".to_owned(), "markup.heading.subject.git-commit".to_owned()];
+        let body = vec!["text.git-commit".to_owned()];
+
+        let text = "Fix the frobnicator\n\nIt was broken.\nNow it isn't.\n";
+        let mut view = MockView::new("Git Commit", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &registry, &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let scopes = resolved_scopes(&view);
+        assert_eq!(scopes[0], Some(subject.clone()));
+        assert_eq!(scopes[text.find("It").unwrap()], Some(body));
+
+        // edits, including to the first line, relex correctly and converge
+        for &edit in &[(0, 0, "Really "), (25, 0, "x"), (0, 10, ""), (3, 0, "\n")] {
+            let updates_before = view.updates.len();
+            apply_edit(&mut view_state, &mut view, edit);
+            let text = view.text().to_string();
+            assert_eq!(
+                resolved_scopes(&view),
+                highlight_from_scratch_with(&registry, "Git Commit", &text)
+            );
+            let relexed: usize = view.updates[updates_before..].iter().map(|u| u.len).sum();
+            assert!(relexed < text.len(), "{:?} relexed the whole document", edit);
+        }
+        assert_eq!(resolved_scopes(&view)[0], Some(subject));
+    }
+
+    #[test]
+    fn stuck_parsers_skip_a_character() {
+        // the test parser makes no progress on `@` or `é`
+        let text = "one @ two\n@@é\nthree\n";
+        let mut view = MockView::new("Test", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        assert_eq!(view.get_frontier(), None);
+        assert_covered_once(&view, 0, 0, text.len());
+        let plain = scope_map::translate(&TestParser::new().scopes()[0]);
+        let mut invalid = plain.clone();
+        invalid.push(STUCK_SCOPE.to_owned());
+        let scopes = resolved_scopes(&view);
+        for (offset, c) in text.char_indices() {
+            let expected = if c == '@' || c == 'é' { &invalid } else { &plain };
+            assert_eq!(scopes[offset].as_ref(), Some(expected), "at {}", offset);
+        }
+        assert_eq!(view_state.stats(&view)["stuck_bytes"], 1 + 2 + "é".len());
+        assert_scopes_consistent(&view, &view_state.scope_tracker);
+    }
+
+    #[test]
+    fn language_change_discards_old_states() {
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.set_parser(LanguageId::from("Rust"), share(Box::new(RustParser::new())));
+
+        // stop partway through a block comment, as if idle were interrupted
+        let (_, state) =
+            view_state.compute_syntax("/* unterminated\n", 0, view_state.initial_state);
+        assert_ne!(state, State::default());
+        view_state.initial_state = state;
+
+        view_state
+            .set_parser(LanguageId::from("Plain Text"), share(Box::new(PlaintextParser::new())));
+        assert_eq!(view_state.initial_state, State::default());
+        // would panic in debug builds if the Rust state leaked through
+        view_state.compute_syntax("plain text\n", 0, view_state.initial_state);
+    }
+
+    #[test]
+    fn language_change_mid_highlight_clears_old_scopes() {
+        let text = RUST_TEXT.repeat(20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        let mut parsers = HashMap::new();
+        view_state.visible_region = Some((30, 40));
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut parsers);
+        view_state.idle_budget = Duration::from_secs(0);
+        // the visible lines, then the first few
+        for _ in 0..14 {
+            view_state.idle(&mut view);
+        }
+        let frontier = view.get_frontier().unwrap();
+        assert!(frontier > 0 && frontier < 30, "frontier at {}", frontier);
+        assert!(resolved_scopes(&view).iter().flatten().any(|scope| scope[0] == "source.rust"));
+
+        view.set_language("Plain Text");
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut parsers);
+        // the old colors are gone before the new parser has lexed anything
+        assert_eq!(resolved_scopes(&view), vec![None; text.len()]);
+
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Plain Text", &text));
+        assert!(resolved_scopes(&view).iter().flatten().all(|scope| scope[0] != "source.rust"));
+    }
+
+    #[test]
+    fn stale_priority_pass_is_dropped() {
+        let text = RUST_TEXT.repeat(20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.visible_region = Some((30, 40));
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        view_state.priority_one_line(&mut view);
+        let updates_before = view.updates.len();
+
+        view_state.generation += 1;
+        view_state.flush_priority_spans(&mut view);
+        assert!(view_state.priority.is_none());
+        assert_eq!(view.updates.len(), updates_before);
+    }
+
+    /// Asserts that `updates` cover `start..end` exactly, in order, each
+    /// starting at a line start of `text`.
+    fn assert_contiguous(updates: &[SpanUpdate], text: &str, start: usize, end: usize) {
+        let mut offset = start;
+        for update in updates {
+            assert_eq!(update.start, offset);
+            assert!(offset == 0 || text.as_bytes()[offset - 1] == b'\n');
+            assert_eq!(update.spans.last().map_or(0, |span| span.end), update.len);
+            offset += update.len;
+        }
+        assert_eq!(offset, end);
+    }
+
+    #[test]
+    fn long_lines_are_flushed_in_pieces() {
+        // a span a dot, so two lines are past the span threshold
+        let line = format!("{}\n", ".".repeat(900));
+        let text = line.repeat(50);
+        let mut view = MockView::new("Test", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.max_pending_spans = 1000;
+        view_state.max_pending_bytes = 4 * line.len();
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+
+        view_state.idle(&mut view);
+        assert!(view.get_frontier().is_none());
+        assert!(view.updates.len() > 10, "only {} flushes", view.updates.len());
+        for update in &view.updates {
+            // no more than a line's worth of spans past the threshold
+            assert!(update.spans.len() < 2 * 1000, "{} spans flushed at once", update.spans.len());
+            assert!(update.len <= 4 * line.len());
+        }
+        assert_contiguous(&view.updates, &text, 0, text.len());
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Test", &text));
+    }
+
+    #[test]
+    fn span_count_bounds_each_flush() {
+        let line = format!("{}\n", ".".repeat(600));
+        let text = line.repeat(10);
+        let mut view = MockView::new("Test", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.max_pending_spans = 1;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+
+        view_state.idle(&mut view);
+        // every line is flushed on its own, however many spans it has
+        assert_eq!(view.updates.len(), 10);
+        assert_contiguous(&view.updates, &text, 0, text.len());
+    }
+
+    #[test]
+    fn idle_slice_sends_spans_together() {
+        let text = "let x = 1;\n".repeat(200);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.catch_up_lines = 0;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        view_state.set_visible_region(&mut view, 150, 160);
+        assert!(view_state.priority.as_ref().unwrap().speculative);
+
+        // the main pass gets through the whole document after the visible
+        // region, so the spans sent for that are superseded
+        view_state.idle(&mut view);
+        assert!(view.get_frontier().is_none());
+        assert_eq!(view.updates.len(), 1);
+        assert_eq!(view_state.counters.update_spans_calls, 1);
+        assert_contiguous(&view.updates, &text, 0, text.len());
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn priority_pass_is_flushed_in_pieces() {
+        let line = format!("{}\n", "let x = 1; ".repeat(100));
+        let text = line.repeat(300);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.max_pending_bytes = 2 * line.len();
+        view_state.catch_up_lines = 0;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        view_state.set_visible_region(&mut view, 200, 209);
+
+        let start = view_state.priority.as_ref().unwrap().offset;
+        while view_state.priority.is_some() {
+            view_state.priority_one_line(&mut view);
+        }
+        assert_eq!(view.updates.len(), (210 * line.len() - start) / (2 * line.len()));
+        assert_contiguous(&view.updates, &text, start, 210 * line.len());
+    }
+
+    #[test]
+    fn long_highlights_report_progress() {
+        let text = "let x = 1;\n".repeat(MAX_LINES_PER_IDLE * 20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.progress_interval = Duration::from_secs(0);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        let status =
+            |value: Option<&str>| (PROGRESS_STATUS_KEY.to_owned(), value.map(str::to_owned));
+        assert_eq!(
+            view.status_log,
+            vec![status(Some("highlighting 40%")), status(Some("highlighting 80%")), status(None)]
+        );
+
+        // highlighting after an edit starts reporting afresh
+        apply_edit(&mut view_state, &mut view, (0, 0, "/*"));
+        assert_eq!(view.status_log.len(), 3 + 3);
+        assert_eq!(view.status_log.last(), Some(&status(None)));
+    }
+
+    #[test]
+    fn quick_highlights_report_no_progress() {
+        let text = "let x = 1;\n".repeat(MAX_LINES_PER_IDLE * 20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.progress_interval = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert!(view.status_log.is_empty());
+    }
+
+    /// The scope at each offset according to a debug dump.
+    fn dumped_scopes(dump: &Value, len: usize) -> Vec<Option<Scope>> {
+        let scopes: Vec<Scope> = serde_json::from_value(dump["scopes"].clone()).unwrap();
+        let mut resolved = vec![None; len];
+        for line in dump["lines"].as_array().unwrap() {
+            let spans = &line["spans"];
+            let gaps = serde_json::from_value(spans["gaps"].clone()).unwrap();
+            let lengths = serde_json::from_value(spans["lengths"].clone()).unwrap();
+            let runs = serde_json::from_value(spans["scopes"].clone()).unwrap();
+            let packed = PackedSpans::from_parts(gaps, lengths, runs);
+            let offset = line["offset"].as_u64().unwrap() as usize;
+            for span in packed.unwrap().iter(offset) {
+                let scope = &scopes[span.scope_id as usize];
+                for slot in &mut resolved[span.start..span.end] {
+                    *slot = Some(scope.clone());
+                }
+            }
+        }
+        resolved
+    }
+
+    #[test]
+    fn debug_dump_matches_the_sent_spans() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let updates_before = view.updates.len();
+
+        let dump = view_state.debug_dump(&mut view, 0, None);
+        assert_eq!(dump["language"], "Rust");
+        assert_eq!(dump["parser"], "Rust");
+        assert_eq!(dump["frontier"], Value::Null);
+        assert_eq!(dump["lines"].as_array().unwrap().len(), 3);
+        assert_eq!(dumped_scopes(&dump, RUST_TEXT.len()), resolved_scopes(&view));
+        // dumping sends nothing
+        assert_eq!(view.updates.len(), updates_before);
+
+        let dump = view_state.debug_dump(&mut view, 1, Some(1));
+        let lines = dump["lines"].as_array().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["line"], 1);
+        assert_eq!(lines[0]["offset"], RUST_TEXT.find('\n').unwrap() + 1);
+        let second_line = RUST_TEXT.find('\n').unwrap() + 1..RUST_TEXT.rfind('}').unwrap();
+        let dumped = dumped_scopes(&dump, RUST_TEXT.len());
+        assert_eq!(dumped[second_line.clone()], resolved_scopes(&view)[second_line]);
+    }
+
+    #[test]
+    fn debug_dumps_are_capped() {
+        let text = "let x = 1;\n".repeat(MAX_DUMP_LINES + 10);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        let dump = view_state.debug_dump(&mut view, 0, None);
+        assert_eq!(dump["lines"].as_array().unwrap().len(), MAX_DUMP_LINES);
+        assert_eq!(dump["truncated"], true);
+        // the rest fits in another
+        let dump = view_state.debug_dump(&mut view, MAX_DUMP_LINES, None);
+        assert_eq!(dump["lines"].as_array().unwrap().len(), 10);
+        assert_eq!(dump["truncated"], false);
+    }
+
+    #[test]
+    fn stats_count_the_work_done() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        let stats = view_state.stats(&view);
+        assert_eq!(stats["lines_highlighted"], 0);
+        assert_eq!(stats["total_lines"], 4);
+
+        run_idle(&mut view_state, &mut view);
+        let stats = view_state.stats(&view);
+        assert_eq!(stats["language"], "Rust");
+        assert_eq!(stats["parser"], "Rust");
+        assert_eq!(stats["capabilities"]["emits_sub_spans"], true);
+        assert_eq!(stats["autoclose_pairs"][0], json!(["(", ")"]));
+        assert_eq!(stats["lines_highlighted"], 4);
+        assert_eq!(stats["lines_lexed"], 3);
+        assert_eq!(stats["update_spans_calls"], view.updates.len());
+        assert_eq!(stats["add_scopes_calls"], 1);
+        assert_eq!(stats["scope_table_size"], view.scopes.len());
+        assert_eq!(stats["memory"]["lines_recorded"], true);
+        assert!(stats["memory"]["outline_bytes"].as_u64().unwrap() > 0);
+
+        let mut uses = HashMap::new();
+        for span in view.updates.iter().flat_map(|update| &update.spans) {
+            *uses.entry(view.scopes[span.scope_id as usize].clone()).or_insert(0) += 1;
+        }
+        let counted: HashMap<Scope, u64> = (stats["scopes"].as_array().unwrap().iter())
+            .map(|entry| {
+                let scope = serde_json::from_value(entry["scope"].clone()).unwrap();
+                (scope, entry["uses"].as_u64().unwrap())
+            })
+            .collect();
+        assert_eq!(counted, uses);
+    }
+
+    #[test]
+    fn highlighting_can_be_turned_off_and_on() {
+        let text = RUST_TEXT.repeat(20);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        let mut parsers = HashMap::new();
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut parsers);
+        // partway through, as when a huge file is opened
+        view_state.idle_budget = Duration::from_secs(0);
+        view_state.idle(&mut view);
+        assert!(view.get_frontier().is_some());
+
+        view_state.disable(&mut view);
+        assert_eq!(resolved_scopes(&view), vec![None; text.len()]);
+        assert_eq!(view.get_frontier(), Some(0));
+        let updates_before = view.updates.len();
+
+        // nothing is highlighted while disabled, whatever happens
+        view.idle_scheduled = false;
+        view_state.idle(&mut view);
+        let delta = RopeDelta::simple_edit(Interval::new(0, 0), Rope::from("// "), text.len());
+        view.apply_edit(&delta);
+        view_state.update(&mut view, Some(&delta), false);
+        view_state.set_visible_region(&mut view, 5, 10);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut parsers);
+        assert!(!view.idle_scheduled);
+        assert_eq!(view.updates.len(), updates_before);
+
+        view_state.enable(&mut view, &ParserRegistry::default(), &mut parsers);
+        run_idle(&mut view_state, &mut view);
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn config_changes_rehighlight_only_when_needed() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        let mut registry = ParserRegistry::default();
+        let mut parsers = HashMap::new();
+        view_state.do_highlighting(&mut view, &registry, &mut parsers);
+        run_idle(&mut view_state, &mut view);
+
+        let changes: &[(Value, bool)] = &[
+            (json!({ "font_size": 16.0, "experimental_lang": {} }), false),
+            // the tab size only matters to indent guides
+            (json!({ "tab_size": 8, "translate_tabs_to_spaces": true }), false),
+            (json!({ "experimental_lang": { "indent_guides": true } }), true),
+            (json!({ "tab_size": 2 }), true),
+            (json!({ "tab_size": 2 }), false),
+            (json!({ "experimental_lang": { "indent_guides": false } }), true),
+            (json!({ "tab_size": 8 }), false),
+            // rerouting another language leaves this view alone
+            (json!({ "experimental_lang": { "language_overrides": { "Svelte": "Rust" } } }), false),
+            (json!({ "experimental_lang": { "language_overrides": { "Rust": "Text" } } }), true),
+            (json!({ "experimental_lang": { "language_overrides": { "Rust": "Text" } } }), false),
+            (json!({ "max_highlight_bytes": 1_000_000 }), false),
+            (json!({ "max_highlight_bytes": 10 }), true),
+            (json!({ "max_highlight_bytes": 20 }), false),
+            (json!({ "max_highlight_bytes": 1_000_000 }), true),
+            (json!({ "syntax_highlighting": true }), false),
+        ];
+        for (change, expected) in changes {
+            let change = change.as_object().unwrap();
+            let generation = view_state.generation;
+            registry.apply_config(change);
+            view_state.config_changed(&mut view, change, &registry, &mut parsers);
+            run_idle(&mut view_state, &mut view);
+            assert_eq!(view_state.generation != generation, *expected, "{:?}", change);
+        }
+
+        assert_eq!(view_state.tab_size, 8);
+        assert!(view_state.translate_tabs_to_spaces);
+        assert_eq!(view_state.current_language.as_ref(), "Rust");
+        assert_eq!(view_state.parser_language, Some("Plain Text".to_owned()));
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Plain Text", RUST_TEXT));
+    }
+
+    #[test]
+    fn raw_scope_names_are_the_parsers_own() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        let registry = ParserRegistry::default();
+        let mut parsers = HashMap::new();
+        view_state.do_highlighting(&mut view, &registry, &mut parsers);
+        run_idle(&mut view_state, &mut view);
+        let textmate = resolved_scopes(&view);
+        assert_eq!(textmate[0], Some(vec!["source.rust".into(), "keyword.other.rust".into()]));
+
+        let raw = json!({ "experimental_lang": { "raw_scope_names": true } });
+        view_state.config_changed(&mut view, raw.as_object().unwrap(), &registry, &mut parsers);
+        run_idle(&mut view_state, &mut view);
+        let scopes = resolved_scopes(&view);
+        assert_eq!(scopes[0], Some(vec!["rust:source".into(), "rust:keyword".into()]));
+        let translated: Vec<_> =
+            scopes.iter().map(|scope| scope.as_ref().map(|s| scope_map::translate(s))).collect();
+        assert_eq!(translated, textmate);
+        assert_scopes_consistent(&view, &view_state.scope_tracker);
+
+        let names = json!({ "experimental_lang": { "raw_scope_names": false } });
+        view_state.config_changed(&mut view, names.as_object().unwrap(), &registry, &mut parsers);
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(resolved_scopes(&view), textmate);
+        // the same setting again changes nothing
+        let generation = view_state.generation;
+        view_state.config_changed(&mut view, names.as_object().unwrap(), &registry, &mut parsers);
+        assert_eq!(view_state.generation, generation);
+    }
+
+    #[test]
+    fn injection_changes_swap_the_parser() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        let mut registry = ParserRegistry::default();
+        let mut parsers = HashMap::new();
+        view_state.do_highlighting(&mut view, &registry, &mut parsers);
+        run_idle(&mut view_state, &mut view);
+        let parser = Rc::clone(&view_state.parser);
+
+        let change = json!({ "experimental_lang": { "injections": [
+            { "host": "Rust", "pattern": "macro:html!", "language": "Plain Text" },
+        ] } });
+        assert!(registry.apply_config(change.as_object().unwrap()));
+        // as the plugin does when the injections change
+        parsers.clear();
+        // the change reported to another view, so not to this one
+        let generation = view_state.generation;
+        view_state.config_changed(&mut view, &ConfigTable::new(), &registry, &mut parsers);
+        run_idle(&mut view_state, &mut view);
+
+        assert_ne!(view_state.generation, generation);
+        assert!(!Rc::ptr_eq(&view_state.parser, &parser));
+        assert_eq!(view_state.registry_generation, registry.generation());
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", RUST_TEXT));
+    }
+
+    #[test]
+    fn large_documents_are_left_alone() {
+        let text = "let x = 1;\n".repeat(100);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.max_highlight_bytes = text.len() - 1;
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        view_state.set_visible_region(&mut view, 10, 20);
+        run_idle(&mut view_state, &mut view);
+        apply_edit(&mut view_state, &mut view, (0, 0, "x"));
+        assert!(view.updates.is_empty());
+        assert!(!view.idle_scheduled);
+
+        // shrinking it back under the limit highlights it
+        apply_edit(&mut view_state, &mut view, (0, 12, ""));
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+
+        // and growing past it again clears the colors
+        apply_edit(&mut view_state, &mut view, (0, 0, "let y = 2;\n"));
+        assert_eq!(resolved_scopes(&view), vec![None; view.text().len()]);
+        assert!(view_state.too_large);
+    }
+
+    #[test]
+    fn typing_only_highlights_around_the_edit() {
+        let text = "fn main() {}\n".repeat(200);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.typing_debounce = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let updates_before = view.updates.len();
+
+        // opening a comment changes the state of every following line
+        for (i, c) in "/* note".chars().enumerate() {
+            let offset = 13 * 100 + i;
+            let len = view.text().len();
+            let delta = RopeDelta::simple_edit(
+                Interval::new(offset, offset),
+                Rope::from(c.to_string()),
+                len,
+            );
+            view.apply_edit(&delta);
+            view_state.update(&mut view, Some(&delta), true);
+            run_idle(&mut view_state, &mut view);
+        }
+        assert!(view.get_frontier().is_some());
+        assert!(view.timer.is_some());
+        let churn: usize = view.updates[updates_before..].iter().map(|u| u.len).sum();
+        assert!(churn <= 7 * 2 * 20, "{} bytes relexed while typing", churn);
+
+        // once typing pauses, the frontier walks on
+        let updates_before = view.updates.len();
+        view_state.typing_until = Some(Instant::now());
+        view_state.idle(&mut view);
+        run_idle(&mut view_state, &mut view);
+        assert!(view.get_frontier().is_none());
+        let churn: usize = view.updates[updates_before..].iter().map(|u| u.len).sum();
+        assert!(churn > 13 * 80);
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    /// Where the states of the lines after an edit stop changing, line 4
+    /// closing a string opened on line 1 or passing one by, as it may.
+    const CONVERGING_TEXT: &str = "fn main() {\n    let s = \"abc\";\n    let t = 1;\n    \
+                                   let u = 2;\n    // \"\n    let v = 3;\n}";
+
+    /// `text` highlighted, fetched as much at a time as core hands back,
+    /// so that the state cache keeps the states below a simple edit.
+    fn highlighted(text: &str) -> (ViewState, MockView) {
+        let mut view = MockView::with_chunk_size("Rust", text, 1024 * 1024);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        (view_state, view)
+    }
+
+    /// Makes `edit`, and returns how many lines were lexed and fetched to
+    /// catch up with it.
+    fn work_for_edit(
+        view_state: &mut ViewState,
+        view: &mut MockView,
+        edit: (usize, usize, &str),
+    ) -> (usize, usize) {
+        let (lexed, fetched) = (view_state.counters.lines_lexed, view.line_fetches);
+        apply_edit(view_state, view, edit);
+        assert!(view.get_frontier().is_none());
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(view), highlight_from_scratch("Rust", &text));
+        (view_state.counters.lines_lexed - lexed, view.line_fetches - fetched)
+    }
+
+    #[test]
+    fn lexing_stops_where_states_converge() {
+        let (mut view_state, mut view) = highlighted(CONVERGING_TEXT);
+        // the string loses its closing quote, and runs on to line 4
+        let quote = CONVERGING_TEXT.find("\";").unwrap();
+        let work = work_for_edit(&mut view_state, &mut view, (quote, 1, ""));
+        assert_eq!(work, (4, 4));
+    }
+
+    #[test]
+    fn lexing_runs_to_the_end_while_states_differ() {
+        let (mut view_state, mut view) = highlighted(CONVERGING_TEXT);
+        let line = CONVERGING_TEXT.find("    let s").unwrap();
+        let work = work_for_edit(&mut view_state, &mut view, (line, 0, "/*"));
+        assert_eq!(work, (6, 6));
+    }
+
+    #[test]
+    fn edits_that_keep_states_resend_only_their_line() {
+        let (mut view_state, mut view) = highlighted(CONVERGING_TEXT);
+        let updates_before = view.updates.len();
+        let digit = CONVERGING_TEXT.find('1').unwrap();
+        let skipped = view_state.counters.lines_skipped;
+        let work = work_for_edit(&mut view_state, &mut view, (digit, 1, "7"));
+        // a replacement drops the states cached below it, so the lines
+        // there are fetched, but stepped over rather than lexed
+        assert_eq!(work, (1, 5));
+        assert_eq!(view_state.counters.lines_skipped - skipped, 4);
+
+        let line_start = CONVERGING_TEXT[..digit].rfind('\n').unwrap() + 1;
+        let line_end = digit + CONVERGING_TEXT[digit..].find('\n').unwrap() + 1;
+        let updates = &view.updates[updates_before..];
+        assert!(!updates.is_empty());
+        for update in updates {
+            assert!(
+                line_start <= update.start && update.start + update.len <= line_end,
+                "{:?} is outside the edited line",
+                update
+            );
+        }
+    }
+
+    #[test]
+    fn deleting_lines_under_the_frontier_is_handled() {
+        let text = "fn main() {\n    let x = 1;\n}\n".repeat(20);
+        // from before the line the main pass has got to, from that line,
+        // and the whole document
+        for &first_deleted in &[3, 10, 0] {
+            let mut view = MockView::new("Rust", &text);
+            let mut view_state = ViewState::new(Arc::default());
+            view_state.slice = SliceSize::fixed(1);
+            view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+            for _ in 0..10 {
+                view_state.idle(&mut view);
+            }
+            assert_eq!(view.get_frontier(), Some(10));
+
+            let start = view.offset_of_line(first_deleted).unwrap();
+            apply_edit(&mut view_state, &mut view, (start, text.len(), ""));
+            assert!(view.get_frontier().is_none());
+            let text = view.text().to_string();
+            assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+        }
+    }
+
+    #[test]
+    fn unchanged_lines_are_not_lexed_again() {
+        // more lines than the state cache keeps
+        let text = "fn add(x: u32) -> u32 { x + 1 } // one more\n".repeat(3000);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.typing_debounce = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let lines_lexed = view_state.counters.lines_lexed;
+
+        // a comment marker typed at the top, then taken out by replacing it
+        // and the letter after it, which drops every cached state below
+        for &(iv, new) in &[(Interval::new(0, 0), "/*"), (Interval::new(0, 3), "f")] {
+            let delta = RopeDelta::simple_edit(iv, Rope::from(new), view.text().len());
+            view.apply_edit(&delta);
+            view_state.update(&mut view, Some(&delta), true);
+        }
+        view_state.typing_until = Some(Instant::now());
+        view_state.idle(&mut view);
+        run_idle(&mut view_state, &mut view);
+
+        assert!(view.get_frontier().is_none());
+        let relexed = view_state.counters.lines_lexed - lines_lexed;
+        assert!(relexed <= 10, "{} lines lexed again", relexed);
+        assert!(view_state.counters.lines_skipped >= 2900);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn spans_core_has_are_not_sent_again() {
+        let block = "fn main() {\n    let x = 1;\n}\n";
+        let text = block.repeat(100);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.max_pending_bytes = 2 * block.len();
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let updates_before = view.updates.len();
+
+        view_state.rehighlight_all(&mut view);
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.updates.len(), updates_before);
+        assert!(view_state.counters.flushes_unchanged > 0);
+
+        // a digit typed over another lexes the same, but core has no spans
+        // for the new one
+        let digit = 40 * block.len() + block.find('1').unwrap();
+        apply_edit(&mut view_state, &mut view, (digit, 1, "2"));
+        view_state.rehighlight_all(&mut view);
+        run_idle(&mut view_state, &mut view);
+        let updates = &view.updates[updates_before..];
+        assert!(!updates.is_empty());
+        for update in updates {
+            assert!(update.start <= digit && digit < update.start + update.len);
+        }
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+
+        // nor after core drops them
+        let updates_before = view.updates.len();
+        view_state.resend_spans(&mut view);
+        run_idle(&mut view_state, &mut view);
+        assert!(view.updates.len() > updates_before);
+        let updates_before = view.updates.len();
+        view_state.invalidate_from(&mut view, 0);
+        run_idle(&mut view_state, &mut view);
+        assert_covered_once(&view, updates_before, 0, text.len());
+    }
+
+    #[test]
+    fn long_lines_are_lexed_across_slices() {
+        // a megabyte of minified JSON on one line, lexed as Rust, as there
+        // is no JSON parser; the tokens are much the same
+        let item = r#"{"id":12,"tags":["a","b\"c"],"ok":true,"n":-1.5e3}"#;
+        let text = format!("[{}]\n", vec![item; 20_000].join(","));
+        assert!(text.len() > 1_000_000);
+        let highlight = |max_line_piece: usize| {
+            let mut view = MockView::with_chunk_size("Rust", &text, 1024 * 1024);
+            let mut view_state = ViewState::new(Arc::default());
+            view_state.idle_budget = Duration::from_secs(0);
+            view_state.max_line_piece = max_line_piece;
+            view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+            let mut slices = 0;
+            view.idle_scheduled = true;
+            while view.idle_scheduled {
+                view.idle_scheduled = false;
+                view_state.idle(&mut view);
+                slices += 1;
+            }
+            assert!(view.get_frontier().is_none());
+            assert_eq!(view_state.counters.lines_lexed, 1);
+            (view, slices)
+        };
+
+        let (view, slices) = highlight(MAX_LINE_PIECE);
+        assert!(slices > text.len() / MAX_LINE_PIECE, "{} slices", slices);
+        // each update carries on where the last left off, and its spans
+        // cover it exactly
+        let mut offset = 0;
+        for update in &view.updates {
+            assert_eq!(update.start, offset);
+            let mut end = 0;
+            for span in &update.spans {
+                assert_eq!(span.start, end);
+                end = span.end;
+            }
+            assert_eq!(end, update.len);
+            offset += update.len;
+        }
+        assert_eq!(offset, text.len());
+
+        // the line, and then the empty one after it
+        let (whole, slices) = highlight(std::usize::MAX);
+        assert_eq!(slices, 2);
+        assert_eq!(resolved_scopes(&view), resolved_scopes(&whole));
+    }
+
+    /// Runs idle callbacks, as timers and requests for them come in, until
+    /// there are none.
+    fn highlight_waiting(view_state: &mut ViewState, view: &mut MockView) {
+        view.idle_scheduled = true;
+        while view.idle_scheduled || view.timer.is_some() {
+            if view.timer.take().is_some() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            view.idle_scheduled = false;
+            view_state.idle(view);
+        }
+    }
+
+    #[test]
+    fn background_lexing_matches_the_main_pass() {
+        let block = "fn main() {\n    let s = \"string\"; // comment\n}\n";
+        let text = block.repeat(1000);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.background_batch_lines = 100;
+        // a batch an idle callback, so that the worker stays ahead
+        view_state.slice = SliceSize::fixed(1);
+        view_state.idle_budget = Duration::from_secs(60);
+        let registry = ParserRegistry::default();
+        view_state.do_highlighting(&mut view, &registry, &mut HashMap::new());
+        view_state.start_background(&mut view, &registry);
+        assert!(view_state.background.is_some());
+
+        let edit = |view_state: &mut ViewState, view: &mut MockView, iv: Interval, new: &str| {
+            let delta = RopeDelta::simple_edit(iv, Rope::from(new), view.text().len());
+            view.apply_edit(&delta);
+            view_state.update(view, Some(&delta), false);
+        };
+        // before any lines are back, a comment opened that runs on below,
+        // which moves what follows along by three bytes
+        let at = |block_num: usize, i: usize| block_num * block.len() + i;
+        edit(&mut view_state, &mut view, Interval::new(at(10, 0), at(10, 0)), "/* ");
+        let at = |block_num: usize, i: usize| at(block_num, i) + 3;
+        while view_state.counters.background_lines == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+            view_state.idle(&mut view);
+        }
+        assert!(view_state.background.is_some());
+        // and closed among the lines taken in; then a line the worker has
+        // yet to send deleted
+        edit(&mut view_state, &mut view, Interval::new(at(20, 3), at(20, 3)), " */");
+        let at = |block_num: usize, i: usize| at(block_num, i) + 3;
+        let (second, third) = (block.find('\n').unwrap() + 1, block.find('}').unwrap());
+        edit(&mut view_state, &mut view, Interval::new(at(900, second), at(900, third)), "");
+        highlight_waiting(&mut view_state, &mut view);
+
+        assert!(view_state.background.is_none());
+        assert!(view.get_frontier().is_none());
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+        assert_eq!(view_state.counters.background_lines, 3000);
+        assert_eq!(view_state.counters.background_lines_discarded, 1);
+        // the main pass only lexed the lines the edits affected
+        assert!(view_state.counters.lines_lexed < 100, "{}", view_state.counters.lines_lexed);
+        // the worker's parser stays with the view
+        assert_eq!(Rc::strong_count(&view_state.parser), 1);
+        assert_scopes_consistent(&view, &view_state.scope_tracker);
+    }
+
+    #[test]
+    fn pastes_are_highlighted_at_once() {
+        assert!(is_typing("insert", "core"));
+        assert!(!is_typing("other", "core"));
+        assert!(!is_typing("undo", "core"));
+        assert!(!is_typing("insert", "rustfmt"));
+
+        let text = "fn main() {}\n".repeat(200);
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.idle_budget = Duration::from_secs(60);
+        view_state.typing_debounce = Duration::from_secs(60);
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        view_state.update(&mut view, None, true);
+        apply_edit(&mut view_state, &mut view, (0, 0, "/* pasted"));
+        assert!(view.get_frontier().is_none());
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    #[test]
+    fn spans_only_reference_announced_scopes() {
+        let tracker = Arc::default();
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::clone(&tracker));
+
+        for language in &["Rust", "Plain Text", "Rust"] {
+            view.set_language(language);
+            let updates_before = view.updates.len();
+            view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+            run_idle(&mut view_state, &mut view);
+
+            assert!(view.updates.len() > updates_before);
+            for update in &view.updates[updates_before..] {
+                assert!(update.start + update.len <= RUST_TEXT.len());
+            }
+            assert_scopes_consistent(&view, &tracker);
+        }
+    }
+
+    #[test]
+    fn known_scopes_are_announced_up_front() {
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        let rust_scopes: Vec<Scope> =
+            RustParser::new().scopes().iter().map(|scope| scope_map::translate(scope)).collect();
+        assert_eq!(view.scopes, rust_scopes);
+
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.scopes.len(), rust_scopes.len());
+
+        // rehighlighting with the same parser announces nothing
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        assert_eq!(view.scopes.len(), rust_scopes.len());
+    }
+
+    #[test]
+    fn parser_scopes_are_well_formed() {
+        let parsers: Vec<Box<dyn Parser>> =
+            vec![Box::new(RustParser::new()), Box::new(PlaintextParser::new())];
+        for parser in parsers {
+            let mut validator = ScopeValidator::new();
+            for prefix in parser.scope_prefixes() {
+                validator.add_prefix(*prefix);
+            }
+            for scope in parser.scopes() {
+                assert_eq!(validator.validate(&scope_map::translate(&scope)), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "Rust parser"))]
+    fn misnamed_scope_is_reported() {
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.set_parser(LanguageId::from("Rust"), share(Box::new(RustParser::new())));
+        view_state.check_scope_name(&vec!["source.rust".into(), "keyowrd.rust".into()]);
+    }
+
+    #[test]
+    fn scopes_past_the_cap_share_the_overflow_scope() {
+        // a group a line, each a scope deeper than the last
+        let text = "(\n".repeat(20);
+        let tracker = Arc::new(Mutex::new(ScopeTracker::with_soft_cap(10)));
+        let mut view = MockView::new("Test", &text);
+        let mut view_state = ViewState::new(Arc::clone(&tracker));
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        assert_eq!(view.scopes.len(), 10 + 1);
+        let overflow = vec![OVERFLOW_SCOPE.to_owned()];
+        assert_eq!(view.scopes[10], overflow);
+        let scopes = resolved_scopes(&view);
+        assert_eq!(scopes[0].as_ref().map(Vec::len), Some(2));
+        assert_eq!(scopes.last().unwrap(), &Some(overflow));
+        assert_scopes_consistent(&view, &tracker);
+    }
+
+    #[test]
+    fn huge_scopes_are_sent_whole() {
+        let text = "a!b\n";
+        let mut view = MockView::new("Test", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        assert_eq!(scope_at(&view, 1).map(|scope| scope.len()), Some(1 + HUGE_SCOPE_LEN));
+        assert_eq!(scope_at(&view, 2), scope_at(&view, 0));
+        assert_covered_once(&view, 0, 0, text.len());
+        assert_scopes_consistent(&view, &view_state.scope_tracker);
+    }
+
+    #[test]
+    fn views_share_scope_ids() {
+        let tracker = Arc::new(Mutex::new(ScopeTracker::new()));
+        let mut rust_view = MockView::new("Rust", RUST_TEXT);
+        let mut rust_state = ViewState::new(Arc::clone(&tracker));
+        rust_state.do_highlighting(&mut rust_view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut rust_state, &mut rust_view);
+
+        let mut plain_view = MockView::new("Plain Text", RUST_TEXT);
+        let mut plain_state = ViewState::new(Arc::clone(&tracker));
+        plain_state.do_highlighting(
+            &mut plain_view,
+            &ParserRegistry::default(),
+            &mut HashMap::new(),
+        );
+        run_idle(&mut plain_state, &mut plain_view);
+
+        // the plain text view is also told about the Rust scopes, so that
+        // its table lines up with the shared ids
+        let rust_scopes: Vec<Scope> =
+            RustParser::new().scopes().iter().map(|scope| scope_map::translate(scope)).collect();
+        assert!(plain_view.scopes.starts_with(&rust_scopes));
+        assert_eq!(plain_view.scopes.len(), rust_scopes.len() + 1);
+        assert_scopes_consistent(&rust_view, &tracker);
+        assert_scopes_consistent(&plain_view, &tracker);
+
+        // switching the second view to Rust uses the ids the Rust view's
+        // scopes already have, so there is nothing new to announce
+        let rust_spans = span_scopes(&rust_view);
+        plain_view.set_language("Rust");
+        let updates_before = plain_view.updates.len();
+        plain_state.do_highlighting(
+            &mut plain_view,
+            &ParserRegistry::default(),
+            &mut HashMap::new(),
+        );
+        run_idle(&mut plain_state, &mut plain_view);
+        let switched: Vec<Scope> = plain_view.updates[updates_before..]
+            .iter()
+            .flat_map(|u| u.spans.iter())
+            .map(|s| plain_view.scopes[s.scope_id as usize].clone())
+            .collect();
+        assert_eq!(switched, rust_spans);
+        assert_eq!(plain_view.scopes.len(), rust_scopes.len() + 1);
+        assert_scopes_consistent(&plain_view, &tracker);
+
+        // nor does switching back and forth again
+        let issued = tracker.lock().unwrap().len();
+        for language in &["Plain Text", "Rust", "Plain Text", "Rust"] {
+            plain_view.set_language(language);
+            plain_state.do_highlighting(
+                &mut plain_view,
+                &ParserRegistry::default(),
+                &mut HashMap::new(),
+            );
+            run_idle(&mut plain_state, &mut plain_view);
+        }
+        assert_eq!(tracker.lock().unwrap().len(), issued);
+        assert_eq!(plain_view.scopes.len(), rust_scopes.len() + 1);
+
+        // the Rust view learns about the other view's scope only once it
+        // next flushes, and then exactly once
+        assert_eq!(rust_view.scopes.len(), rust_scopes.len());
+        rust_state.do_highlighting(&mut rust_view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut rust_state, &mut rust_view);
+        assert_eq!(rust_view.scopes, plain_view.scopes);
+        assert_eq!(span_scopes(&rust_view), rust_spans);
+        assert_scopes_consistent(&rust_view, &tracker);
+    }
+
+    /// A Rust parser that counts how often it is asked for a scope.
+    struct ScopeCountingParser(RustParser, Arc<AtomicUsize>);
+
+    impl Parser for ScopeCountingParser {
+        fn name(&self) -> &'static str {
+            "Scope counting"
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.get_scope_for_state(state)
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            self.0.parse(text, state)
+        }
+
+        fn stack_stats(&self) -> statestack::Stats {
+            self.0.stack_stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.0.shrink_to_fit()
+        }
+    }
+
+    #[test]
+    fn scopes_are_built_once_per_state() {
+        let text = RUST_TEXT.repeat(20);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut parsers = HashMap::new();
+        let parser = ScopeCountingParser(RustParser::new(), Arc::clone(&calls));
+        parsers.insert(LanguageId::from("Rust"), share(Box::new(parser)));
+        let mut view = MockView::new("Rust", &text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut parsers);
+        run_idle(&mut view_state, &mut view);
+
+        let spans: usize = view.updates.iter().map(|u| u.spans.len()).sum();
+        let calls = calls.load(Ordering::Relaxed);
+        assert_eq!(calls, view_state.state_scopes.len());
+        assert!(calls * 10 < spans, "{} calls for {} spans", calls, spans);
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+        // each cached id is the one the tracker gives the state's scope
+        let tracker = view_state.scope_tracker.clone();
+        for (state, id) in view_state.state_scopes.iter() {
+            let scope =
+                view_state.sent_scope(view_state.parser.borrow().get_scope_for_state(state));
+            assert_eq!(tracker.lock().unwrap().lookup(&scope), LookupResult::Existing(id));
+        }
+
+        // a new parser's states mean something else
+        let plain = share(Box::new(PlaintextParser::new()));
+        view_state.set_parser(LanguageId::from("Plain Text"), plain);
+        assert!(view_state.state_scopes.is_empty());
+    }
+
+    /// A Rust parser that claims to parse whole documents, and notes what
+    /// it is handed; and whether it says its states outlive an edit.
+    struct DocumentParser(RustParser, Arc<Mutex<Vec<&'static str>>>, bool);
+
+    impl Parser for DocumentParser {
+        fn name(&self) -> &'static str {
+            "Document"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities { parses_documents: true, ..self.0.capabilities() }
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            self.0.get_scope_for_state(state)
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            self.0.parse(text, state)
+        }
+
+        fn parse_document(&mut self, _text: &str) {
+            self.1.lock().unwrap().push("document");
+        }
+
+        fn apply_delta(&mut self, _delta: &RopeDelta, _text: &str) -> bool {
+            self.1.lock().unwrap().push("delta");
+            self.2
+        }
+
+        fn stack_stats(&self) -> statestack::Stats {
+            self.0.stack_stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.0.shrink_to_fit()
+        }
+    }
+
+    #[test]
+    fn document_parsers_are_told_of_each_edit() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut parsers = HashMap::new();
+        let parser = DocumentParser(RustParser::new(), Arc::clone(&calls), true);
+        parsers.insert(LanguageId::from("Rust"), share(Box::new(parser)));
+        let mut view = MockView::new("Rust", RUST_TEXT);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut parsers);
+        run_idle(&mut view_state, &mut view);
+        apply_edit(&mut view_state, &mut view, (0, 0, "// new\n"));
+        view_state.update(&mut view, None, false);
+        assert_eq!(*calls.lock().unwrap(), ["document", "delta", "document"]);
+
+        // edits while disabled can't be applied later
+        calls.lock().unwrap().clear();
+        view_state.disable(&mut view);
+        apply_edit(&mut view_state, &mut view, (0, 0, "// newer\n"));
+        view_state.enable(&mut view, &ParserRegistry::default(), &mut parsers);
+        apply_edit(&mut view_state, &mut view, (0, 0, "// newest\n"));
+        assert_eq!(*calls.lock().unwrap(), ["document", "delta"]);
+
+        // and each view has a parser of its own
+        let mut registry = ParserRegistry::new();
+        registry.register("Documents", || {
+            Box::new(DocumentParser(RustParser::new(), Arc::default(), true))
+        });
+        let mut parsers = HashMap::new();
+        let mut view = MockView::new("Documents", RUST_TEXT);
+        ViewState::new(Arc::default()).do_highlighting(&mut view, &registry, &mut parsers);
+        assert!(parsers.is_empty());
+    }
+
+    #[test]
+    fn states_a_parser_starts_over_are_forgotten() {
+        let text = "fn main() {\n    let x = 1;\n    // comment\n}\n";
+        let mut parsers = HashMap::new();
+        let parser = DocumentParser(RustParser::new(), Arc::default(), false);
+        parsers.insert(LanguageId::from("Rust"), share(Box::new(parser)));
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut parsers);
+        run_idle(&mut view_state, &mut view);
+
+        let clears = view.cache_clears;
+        apply_edit(&mut view_state, &mut view, (11, 0, "/*"));
+        assert_eq!(view.cache_clears, clears + 1);
+        let text = view.text().to_string();
+        assert_eq!(resolved_scopes(&view), highlight_from_scratch("Rust", &text));
+    }
+
+    /// Counts each thread's allocations, and the bytes it has live, so that
+    /// tests running alongside don't show up in each other's counts.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+        // signed, as a thread may free what another allocated
+        static LIVE_BYTES: std::cell::Cell<isize> = const { std::cell::Cell::new(0) };
+        static PEAK_BYTES: std::cell::Cell<isize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            // the counts are gone while the thread is torn down
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            let _ = LIVE_BYTES.try_with(|live| {
+                live.set(live.get() + layout.size() as isize);
+                let _ = PEAK_BYTES.try_with(|peak| peak.set(peak.get().max(live.get())));
+            });
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            let _ = LIVE_BYTES.try_with(|live| live.set(live.get() - layout.size() as isize));
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
+
+    /// Starts measuring the peak from the bytes live now, returning them.
+    fn reset_peak_bytes() -> isize {
+        let live = LIVE_BYTES.with(|live| live.get());
+        PEAK_BYTES.with(|peak| peak.set(live));
+        live
+    }
+
+    fn peak_bytes() -> isize {
+        PEAK_BYTES.with(|peak| peak.get())
+    }
+
+    /// Opening the first view is most of what the plugin does before it
+    /// first answers core; far under this even in a debug build.
+    const STARTUP_BUDGET_MS: u64 = 100;
+
+    #[test]
+    fn startup_makes_only_the_parser_it_needs() {
+        let best = (0..5).map(|_| bench::startup()).min().unwrap();
+        assert!(best < Duration::from_millis(STARTUP_BUDGET_MS), "startup took {:?}", best);
+
+        let mut plugin = LangPlugin::new();
+        let mut view = MockView::new("Plain Text", "text\n");
+        let view_state = plugin.open_view(&mut view, &ConfigTable::new());
+        assert_eq!(view_state.parser_name(), "Plain Text");
+        let made: Vec<_> = plugin.parsers.keys().map(|language| language.as_ref()).collect();
+        assert_eq!(made, ["Plain Text"]);
+    }
+
+    #[test]
+    fn views_open_with_their_config() {
+        let mut plugin = LangPlugin::new();
+        let mut view = MockView::new("Svelte", RUST_TEXT);
+        let config = json!({
+            "syntax_highlighting": false,
+            "experimental_lang": {
+                "language_overrides": { "Svelte": "Rust" },
+                "indent_guides": true,
+            },
+        });
+        let mut view_state = plugin.open_view(&mut view, config.as_object().unwrap());
+        assert_eq!(view_state.parser_language, Some("Rust".to_owned()));
+        assert!(view_state.indent_guides);
+        run_idle(&mut view_state, &mut view);
+        assert!(view.updates.is_empty());
+    }
+
+    #[test]
+    fn lexing_reuses_its_buffers() {
+        let mut view_state = ViewState::new(Arc::default());
+        let parser = share(Box::new(PlaintextParser::new()));
+        view_state.set_parser(LanguageId::from("Plain Text"), parser);
+        view_state.indent_guides = true;
+        let lines = ["plain text\n", "    indented\n", "\t\ttabbed\n", "\n", "  two\n"];
+        let lex_and_flush = |view_state: &mut ViewState| {
+            for line in lines.iter().cycle().take(100) {
+                view_state.compute_syntax(line, 0, view_state.initial_state);
+                view_state.offset += line.len();
+            }
+            view_state.spans.clear();
+            view_state.spans_start = view_state.offset;
+        };
+
+        // the first batch finds the scopes and sizes the buffers
+        lex_and_flush(&mut view_state);
+        let before = allocations();
+        for _ in 0..10 {
+            lex_and_flush(&mut view_state);
+        }
+        assert_eq!(allocations() - before, 0);
+    }
+
+    #[test]
+    fn flushing_formats_nothing_while_not_tracing() {
+        assert!(!xi_trace::is_enabled());
+        // long enough for every flush below
+        let mut view = MockView::new("Rust", &" ".repeat(20_000));
+        view.record_spans = false;
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.verbose_trace = true;
+        // the scope the spans have, announced with the first flush
+        let scope_tracker = Arc::clone(&view_state.scope_tracker);
+        view_state.identifier_for_state(State::default(), &mut TrackerLock::new(&scope_tracker));
+        let spans = |count: usize| -> Vec<ScopeSpan> {
+            (0..count).map(|i| ScopeSpan { start: i, end: i + 1, scope_id: 0 }).collect()
+        };
+        let many = spans(10_000);
+        let region = Interval::new(0, many.len());
+        let made = allocations();
+        trace_spans("flushing spans", region, &many, false);
+        trace_spans("flushing spans", region, &many, true);
+        assert_eq!(allocations(), made);
+
+        // a flush allocates as much for ten thousand spans as for ten
+        let mut flush = |view_state: &mut ViewState, spans: Vec<ScopeSpan>| {
+            view_state.offset += spans.len();
+            view_state.spans = spans;
+            let before = allocations();
+            view_state.flush_spans(&mut view);
+            allocations() - before
+        };
+        flush(&mut view_state, spans(10));
+        assert_eq!(flush(&mut view_state, many), flush(&mut view_state, spans(10)));
+    }
+
+    /// The most that highlighting a document of `HUGE_LINES` lines may
+    /// allocate at once, on top of its text: the state cache, a slice's
+    /// worth of spans and the parser's states, but nothing per line.
+    const MEMORY_BUDGET: isize = 8 * 1024 * 1024;
+    const HUGE_LINES: usize = 500_000;
+
+    #[test]
+    fn huge_documents_stay_within_the_memory_budget() {
+        // a log-like document, just under the highlighting limit
+        let text: String = (0..HUGE_LINES).map(|i| format!("let x = {};\n", i % 1000)).collect();
+        assert!(text.len() < MAX_HIGHLIGHT_BYTES);
+        let mut view = MockView::with_chunk_size("Rust", &text, 1024 * 1024);
+        view.record_spans = false;
+        let mut view_state = ViewState::new(Arc::default());
+
+        let before = reset_peak_bytes();
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        let peak = peak_bytes() - before;
+
+        assert!(view.get_frontier().is_none());
+        assert!(peak < MEMORY_BUDGET, "{} bytes at the peak", peak);
+        // too many lines to keep records of
+        assert_eq!(view_state.stats(&view)["memory"]["lines_recorded"], false);
+    }
+
+    #[test]
+    fn closing_views_releases_shared_state() {
+        let mut plugin = LangPlugin::new();
+        let text = RUST_TEXT.repeat(50);
+        let mut views = vec![
+            (ViewId::from(1), MockView::new("Rust", &text)),
+            (ViewId::from(2), MockView::new("Rust", RUST_TEXT)),
+        ];
+        for (view_id, view) in &mut views {
+            let mut view_state = ViewState::new(plugin.scope_tracker.clone());
+            view_state.idle_budget = Duration::from_secs(0);
+            view_state.do_highlighting(view, &plugin.registry, &mut plugin.parsers);
+            plugin.view_states.insert(*view_id, view_state);
+        }
+
+        // closed between two idle batches
+        let (first_id, first_view) = &mut views[0];
+        plugin.view_states.get_mut(first_id).unwrap().idle(first_view);
+        assert!(first_view.get_frontier().is_some());
+        let updates_before = first_view.updates.len();
+        plugin.close_view(*first_id);
+        assert!(!plugin.view_states.contains_key(first_id));
+        assert_eq!(first_view.updates.len(), updates_before);
+        // closing it again is harmless
+        plugin.close_view(*first_id);
+
+        // the other view still needs the parser and scopes
+        assert_eq!(plugin.view_states.len(), 1);
+        assert_eq!(plugin.parsers.len(), 1);
+        let (second_id, second_view) = &mut views[1];
+        run_idle(plugin.view_states.get_mut(second_id).unwrap(), second_view);
+        assert_eq!(resolved_scopes(second_view), highlight_from_scratch("Rust", RUST_TEXT));
+
+        plugin.close_view(*second_id);
+        assert!(plugin.view_states.is_empty());
+        assert!(plugin.parsers.is_empty());
+        assert!(plugin.scope_tracker.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_scope_cap_outlives_the_tracker() {
+        let mut plugin = LangPlugin::new();
+        let config = json!({ "experimental_lang": { "scope_soft_cap": 1 } });
+        plugin.apply_config(config.as_object().unwrap());
+        // the tracker is replaced once no view is open
+        plugin.close_view(ViewId::from(1));
+
+        let mut tracker = plugin.scope_tracker.lock().unwrap();
+        assert_eq!(tracker.lookup(&["a"]), LookupResult::New(0));
+        assert_eq!(tracker.lookup(&["b"]), LookupResult::New(1));
+        assert_eq!(tracker.lookup(&["c"]), LookupResult::Existing(1));
+    }
+
+    #[test]
+    fn views_of_a_language_share_a_parser() {
+        let registry = ParserRegistry::default();
+        let mut parsers = HashMap::new();
+        let tracker = Arc::new(Mutex::new(ScopeTracker::new()));
+        let other_text = "/* another\n file */ fn main() {}\n";
+        let mut views: Vec<_> =
+            [("Rust", RUST_TEXT), ("Rust", other_text), ("Plain Text", RUST_TEXT)]
+                .iter()
+                .map(|&(language, text)| {
+                    let mut view = MockView::new(language, text);
+                    let mut view_state = ViewState::new(Arc::clone(&tracker));
+                    view_state.do_highlighting(&mut view, &registry, &mut parsers);
+                    (view_state, view)
+                })
+                .collect();
+        assert_eq!(parsers.len(), 2);
+        assert!(Rc::ptr_eq(&views[0].0.parser, &views[1].0.parser));
+        assert!(!Rc::ptr_eq(&views[0].0.parser, &views[2].0.parser));
+
+        // interleaving the views' work doesn't disturb any of them
+        while views.iter_mut().any(|(_, view)| view.get_frontier().is_some()) {
+            for (view_state, view) in &mut views {
+                view_state.idle_budget = Duration::from_secs(0);
+                view_state.idle(view);
+            }
+        }
+        let (view_state, view) = &mut views[1];
+        apply_edit(view_state, view, (0, 0, "\"unterminated\n"));
+        for (view_state, view) in &mut views {
+            run_idle(view_state, view);
+            let text = view.text().to_string();
+            let language = view.get_language_id().as_ref().to_owned();
+            assert_eq!(resolved_scopes(view), highlight_from_scratch(&language, &text));
+        }
+
+        // a view leaving the language leaves the parser to the others
+        let (view_state, view) = &mut views[0];
+        view.set_language("Plain Text");
+        view_state.do_highlighting(view, &registry, &mut parsers);
+        assert!(Rc::ptr_eq(&views[0].0.parser, &views[2].0.parser));
+        assert_eq!(Rc::strong_count(&parsers[&LanguageId::from("Rust")]), 2);
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A value for each line, or piece of a long line, kept by offset so that
+//! edits move it along with the text.
+
+use std::mem;
+
+use xi_rope::spans::{Spans, SpansBuilder};
+use xi_rope::{Interval, RopeDelta};
+
+/// One span per line that has a value. Edits only break up the lines they
+/// touch, so a line's value is kept wherever in the document it moves.
+pub struct LineMap<T: Clone + Default> {
+    lines: Spans<T>,
+}
+
+impl<T: Clone + Default> LineMap<T> {
+    pub fn new(len: usize) -> LineMap<T> {
+        LineMap { lines: SpansBuilder::new(len).build() }
+    }
+
+    /// The length of the document the values are kept for.
+    pub fn doc_len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Rough estimate of the heap memory held for the values, in bytes.
+    pub fn estimated_bytes(&self) -> usize {
+        self.lines.iter().count() * mem::size_of::<(Interval, T)>()
+    }
+
+    /// Moves the values after an edit. Inserted text has none, and the
+    /// lines an edit touched are left in pieces, which never match a whole
+    /// line again.
+    pub fn apply_shape(&mut self, delta: &RopeDelta) {
+        self.lines.apply_shape(delta);
+    }
+
+    /// The value kept for exactly `iv`, if there is one.
+    pub fn get(&self, iv: Interval) -> Option<T> {
+        if iv.end() > self.lines.len() {
+            return None;
+        }
+        // a byte either side, as a line that goes on past `iv` would
+        // otherwise be cut down to it
+        let start = iv.start().saturating_sub(1);
+        let around = self.lines.subseq(Interval::new(start, (iv.end() + 1).min(self.lines.len())));
+        let line = Interval::new(iv.start() - start, iv.end() - start);
+        around.iter().find(|&(kept_for, _)| kept_for == line).map(|(_, value)| value.clone())
+    }
+
+    /// Keeps `value` for `iv`, in place of whatever overlapped it.
+    pub fn record(&mut self, iv: Interval, value: T) {
+        if iv.end() > self.lines.len() {
+            // kept for another version of the document
+            return;
+        }
+        let mut builder = SpansBuilder::new(iv.size());
+        builder.add_span(Interval::new(0, iv.size()), value);
+        self.lines.edit(iv, builder.build());
+    }
+
+    /// Forgets the values in `iv`.
+    pub fn forget(&mut self, iv: Interval) {
+        let iv = iv.intersect(Interval::new(0, self.lines.len()));
+        self.lines.edit(iv, SpansBuilder::new(iv.size()).build());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::Rope;
+
+    #[test]
+    fn only_whole_untouched_lines_have_values() {
+        let mut map = LineMap::new(30);
+        map.record(Interval::new(0, 10), 'a');
+        map.record(Interval::new(10, 20), 'b');
+        map.record(Interval::new(40, 50), 'x');
+        assert_eq!(map.get(Interval::new(10, 20)), Some('b'));
+        assert_eq!(map.get(Interval::new(10, 15)), None);
+        assert_eq!(map.get(Interval::new(5, 20)), None);
+        assert_eq!(map.get(Interval::new(20, 30)), None);
+
+        // an edit in the first line moves the second
+        let delta = RopeDelta::simple_edit(Interval::new(4, 5), Rope::from("xy"), 30);
+        map.apply_shape(&delta);
+        assert_eq!(map.doc_len(), 31);
+        assert_eq!(map.get(Interval::new(0, 11)), None);
+        assert_eq!(map.get(Interval::new(11, 21)), Some('b'));
+
+        map.forget(Interval::new(15, 40));
+        assert_eq!(map.get(Interval::new(11, 21)), None);
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Foldable regions and declarations, worked out from what the parser
+//! said about each line as the main pass lexed it.
+
+use std::mem;
+
+use serde_json::{json, Value};
+use xi_rope::spans::{Spans, SpansBuilder};
+use xi_rope::{Interval, RopeDelta};
+
+use crate::parser::{FoldHint, FoldKind, LineFolding, Symbol};
+use crate::view::HighlightView;
+use crate::ViewState;
+
+/// A foldable run of lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fold {
+    pub start_line: usize,
+    /// The last line of the fold, inclusive.
+    pub end_line: usize,
+    pub kind: FoldKind,
+}
+
+/// A declaration in an outline, with those nested in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolNode {
+    pub symbol: Symbol,
+    pub line: usize,
+    pub children: Vec<SymbolNode>,
+}
+
+/// What the outline needs to know about a line.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct LineInfo {
+    folding: LineFolding,
+    symbol: Option<Symbol>,
+    /// Blank lines have no indentation of their own.
+    indent: Option<usize>,
+}
+
+/// What the parser said about each line lexed, as one span per line.
+/// Keeping it by offset lets edits move the lines below them, which
+/// aren't lexed again once their states converge.
+pub struct Outline {
+    lines: Spans<LineInfo>,
+}
+
+impl Outline {
+    pub fn new(len: usize) -> Outline {
+        Outline { lines: SpansBuilder::new(len).build() }
+    }
+
+    /// The length of the document the lines are kept for.
+    pub fn doc_len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Moves the lines after an edit. The lines it touched are left in
+    /// pieces until they are lexed again.
+    pub fn apply_shape(&mut self, delta: &RopeDelta) {
+        self.lines.apply_shape(delta);
+    }
+
+    /// Notes what the parser said about the line at `offset`.
+    pub fn record(
+        &mut self,
+        offset: usize,
+        line: &str,
+        folding: LineFolding,
+        symbol: Option<Symbol>,
+        tab_size: usize,
+    ) {
+        let iv = Interval::new(offset, offset + line.len());
+        self.record_indented(iv, folding, symbol, line_indent(line, tab_size));
+    }
+
+    /// Notes what the parser said about the line at `iv`, indented by
+    /// `indent`, for when the text is gone.
+    pub fn record_indented(
+        &mut self,
+        iv: Interval,
+        folding: LineFolding,
+        symbol: Option<Symbol>,
+        indent: Option<usize>,
+    ) {
+        if iv.end() > self.lines.len() {
+            // kept for another version of the document
+            return;
+        }
+        let mut builder = SpansBuilder::new(iv.size());
+        builder.add_span(Interval::new(0, iv.size()), LineInfo { folding, symbol, indent });
+        self.lines.edit(iv, builder.build());
+    }
+
+    /// Rough estimate of the heap memory held for the lines, in bytes.
+    pub fn estimated_bytes(&self) -> usize {
+        let symbol_bytes = |info: &LineInfo| info.symbol.as_ref().map_or(0, |s| s.name.capacity());
+        (self.lines.iter())
+            .map(|(_, info)| mem::size_of::<(Interval, LineInfo)>() + symbol_bytes(info))
+            .sum()
+    }
+
+    /// The folds among the lines before `end`, which must be the start of
+    /// a line. Folds still open there are left out unless `complete`, when
+    /// they end on the last line.
+    pub fn folds(&self, end: usize, complete: bool) -> Vec<Fold> {
+        let (lines, reached) = self.lines_before(end);
+        let complete = complete && reached;
+
+        let mut folds = Vec::new();
+        nesting_folds(&lines, complete, &mut folds);
+        run_folds(&lines, complete, &mut folds);
+        item_folds(&lines, complete, &mut folds);
+        folds.sort_by_key(|fold| (fold.start_line, std::cmp::Reverse(fold.end_line)));
+        folds
+    }
+
+    /// The declarations among the lines before `end`, nested by their
+    /// indentation, as the declarations in an `impl` are.
+    pub fn symbols(&self, end: usize) -> Vec<SymbolNode> {
+        let (lines, _) = self.lines_before(end);
+        let mut roots = Vec::new();
+        // the declarations that may still get children, innermost last
+        let mut open: Vec<(usize, SymbolNode)> = Vec::new();
+        for (line_num, line) in lines.iter().enumerate() {
+            let (symbol, indent) = match (&line.symbol, line.indent) {
+                (Some(symbol), Some(indent)) => (symbol, indent),
+                _ => continue,
+            };
+            while open.last().map_or(false, |&(open_indent, _)| open_indent >= indent) {
+                close_symbol(&mut open, &mut roots);
+            }
+            let node = SymbolNode { symbol: symbol.clone(), line: line_num, children: Vec::new() };
+            open.push((indent, node));
+        }
+        while !open.is_empty() {
+            close_symbol(&mut open, &mut roots);
+        }
+        roots
+    }
+
+    /// The lines recorded before `end`, which must be the start of a line,
+    /// from the first on, and whether they reach it. They stop early at a
+    /// line not lexed again since an edit.
+    fn lines_before(&self, end: usize) -> (Vec<&LineInfo>, bool) {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        for (iv, info) in self.lines.iter() {
+            if iv.start() != offset || iv.end() > end {
+                break;
+            }
+            lines.push(info);
+            offset = iv.end();
+        }
+        (lines, offset == end)
+    }
+}
+
+/// The width of `line`'s indentation, in columns; blank lines have none.
+pub fn line_indent(line: &str, tab_size: usize) -> Option<usize> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    let width = |c: char| if c == '\t' { tab_size } else { 1 };
+    Some(line.chars().take_while(|&c| c == ' ' || c == '\t').map(width).sum())
+}
+
+/// Moves the innermost open declaration into the one around it, or into
+/// `roots` if there is none.
+fn close_symbol(open: &mut Vec<(usize, SymbolNode)>, roots: &mut Vec<SymbolNode>) {
+    if let Some((_, node)) = open.pop() {
+        match open.last_mut() {
+            Some((_, parent)) => parent.children.push(node),
+            None => roots.push(node),
+        }
+    }
+}
+
+/// A fold starts on the line above one nested deeper, and ends on the last
+/// line before the nesting returns to where it started.
+fn nesting_folds(lines: &[&LineInfo], complete: bool, folds: &mut Vec<Fold>) {
+    // the line each open fold started on, with its depth and kind
+    let mut open: Vec<(usize, usize, FoldKind)> = Vec::new();
+    for (line_num, line) in lines.iter().enumerate().skip(1) {
+        let depth = line.folding.depth;
+        while let Some(&(start_line, start_depth, kind)) = open.last() {
+            if start_depth < depth {
+                break;
+            }
+            open.pop();
+            folds.push(Fold { start_line, end_line: line_num - 1, kind });
+        }
+        let above = lines[line_num - 1].folding.depth;
+        if depth > above {
+            open.push((line_num - 1, above, line.folding.kind));
+        }
+    }
+    if complete && !lines.is_empty() {
+        let end_line = lines.len() - 1;
+        folds.extend(open.into_iter().map(|(start_line, _, kind)| Fold {
+            start_line,
+            end_line,
+            kind,
+        }));
+    }
+}
+
+/// Consecutive lines with the same run hint fold together.
+fn run_folds(lines: &[&LineInfo], complete: bool, folds: &mut Vec<Fold>) {
+    let mut line_num = 0;
+    while line_num < lines.len() {
+        let kind = match lines[line_num].folding.hint {
+            Some(FoldHint::Run(kind)) => kind,
+            _ => {
+                line_num += 1;
+                continue;
+            }
+        };
+        let start_line = line_num;
+        while line_num < lines.len() && lines[line_num].folding.hint == Some(FoldHint::Run(kind)) {
+            line_num += 1;
+        }
+        // the run may carry on into lines not lexed yet
+        let known = complete || line_num < lines.len();
+        if line_num - start_line > 1 && known {
+            folds.push(Fold { start_line, end_line: line_num - 1, kind });
+        }
+    }
+}
+
+/// A line with an item hint folds together with the item after it, which
+/// ends on the first line at the hinted line's indentation or less that
+/// ends an item. Lines indented deeper are inside it.
+fn item_folds(lines: &[&LineInfo], complete: bool, folds: &mut Vec<Fold>) {
+    for (start_line, line) in lines.iter().enumerate() {
+        let (kind, indent) = match (line.folding.hint, line.indent) {
+            (Some(FoldHint::Item(kind)), Some(indent)) => (kind, indent),
+            _ => continue,
+        };
+        let end = lines[start_line + 1..].iter().position(|line| match line.indent {
+            Some(line_indent) => line_indent <= indent && line.folding.ends_item,
+            None => false,
+        });
+        let end_line = match end {
+            Some(end) => start_line + 1 + end,
+            None if complete && start_line + 1 < lines.len() => lines.len() - 1,
+            None => continue,
+        };
+        folds.push(Fold { start_line, end_line, kind });
+    }
+}
+
+/// Folds and declarations, as the main pass records them and the
+/// `folds` and `symbols` commands ask for them.
+impl ViewState {
+    /// Notes what the parser says about folding and declarations on `line`,
+    /// which the main pass is about to lex, if it says anything at all.
+    pub fn record_outline(&mut self, line: &str) {
+        let capabilities = self.capabilities();
+        if !capabilities.supports_folding && !capabilities.reports_symbols {
+            return;
+        }
+        let parser = self.parser.borrow();
+        let folding = if capabilities.supports_folding {
+            parser.line_folding(line, self.initial_state)
+        } else {
+            LineFolding::default()
+        };
+        let symbol = if capabilities.reports_symbols {
+            parser.line_symbol(line, self.initial_state)
+        } else {
+            None
+        };
+        self.outline.record(self.offset, line, folding, symbol, self.tab_size);
+    }
+
+    /// The offset the main pass has finished the lines up to.
+    fn finished_offset<V: HighlightView>(view: &mut V) -> usize {
+        match view.get_frontier() {
+            Some(line_num) => view.offset_of_line(line_num).unwrap_or(0),
+            None => view.get_buf_size(),
+        }
+    }
+
+    /// The folds among the lines the main pass has finished, as JSON. Folds
+    /// still open at the frontier are left out until it gets past their
+    /// end, or reaches the end of the document.
+    pub fn folds<V: HighlightView>(&mut self, view: &mut V) -> Value {
+        if !self.is_highlighting() || !self.capabilities().supports_folding {
+            return json!([]);
+        }
+        let end = Self::finished_offset(view);
+        let folds = self.outline.folds(end, view.get_frontier().is_none());
+        let folds: Vec<Value> = folds
+            .iter()
+            .map(|fold| {
+                json!({
+                    "start_line": fold.start_line,
+                    "end_line": fold.end_line,
+                    "kind": fold.kind.as_str(),
+                })
+            })
+            .collect();
+        Value::Array(folds)
+    }
+
+    /// The declarations among the lines the main pass has finished, nested
+    /// by indentation, as JSON.
+    pub fn symbols<V: HighlightView>(&mut self, view: &mut V) -> Value {
+        if !self.is_highlighting() || !self.capabilities().reports_symbols {
+            return json!([]);
+        }
+        let end = Self::finished_offset(view);
+        fn to_json(node: &SymbolNode) -> Value {
+            json!({
+                "name": node.symbol.name,
+                "kind": node.symbol.kind.as_str(),
+                "line": node.line,
+                "children": node.children.iter().map(to_json).collect::<Vec<_>>(),
+            })
+        }
+        Value::Array(self.outline.symbols(end).iter().map(to_json).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ParserRegistry;
+    use crate::view::mock::{apply_edit, run_idle, MockView};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Folds for lines given as their depth, hint, whether they end an
+    /// item, and their text, which is only looked at for indentation.
+    fn folds_of(lines: &[(usize, Option<FoldHint>, bool, &str)], complete: bool) -> Vec<Fold> {
+        let mut offset = 0;
+        let len = lines.iter().map(|line| line.3.len() + 1).sum();
+        let mut outline = Outline::new(len);
+        for &(depth, hint, ends_item, text) in lines {
+            let text = format!("{}\n", text);
+            let folding = LineFolding { depth, kind: FoldKind::Comment, hint, ends_item };
+            outline.record(offset, &text, folding, None, 4);
+            offset += text.len();
+        }
+        outline.folds(len, complete)
+    }
+
+    fn fold(start_line: usize, end_line: usize, kind: FoldKind) -> Fold {
+        Fold { start_line, end_line, kind }
+    }
+
+    #[test]
+    fn nested_folds() {
+        let lines = &[
+            (0, None, false, "/*"),
+            (1, None, false, "/*"),
+            (2, None, false, ""),
+            (2, None, false, "*/"),
+            (1, None, false, "*/"),
+            (0, None, false, "x"),
+        ];
+        let expected = vec![fold(0, 4, FoldKind::Comment), fold(1, 3, FoldKind::Comment)];
+        assert_eq!(folds_of(lines, true), expected);
+    }
+
+    #[test]
+    fn folds_open_at_the_end() {
+        let lines = &[(0, None, false, "x"), (0, None, false, "/*"), (1, None, false, "")];
+        assert_eq!(folds_of(lines, true), vec![fold(1, 2, FoldKind::Comment)]);
+        // the document may close it further down
+        assert_eq!(folds_of(lines, false), vec![]);
+    }
+
+    #[test]
+    fn runs_and_items() {
+        let imports = Some(FoldHint::Run(FoldKind::Imports));
+        let cfg = Some(FoldHint::Item(FoldKind::Region));
+        let lines = &[
+            (0, imports, true, "use a;"),
+            (0, imports, true, "use b;"),
+            (0, None, false, ""),
+            (0, imports, true, "use c;"),
+            (0, cfg, false, "#[cfg(test)]"),
+            (0, None, false, "mod tests {"),
+            (0, imports, true, "    use super::*;"),
+            (0, None, true, "}"),
+            (0, cfg, false, "#[cfg(unix)]"),
+            (0, None, true, "fn f() {}"),
+        ];
+        let expected = vec![
+            fold(0, 1, FoldKind::Imports),
+            fold(4, 7, FoldKind::Region),
+            fold(8, 9, FoldKind::Region),
+        ];
+        assert_eq!(folds_of(lines, true), expected);
+    }
+
+    #[test]
+    fn edits_move_the_lines_below() {
+        let mut outline = Outline::new(6);
+        let comment = LineFolding { depth: 0, ..LineFolding::default() };
+        let nested = LineFolding { depth: 1, ..LineFolding::default() };
+        outline.record(0, "/*\n", comment, None, 4);
+        outline.record(3, "*/\n", nested, None, 4);
+        assert_eq!(outline.folds(6, true), vec![fold(0, 1, FoldKind::Region)]);
+
+        let delta = RopeDelta::simple_edit(Interval::new(0, 0), "x\n".into(), 6);
+        outline.apply_shape(&delta);
+        // the new line isn't known yet
+        assert_eq!(outline.folds(8, true), vec![]);
+        outline.record(0, "x\n", LineFolding::default(), None, 4);
+        assert_eq!(outline.folds(8, true), vec![fold(1, 2, FoldKind::Region)]);
+    }
+
+    #[test]
+    fn symbols_nest_by_indentation() {
+        use crate::parser::SymbolKind;
+        let text = [
+            ("struct Point;", Some((SymbolKind::Struct, "Point"))),
+            ("", None),
+            ("impl Point {", Some((SymbolKind::Impl, "Point"))),
+            ("    fn new() -> Point {", Some((SymbolKind::Function, "new"))),
+            ("        fn helper() {}", Some((SymbolKind::Function, "helper"))),
+            ("    }", None),
+            ("", None),
+            ("    fn x(&self) {}", Some((SymbolKind::Function, "x"))),
+            ("}", None),
+            ("mod tests {", Some((SymbolKind::Module, "tests"))),
+        ];
+        let len = text.iter().map(|line| line.0.len() + 1).sum();
+        let mut outline = Outline::new(len);
+        let mut offset = 0;
+        for (line, symbol) in &text {
+            let line = format!("{}\n", line);
+            let symbol = symbol.map(|(kind, name)| Symbol { kind, name: name.to_owned() });
+            outline.record(offset, &line, LineFolding::default(), symbol, 4);
+            offset += line.len();
+        }
+
+        let node = |line: usize, children: Vec<SymbolNode>| {
+            let (kind, name) = text[line].1.unwrap();
+            SymbolNode { symbol: Symbol { kind, name: name.to_owned() }, line, children }
+        };
+        let expected = vec![
+            node(0, vec![]),
+            node(2, vec![node(3, vec![node(4, vec![])]), node(7, vec![])]),
+            node(9, vec![]),
+        ];
+        assert_eq!(outline.symbols(len), expected);
+        // only as far as the lines known
+        assert_eq!(outline.symbols(len - "mod tests {\n".len()), expected[..2].to_vec());
+    }
+
+    #[test]
+    fn folds_follow_edits() {
+        let text = "use a;\nuse b;\n/* one\n   two\n*/\n#[cfg(test)]\nmod tests {\n}\n";
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        assert_eq!(view_state.folds(&mut view), json!([]));
+        run_idle(&mut view_state, &mut view);
+        let folds = json!([
+            { "start_line": 0, "end_line": 1, "kind": "imports" },
+            { "start_line": 2, "end_line": 4, "kind": "comment" },
+            { "start_line": 5, "end_line": 7, "kind": "region" },
+        ]);
+        assert_eq!(view_state.folds(&mut view), folds);
+
+        // an edit inside a fold leaves it be
+        let offset = text.find("two").unwrap();
+        apply_edit(&mut view_state, &mut view, (offset, 0, "and "));
+        assert_eq!(view_state.folds(&mut view), folds);
+
+        // and one above it moves it down
+        apply_edit(&mut view_state, &mut view, (0, 0, "\n"));
+        let moved = json!([
+            { "start_line": 1, "end_line": 2, "kind": "imports" },
+            { "start_line": 3, "end_line": 5, "kind": "comment" },
+            { "start_line": 6, "end_line": 8, "kind": "region" },
+        ]);
+        assert_eq!(view_state.folds(&mut view), moved);
+    }
+
+    #[test]
+    fn symbols_outline_the_declarations() {
+        let text = r#"//! A module.
+use std::fmt;
+
+/// fn not_a_function() {}
+pub struct Point {
+    x: i32,
+}
+
+impl Point {
+    pub const ORIGIN: Point = Point { x: 0 };
+
+    pub fn new(x: i32) -> Point {
+        let s = "fn nor_this() {}";
+        Point { x }
+    }
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn it_works() {}
+}
+"#;
+        let mut view = MockView::new("Rust", text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, &ParserRegistry::default(), &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+
+        let expected = json!([
+            { "name": "Point", "kind": "struct", "line": 4, "children": [] },
+            { "name": "Point", "kind": "impl", "line": 8, "children": [
+                { "name": "ORIGIN", "kind": "constant", "line": 9, "children": [] },
+                { "name": "new", "kind": "function", "line": 11, "children": [] },
+            ] },
+            { "name": "fmt::Display for Point", "kind": "impl", "line": 17, "children": [
+                { "name": "fmt", "kind": "function", "line": 18, "children": [] },
+            ] },
+            { "name": "tests", "kind": "module", "line": 24, "children": [
+                { "name": "it_works", "kind": "function", "line": 26, "children": [] },
+            ] },
+        ]);
+        assert_eq!(view_state.symbols(&mut view), expected);
+
+        // editing a method leaves the outline as it was
+        let offset = text.find("Point { x }").unwrap();
+        apply_edit(&mut view_state, &mut view, (offset, 0, "    "));
+        assert_eq!(view_state.symbols(&mut view), expected);
+    }
+}
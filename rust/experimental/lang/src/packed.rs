@@ -0,0 +1,308 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A compact form of a list of spans, for spans that are kept, hashed or
+//! written out rather than sent. A `ScopeSpan` is three words; here a span
+//! is the gap since the one before it ended and its length, as `u32`s, and
+//! the scope ids are kept as runs, as neighbouring spans often share one.
+//! Spans are only made `ScopeSpan`s again to be sent.
+
+use std::fmt;
+use std::iter;
+
+use xi_core_lib::plugins::rpc::ScopeSpan;
+
+/// Why spans couldn't be packed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackError {
+    /// A span ended before it started, or started before the start or
+    /// before the span before it ended.
+    Unordered,
+    /// A span, or the gap before it, was 4GiB or longer. A document small
+    /// enough to highlight has neither.
+    TooLong,
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackError::Unordered => write!(f, "spans out of order"),
+            PackError::TooLong => write!(f, "span or gap too long to pack"),
+        }
+    }
+}
+
+/// Spans in order, relative to a start given when they are packed and
+/// again when they are unpacked.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct PackedSpans {
+    /// For each span, the bytes between the end of the span before it, or
+    /// the start, and its start.
+    gaps: Vec<u32>,
+    lengths: Vec<u32>,
+    /// The scope ids of the spans, in order, each with how many spans in a
+    /// row have it.
+    runs: Vec<(u32, u32)>,
+    /// Where the last span ends, relative to the start.
+    end: usize,
+}
+
+impl PackedSpans {
+    pub fn new() -> PackedSpans {
+        PackedSpans::default()
+    }
+
+    /// Packs `spans`, which are relative to `start`'s offset.
+    pub fn pack(spans: &[ScopeSpan], start: usize) -> Result<PackedSpans, PackError> {
+        let mut packed = PackedSpans::new();
+        packed.repack(spans, start)?;
+        Ok(packed)
+    }
+
+    /// Packs `spans` in place of the spans here, keeping the memory they
+    /// had. Nothing is left on an error.
+    pub fn repack(&mut self, spans: &[ScopeSpan], start: usize) -> Result<(), PackError> {
+        self.clear();
+        for span in spans {
+            let pushed = match (span.start.checked_sub(start), span.end.checked_sub(start)) {
+                (Some(span_start), Some(span_end)) => {
+                    self.push(span_start, span_end, span.scope_id)
+                }
+                _ => Err(PackError::Unordered),
+            };
+            if let Err(err) = pushed {
+                self.clear();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a span from `start` to `end`, relative to the start, after the
+    /// spans here.
+    pub fn push(&mut self, start: usize, end: usize, scope_id: u32) -> Result<(), PackError> {
+        if start < self.end || end < start {
+            return Err(PackError::Unordered);
+        }
+        let (gap, length) = (start - self.end, end - start);
+        if gap > std::u32::MAX as usize || length > std::u32::MAX as usize {
+            return Err(PackError::TooLong);
+        }
+        self.gaps.push(gap as u32);
+        self.lengths.push(length as u32);
+        match self.runs.last_mut() {
+            Some((id, run)) if *id == scope_id && *run < std::u32::MAX => *run += 1,
+            _ => self.runs.push((scope_id, 1)),
+        }
+        self.end = end;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.gaps.clear();
+        self.lengths.clear();
+        self.runs.clear();
+        self.end = 0;
+    }
+
+    /// The number of spans.
+    pub fn len(&self) -> usize {
+        self.gaps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gaps.is_empty()
+    }
+
+    /// Where the last span ends, relative to the start.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// The spans, with the start at `start`.
+    pub fn iter(&self, start: usize) -> impl Iterator<Item = ScopeSpan> + '_ {
+        let scope_ids =
+            self.runs.iter().flat_map(|&(scope_id, run)| iter::repeat(scope_id).take(run as usize));
+        let mut offset = start;
+        self.gaps.iter().zip(&self.lengths).zip(scope_ids).map(
+            move |((&gap, &length), scope_id)| {
+                let start = offset + gap as usize;
+                offset = start + length as usize;
+                ScopeSpan { start, end: offset, scope_id }
+            },
+        )
+    }
+
+    /// The spans as they are sent, with the start at `start`.
+    pub fn unpack(&self, start: usize) -> Vec<ScopeSpan> {
+        self.iter(start).collect()
+    }
+
+    pub fn gaps(&self) -> &[u32] {
+        &self.gaps
+    }
+
+    pub fn lengths(&self) -> &[u32] {
+        &self.lengths
+    }
+
+    pub fn runs(&self) -> &[(u32, u32)] {
+        &self.runs
+    }
+
+    /// Spans from what `gaps`, `lengths` and `runs` returned, if those go
+    /// together.
+    pub fn from_parts(
+        gaps: Vec<u32>,
+        lengths: Vec<u32>,
+        runs: Vec<(u32, u32)>,
+    ) -> Option<PackedSpans> {
+        let spans = runs.iter().try_fold(0usize, |spans, &(_, run)| match run {
+            0 => None,
+            run => spans.checked_add(run as usize),
+        })?;
+        if gaps.len() != spans || lengths.len() != spans {
+            return None;
+        }
+        let end = gaps.iter().zip(&lengths).try_fold(0usize, |end, (&gap, &length)| {
+            end.checked_add(gap as usize)?.checked_add(length as usize)
+        })?;
+        Some(PackedSpans { gaps, lengths, runs, end })
+    }
+
+    /// Rough estimate of the heap memory held for the spans, in bytes.
+    pub fn estimated_bytes(&self) -> usize {
+        (self.gaps.capacity() + self.lengths.capacity()) * 4 + self.runs.capacity() * 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize, end: usize, scope_id: u32) -> ScopeSpan {
+        ScopeSpan { start, end, scope_id }
+    }
+
+    /// `ScopeSpan` can't be compared, so tests compare these.
+    fn triples(spans: &[ScopeSpan]) -> Vec<(usize, usize, u32)> {
+        spans.iter().map(|span| (span.start, span.end, span.scope_id)).collect()
+    }
+
+    /// Packs and unpacks `spans` at `start`, and again through the parts.
+    fn round_trip(spans: &[ScopeSpan], start: usize) {
+        let packed = PackedSpans::pack(spans, start).unwrap();
+        assert_eq!(triples(&packed.unpack(start)), triples(spans));
+        assert_eq!(packed.len(), spans.len());
+        assert_eq!(packed.end(), spans.last().map_or(0, |span| span.end - start));
+        let parts = (packed.gaps().to_vec(), packed.lengths().to_vec(), packed.runs().to_vec());
+        assert_eq!(PackedSpans::from_parts(parts.0, parts.1, parts.2), Some(packed));
+    }
+
+    #[test]
+    fn every_small_list_round_trips() {
+        // every list of up to three spans with short gaps and lengths, and
+        // two scope ids, at the start and further in
+        let mut lists = vec![Vec::new()];
+        for _ in 0..3 {
+            let mut longer = Vec::new();
+            for list in &lists {
+                let end = list.last().map_or(0, |span: &ScopeSpan| span.end);
+                for gap in 0..2 {
+                    for length in 0..3 {
+                        for scope_id in 0..2 {
+                            let mut list = list.clone();
+                            list.push(span(end + gap, end + gap + length, scope_id));
+                            longer.push(list);
+                        }
+                    }
+                }
+            }
+            for list in &lists {
+                for start in &[0, 7] {
+                    let moved: Vec<_> = list
+                        .iter()
+                        .map(|s| span(s.start + start, s.end + start, s.scope_id))
+                        .collect();
+                    round_trip(&moved, *start);
+                }
+            }
+            lists = longer;
+        }
+        assert_eq!(lists.len(), 12 * 12 * 12);
+        for list in &lists {
+            round_trip(list, 0);
+        }
+    }
+
+    #[test]
+    fn repeated_scopes_are_runs() {
+        let spans = [span(0, 2, 5), span(2, 3, 5), span(3, 3, 5), span(4, 9, 1), span(9, 10, 5)];
+        let packed = PackedSpans::pack(&spans, 0).unwrap();
+        assert_eq!(packed.runs(), &[(5, 3), (1, 1), (5, 1)]);
+        assert_eq!(packed.gaps(), &[0, 0, 0, 1, 0]);
+        assert_eq!(packed.lengths(), &[2, 1, 0, 5, 1]);
+
+        // a run only goes so far
+        let mut packed = PackedSpans::new();
+        packed.push(0, 1, 5).unwrap();
+        packed.runs[0].1 = std::u32::MAX;
+        packed.push(1, 2, 5).unwrap();
+        assert_eq!(packed.runs(), &[(5, std::u32::MAX), (5, 1)]);
+    }
+
+    #[test]
+    fn pathological_spans_round_trip() {
+        // nothing but zero-length spans
+        round_trip(&[span(3, 3, 1), span(3, 3, 2), span(3, 3, 2), span(8, 8, 0)], 3);
+        // the longest gap and span there can be
+        let max = std::u32::MAX as usize;
+        round_trip(&[span(max, 2 * max, 1), span(3 * max, 3 * max, 2)], 0);
+        // at the very end of the address space
+        let top = std::usize::MAX - 10;
+        round_trip(&[span(top, top + 4, 1), span(top + 4, std::usize::MAX, std::u32::MAX)], top);
+        round_trip(&[span(std::usize::MAX, std::usize::MAX, 0)], std::usize::MAX);
+    }
+
+    #[test]
+    fn unpackable_spans_are_refused() {
+        let max = std::u32::MAX as usize;
+        let refused = |spans: &[ScopeSpan], start| PackedSpans::pack(spans, start).unwrap_err();
+        assert_eq!(refused(&[span(0, max + 1, 1)], 0), PackError::TooLong);
+        assert_eq!(refused(&[span(0, 1, 1), span(max + 2, max + 3, 1)], 0), PackError::TooLong);
+        assert_eq!(refused(&[span(5, 4, 1)], 0), PackError::Unordered);
+        assert_eq!(refused(&[span(0, 4, 1), span(3, 6, 1)], 0), PackError::Unordered);
+        assert_eq!(refused(&[span(2, 4, 1)], 3), PackError::Unordered);
+
+        // nothing is left behind
+        let mut packed = PackedSpans::pack(&[span(0, 3, 1)], 0).unwrap();
+        assert_eq!(packed.repack(&[span(0, 4, 1), span(3, 6, 1)], 0), Err(PackError::Unordered));
+        assert_eq!(packed, PackedSpans::new());
+        packed.repack(&[span(10, 12, 2)], 10).unwrap();
+        assert_eq!(triples(&packed.unpack(0)), [(0, 2, 2)]);
+    }
+
+    #[test]
+    fn parts_must_go_together() {
+        let parts = |gaps: &[u32], lengths: &[u32], runs: &[(u32, u32)]| {
+            PackedSpans::from_parts(gaps.to_vec(), lengths.to_vec(), runs.to_vec())
+        };
+        assert!(parts(&[0, 1], &[2, 3], &[(1, 2)]).is_some());
+        assert!(parts(&[0, 1], &[2, 3], &[(1, 1)]).is_none());
+        assert!(parts(&[0, 1], &[2], &[(1, 2)]).is_none());
+        assert!(parts(&[0], &[2], &[(1, 1), (2, 0)]).is_none());
+        assert!(parts(&[], &[], &[(1, std::u32::MAX), (1, std::u32::MAX)]).is_none());
+        assert_eq!(parts(&[], &[], &[]), Some(PackedSpans::new()));
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Picking how to show text by its scope, for highlighting outside of
+//! core. Rules are keyed by scope prefixes, as themes are: `string`
+//! applies to `string.quoted.double.rust`, but not to `strings`.
+
+use crate::scope_tracker::Scope;
+use crate::text::strip_prefix;
+
+/// Turns off every attribute.
+pub const ANSI_RESET: &str = "\x1b[0m";
+
+/// Names of the first eight colors; `bright-` before one names the eight
+/// after them.
+const COLOR_NAMES: &[&str] =
+    &["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// The built-in palette, in the 16 colors every terminal has.
+const DEFAULT_STYLES: &[(&str, &str)] = &[
+    ("comment", "dim"),
+    ("string", "green"),
+    ("constant", "yellow"),
+    ("constant.character.escape", "cyan"),
+    ("keyword", "magenta"),
+    ("storage", "magenta"),
+    ("entity.name", "blue"),
+    ("entity.name.type", "cyan"),
+    ("support", "cyan"),
+    ("markup.heading", "bold"),
+    ("invalid", "bold red"),
+];
+
+/// Values picked by scope. A scope stack is matched from its innermost
+/// scope out, and the first scope any rule applies to picks the value of
+/// the longest rule that applies to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeMap<T> {
+    rules: Vec<(String, T)>,
+}
+
+impl<T> Default for ScopeMap<T> {
+    fn default() -> ScopeMap<T> {
+        ScopeMap { rules: Vec::new() }
+    }
+}
+
+impl<T> ScopeMap<T> {
+    pub fn new() -> ScopeMap<T> {
+        ScopeMap::default()
+    }
+
+    /// Adds a rule for the scopes starting with `prefix`, replacing any
+    /// rule there was for it.
+    pub fn insert(&mut self, prefix: &str, value: T) {
+        match self.rules.iter_mut().find(|(p, _)| p == prefix) {
+            Some(rule) => rule.1 = value,
+            None => self.rules.push((prefix.to_owned(), value)),
+        }
+    }
+
+    pub fn lookup(&self, scope: &[String]) -> Option<&T> {
+        scope.iter().rev().find_map(|name| {
+            self.rules
+                .iter()
+                .filter(|(prefix, _)| applies_to(prefix, name))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, value)| value)
+        })
+    }
+
+    /// The rules, as their prefixes and values, in the order they were
+    /// first added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.rules.iter().map(|(prefix, value)| (prefix.as_str(), value))
+    }
+}
+
+/// Whether a rule for `prefix` applies to the scope `name`, which it does
+/// for whole dot-separated parts only.
+fn applies_to(prefix: &str, name: &str) -> bool {
+    match strip_prefix(name, prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.'),
+        None => false,
+    }
+}
+
+/// How text is shown in a terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    /// A color of the 256-color palette, whose first 16 are the terminal's
+    /// own colors; `None` leaves the color as it is.
+    pub foreground: Option<u8>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+impl Style {
+    /// Reads a style written as words, as in `bold red` or `dim 244`:
+    /// color names, with `bright-` for the brighter eight, color numbers,
+    /// and attributes. `plain` is the style that changes nothing.
+    pub fn parse(s: &str) -> Result<Style, String> {
+        let mut style = Style::default();
+        for word in s.split_whitespace() {
+            let (bright, name) = match strip_prefix(word, "bright-") {
+                Some(name) => (8, name),
+                None => (0, word),
+            };
+            if let Some(color) = COLOR_NAMES.iter().position(|&c| c == name) {
+                style.foreground = Some(color as u8 + bright);
+                continue;
+            }
+            match word {
+                "plain" => (),
+                "bold" => style.bold = true,
+                "dim" => style.dim = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                _ => match word.parse() {
+                    Ok(color) => style.foreground = Some(color),
+                    Err(_) => return Err(format!("unknown color or attribute: {}", word)),
+                },
+            }
+        }
+        Ok(style)
+    }
+
+    /// The escape sequence that turns the style on, or `None` if it is
+    /// plain.
+    pub fn ansi(&self) -> Option<String> {
+        let mut codes = Vec::new();
+        for &(on, code) in
+            &[(self.bold, "1"), (self.dim, "2"), (self.italic, "3"), (self.underline, "4")]
+        {
+            if on {
+                codes.push(code.to_owned());
+            }
+        }
+        match self.foreground {
+            Some(color @ 0..=7) => codes.push(format!("{}", 30 + color)),
+            Some(color @ 8..=15) => codes.push(format!("{}", 90 + color - 8)),
+            Some(color) => codes.push(format!("38;5;{}", color)),
+            None => (),
+        }
+        if codes.is_empty() {
+            None
+        } else {
+            Some(format!("\x1b[{}m", codes.join(";")))
+        }
+    }
+}
+
+/// The styles of scopes in a terminal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    styles: ScopeMap<Style>,
+}
+
+impl Default for Palette {
+    /// The built-in palette.
+    fn default() -> Palette {
+        let mut styles = ScopeMap::new();
+        for &(prefix, style) in DEFAULT_STYLES {
+            styles.insert(prefix, Style::parse(style).unwrap());
+        }
+        Palette { styles }
+    }
+}
+
+impl Palette {
+    /// Applies the styles in a palette file over the ones here. The file
+    /// is TOML, with a style for each scope prefix:
+    ///
+    /// ```toml
+    /// comment = "italic 244"
+    /// "entity.name" = "bold blue"
+    /// keyword = "plain"
+    /// ```
+    pub fn apply_toml(&mut self, source: &str) -> Result<(), String> {
+        let table = match source.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => return Err("a palette must be a table".to_owned()),
+            Err(err) => return Err(err.to_string()),
+        };
+        for (prefix, style) in table {
+            let style =
+                style.as_str().ok_or_else(|| format!("{}: style isn't a string", prefix))?;
+            let style = Style::parse(style).map_err(|err| format!("{}: {}", prefix, err))?;
+            self.styles.insert(&prefix, style);
+        }
+        Ok(())
+    }
+
+    /// The style of text in `scope`, if it has one.
+    pub fn style(&self, scope: &Scope) -> Option<&Style> {
+        self.styles.lookup(scope)
+    }
+
+    /// The style of each scope prefix.
+    pub fn styles(&self) -> &ScopeMap<Style> {
+        &self.styles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(names: &[&str]) -> Scope {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn longest_prefix_of_the_innermost_scope_wins() {
+        let mut map = ScopeMap::new();
+        map.insert("string", 1);
+        map.insert("string.quoted.double", 2);
+        map.insert("constant", 3);
+        map.insert("source", 4);
+        let lookup = |names: &[&str]| map.lookup(&scope(names)).cloned();
+        assert_eq!(lookup(&["source.rust", "string.quoted.single.rust"]), Some(1));
+        assert_eq!(lookup(&["source.rust", "string.quoted.double.rust"]), Some(2));
+        assert_eq!(lookup(&["source.rust", "string.quoted.double"]), Some(2));
+        // the inner scope picks, however long a rule for an outer one
+        assert_eq!(lookup(&["string.quoted.double.rust", "constant.character"]), Some(3));
+        // an inner scope no rule applies to is skipped
+        assert_eq!(lookup(&["source.rust", "string.quoted.double", "meta.item"]), Some(2));
+        assert_eq!(lookup(&["source.rust"]), Some(4));
+        // rules apply to whole parts of a name only
+        assert_eq!(lookup(&["strings.rust", "constants"]), None);
+        assert_eq!(lookup(&[]), None);
+
+        // a rule for the same prefix replaces the one there was
+        map.insert("string", 5);
+        assert_eq!(map.lookup(&scope(&["string.quoted.single"])), Some(&5));
+    }
+
+    #[test]
+    fn styles_are_words() {
+        let style = Style::parse("bold  red").unwrap();
+        assert_eq!(style, Style { foreground: Some(1), bold: true, ..Style::default() });
+        assert_eq!(style.ansi().unwrap(), "\x1b[1;31m");
+        assert_eq!(Style::parse("bright-cyan").unwrap().ansi().unwrap(), "\x1b[96m");
+        assert_eq!(Style::parse("dim italic 244").unwrap().ansi().unwrap(), "\x1b[2;3;38;5;244m");
+        assert_eq!(Style::parse("plain").unwrap().ansi(), None);
+        assert_eq!(Style::parse("").unwrap(), Style::default());
+        assert!(Style::parse("blink").is_err());
+        assert!(Style::parse("256").is_err());
+    }
+
+    #[test]
+    fn palette_files_apply_over_the_defaults() {
+        let mut palette = Palette::default();
+        let string = scope(&["source.rust", "string.quoted.double.rust"]);
+        let comment = scope(&["source.rust", "comment.line.rust"]);
+        assert_eq!(palette.style(&string).unwrap().foreground, Some(2));
+        palette.apply_toml("comment = \"italic 244\"\n\"string.quoted\" = \"plain\"\n").unwrap();
+        assert_eq!(palette.style(&string).unwrap().ansi(), None);
+        assert_eq!(palette.style(&comment).unwrap().ansi().unwrap(), "\x1b[3;38;5;244m");
+        assert_eq!(palette.style(&scope(&["string.unquoted"])).unwrap().foreground, Some(2));
+
+        let err = palette.apply_toml("keyword = 5\n").unwrap_err();
+        assert!(err.contains("keyword"), "{}", err);
+        assert!(palette.apply_toml("keyword = \"mauve\"\n").is_err());
+        assert!(palette.apply_toml("keyword = ").is_err());
+    }
+}
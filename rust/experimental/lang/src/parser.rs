@@ -12,14 +12,799 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::statestack::State;
-use crate::ScopeId;
-
-/// Trait for abstracting over text parsing and [Scope] extraction
-pub trait Parser {
-    fn has_offset(&mut self) -> bool;
-    fn set_scope_offset(&mut self, offset: u32);
-    fn get_all_scopes(&self) -> Vec<Vec<String>>;
-    fn get_scope_id_for_state(&self, state: State) -> ScopeId;
+use crate::scope_tracker::Scope;
+use crate::statestack::{Context, State, Stats};
+use xi_rope::RopeDelta;
+
+/// What a parser can do beyond highlighting. The plugin only calls the
+/// hooks a parser claims to support.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether the parser can suggest indentation.
+    pub supports_indentation: bool,
+    /// Whether the parser can report foldable regions.
+    pub supports_folding: bool,
+    /// Whether the parser can name the declarations lines start with.
+    pub reports_symbols: bool,
+    /// Whether `parse` may return a span before the one it stopped at.
+    pub emits_sub_spans: bool,
+    /// Whether `parse` always consumes the rest of the line.
+    pub wants_whole_line: bool,
+    /// Whether the parser keeps a parse of the whole document, which it is
+    /// handed with `parse_document` and kept up to date with `apply_delta`.
+    /// Such a parser belongs to one document, and isn't shared.
+    pub parses_documents: bool,
+}
+
+/// How a line's indentation relates to the lines above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndentHint {
+    /// Indented like the line above.
+    None,
+    /// The lines after this one are indented one level deeper.
+    Increase,
+    /// This line is indented one level less than the line above.
+    DecreaseThisLine,
+    /// This line continues a construct begun above, and is indented like
+    /// the line above followed by the given text, as with ` * ` inside a
+    /// block comment.
+    Hanging(&'static str),
+    /// The line's indentation is part of its content, as inside a string,
+    /// and is left alone.
+    Verbatim,
+}
+
+/// What a fold holds, in the terms frontends use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FoldKind {
+    Comment,
+    Imports,
+    Region,
+}
+
+impl FoldKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FoldKind::Comment => "comment",
+            FoldKind::Imports => "imports",
+            FoldKind::Region => "region",
+        }
+    }
+}
+
+/// A fold a line takes part in beyond those its nesting makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FoldHint {
+    /// Consecutive lines with the same hint fold together, like a run of
+    /// imports.
+    Run(FoldKind),
+    /// The line folds together with the item after it, like an attribute.
+    Item(FoldKind),
+}
+
+/// How a line takes part in folding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineFolding {
+    /// How deeply the start of the line is nested. A fold starts on the
+    /// line above a deeper one.
+    pub depth: usize,
+    /// The kind of the fold made by nesting this line deeper than the line
+    /// above, if it is.
+    pub kind: FoldKind,
+    pub hint: Option<FoldHint>,
+    /// Whether the line ends an item begun above it, as `;` or `}` end one
+    /// in Rust.
+    pub ends_item: bool,
+}
+
+impl Default for LineFolding {
+    fn default() -> LineFolding {
+        LineFolding { depth: 0, kind: FoldKind::Region, hint: None, ends_item: false }
+    }
+}
+
+/// What a declaration declares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Struct,
+    Enum,
+    Trait,
+    Impl,
+    Module,
+    Constant,
+}
+
+impl SymbolKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SymbolKind::Function => "function",
+            SymbolKind::Struct => "struct",
+            SymbolKind::Enum => "enum",
+            SymbolKind::Trait => "trait",
+            SymbolKind::Impl => "impl",
+            SymbolKind::Module => "module",
+            SymbolKind::Constant => "constant",
+        }
+    }
+}
+
+/// A declaration, as listed in an outline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: String,
+}
+
+/// A stretch of text that a parser hands over to the parser of another
+/// language.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Embedding {
+    /// The language the stretch is in, as a [`DelegatingParser`] knows it,
+    /// or the name of a region, like `macro:html!`, for the user's injection
+    /// table to pick the language of.
+    pub language: String,
+    /// The text that ends the stretch and hands back to the outer parser.
+    pub terminator: String,
+}
+
+/// Trait for abstracting over text parsing and [Scope] extraction. A
+/// parser keeps everything it needs to itself, so that one can be handed
+/// to another thread to lex a document there.
+pub trait Parser: Send {
+    /// A short name for the parser, for traces and debugging.
+    fn name(&self) -> &'static str;
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+    /// The state at the start of a document.
+    fn initial_state(&self) -> State {
+        State::default()
+    }
+    /// The scope of text parsed in `state`.
+    fn get_scope_for_state(&self, state: State) -> Scope;
+    /// Every scope this parser can produce, if known in advance; these are
+    /// announced to core in one batch when the parser is installed.
+    fn scopes(&self) -> Vec<Scope> {
+        Vec::new()
+    }
+    /// Top-level scope names this parser uses beyond the conventional
+    /// TextMate ones.
+    fn scope_prefixes(&self) -> &[&str] {
+        &[]
+    }
     fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State);
+    /// Parses the whole of `text`, the document the lines `parse` is given
+    /// come from. The states handed out before may mean something else
+    /// afterwards. Only called on parsers that claim `parses_documents`.
+    fn parse_document(&mut self, _text: &str) {}
+    /// Brings the parse of the document up to date with `delta`, after
+    /// which the document is `text`, and returns whether the states handed
+    /// out before still mean what they did. Only called on parsers that
+    /// claim `parses_documents`, once they have been given the document.
+    fn apply_delta(&mut self, _delta: &RopeDelta, _text: &str) -> bool {
+        true
+    }
+    /// How `line`, which starts in `state_before`, is indented. Only asked
+    /// of parsers that claim `supports_indentation`.
+    fn indent_delta(&self, _line: &str, _state_before: State) -> IndentHint {
+        IndentHint::None
+    }
+    /// The characters typed that a frontend may close automatically, each
+    /// with its closer.
+    fn autoclose_pairs(&self) -> &[(char, char)] {
+        &[]
+    }
+    /// Whether `opener`, typed in code after `prefix` on its line, opens a
+    /// pair rather than, say, a lifetime.
+    fn opens_pair_after(&self, _prefix: &str, _opener: char) -> bool {
+        true
+    }
+    /// What to start the line after `line` with, indentation included, when
+    /// a newline is typed at the end of `line`, leaving off in `state`:
+    /// the leader of a comment that goes on, as with ` * ` or `/// `.
+    fn comment_continuation(&self, _state: State, _line: &str) -> Option<String> {
+        None
+    }
+    /// Whether text lexed in `state` is code, as opposed to a comment or a
+    /// string, so that the brackets in it count.
+    fn in_code(&self, _state: State) -> bool {
+        true
+    }
+    /// The token that starts a comment running to the end of the line, if
+    /// the language has one.
+    fn line_comment(&self) -> Option<&str> {
+        None
+    }
+    /// The tokens that open and close a block comment, if the language has
+    /// them.
+    fn block_comment(&self) -> Option<(&str, &str)> {
+        None
+    }
+    /// How `line`, which starts in `state_before`, takes part in folding.
+    /// Only asked of parsers that claim `supports_folding`.
+    fn line_folding(&self, _line: &str, _state_before: State) -> LineFolding {
+        LineFolding::default()
+    }
+    /// The declaration `line`, which starts in `state_before`, begins
+    /// with, if any. Only asked of parsers that claim `reports_symbols`.
+    fn line_symbol(&self, _line: &str, _state_before: State) -> Option<Symbol> {
+        None
+    }
+    /// The embedded language the text after a span ending in `state` is in,
+    /// if the parser hands that text over. Only asked by a
+    /// [`DelegatingParser`].
+    fn embedding(&self, _state: State) -> Option<Embedding> {
+        None
+    }
+    /// Memory statistics for the parser's state context.
+    fn stack_stats(&self) -> Stats;
+    /// Releases excess capacity held by the parser's state context.
+    fn shrink_to_fit(&mut self);
+}
+
+/// A piece of a line lexed by [`Tokens`], from `start` to `end` in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub start: usize,
+    pub end: usize,
+    /// The state the text was parsed in.
+    pub state: State,
+    /// Whether the parser made no progress here, and the character was
+    /// skipped rather than parsed.
+    pub stuck: bool,
+}
+
+/// Steps through a line a token at a time. The parser is lent for each
+/// step rather than held, so that it can be asked about the states in
+/// between, as turning tokens into spans does.
+pub struct Tokens {
+    /// Where lexing stopped, and the state there.
+    i: usize,
+    state: State,
+    from: usize,
+    max_bytes: usize,
+    /// The second of the tokens a step found, when it found two.
+    next: Option<Token>,
+}
+
+impl Tokens {
+    /// Lexes from `from` bytes into a line, where the parser is in `state`,
+    /// until at least `max_bytes` are lexed or the line ends.
+    pub fn new(from: usize, state: State, max_bytes: usize) -> Tokens {
+        Tokens { i: from, state, from, max_bytes, next: None }
+    }
+
+    /// The next token of `line`, which must be the same line every call.
+    pub fn next(&mut self, parser: &mut dyn Parser, line: &str) -> Option<Token> {
+        if let Some(token) = self.next.take() {
+            return Some(token);
+        }
+        if self.i >= line.len() || self.i - self.from >= self.max_bytes {
+            return None;
+        }
+        let (i, state) = (self.i, self.state);
+        let (prevlen, s0, len, s1) = parser.parse(&line[i..], state);
+
+        if prevlen == 0 && len == 0 && s1 == state {
+            // A grammar bug; skip a character rather than loop forever.
+            let len = line[i..].chars().next().map_or(1, char::len_utf8);
+            self.i += len;
+            return Some(Token { start: i, end: i + len, state, stuck: true });
+        }
+
+        self.i += prevlen + len;
+        self.state = s1;
+        let token = Token { start: i + prevlen, end: i + prevlen + len, state: s0, stuck: false };
+        if prevlen == 0 {
+            return Some(token);
+        }
+        self.next = Some(token);
+        Some(Token { start: i, end: i + prevlen, state, stuck: false })
+    }
+
+    /// Where lexing stopped, once `next` has run out, and the state there.
+    pub fn end(&self) -> (usize, State) {
+        (self.i, self.state)
+    }
+}
+
+/// Where a [`DelegatingParser`] is: in the outer parser, or in one of the
+/// inner ones, with the outer state to go back to. Each parser's states
+/// are from its own context, so they only ever go back to that parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Layer {
+    Outer(State),
+    Inner { outer: State, inner: usize, state: State },
+}
+
+/// Composes an outer parser with parsers for the languages it embeds. When
+/// the outer parser reaches a state with an [`Embedding`] for one of them,
+/// the text after it goes to that language's parser until the embedding's
+/// terminator, which the outer parser lexes again. The states of both are
+/// kept as one element pushed on the delegating parser's own stack.
+pub struct DelegatingParser {
+    name: &'static str,
+    outer: Box<dyn Parser>,
+    inners: Vec<(String, Box<dyn Parser>)>,
+    /// Whether a terminator only counts where the inner parser is in code,
+    /// and not, say, inside one of its strings.
+    syntax_aware: bool,
+    ctx: Context<Layer>,
+}
+
+impl DelegatingParser {
+    pub fn new(name: &'static str, outer: Box<dyn Parser>) -> DelegatingParser {
+        DelegatingParser {
+            name,
+            outer,
+            inners: Vec::new(),
+            syntax_aware: false,
+            ctx: Context::new(),
+        }
+    }
+
+    /// Hands the text the outer parser says is in `language` to `parser`.
+    pub fn embed(mut self, language: &str, parser: Box<dyn Parser>) -> DelegatingParser {
+        self.inners.push((language.to_owned(), parser));
+        self
+    }
+
+    /// Makes terminators count only where the inner parser is in code.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn syntax_aware(mut self, syntax_aware: bool) -> DelegatingParser {
+        self.syntax_aware = syntax_aware;
+        self
+    }
+
+    fn layer(&self, state: State) -> Layer {
+        self.ctx.tos(state).unwrap_or_else(|| Layer::Outer(self.outer.initial_state()))
+    }
+
+    fn state(&mut self, layer: Layer) -> State {
+        self.ctx.push(State::default(), layer)
+    }
+
+    /// The state to go on from after the outer parser reaches `outer`,
+    /// which is in an inner parser if `outer` hands over to one.
+    fn after_outer(&mut self, outer: State) -> State {
+        let inner = self.outer.embedding(outer).and_then(|embedding| {
+            self.inners.iter().position(|(language, _)| *language == embedding.language)
+        });
+        match inner {
+            Some(inner) => {
+                let state = self.inners[inner].1.initial_state();
+                self.state(Layer::Inner { outer, inner, state })
+            }
+            None => self.state(Layer::Outer(outer)),
+        }
+    }
+
+    /// Where in `text` the inner parser, starting in `state`, hands back to
+    /// the outer one; the end of `text` if it doesn't.
+    fn terminator_at(&mut self, inner: usize, text: &str, state: State, terminator: &str) -> usize {
+        if terminator.is_empty() {
+            return text.len();
+        }
+        let mut from = 0;
+        while let Some(found) = text[from..].find(terminator) {
+            let at = from + found;
+            if !self.syntax_aware {
+                return at;
+            }
+            let parser = &mut *self.inners[inner].1;
+            let before = state_after(parser, &text[..at], state);
+            if parser.in_code(before) {
+                return at;
+            }
+            from = at + text[at..].chars().next().map_or(1, char::len_utf8);
+        }
+        text.len()
+    }
+}
+
+/// The state `parser` is in after lexing all of `text` from `state`.
+fn state_after(parser: &mut dyn Parser, text: &str, mut state: State) -> State {
+    let mut i = 0;
+    while i < text.len() {
+        let (prevlen, _, len, next) = parser.parse(&text[i..], state);
+        if prevlen == 0 && len == 0 && next == state {
+            i += text[i..].chars().next().map_or(1, char::len_utf8);
+            continue;
+        }
+        i += prevlen + len;
+        state = next;
+    }
+    state
+}
+
+impl Parser for DelegatingParser {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // the inner parsers' lines get the defaults of the outer's hooks
+        Capabilities { emits_sub_spans: true, wants_whole_line: false, ..self.outer.capabilities() }
+    }
+
+    fn initial_state(&self) -> State {
+        // the outer parser's first state is pushed on the first `parse`
+        State::default()
+    }
+
+    fn get_scope_for_state(&self, state: State) -> Scope {
+        match self.layer(state) {
+            Layer::Outer(outer) => self.outer.get_scope_for_state(outer),
+            Layer::Inner { outer, inner, state } => {
+                let mut scope = self.outer.get_scope_for_state(outer);
+                scope.extend(self.inners[inner].1.get_scope_for_state(state));
+                scope
+            }
+        }
+    }
+
+    fn scopes(&self) -> Vec<Scope> {
+        self.outer.scopes()
+    }
+
+    fn scope_prefixes(&self) -> &[&str] {
+        self.outer.scope_prefixes()
+    }
+
+    fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+        match self.layer(state) {
+            Layer::Outer(outer) => {
+                let (prevlen, s0, len, s1) = self.outer.parse(text, outer);
+                let s0 = self.state(Layer::Outer(s0));
+                (prevlen, s0, len, self.after_outer(s1))
+            }
+            Layer::Inner { outer, inner, state: inner_state } => {
+                let terminator = self.outer.embedding(outer).map(|e| e.terminator);
+                let end = match terminator {
+                    Some(terminator) => self.terminator_at(inner, text, inner_state, &terminator),
+                    None => 0,
+                };
+                if end == 0 {
+                    // the outer parser takes over again at its terminator
+                    let (prevlen, s0, len, s1) = self.outer.parse(text, outer);
+                    if prevlen > 0 {
+                        // text still in `outer` would otherwise be scoped
+                        // as part of the embedding
+                        let outer = self.state(Layer::Outer(outer));
+                        return (0, outer, prevlen, outer);
+                    }
+                    let s0 = self.state(Layer::Outer(s0));
+                    return (0, s0, len, self.after_outer(s1));
+                }
+                let (prevlen, s0, len, s1) = self.inners[inner].1.parse(&text[..end], inner_state);
+                let s0 = self.state(Layer::Inner { outer, inner, state: s0 });
+                let s1 = self.state(Layer::Inner { outer, inner, state: s1 });
+                (prevlen, s0, len, s1)
+            }
+        }
+    }
+
+    fn parse_document(&mut self, text: &str) {
+        self.ctx = Context::new();
+        self.outer.parse_document(text);
+    }
+
+    fn apply_delta(&mut self, delta: &RopeDelta, text: &str) -> bool {
+        // the outer parser's states are in ours
+        let kept = self.outer.apply_delta(delta, text);
+        if !kept {
+            self.ctx = Context::new();
+        }
+        kept
+    }
+
+    fn indent_delta(&self, line: &str, state_before: State) -> IndentHint {
+        match self.layer(state_before) {
+            Layer::Outer(outer) => self.outer.indent_delta(line, outer),
+            Layer::Inner { .. } => IndentHint::Verbatim,
+        }
+    }
+
+    fn autoclose_pairs(&self) -> &[(char, char)] {
+        self.outer.autoclose_pairs()
+    }
+
+    fn opens_pair_after(&self, prefix: &str, opener: char) -> bool {
+        self.outer.opens_pair_after(prefix, opener)
+    }
+
+    fn comment_continuation(&self, state: State, line: &str) -> Option<String> {
+        match self.layer(state) {
+            Layer::Outer(outer) => self.outer.comment_continuation(outer, line),
+            Layer::Inner { inner, state, .. } => {
+                self.inners[inner].1.comment_continuation(state, line)
+            }
+        }
+    }
+
+    fn in_code(&self, state: State) -> bool {
+        match self.layer(state) {
+            Layer::Outer(outer) => self.outer.in_code(outer),
+            Layer::Inner { inner, state, .. } => self.inners[inner].1.in_code(state),
+        }
+    }
+
+    fn line_comment(&self) -> Option<&str> {
+        self.outer.line_comment()
+    }
+
+    fn block_comment(&self) -> Option<(&str, &str)> {
+        self.outer.block_comment()
+    }
+
+    fn line_folding(&self, line: &str, state_before: State) -> LineFolding {
+        match self.layer(state_before) {
+            Layer::Outer(outer) => self.outer.line_folding(line, outer),
+            // folded with the outer parser's region around it
+            Layer::Inner { outer, .. } => {
+                LineFolding { hint: None, ..self.outer.line_folding(line, outer) }
+            }
+        }
+    }
+
+    fn line_symbol(&self, line: &str, state_before: State) -> Option<Symbol> {
+        match self.layer(state_before) {
+            Layer::Outer(outer) => self.outer.line_symbol(line, outer),
+            Layer::Inner { .. } => None,
+        }
+    }
+
+    fn stack_stats(&self) -> Stats {
+        let parsers = Some(&self.outer).into_iter().chain(self.inners.iter().map(|(_, p)| p));
+        parsers.map(|parser| parser.stack_stats()).fold(self.ctx.stats(), |total, stats| Stats {
+            states: total.states + stats.states,
+            elements: total.elements + stats.elements,
+            max_depth: total.max_depth.max(stats.max_depth),
+            estimated_bytes: total.estimated_bytes + stats.estimated_bytes,
+        })
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.ctx.shrink_to_fit();
+        self.outer.shrink_to_fit();
+        for (_, parser) in &mut self.inners {
+            parser.shrink_to_fit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text with `<% ... %>` tags, whose contents are in "Code".
+    struct TemplateParser {
+        ctx: Context<u8>,
+    }
+
+    const TAG: u8 = 1;
+    const EMBEDDED: u8 = 2;
+
+    impl Parser for TemplateParser {
+        fn name(&self) -> &'static str {
+            "Template"
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            let scope = match self.ctx.tos(state) {
+                Some(TAG) => "punctuation.section.embedded.toy",
+                Some(_) => "meta.embedded.toy",
+                None => "text.toy",
+            };
+            vec![scope.to_owned()]
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            let inside = self.ctx.tos(state) == Some(EMBEDDED);
+            let (marker, after) = if inside {
+                ("%>", State::default())
+            } else {
+                ("<%", self.ctx.push(State::default(), EMBEDDED))
+            };
+            match text.find(marker) {
+                Some(at) => (at, self.ctx.push(State::default(), TAG), 2, after),
+                None => (0, state, text.len(), state),
+            }
+        }
+
+        fn embedding(&self, state: State) -> Option<Embedding> {
+            match self.ctx.tos(state) {
+                Some(EMBEDDED) => {
+                    Some(Embedding { language: "Code".into(), terminator: "%>".into() })
+                }
+                _ => None,
+            }
+        }
+
+        fn stack_stats(&self) -> Stats {
+            self.ctx.stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.ctx.shrink_to_fit()
+        }
+    }
+
+    /// Code with double-quoted strings.
+    struct CodeParser {
+        ctx: Context<()>,
+    }
+
+    impl Parser for CodeParser {
+        fn name(&self) -> &'static str {
+            "Code"
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            match self.ctx.tos(state) {
+                Some(()) => vec!["source.toy".to_owned(), "string.quoted.toy".to_owned()],
+                None => vec!["source.toy".to_owned()],
+            }
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            let in_string = self.ctx.tos(state).is_some();
+            match text.find('"') {
+                Some(at) if in_string => (at, state, 1, State::default()),
+                Some(at) => {
+                    let string = self.ctx.push(State::default(), ());
+                    (at, string, 1, string)
+                }
+                None => (0, state, text.len(), state),
+            }
+        }
+
+        fn in_code(&self, state: State) -> bool {
+            self.ctx.tos(state).is_none()
+        }
+
+        fn stack_stats(&self) -> Stats {
+            self.ctx.stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.ctx.shrink_to_fit()
+        }
+    }
+
+    fn template(syntax_aware: bool) -> DelegatingParser {
+        let outer = Box::new(TemplateParser { ctx: Context::new() });
+        let inner = Box::new(CodeParser { ctx: Context::new() });
+        DelegatingParser::new("Template", outer).embed("Code", inner).syntax_aware(syntax_aware)
+    }
+
+    /// Lexes `line` from `state`, returning each span's text with the last
+    /// scope name of its state, and the state at the end of the line.
+    fn lex(
+        parser: &mut dyn Parser,
+        line: &str,
+        mut state: State,
+    ) -> (Vec<(String, String)>, State) {
+        let mut spans = Vec::new();
+        let mut i = 0;
+        while i < line.len() {
+            let (prevlen, s0, len, s1) = parser.parse(&line[i..], state);
+            assert!(prevlen + len > 0 || s1 != state, "stuck at {}", i);
+            for (range, state) in
+                vec![(i..i + prevlen, state), (i + prevlen..i + prevlen + len, s0)]
+            {
+                if range.start < range.end {
+                    let scope = parser.get_scope_for_state(state).pop().unwrap();
+                    spans.push((line[range].to_owned(), scope));
+                }
+            }
+            i += prevlen + len;
+            state = s1;
+        }
+        (spans, state)
+    }
+
+    fn spans(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|&(text, scope)| (text.to_owned(), scope.to_owned())).collect()
+    }
+
+    #[test]
+    fn embedded_text_goes_to_the_inner_parser() {
+        let mut parser = template(true);
+        let state = parser.initial_state();
+        let (result, end) = lex(&mut parser, "a <% b %> c", state);
+        assert_eq!(
+            result,
+            spans(&[
+                ("a ", "text.toy"),
+                ("<%", "punctuation.section.embedded.toy"),
+                (" b ", "source.toy"),
+                ("%>", "punctuation.section.embedded.toy"),
+                (" c", "text.toy"),
+            ])
+        );
+        assert!(parser.in_code(end));
+        assert_eq!(
+            parser.get_scope_for_state(end),
+            parser.get_scope_for_state(parser.initial_state())
+        );
+
+        // the inner scope goes under the outer one
+        let (_, inside) = lex(&mut parser, "<% \"", state);
+        assert_eq!(
+            parser.get_scope_for_state(inside),
+            vec!["meta.embedded.toy", "source.toy", "string.quoted.toy"]
+        );
+        assert!(!parser.in_code(inside));
+    }
+
+    #[test]
+    fn the_inner_state_carries_across_lines() {
+        let mut parser = template(true);
+        let state = parser.initial_state();
+        let (_, first) = lex(&mut parser, "<% \"one", state);
+        let (result, _) = lex(&mut parser, "two\" %> three", first);
+        assert_eq!(
+            result,
+            spans(&[
+                ("two", "string.quoted.toy"),
+                ("\"", "string.quoted.toy"),
+                (" ", "source.toy"),
+                ("%>", "punctuation.section.embedded.toy"),
+                (" three", "text.toy"),
+            ])
+        );
+
+        // the same line from the same state lexes to the same state
+        let (_, again) = lex(&mut parser, "<% \"one", state);
+        assert_eq!(first, again);
+    }
+
+    #[test]
+    fn terminators_in_strings_only_count_when_not_syntax_aware() {
+        let line = "<% \"%>\" %> x";
+
+        let mut parser = template(true);
+        let (result, _) = lex(&mut parser, line, State::default());
+        assert_eq!(result[3], ("%>".to_owned(), "string.quoted.toy".to_owned()));
+        assert_eq!(result.last().unwrap(), &(" x".to_owned(), "text.toy".to_owned()));
+
+        let mut parser = template(false);
+        let (result, _) = lex(&mut parser, line, State::default());
+        assert_eq!(result[3], ("%>".to_owned(), "punctuation.section.embedded.toy".to_owned()));
+    }
+
+    #[test]
+    fn unknown_languages_stay_in_the_outer_parser() {
+        let outer = Box::new(TemplateParser { ctx: Context::new() });
+        let mut parser = DelegatingParser::new("Template", outer);
+        let (result, _) = lex(&mut parser, "<% b %>", State::default());
+        assert_eq!(result[1], (" b ".to_owned(), "meta.embedded.toy".to_owned()));
+    }
+
+    #[test]
+    fn tokens_cover_the_line_in_pieces() {
+        let mut parser = TemplateParser { ctx: Context::new() };
+        let line = "a <% b %> c";
+        let mut texts = |max_bytes| {
+            let mut tokens = Tokens::new(0, State::default(), max_bytes);
+            let mut texts = Vec::new();
+            while let Some(token) = tokens.next(&mut parser, line) {
+                assert!(!token.stuck);
+                texts.push(&line[token.start..token.end]);
+            }
+            (texts, tokens.end())
+        };
+        let (all, (end, _)) = texts(std::usize::MAX);
+        assert_eq!(all, vec!["a ", "<%", " b ", "%>", " c"]);
+        assert_eq!(end, line.len());
+
+        // a step finds both the text before a tag and the tag
+        let (piece, (end, state)) = texts(1);
+        assert_eq!(piece, vec!["a ", "<%"]);
+        assert_eq!(end, 4);
+        let mut rest = Tokens::new(end, state, std::usize::MAX);
+        assert_eq!(rest.next(&mut parser, line).map(|t| &line[t.start..t.end]), Some(" b "));
+    }
 }
@@ -96,6 +96,11 @@ fn decode_utf8(s: &[u8]) -> Option<(char, usize)> {
 impl<F: Fn(char) -> bool> Peg for OneChar<F> {
     #[inline(always)]
     fn p(&self, s: &[u8]) -> Option<usize> {
+        // ASCII needs no decoding
+        if let Some(&b) = s.first().filter(|b| b.is_ascii()) {
+            debug_assert_eq!(decode_utf8(s), Some((b as char, 1)));
+            return if self.0(b as char) { Some(1) } else { None };
+        }
         if let Some((ch, len)) = decode_utf8(s) {
             if self.0(ch) {
                 return Some(len);
@@ -123,6 +128,104 @@ impl Peg for char {
     }
 }
 
+/// A set of bytes, matching any one of them with a single table lookup.
+/// Bytes of multi-byte characters are never in a class built from ASCII,
+/// so scanning with one stops at the first non-ASCII character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteClass([u64; 4]);
+
+impl ByteClass {
+    pub fn new(bytes: &[u8]) -> ByteClass {
+        bytes.iter().fold(ByteClass([0; 4]), |class, &b| class.with(b))
+    }
+
+    /// The class of the bytes whose bits are set, a word for each 64 bytes,
+    /// for a class that has to be built at compile time.
+    pub const fn from_bits(bits: [u64; 4]) -> ByteClass {
+        ByteClass(bits)
+    }
+
+    pub fn with(mut self, b: u8) -> ByteClass {
+        self.0[(b >> 6) as usize] |= 1 << (b & 63);
+        self
+    }
+
+    /// Adds `first` to `last`, inclusive.
+    pub fn with_range(self, first: u8, last: u8) -> ByteClass {
+        (first..=last).fold(self, ByteClass::with)
+    }
+
+    pub fn union(self, other: ByteClass) -> ByteClass {
+        let (a, b) = (self.0, other.0);
+        ByteClass([a[0] | b[0], a[1] | b[1], a[2] | b[2], a[3] | b[3]])
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, b: u8) -> bool {
+        self.0[(b >> 6) as usize] & (1 << (b & 63)) != 0
+    }
+
+    /// The length of the run of bytes in the class that `s` starts with.
+    #[inline]
+    pub fn run(&self, s: &[u8]) -> usize {
+        s.iter().position(|&b| !self.contains(b)).unwrap_or(s.len())
+    }
+}
+
+impl Peg for ByteClass {
+    #[inline(always)]
+    fn p(&self, s: &[u8]) -> Option<usize> {
+        match s.first() {
+            Some(&b) if self.contains(b) => Some(1),
+            _ => None,
+        }
+    }
+}
+
+/// Words, each with a class like the scope to give it, bucketed by length
+/// so that a lookup only compares a word against words as long as it.
+#[derive(Debug, Clone)]
+pub struct KeywordSet<C> {
+    /// The words of each length, sorted.
+    buckets: Vec<Vec<(&'static [u8], C)>>,
+}
+
+impl<C: Clone> KeywordSet<C> {
+    /// Builds the set from lists of words, each sorted, and the class of
+    /// the words in it. A word in two lists takes the class of the first.
+    pub fn new(lists: &[(&[&'static str], C)]) -> KeywordSet<C> {
+        let mut buckets: Vec<Vec<(&'static [u8], C)>> = Vec::new();
+        for (words, class) in lists {
+            debug_assert!(words.windows(2).all(|w| w[0] < w[1]), "words aren't sorted");
+            for word in words.iter() {
+                let len = word.len();
+                if buckets.len() <= len {
+                    buckets.resize(len + 1, Vec::new());
+                }
+                buckets[len].push((word.as_bytes(), class.clone()));
+            }
+        }
+        for bucket in &mut buckets {
+            // stable, so the first list's class comes first
+            bucket.sort_by_key(|&(word, _)| word);
+            bucket.dedup_by_key(|&mut (word, _)| word);
+        }
+        KeywordSet { buckets }
+    }
+
+    /// The class of `word`, if it is in the set.
+    #[inline]
+    pub fn get(&self, word: &[u8]) -> Option<C> {
+        let bucket = self.buckets.get(word.len())?;
+        let i = bucket.binary_search_by(|&(w, _)| w.cmp(word)).ok()?;
+        Some(bucket[i].1.clone())
+    }
+
+    pub fn contains(&self, word: &[u8]) -> bool {
+        self.get(word).is_some()
+    }
+}
+
 // byte ranges, including inclusive variants
 
 /// Use Inclusive(a..b) to indicate an inclusive range. When a...b syntax becomes
@@ -145,7 +248,7 @@ impl Peg for Inclusive<ops::Range<u8>> {
 
 // Note: char ranges are also possible, but probably not commonly used, and inefficient
 
-impl<'a> Peg for &'a [u8] {
+impl Peg for &[u8] {
     #[inline(always)]
     fn p(&self, s: &[u8]) -> Option<usize> {
         let len = self.len();
@@ -157,7 +260,7 @@ impl<'a> Peg for &'a [u8] {
     }
 }
 
-impl<'a> Peg for &'a str {
+impl Peg for &str {
     #[inline(always)]
     fn p(&self, s: &[u8]) -> Option<usize> {
         self.as_bytes().p(s)
@@ -368,3 +471,53 @@ impl<'a, P: Peg> Peg for Ref<'a, P> {
         self.0.p(s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_classes_match_their_bytes() {
+        let class = ByteClass::new(b"_-").with_range(b'a', b'z').union(ByteClass::new(&[0xff]));
+        for b in 0..=std::u8::MAX {
+            let expected = b == b'_' || b == b'-' || b.is_ascii_lowercase() || b == 0xff;
+            assert_eq!(class.contains(b), expected, "{:#x}", b);
+            assert_eq!(class.p(&[b, b'a']), if expected { Some(1) } else { None });
+        }
+        assert_eq!(class.p(b""), None);
+        assert_eq!(ByteClass::new(b"").with_range(0xf0, std::u8::MAX).run(&[0xf0, 0xff, 1]), 2);
+
+        let ident = ByteClass::new(b"_").with_range(b'a', b'z').with_range(b'0', b'9');
+        for text in &["snake_case2 x", "", "abc", "é", "naïve", "x\u{2014}y"] {
+            let chars =
+                text.find(|c: char| !(c == '_' || c.is_ascii_lowercase() || c.is_ascii_digit()));
+            assert_eq!(ident.run(text.as_bytes()), chars.unwrap_or(text.len()), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn keyword_sets_match_whole_words() {
+        let set =
+            KeywordSet::new(&[(&["if", "in", "struct"][..], 1), (&["i32", "in", "str"][..], 2)]);
+        assert_eq!(set.get(b"if"), Some(1));
+        assert_eq!(set.get(b"struct"), Some(1));
+        assert_eq!(set.get(b"i32"), Some(2));
+        // the first list wins
+        assert_eq!(set.get(b"in"), Some(1));
+        for near_miss in &["structs", "struc", "iffy", "i", "", "IF", "i3", "string", "in "] {
+            assert!(!set.contains(near_miss.as_bytes()), "{:?}", near_miss);
+        }
+    }
+
+    #[test]
+    fn one_char_agrees_with_decoding() {
+        let text = "a\u{7f}é\u{800}\u{10000}\u{10ffff}z";
+        for (i, c) in text.char_indices() {
+            assert_eq!(OneChar(|x| x == c).p(&text.as_bytes()[i..]), Some(c.len_utf8()));
+            assert_eq!(OneChar(|x| x != c).p(&text.as_bytes()[i..]), None);
+        }
+        // continuation bytes and truncated characters aren't characters
+        assert_eq!(OneChar(|_| true).p(&[0x80]), None);
+        assert_eq!(OneChar(|_| true).p(&"é".as_bytes()[..1]), None);
+    }
+}
@@ -0,0 +1,614 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The mapping from language ids to the parsers that highlight them.
+
+use std::collections::HashMap;
+#[cfg(test)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use xi_core_lib::ConfigTable;
+use xi_trace::trace_payload;
+
+use crate::injection::{self, Injection};
+use crate::language::literate::{LiterateParser, Style};
+use crate::language::sublime::{self, SublimeParser, Syntax};
+#[cfg(test)]
+use crate::language::testlang::TestParser;
+#[cfg(feature = "tree-sitter")]
+use crate::language::treesitter::{self, TreeSitterParser};
+use crate::language::{plaintext::PlaintextParser, rust::RustParser, template::TemplateParser};
+use crate::parser::{DelegatingParser, Parser};
+use crate::text::{rsplit_once, strip_suffix};
+
+/// Creates a parser for a fresh session.
+pub type ParserFactory = fn() -> Box<dyn Parser>;
+/// Creates a parser layered over the parser of a host language.
+pub type OverlayFactory = fn(Box<dyn Parser>) -> Box<dyn Parser>;
+
+/// The config table holding this plugin's settings.
+pub const CONFIG_SECTION: &str = "experimental_lang";
+/// A table of language ids to route to another language's parser.
+const LANGUAGE_OVERRIDES_KEY: &str = "language_overrides";
+/// A list of regions of one language to highlight as another.
+const INJECTIONS_KEY: &str = "injections";
+/// A list of directories to read `.sublime-syntax` files from.
+const SYNTAX_DIRS_KEY: &str = "sublime_syntax_dirs";
+
+pub struct ParserRegistry {
+    factories: HashMap<String, ParserFactory>,
+    /// Other names for registered languages.
+    aliases: HashMap<String, String>,
+    /// Routing set by the user, consulted before anything else.
+    overrides: HashMap<String, String>,
+    /// Parsers for dialects like "Jinja", which handle language ids like
+    /// "HTML (Jinja)" over the parser of the language in front.
+    overlays: HashMap<String, OverlayFactory>,
+    /// Embeddings set by the user, handed to the host language's parser.
+    injections: Vec<Injection>,
+    /// Languages read from Sublime syntax files, by the name each declares;
+    /// a language with a parser of its own keeps it.
+    syntaxes: HashMap<String, Arc<Syntax>>,
+    /// The file extensions the syntaxes claim, each with its language.
+    extensions: HashMap<String, String>,
+    /// The directories the syntaxes were read from.
+    syntax_dirs: Vec<PathBuf>,
+    /// Bumped whenever the injection table or the syntaxes change.
+    generation: u64,
+}
+
+impl Default for ParserRegistry {
+    /// A registry of the built-in parsers.
+    fn default() -> ParserRegistry {
+        let mut registry = ParserRegistry::new();
+        registry.register("Rust", || Box::new(RustParser::new()));
+        registry.register("Plain Text", || Box::new(PlaintextParser::new()));
+        registry.add_alias("Text", "Plain Text");
+        registry.register_overlay("Jinja", |host| Box::new(TemplateParser::new(host)));
+        registry.register_overlay("Handlebars", |host| Box::new(TemplateParser::new(host)));
+        registry.add_alias("Handlebars", "HTML (Handlebars)");
+        // prose with code lines in the language in front
+        registry
+            .register_overlay("Literate", |code| Box::new(LiterateParser::new(Style::Bird, code)));
+        registry.register_overlay("Indented", |code| {
+            Box::new(LiterateParser::new(Style::Indented, code))
+        });
+        registry.add_alias("Literate Haskell", "Haskell (Literate)");
+        // Rust keeps its own parser unless routed here by an override
+        #[cfg(feature = "tree-sitter")]
+        {
+            registry.register("JSON", || Box::new(TreeSitterParser::new(treesitter::JSON)));
+            registry
+                .register("Tree-sitter Rust", || Box::new(TreeSitterParser::new(treesitter::RUST)));
+        }
+        // for the plumbing tests, which shouldn't change with the grammars
+        #[cfg(test)]
+        registry.register("Test", || Box::new(TestParser::new()));
+        // and a grammar of the kind users install, for the syntax backend
+        #[cfg(test)]
+        registry.load_syntaxes(vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("sublime")]);
+        registry
+    }
+}
+
+impl ParserRegistry {
+    /// An empty registry.
+    pub fn new() -> ParserRegistry {
+        ParserRegistry {
+            factories: HashMap::new(),
+            aliases: HashMap::new(),
+            overrides: HashMap::new(),
+            overlays: HashMap::new(),
+            injections: Vec::new(),
+            syntaxes: HashMap::new(),
+            extensions: HashMap::new(),
+            syntax_dirs: Vec::new(),
+            generation: 0,
+        }
+    }
+
+    pub fn register(&mut self, language_id: &str, factory: ParserFactory) {
+        self.factories.insert(language_id.to_owned(), factory);
+    }
+
+    /// Registers a dialect that can be layered over any language, as in
+    /// "Rust (Jinja)". The language in front, the host, is picked by the
+    /// language id, so it can be changed with an override; hosts we can't
+    /// highlight are plain text.
+    pub fn register_overlay(&mut self, dialect: &str, factory: OverlayFactory) {
+        self.overlays.insert(dialect.to_owned(), factory);
+    }
+
+    pub fn add_alias(&mut self, alias: &str, language_id: &str) {
+        self.aliases.insert(alias.to_owned(), language_id.to_owned());
+    }
+
+    /// The registered language whose parser handles `language_id`, after
+    /// applying overrides and aliases, if there is one.
+    pub fn resolve<'a>(&'a self, language_id: &'a str) -> Option<&'a str> {
+        let language_id = self.overrides.get(language_id).map_or(language_id, String::as_str);
+        let language_id = self.aliases.get(language_id).map_or(language_id, String::as_str);
+        if self.overlay(language_id).is_some() {
+            return Some(language_id);
+        }
+        let known =
+            self.factories.contains_key(language_id) || self.syntaxes.contains_key(language_id);
+        if known {
+            Some(language_id)
+        } else {
+            None
+        }
+    }
+
+    /// The language whose syntax claims files with the extension `ext`, if
+    /// one does.
+    #[cfg_attr(not(feature = "tools"), allow(dead_code))]
+    pub fn language_for_extension(&self, ext: &str) -> Option<&str> {
+        self.extensions.get(ext).map(String::as_str)
+    }
+
+    /// Splits an id like "HTML (Jinja)" into the host language and the
+    /// overlay for a registered dialect.
+    fn overlay<'a>(&self, language_id: &'a str) -> Option<(&'a str, OverlayFactory)> {
+        let (host, dialect) = rsplit_once(strip_suffix(language_id, ")")?, " (")?;
+        Some((host, *self.overlays.get(dialect)?))
+    }
+
+    /// A new parser for `language_id`, if some registered parser handles it.
+    /// If the injection table has entries for the language, the parser
+    /// hands the regions they name to the parsers of their languages.
+    pub fn create(&self, language_id: &str) -> Option<Box<dyn Parser>> {
+        let language_id = self.resolve(language_id)?;
+        let parser = self.create_resolved(language_id)?;
+        let injections: Vec<_> = self
+            .injections
+            .iter()
+            .filter(|injection| self.resolve(&injection.host) == Some(language_id))
+            .collect();
+        if injections.is_empty() {
+            return Some(parser);
+        }
+        // injected parsers don't get injections of their own, which could
+        // go on forever
+        let mut delegating = DelegatingParser::new(parser.name(), parser);
+        for injection in injections {
+            let inner = self.resolve(&injection.language).and_then(|l| self.create_resolved(l));
+            if let Some(inner) = inner {
+                delegating = delegating.embed(&injection.pattern.region(), inner);
+            }
+        }
+        Some(Box::new(delegating))
+    }
+
+    fn create_resolved(&self, language_id: &str) -> Option<Box<dyn Parser>> {
+        if let Some((host, overlay)) = self.overlay(language_id) {
+            // overlays don't stack
+            let host = self.resolve(host).and_then(|host| self.create_base(host));
+            let host = host.or_else(|| self.create_base("Plain Text"))?;
+            return Some(overlay(host));
+        }
+        self.create_base(language_id)
+    }
+
+    /// A parser for a language that isn't a dialect: its own, or else one
+    /// interpreting its syntax.
+    fn create_base(&self, language_id: &str) -> Option<Box<dyn Parser>> {
+        if let Some(factory) = self.factories.get(language_id) {
+            return Some(factory());
+        }
+        let syntax = self.syntaxes.get(language_id)?;
+        Some(Box::new(SublimeParser::new(syntax.clone())))
+    }
+
+    /// Every language with a parser registered or a syntax read, and every
+    /// dialect over each of them, as in "Rust (Jinja)", sorted.
+    #[cfg(test)]
+    pub fn languages(&self) -> Vec<String> {
+        let hosts: Vec<&String> = self.factories.keys().chain(self.syntaxes.keys()).collect();
+        let mut languages: Vec<String> = hosts.iter().map(|&host| host.clone()).collect();
+        for host in hosts {
+            languages.extend(self.overlays.keys().map(|dialect| format!("{} ({})", host, dialect)));
+        }
+        languages.sort();
+        languages.dedup();
+        languages
+    }
+
+    /// The name of the parser that handles `language_id`, if there is one.
+    #[cfg(test)]
+    pub fn parser_name(&self, language_id: &str) -> Option<&'static str> {
+        self.create(language_id).map(|parser| parser.name())
+    }
+
+    /// Picks up `experimental_lang.language_overrides`,
+    /// `experimental_lang.sublime_syntax_dirs` and
+    /// `experimental_lang.injections` from a change to the config, returning
+    /// whether any changed. Parsers made before then don't see the change.
+    pub fn apply_config(&mut self, changes: &ConfigTable) -> bool {
+        let overrides_changed = self.apply_overrides(changes);
+        // before the injections, which may name the syntaxes' languages
+        let syntaxes_changed = self.apply_syntax_dirs(changes);
+        let injections_changed = self.apply_injections(changes);
+        if syntaxes_changed || injections_changed {
+            self.generation += 1;
+        }
+        overrides_changed || syntaxes_changed || injections_changed
+    }
+
+    /// Reads the syntaxes in `dirs`, and those in the directories in them,
+    /// in place of any read before. Syntaxes that can't be read, and what
+    /// is read but not understood, are traced and left out.
+    pub fn load_syntaxes(&mut self, dirs: Vec<PathBuf>) {
+        self.syntaxes.clear();
+        self.extensions.clear();
+        for dir in &dirs {
+            let (syntaxes, mut problems) = sublime::load_dir(dir);
+            for syntax in syntaxes {
+                if self.syntaxes.contains_key(&syntax.name) {
+                    problems.push(format!("{} has two syntaxes; the first is used", syntax.name));
+                    continue;
+                }
+                for ext in &syntax.file_extensions {
+                    self.extensions.entry(ext.clone()).or_insert_with(|| syntax.name.clone());
+                }
+                self.syntaxes.insert(syntax.name.clone(), Arc::new(syntax));
+            }
+            for problem in problems {
+                trace_payload("sublime syntax problem", &["experimental-lang"], problem);
+            }
+        }
+        self.syntax_dirs = dirs;
+    }
+
+    /// Counts the changes to the injection table and the syntaxes, so
+    /// parsers made before one can be told apart.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn apply_overrides(&mut self, changes: &ConfigTable) -> bool {
+        let table = match changes.get(CONFIG_SECTION).and_then(|s| s.get(LANGUAGE_OVERRIDES_KEY)) {
+            Some(table) => table,
+            None => return false,
+        };
+        let overrides: HashMap<String, String> = table
+            .as_object()
+            .map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(from, to)| Some((from.clone(), to.as_str()?.to_owned())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let changed = overrides != self.overrides;
+        self.overrides = overrides;
+        changed
+    }
+
+    /// Entries that aren't directory names are traced and left out. The
+    /// syntaxes are only read again if the list changes.
+    fn apply_syntax_dirs(&mut self, changes: &ConfigTable) -> bool {
+        let list = match changes.get(CONFIG_SECTION).and_then(|s| s.get(SYNTAX_DIRS_KEY)) {
+            Some(list) => list,
+            None => return false,
+        };
+        let mut problems = Vec::new();
+        let dirs: Vec<PathBuf> = match list.as_array() {
+            Some(list) => list
+                .iter()
+                .filter_map(|dir| {
+                    let path = dir.as_str().map(PathBuf::from);
+                    if path.is_none() {
+                        problems.push(format!("{} isn't a directory", dir));
+                    }
+                    path
+                })
+                .collect(),
+            None => {
+                problems.push(format!("{} should be a list of directories", SYNTAX_DIRS_KEY));
+                Vec::new()
+            }
+        };
+        for problem in problems {
+            trace_payload("invalid syntax directory", &["experimental-lang"], problem);
+        }
+
+        if dirs == self.syntax_dirs {
+            return false;
+        }
+        self.load_syntaxes(dirs);
+        true
+    }
+
+    /// Bad entries in the table are traced and left out, rather than
+    /// failing the whole table.
+    fn apply_injections(&mut self, changes: &ConfigTable) -> bool {
+        let table = match changes.get(CONFIG_SECTION).and_then(|s| s.get(INJECTIONS_KEY)) {
+            Some(table) => table,
+            None => return false,
+        };
+        let (injections, mut problems) = injection::parse_table(table);
+        for injection in &injections {
+            if self.resolve(&injection.language).is_none() {
+                problems.push(format!(
+                    "can't highlight {} for {} in {}",
+                    injection.language,
+                    injection.pattern.region(),
+                    injection.host
+                ));
+            }
+        }
+        for problem in problems {
+            trace_payload("invalid injection", &["experimental-lang"], problem);
+        }
+
+        let changed = injections != self.injections;
+        self.injections = injections;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Embedding;
+    use crate::scope_tracker::Scope;
+    use crate::statestack::{Context, State, Stats};
+    use crate::text::{lines_with_endings, strip_prefix};
+    use serde_json::json;
+
+    /// Text with fenced blocks, which it reports by their info strings.
+    struct FencedParser {
+        ctx: Context<String>,
+    }
+
+    impl Parser for FencedParser {
+        fn name(&self) -> &'static str {
+            "Fenced"
+        }
+
+        fn get_scope_for_state(&self, state: State) -> Scope {
+            match self.ctx.tos(state) {
+                Some(_) => vec!["text.fenced".to_owned(), "markup.raw.block.fenced".to_owned()],
+                None => vec!["text.fenced".to_owned()],
+            }
+        }
+
+        fn parse(&mut self, text: &str, state: State) -> (usize, State, usize, State) {
+            let next = match (self.ctx.tos(state), strip_prefix(text, "```")) {
+                (None, Some(info)) => self.ctx.push(state, info.trim().to_owned()),
+                (Some(_), Some(_)) => self.ctx.pop(state).unwrap(),
+                _ => state,
+            };
+            (0, state, text.len(), next)
+        }
+
+        fn embedding(&self, state: State) -> Option<Embedding> {
+            let info = self.ctx.tos(state)?;
+            Some(Embedding { language: format!("fence-info:{}", info), terminator: "```".into() })
+        }
+
+        fn stack_stats(&self) -> Stats {
+            self.ctx.stats()
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.ctx.shrink_to_fit()
+        }
+    }
+
+    /// The scope of each line of `text`, lexed by `parser` a line at a time.
+    fn line_scopes(parser: &mut dyn Parser, text: &str) -> Vec<Scope> {
+        let mut state = parser.initial_state();
+        let mut scopes = Vec::new();
+        for line in lines_with_endings(text) {
+            let (prevlen, s0, len, s1) = parser.parse(line, state);
+            let scope = if prevlen > 0 { state } else { s0 };
+            scopes.push(parser.get_scope_for_state(scope));
+            let mut i = prevlen + len;
+            state = s1;
+            while i < line.len() {
+                let (prevlen, _, len, s1) = parser.parse(&line[i..], state);
+                i += prevlen + len;
+                state = s1;
+            }
+        }
+        scopes
+    }
+
+    fn config(value: serde_json::Value) -> ConfigTable {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn builtin_languages_resolve() {
+        let registry = ParserRegistry::default();
+        assert_eq!(registry.resolve("Rust"), Some("Rust"));
+        assert_eq!(registry.resolve("Plain Text"), Some("Plain Text"));
+        assert_eq!(registry.resolve("Text"), Some("Plain Text"));
+        assert_eq!(registry.resolve("Svelte"), None);
+        assert!(registry.create("Text").is_some());
+        assert!(registry.create("Svelte").is_none());
+        assert_eq!(ParserRegistry::new().resolve("Rust"), None);
+    }
+
+    #[test]
+    fn parsers_are_named() {
+        let registry = ParserRegistry::default();
+        assert_eq!(registry.parser_name("Rust"), Some("Rust"));
+        assert_eq!(registry.parser_name("Text"), Some("Plain Text"));
+        assert_eq!(registry.parser_name("Svelte"), None);
+    }
+
+    #[test]
+    fn languages_include_each_dialect() {
+        let languages = ParserRegistry::default().languages();
+        let hosts = if cfg!(feature = "tree-sitter") { 6 } else { 4 };
+        assert_eq!(languages.len(), hosts + 4 * hosts);
+        assert_eq!(&languages[..2], &["INI", "INI (Handlebars)"]);
+        assert!(languages.iter().any(|language| language == "Rust (Literate)"));
+        assert!(languages
+            .iter()
+            .all(|language| ParserRegistry::default().create(language).is_some()));
+    }
+
+    #[test]
+    fn overrides_route_through_aliases() {
+        let mut registry = ParserRegistry::default();
+        let changes = config(json!({
+            "experimental_lang": {
+                "language_overrides": { "Svelte": "Text", "Cargo": "Rust", "Vue": "HTML" }
+            }
+        }));
+        assert!(registry.apply_config(&changes));
+        assert!(!registry.apply_config(&changes));
+
+        assert_eq!(registry.resolve("Svelte"), Some("Plain Text"));
+        assert_eq!(registry.resolve("Cargo"), Some("Rust"));
+        // overriding to a language we can't highlight doesn't help
+        assert_eq!(registry.resolve("Vue"), None);
+        assert_eq!(registry.resolve("Rust"), Some("Rust"));
+    }
+
+    #[test]
+    fn overlays_pick_their_host() {
+        let mut registry = ParserRegistry::default();
+        assert_eq!(registry.resolve("HTML (Jinja)"), Some("HTML (Jinja)"));
+        assert_eq!(registry.resolve("Handlebars"), Some("HTML (Handlebars)"));
+        assert_eq!(registry.resolve("HTML (Mustache)"), None);
+        assert_eq!(registry.parser_name("Rust (Jinja)"), Some("Template"));
+        assert_eq!(registry.parser_name("Handlebars"), Some("Template"));
+
+        // without an HTML parser the host is plain text, until overridden
+        let host_scope = |registry: &ParserRegistry, language_id| {
+            let parser = registry.create(language_id).unwrap();
+            parser.get_scope_for_state(parser.initial_state())
+        };
+        assert_eq!(host_scope(&registry, "HTML (Jinja)"), vec!["plaintext:source"]);
+        assert_eq!(host_scope(&registry, "Rust (Jinja) (Jinja)"), vec!["plaintext:source"]);
+        registry.apply_config(&config(json!({
+            "experimental_lang": { "language_overrides": { "HTML (Jinja)": "Rust (Jinja)" } }
+        })));
+        assert_eq!(host_scope(&registry, "HTML (Jinja)"), vec!["rust:source"]);
+
+        // literate files have prose around their code
+        assert_eq!(registry.resolve("Literate Haskell"), Some("Haskell (Literate)"));
+        assert_eq!(registry.parser_name("Rust (Indented)"), Some("Literate"));
+        assert_eq!(host_scope(&registry, "Literate Haskell"), vec!["literate:prose"]);
+    }
+
+    #[test]
+    fn injections_hand_regions_to_other_parsers() {
+        let mut registry = ParserRegistry::default();
+        registry.register("Fenced", || Box::new(FencedParser { ctx: Context::new() }));
+        assert!(registry.apply_config(&config(json!({
+            "experimental_lang": { "injections": [
+                { "host": "Fenced", "pattern": "fence-info:rust", "language": "Rust" },
+                // bad entries are traced and skipped
+                { "host": "Fenced", "pattern": "fence-info:svg" },
+                { "host": "Fenced", "pattern": "fence-info:mermaid", "language": "Mermaid" },
+            ] }
+        }))));
+        assert_eq!(registry.generation(), 1);
+        assert_eq!(registry.parser_name("Fenced"), Some("Fenced"));
+
+        let text = "```rust\nfn main\n```\n```python\ndef main\n```\n";
+        let scopes = line_scopes(&mut *registry.create("Fenced").unwrap(), text);
+        let innermost: Vec<_> = scopes.iter().map(|scope| scope.last().unwrap().as_str()).collect();
+        assert_eq!(
+            innermost,
+            vec![
+                "text.fenced",
+                "rust:keyword",
+                "markup.raw.block.fenced",
+                "text.fenced",
+                "markup.raw.block.fenced",
+                "markup.raw.block.fenced",
+            ]
+        );
+        assert_eq!(scopes[1][..2], ["text.fenced", "markup.raw.block.fenced"]);
+
+        // the same table again changes nothing
+        assert!(!registry.apply_config(&config(json!({
+            "experimental_lang": { "injections": [
+                { "host": "Fenced", "pattern": "fence-info:rust", "language": "Rust" },
+                { "host": "Fenced", "pattern": "fence-info:mermaid", "language": "Mermaid" },
+            ] }
+        }))));
+        assert_eq!(registry.generation(), 1);
+        assert!(registry.apply_config(&config(json!({
+            "experimental_lang": { "injections": "not a list" }
+        }))));
+        let scopes = line_scopes(&mut *registry.create("Fenced").unwrap(), text);
+        assert_eq!(scopes[1].last().unwrap(), "markup.raw.block.fenced");
+    }
+
+    #[test]
+    fn syntaxes_are_read_from_the_configured_directories() {
+        let mut registry = ParserRegistry::default();
+        assert_eq!(registry.resolve("INI"), Some("INI"));
+        assert_eq!(registry.parser_name("INI"), Some("Sublime"));
+        assert_eq!(registry.language_for_extension("cfg"), Some("INI"));
+        assert_eq!(registry.language_for_extension("rs"), None);
+        let parser = registry.create("INI (Jinja)").unwrap();
+        assert_eq!(parser.name(), "Template");
+        assert_eq!(parser.get_scope_for_state(parser.initial_state()), vec!["source.ini"]);
+
+        // what can't be read is traced and left out
+        assert!(registry.apply_config(&config(json!({
+            "experimental_lang": { "sublime_syntax_dirs": ["/nonexistent", 7] }
+        }))));
+        assert_eq!(registry.generation(), 1);
+        assert_eq!(registry.resolve("INI"), None);
+        assert_eq!(registry.language_for_extension("cfg"), None);
+
+        let sublime = Path::new(env!("CARGO_MANIFEST_DIR")).join("sublime");
+        let changes = config(json!({ "experimental_lang": { "sublime_syntax_dirs": [sublime] } }));
+        assert!(registry.apply_config(&changes));
+        assert!(!registry.apply_config(&changes));
+        assert_eq!(registry.generation(), 2);
+        assert_eq!(registry.resolve("INI"), Some("INI"));
+    }
+
+    #[test]
+    fn parsers_outrank_syntaxes() {
+        let mut registry = ParserRegistry::default();
+        registry.register("INI", || Box::new(PlaintextParser::new()));
+        assert_eq!(registry.resolve("INI"), Some("INI"));
+        assert_eq!(registry.parser_name("INI"), Some("Plain Text"));
+        // the syntax still claims its extensions
+        assert_eq!(registry.language_for_extension("ini"), Some("INI"));
+    }
+
+    #[test]
+    fn overrides_can_be_replaced() {
+        let mut registry = ParserRegistry::default();
+        registry.apply_config(&config(json!({
+            "experimental_lang": { "language_overrides": { "Svelte": "Rust" } }
+        })));
+        assert_eq!(registry.resolve("Svelte"), Some("Rust"));
+
+        // unrelated changes leave the overrides alone
+        assert!(!registry.apply_config(&config(json!({ "tab_size": 4 }))));
+        assert_eq!(registry.resolve("Svelte"), Some("Rust"));
+
+        assert!(registry.apply_config(&config(json!({
+            "experimental_lang": { "language_overrides": {} }
+        }))));
+        assert_eq!(registry.resolve("Svelte"), None);
+    }
+}
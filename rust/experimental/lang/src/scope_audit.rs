@@ -0,0 +1,192 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Audits the scope names the parsers use against the ones themes style.
+//! A theme colors the scopes it has a selector for and nothing else, so a
+//! keyword scoped `source.rust.keyword` rather than `keyword.control.rust`
+//! is shown uncolored, and nothing says why. The scope validator only
+//! checks that names are well formed; this checks that they are ones the
+//! themes in `scope_audit/themed.txt` know.
+//!
+//! The scopes audited are those each registered language and dialect
+//! announces up front, and those it gives the fixtures, which catches the
+//! ones dialects add over their host. Both are audited by the names
+//! they are sent with, after `scope_map` has translated them.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::golden;
+use crate::highlight;
+use crate::registry::ParserRegistry;
+use crate::scope_map;
+use crate::scope_tracker::Scope;
+
+/// The first dotted part of the names of a document's scope.
+const ROOTS: &[&str] = &["source", "text"];
+
+/// The scope a root may be just inside of, other than none.
+const EMBEDDING: &str = "meta.embedded";
+
+/// Scopes no theme styles that are used anyway, with why. A name passes
+/// if one of these starts it, whole dotted parts at a time.
+const EXCEPTIONS: &[(&str, &str)] = &[];
+
+fn themed_prefixes() -> Vec<String> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scope_audit/themed.txt");
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Whether `name` starts with `prefix`, whole dotted parts at a time.
+fn starts_with_parts(name: &str, prefix: &str) -> bool {
+    name.starts_with(prefix)
+        && (name.len() == prefix.len() || name[prefix.len()..].starts_with('.'))
+}
+
+/// Why the name at `index` in `scope` won't be styled, if it won't be.
+fn check_name(scope: &Scope, index: usize, themed: &[String]) -> Option<String> {
+    let name = &scope[index];
+    if EXCEPTIONS.iter().any(|&(prefix, _)| starts_with_parts(name, prefix)) {
+        return None;
+    }
+    if ROOTS.iter().any(|root| starts_with_parts(name, root)) {
+        let embedded = index > 0 && starts_with_parts(&scope[index - 1], EMBEDDING);
+        return if index == 0 || embedded {
+            None
+        } else {
+            Some(format!("`{}` is named like a document, inside `{}`", name, scope[index - 1]))
+        };
+    }
+    if index == 0 {
+        return Some(format!("`{}` is outermost, but isn't a document's scope", name));
+    }
+    if themed.iter().any(|prefix| starts_with_parts(name, prefix)) {
+        None
+    } else {
+        Some(format!("`{}` isn't a scope themes style", name))
+    }
+}
+
+/// Every scope the registered languages announce or give the fixtures,
+/// and the languages that use it.
+fn scopes_in_use() -> BTreeMap<Scope, Vec<String>> {
+    let registry = ParserRegistry::default();
+    let mut scopes: BTreeMap<Scope, Vec<String>> = BTreeMap::new();
+    let mut add = |scope: Scope, language: &str| {
+        let languages = scopes.entry(scope).or_default();
+        if !languages.iter().any(|l| l == language) {
+            languages.push(language.to_owned());
+        }
+    };
+    for language in registry.languages() {
+        for scope in registry.create(&language).unwrap().scopes() {
+            add(scope_map::translate(&scope), &language);
+        }
+    }
+    for &(dir, language) in golden::LANGUAGES {
+        for path in golden::fixtures(dir) {
+            let text = fs::read_to_string(&path).unwrap();
+            let highlighted = highlight::highlight(&registry, language, &text).unwrap();
+            for scope in highlighted.scopes {
+                add(scope, language);
+            }
+        }
+    }
+    scopes
+}
+
+#[test]
+fn scopes_in_use_are_styled_by_themes() {
+    let themed = themed_prefixes();
+    let mut problems = Vec::new();
+    for (scope, languages) in scopes_in_use() {
+        for index in 0..scope.len() {
+            if let Some(problem) = check_name(&scope, index, &themed) {
+                problems.push(format!(
+                    "{}, in `{}`, from {}",
+                    problem,
+                    scope.join(" "),
+                    languages.join(", ")
+                ));
+            }
+        }
+    }
+    assert!(
+        problems.is_empty(),
+        "scopes themes won't style; rename them, or add them to EXCEPTIONS with why:\n{}",
+        problems.join("\n")
+    );
+}
+
+#[test]
+fn scopes_in_use_are_all_translated() {
+    let untranslated: Vec<String> = scopes_in_use()
+        .into_iter()
+        .flat_map(|(scope, languages)| {
+            scope
+                .into_iter()
+                .filter(|name| name.contains(scope_map::SEPARATOR))
+                .map(move |name| format!("`{}`, from {}", name, languages.join(", ")))
+        })
+        .collect();
+    assert!(
+        untranslated.is_empty(),
+        "scope names no table in scope_map has; add them to their language's:\n{}",
+        untranslated.join("\n")
+    );
+}
+
+#[test]
+fn misnamed_scopes_are_caught() {
+    let themed: Vec<String> = vec!["comment".into(), "keyword.control".into(), "meta".into()];
+    let check = |names: &[&str]| {
+        let scope: Scope = names.iter().map(|&name| name.to_owned()).collect();
+        (0..scope.len()).filter_map(|i| check_name(&scope, i, &themed)).collect::<Vec<_>>()
+    };
+    assert!(check(&["source.rust", "keyword.control.rust"]).is_empty());
+    assert!(check(&["text.literate", "meta.embedded.block.literate", "source.rust"]).is_empty());
+    assert_eq!(
+        check(&["source.rust", "source.rust.keyword"]),
+        vec!["`source.rust.keyword` is named like a document, inside `source.rust`"]
+    );
+    assert_eq!(
+        check(&["source.rust", "keyword.controller.rust"]),
+        vec!["`keyword.controller.rust` isn't a scope themes style"]
+    );
+    assert_eq!(
+        check(&["comment.line.rust"]),
+        vec!["`comment.line.rust` is outermost, but isn't a document's scope"]
+    );
+}
+
+#[test]
+fn themed_prefixes_are_well_formed() {
+    let themed = themed_prefixes();
+    assert!(themed.iter().any(|prefix| prefix == "keyword.control"));
+    for prefix in &themed {
+        assert!(!ROOTS.iter().any(|root| starts_with_parts(prefix, root)), "{}", prefix);
+        assert!(
+            prefix.split('.').all(|part| !part.is_empty() && !part.contains(char::is_whitespace)),
+            "{:?}",
+            prefix
+        );
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The names scopes are sent to core with. Parsers name their scopes for
+//! themselves, as `rust:keyword`: the language, then what the scope is in
+//! it. Each language has a table here of the TextMate name of each of
+//! its scopes, which is what themes are written against, and a scope is
+//! sent with that name and the language after it, as `keyword.other.rust`.
+//! With `raw_scope_names` set, the parsers' own names are sent instead,
+//! to see which of a parser's scopes is which.
+//!
+//! Names without a language, like those the plugin adds to a scope, are
+//! TextMate names already, and are sent as they are.
+
+use std::borrow::Cow;
+
+use crate::scope_tracker::Scope;
+use crate::text::split_once;
+use crate::trace_lazily;
+
+/// Between the language and the rest of a parser's name for a scope.
+pub const SEPARATOR: &str = ":";
+
+const RUST: &[(&str, &str)] = &[
+    ("source", "source"),
+    ("string.double", "string.quoted.double"),
+    ("string.single", "string.quoted.single"),
+    ("comment", "comment.line.double-slash"),
+    ("escape", "constant.character.escape"),
+    ("number", "constant.numeric.decimal"),
+    ("invalid", "invalid.illegal"),
+    ("keyword", "keyword.other"),
+    ("operator", "keyword.operator.arithmetic"),
+    ("primitive-type", "storage.type"),
+];
+
+const PLAINTEXT: &[(&str, &str)] = &[("source", "source")];
+
+const LITERATE: &[(&str, &str)] =
+    &[("prose", "text"), ("marker", "punctuation.definition"), ("code", "meta.embedded.block")];
+
+const TEMPLATE: &[(&str, &str)] = &[
+    ("expression", "meta.embedded.expression"),
+    ("statement", "meta.embedded.statement"),
+    ("comment", "comment.block"),
+    ("delimiter", "punctuation.section.embedded"),
+    ("string.single", "string.quoted.single"),
+    ("string.double", "string.quoted.double"),
+    ("keyword", "keyword.control"),
+    ("filter", "support.function.filter"),
+    ("variable", "variable.other"),
+    ("number", "constant.numeric"),
+    ("operator", "keyword.operator"),
+];
+
+const JSON: &[(&str, &str)] = &[
+    ("source", "source"),
+    ("key", "support.type.property-name"),
+    ("string", "string.quoted.double"),
+    ("escape", "constant.character.escape"),
+    ("number", "constant.numeric"),
+    ("constant", "constant.language"),
+    ("comment", "comment"),
+];
+
+#[cfg(test)]
+const TESTLANG: &[(&str, &str)] = &[
+    ("source", "source"),
+    ("dot", "constant.character.dot"),
+    ("group", "meta.group"),
+    ("huge", "meta.huge"),
+    ("tally", "comment.tally"),
+];
+
+/// The table of the language that goes by `language` in scope names.
+fn table(language: &str) -> Option<&'static [(&'static str, &'static str)]> {
+    match language {
+        "rust" => Some(RUST),
+        "plaintext" => Some(PLAINTEXT),
+        "literate" => Some(LITERATE),
+        "template" => Some(TEMPLATE),
+        "json" => Some(JSON),
+        #[cfg(test)]
+        "testlang" => Some(TESTLANG),
+        _ => None,
+    }
+}
+
+/// The TextMate name of a parser's name for a scope, with the language
+/// after it; any other name is one already. A parser's name no table has
+/// is an error.
+pub fn translate_name(name: &str) -> Result<Cow<'_, str>, String> {
+    let (language, id) = match split_once(name, SEPARATOR) {
+        Some(parts) => parts,
+        None => return Ok(Cow::Borrowed(name)),
+    };
+    let table = table(language).ok_or_else(|| format!("{}: no table for {}", name, language))?;
+    match table.iter().find(|&&(own, _)| own == id) {
+        Some(&(_, textmate)) => Ok(Cow::Owned(format!("{}.{}", textmate, language))),
+        None => Err(format!("{}: not in the table for {}", name, language)),
+    }
+}
+
+/// `scope` with its names as they are sent. A name that isn't in its
+/// language's table is traced, and sent as the parser has it, so that it
+/// shows up unstyled rather than not at all.
+pub fn translate(scope: &[String]) -> Scope {
+    scope
+        .iter()
+        .map(|name| match translate_name(name) {
+            Ok(name) => name.into_owned(),
+            Err(err) => {
+                trace_lazily("unmapped scope name", &["experimental-lang"], || err);
+                name.clone()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(names: &[&str]) -> Scope {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn each_language_has_textmate_names() {
+        let name = |name| translate_name(name).unwrap().into_owned();
+        assert_eq!(name("rust:source"), "source.rust");
+        assert_eq!(name("rust:keyword"), "keyword.other.rust");
+        assert_eq!(name("rust:string.double"), "string.quoted.double.rust");
+        assert_eq!(name("rust:comment"), "comment.line.double-slash.rust");
+        assert_eq!(name("rust:primitive-type"), "storage.type.rust");
+        assert_eq!(name("plaintext:source"), "source.plaintext");
+        assert_eq!(name("literate:prose"), "text.literate");
+        assert_eq!(name("literate:code"), "meta.embedded.block.literate");
+        assert_eq!(name("template:keyword"), "keyword.control.template");
+        assert_eq!(name("template:filter"), "support.function.filter.template");
+        assert_eq!(name("json:key"), "support.type.property-name.json");
+        assert_eq!(name("testlang:dot"), "constant.character.dot.testlang");
+        // the plugin's own names are sent as they are
+        assert_eq!(name("meta.indent-guide.1"), "meta.indent-guide.1");
+        match translate_name("meta.overflow") {
+            Ok(Cow::Borrowed(_)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn unmapped_names_are_sent_as_they_are() {
+        assert!(translate_name("rust:keyword.control").is_err());
+        assert!(translate_name("klingon:source").is_err());
+        assert!(translate_name("rust:").is_err());
+
+        // tracing is left off, as the tests share it
+        let raw = scope(&["rust:source", "rust:keyword.control", "invalid.illegal.stuck"]);
+        assert_eq!(
+            translate(&raw),
+            scope(&["source.rust", "rust:keyword.control", "invalid.illegal.stuck"])
+        );
+    }
+
+    #[test]
+    fn tables_have_each_name_once() {
+        for language in &["rust", "plaintext", "literate", "template", "json", "testlang"] {
+            let table = table(language).unwrap();
+            for (i, &(own, textmate)) in table.iter().enumerate() {
+                assert!(!own.contains(SEPARATOR) && !own.is_empty(), "{}:{}", language, own);
+                assert!(!table[..i].iter().any(|&(o, _)| o == own), "{}:{}", language, own);
+                assert!(!table[..i].iter().any(|&(_, t)| t == textmate), "{}", textmate);
+            }
+        }
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Assignment of numeric identifiers to scopes.
+
+use std::collections::hash_map::{HashMap, RandomState};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+
+use xi_trace::trace_payload;
+
+/// The default number of identifiers a tracker issues before falling back
+/// to [`OVERFLOW_SCOPE`].
+pub const DEFAULT_SOFT_CAP: usize = 4096;
+
+/// The scope that new scopes are mapped to once the soft cap is reached.
+pub const OVERFLOW_SCOPE: &str = "meta.overflow";
+
+/// A scope is a stack of scope names, outermost first.
+pub type Scope = Vec<String>;
+
+/// The result of looking up a scope in a [`ScopeTracker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupResult {
+    /// The scope already had an identifier.
+    Existing(u32),
+    /// The scope was assigned a fresh identifier, which the frontend has not
+    /// been told about yet.
+    New(u32),
+}
+
+impl LookupResult {
+    pub fn id(self) -> u32 {
+        match self {
+            LookupResult::Existing(id) | LookupResult::New(id) => id,
+        }
+    }
+}
+
+/// Hands out dense identifiers for scopes, in order of first appearance.
+///
+/// Identifiers index into core's scope table for a view, which only ever
+/// grows; they are therefore never reused, even across a [`clear`]. A
+/// tracker may be shared between views, as long as each view is sent the
+/// scopes in id order, so that its table is a prefix of the tracker's.
+///
+/// Because identifiers are never reclaimed, the tracker stops issuing them
+/// for new scopes once a soft cap is reached; from then on, every new scope
+/// is given the identifier of a single [`OVERFLOW_SCOPE`].
+///
+/// [`clear`]: #method.clear
+pub struct ScopeTracker {
+    // Keyed by a hash of the scope rather than the scope itself, so that
+    // looking up a known scope needs neither an owned key nor a clone.
+    ids: HashMap<u64, Vec<u32>>,
+    // indexed by id
+    scopes: Vec<Scope>,
+    hasher: RandomState,
+    soft_cap: usize,
+    overflow_id: Option<u32>,
+}
+
+impl Default for ScopeTracker {
+    fn default() -> ScopeTracker {
+        ScopeTracker::with_soft_cap(DEFAULT_SOFT_CAP)
+    }
+}
+
+impl ScopeTracker {
+    pub fn new() -> ScopeTracker {
+        ScopeTracker::default()
+    }
+
+    /// Creates a tracker that issues at most `soft_cap` identifiers, plus
+    /// one for the overflow scope.
+    pub fn with_soft_cap(soft_cap: usize) -> ScopeTracker {
+        ScopeTracker {
+            ids: HashMap::new(),
+            scopes: Vec::new(),
+            hasher: RandomState::new(),
+            soft_cap,
+            overflow_id: None,
+        }
+    }
+
+    /// Changes how many identifiers are issued before new scopes overflow.
+    /// Those already issued are kept, even past a lowered cap.
+    pub fn set_soft_cap(&mut self, soft_cap: usize) {
+        self.soft_cap = soft_cap;
+    }
+
+    /// Returns the identifier for `scope`, assigning one if necessary.
+    /// Only a new scope allocates.
+    pub fn lookup<S: AsRef<str>>(&mut self, scope: &[S]) -> LookupResult {
+        let hash = self.hash_scope(scope);
+        let scopes = &self.scopes;
+        if let Some(candidates) = self.ids.get(&hash) {
+            let existing = candidates.iter().find(|&&id| {
+                let known = &scopes[id as usize];
+                known.len() == scope.len()
+                    && known.iter().zip(scope).all(|(a, b)| a.as_str() == b.as_ref())
+            });
+            if let Some(&id) = existing {
+                return LookupResult::Existing(id);
+            }
+        }
+
+        if self.scopes.len() >= self.soft_cap {
+            return self.overflow(scope);
+        }
+        LookupResult::New(self.insert(hash, scope.iter().map(|s| s.as_ref().to_owned()).collect()))
+    }
+
+    fn insert(&mut self, hash: u64, scope: Scope) -> u32 {
+        let id = self.scopes.len() as u32;
+        self.scopes.push(scope);
+        self.ids.entry(hash).or_default().push(id);
+        id
+    }
+
+    /// The identifier handed out in place of `scope` once the cap is hit.
+    fn overflow<S: AsRef<str>>(&mut self, scope: &[S]) -> LookupResult {
+        if let Some(id) = self.overflow_id {
+            return LookupResult::Existing(id);
+        }
+
+        if xi_trace::is_enabled() {
+            let names: Vec<&str> = scope.iter().map(|s| s.as_ref()).collect();
+            trace_payload(
+                "scope limit reached",
+                &["experimental-lang"],
+                format!(
+                    "more than {} scopes; {:?} and later scopes use {}",
+                    self.soft_cap, names, OVERFLOW_SCOPE
+                ),
+            );
+        }
+        let overflow = vec![OVERFLOW_SCOPE.to_owned()];
+        let id = self.insert(self.hash_scope(&overflow), overflow);
+        self.overflow_id = Some(id);
+        LookupResult::New(id)
+    }
+
+    fn hash_scope<S: AsRef<str>>(&self, scope: &[S]) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        for name in scope {
+            hasher.write(name.as_ref().as_bytes());
+            // separator, so that ["a.b", "c"] and ["a", "b.c"] differ
+            hasher.write_u8(0xff);
+        }
+        hasher.finish()
+    }
+
+    /// Looks up each of `scopes`, returning their identifiers in order.
+    /// Parsers that know their vocabulary up front use this so that scopes
+    /// can be announced in a single batch.
+    pub fn register_all(&mut self, scopes: &[Scope]) -> Vec<u32> {
+        scopes.iter().map(|scope| self.lookup(scope).id()).collect()
+    }
+
+    /// Forgets every scope, so that each is issued a new identifier (and
+    /// reported as `New`) the next time it is looked up.
+    pub fn clear(&mut self) {
+        self.ids.clear();
+    }
+
+    /// The scope that was assigned `id`, if any.
+    pub fn scope_for_id(&self, id: u32) -> Option<&Scope> {
+        self.scopes.get(id as usize)
+    }
+
+    /// Every issued identifier and its scope, in order of id. After a
+    /// [`clear`] a scope may appear more than once, with different ids.
+    ///
+    /// [`clear`]: #method.clear
+    pub fn iter(&self) -> impl Iterator<Item = (&Scope, u32)> {
+        self.scopes.iter().enumerate().map(|(id, scope)| (scope, id as u32))
+    }
+
+    /// The scopes issued `start` and later, in order of id; for a view that
+    /// has been told about the first `start` scopes, exactly the ones it is
+    /// missing.
+    pub fn scopes_since(&self, start: usize) -> &[Scope] {
+        &self.scopes[start.min(self.scopes.len())..]
+    }
+
+    /// An owned copy of the mapping, sorted by id.
+    pub fn snapshot(&self) -> Vec<(Scope, u32)> {
+        self.iter().map(|(scope, id)| (scope.clone(), id)).collect()
+    }
+
+    /// The number of identifiers issued so far.
+    pub fn len(&self) -> usize {
+        self.scopes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scopes.is_empty()
+    }
+}
+
+impl fmt::Debug for ScopeTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter().map(|(scope, id)| (id, scope.join(" ")))).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(names: &[&str]) -> Scope {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn lookup_and_reverse_lookup() {
+        let mut tracker = ScopeTracker::new();
+        let source = scope(&["source.rust"]);
+        let string = scope(&["source.rust", "string.quoted.double.rust"]);
+
+        assert_eq!(tracker.lookup(&source), LookupResult::New(0));
+        assert_eq!(tracker.scope_for_id(0), Some(&source));
+        assert_eq!(tracker.lookup(&string), LookupResult::New(1));
+        assert_eq!(tracker.lookup(&source), LookupResult::Existing(0));
+        assert_eq!(tracker.scope_for_id(1), Some(&string));
+        assert_eq!(tracker.scope_for_id(0), Some(&source));
+        assert_eq!(tracker.lookup(&string), LookupResult::Existing(1));
+        assert_eq!(tracker.len(), 2);
+    }
+
+    #[test]
+    fn registered_scopes_are_existing() {
+        let mut tracker = ScopeTracker::new();
+        let comment = scope(&["source.rust", "comment.line.double-slash.rust"]);
+        let scopes = vec![scope(&["source.rust"]), comment.clone(), scope(&["source.rust"])];
+        assert_eq!(tracker.register_all(&scopes), vec![0, 1, 0]);
+        assert_eq!(tracker.lookup(&comment), LookupResult::Existing(1));
+        assert_eq!(tracker.register_all(&scopes), vec![0, 1, 0]);
+        assert_eq!(tracker.len(), 2);
+    }
+
+    #[test]
+    fn clear_issues_fresh_ids() {
+        let mut tracker = ScopeTracker::new();
+        let source = scope(&["source.rust"]);
+        assert_eq!(tracker.lookup(&source), LookupResult::New(0));
+        tracker.clear();
+        assert_eq!(tracker.lookup(&source), LookupResult::New(1));
+        assert_eq!(tracker.lookup(&source), LookupResult::Existing(1));
+        // the old id still means what core thinks it means
+        assert_eq!(tracker.scope_for_id(0), Some(&source));
+    }
+
+    #[test]
+    fn scopes_since_is_the_unannounced_tail() {
+        let mut tracker = ScopeTracker::new();
+        let scopes = vec![scope(&["source.rust"]), scope(&["source.plaintext"])];
+        tracker.register_all(&scopes);
+        assert_eq!(tracker.scopes_since(0), &scopes[..]);
+        assert_eq!(tracker.scopes_since(1), &scopes[1..]);
+        assert!(tracker.scopes_since(2).is_empty());
+        assert!(tracker.scopes_since(5).is_empty());
+    }
+
+    #[test]
+    fn scopes_past_the_cap_overflow() {
+        const CAP: usize = 16;
+        let mut tracker = ScopeTracker::with_soft_cap(CAP);
+        let scopes: Vec<Scope> = (0..CAP + 100)
+            .map(|i| scope(&["source.test", &format!("entity.name.n{}", i)]))
+            .collect();
+
+        let ids = tracker.register_all(&scopes);
+        assert_eq!(ids[..CAP], (0..CAP as u32).collect::<Vec<_>>()[..]);
+        let overflow = CAP as u32;
+        assert!(ids[CAP..].iter().all(|&id| id == overflow));
+        assert_eq!(tracker.scope_for_id(overflow), Some(&scope(&[OVERFLOW_SCOPE])));
+        assert_eq!(tracker.len(), CAP + 1);
+
+        // the overflow id is only new the first time it is handed out
+        assert_eq!(tracker.lookup(&["source.test", "another"]), LookupResult::Existing(overflow));
+        assert_eq!(tracker.lookup(&[OVERFLOW_SCOPE]), LookupResult::Existing(overflow));
+
+        // ids issued before the cap was hit keep working
+        for (id, scope) in scopes[..CAP].iter().enumerate() {
+            assert_eq!(tracker.lookup(scope), LookupResult::Existing(id as u32));
+            assert_eq!(tracker.scope_for_id(id as u32), Some(scope));
+        }
+        assert_eq!(tracker.len(), CAP + 1);
+    }
+
+    #[test]
+    fn a_raised_cap_issues_ids_again() {
+        let mut tracker = ScopeTracker::with_soft_cap(1);
+        assert_eq!(tracker.lookup(&["a"]), LookupResult::New(0));
+        assert_eq!(tracker.lookup(&["b"]), LookupResult::New(1));
+        assert_eq!(tracker.scope_for_id(1), Some(&scope(&[OVERFLOW_SCOPE])));
+
+        tracker.set_soft_cap(3);
+        assert_eq!(tracker.lookup(&["c"]), LookupResult::New(2));
+        assert_eq!(tracker.lookup(&["d"]), LookupResult::Existing(1));
+        // and a lowered one keeps those issued
+        tracker.set_soft_cap(1);
+        assert_eq!(tracker.lookup(&["c"]), LookupResult::Existing(2));
+    }
+
+    #[test]
+    fn borrowed_and_owned_scopes_agree() {
+        let mut tracker = ScopeTracker::new();
+        let owned = scope(&["source.rust", "keyword.operator.rust"]);
+        assert_eq!(tracker.lookup(&["source.rust", "keyword.operator.rust"]), LookupResult::New(0));
+        assert_eq!(tracker.lookup(&owned), LookupResult::Existing(0));
+        // same characters, split differently
+        assert_eq!(tracker.lookup(&["source.rustkeyword.operator.rust"]), LookupResult::New(1));
+        assert_eq!(tracker.lookup(&["source.rust"]), LookupResult::New(2));
+        assert_eq!(tracker.lookup(&[] as &[&str]), LookupResult::New(3));
+        assert_eq!(tracker.scope_for_id(0), Some(&owned));
+    }
+
+    #[test]
+    fn snapshot_is_sorted_by_id() {
+        let mut tracker = ScopeTracker::new();
+        let names = ["source.rust", "string.quoted.double.rust", "comment.block.rust"];
+        for name in names.iter().rev() {
+            tracker.lookup(&[name]);
+        }
+        tracker.lookup(&["source.rust"]);
+
+        let snapshot = tracker.snapshot();
+        let ids = snapshot.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(snapshot[0].0, scope(&["comment.block.rust"]));
+        assert_eq!(snapshot[2].0, scope(&["source.rust"]));
+        assert_eq!(
+            format!("{:?}", tracker),
+            r#"{0: "comment.block.rust", 1: "string.quoted.double.rust", 2: "source.rust"}"#
+        );
+    }
+
+    #[test]
+    fn iter_covers_issued_ids() {
+        let mut tracker = ScopeTracker::new();
+        assert_eq!(tracker.iter().count(), 0);
+        tracker.lookup(&["a"]);
+        tracker.lookup(&["b"]);
+        tracker.lookup(&["a"]);
+        tracker.clear();
+        tracker.lookup(&["a"]);
+
+        let issued = tracker.iter().map(|(scope, id)| (scope.join(" "), id)).collect::<Vec<_>>();
+        assert_eq!(issued, vec![("a".into(), 0), ("b".into(), 1), ("a".into(), 2)]);
+        for (scope, id) in tracker.iter() {
+            assert_eq!(tracker.scope_for_id(id), Some(scope));
+        }
+    }
+
+    #[test]
+    fn unissued_id_has_no_scope() {
+        let mut tracker = ScopeTracker::new();
+        assert_eq!(tracker.scope_for_id(0), None);
+        tracker.lookup(&scope(&["source.plaintext"]));
+        assert!(tracker.scope_for_id(0).is_some());
+        assert_eq!(tracker.scope_for_id(1), None);
+        assert_eq!(tracker.scope_for_id(std::u32::MAX), None);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks that scope names follow TextMate naming conventions, so that a
+//! typo in a parser doesn't silently produce a scope no theme matches.
+
+use std::fmt;
+
+/// The top-level scope names themes are written against. `source` and
+/// `text` are the roots of a document's scope; the rest name the kinds of
+/// token within it.
+pub const KNOWN_PREFIXES: &[&str] = &[
+    "comment",
+    "constant",
+    "entity",
+    "invalid",
+    "keyword",
+    "markup",
+    "meta",
+    "punctuation",
+    "source",
+    "storage",
+    "string",
+    "support",
+    "text",
+    "variable",
+];
+
+/// Whether scopes should be validated in this build.
+pub const ENABLED: bool = cfg!(any(debug_assertions, feature = "validate-scopes"));
+
+/// Why a scope name was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeNameError {
+    /// The name has an empty component, as in `"keyword..rust"`.
+    EmptyComponent(String),
+    /// A component has a character other than a lowercase letter, digit,
+    /// `-`, `+` or `_`.
+    InvalidCharacter(String, char),
+    /// The first component is not a known prefix.
+    UnknownPrefix(String),
+}
+
+impl fmt::Display for ScopeNameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScopeNameError::EmptyComponent(name) => {
+                write!(f, "scope name {:?} has an empty component", name)
+            }
+            ScopeNameError::InvalidCharacter(name, c) => {
+                write!(f, "scope name {:?} contains invalid character {:?}", name, c)
+            }
+            ScopeNameError::UnknownPrefix(name) => {
+                write!(f, "scope name {:?} does not start with a known prefix", name)
+            }
+        }
+    }
+}
+
+/// Validates scope names against [`KNOWN_PREFIXES`] and any prefixes a
+/// parser registers for itself.
+#[derive(Debug, Clone, Default)]
+pub struct ScopeValidator {
+    custom_prefixes: Vec<String>,
+}
+
+impl ScopeValidator {
+    pub fn new() -> ScopeValidator {
+        ScopeValidator::default()
+    }
+
+    /// Accepts `prefix` as a top-level name, in addition to the known ones.
+    pub fn add_prefix<S: Into<String>>(&mut self, prefix: S) {
+        self.custom_prefixes.push(prefix.into());
+    }
+
+    /// Checks a single dot-separated scope name, like `"keyword.operator.rust"`.
+    pub fn validate_name(&self, name: &str) -> Result<(), ScopeNameError> {
+        for component in name.split('.') {
+            if component.is_empty() {
+                return Err(ScopeNameError::EmptyComponent(name.to_owned()));
+            }
+            let bad_char = component.chars().find(|&c| {
+                !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '+' || c == '_')
+            });
+            if let Some(c) = bad_char {
+                return Err(ScopeNameError::InvalidCharacter(name.to_owned(), c));
+            }
+        }
+
+        let prefix = name.split('.').next().unwrap_or("");
+        let known = KNOWN_PREFIXES.contains(&prefix)
+            || self.custom_prefixes.iter().any(|custom| custom == prefix);
+        if known {
+            Ok(())
+        } else {
+            Err(ScopeNameError::UnknownPrefix(name.to_owned()))
+        }
+    }
+
+    /// Checks every name in `scope`, stopping at the first violation.
+    pub fn validate<S: AsRef<str>>(&self, scope: &[S]) -> Result<(), ScopeNameError> {
+        scope.iter().try_for_each(|name| self.validate_name(name.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conventional_names_are_valid() {
+        let validator = ScopeValidator::new();
+        assert_eq!(validator.validate(&["source.rust", "keyword.operator.rust"]), Ok(()));
+        assert_eq!(validator.validate(&["source.c++", "entity.name.function.c++"]), Ok(()));
+        assert_eq!(validator.validate(&["text.html.markdown", "markup.heading.1"]), Ok(()));
+        assert_eq!(validator.validate(&[] as &[&str]), Ok(()));
+    }
+
+    #[test]
+    fn malformed_names_are_rejected() {
+        let validator = ScopeValidator::new();
+        assert_eq!(
+            validator.validate(&["source.rust", "keyowrd.rust"]),
+            Err(ScopeNameError::UnknownPrefix("keyowrd.rust".into()))
+        );
+        assert_eq!(
+            validator.validate_name("keyword..rust"),
+            Err(ScopeNameError::EmptyComponent("keyword..rust".into()))
+        );
+        assert_eq!(
+            validator.validate_name("keyword.Rust"),
+            Err(ScopeNameError::InvalidCharacter("keyword.Rust".into(), 'R'))
+        );
+        assert_eq!(
+            validator.validate_name("keyword rust"),
+            Err(ScopeNameError::InvalidCharacter("keyword rust".into(), ' '))
+        );
+        assert_eq!(validator.validate_name(""), Err(ScopeNameError::EmptyComponent("".into())));
+    }
+
+    #[test]
+    fn custom_prefixes_are_accepted() {
+        let mut validator = ScopeValidator::new();
+        assert!(validator.validate_name("heading.1.markdown").is_err());
+        validator.add_prefix("heading");
+        assert_eq!(validator.validate_name("heading.1.markdown"), Ok(()));
+        assert!(ScopeValidator::new().validate_name("heading.1.markdown").is_err());
+    }
+}
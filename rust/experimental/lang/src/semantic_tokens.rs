@@ -0,0 +1,346 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `xi-lang-tools highlight --format semantic-tokens`: a highlighted file as the
+//! semantic tokens of the Language Server Protocol, to set beside what a
+//! language server makes of it. Each scope's token comes from the rule
+//! for its prefix, as a palette's styles do; text no rule applies to is
+//! left out. Tokens are on one line each, as the protocol has them unless
+//! the client says otherwise, so a span's newline isn't in its token, and
+//! their positions are in UTF-16 code units, the protocol's default.
+
+use serde_json::{json, Value};
+
+use crate::highlight::Highlighted;
+use crate::palette::ScopeMap;
+use crate::text::lines_with_endings;
+
+/// The protocol's token types, in the order of the legend.
+const TOKEN_TYPES: &[&str] = &[
+    "namespace",
+    "type",
+    "class",
+    "enum",
+    "interface",
+    "struct",
+    "typeParameter",
+    "parameter",
+    "variable",
+    "property",
+    "enumMember",
+    "event",
+    "function",
+    "method",
+    "macro",
+    "keyword",
+    "modifier",
+    "comment",
+    "string",
+    "number",
+    "regexp",
+    "operator",
+    "decorator",
+];
+
+/// The protocol's token modifiers, each the bit of its position.
+const TOKEN_MODIFIERS: &[&str] = &[
+    "declaration",
+    "definition",
+    "readonly",
+    "static",
+    "deprecated",
+    "abstract",
+    "async",
+    "modification",
+    "documentation",
+    "defaultLibrary",
+];
+
+/// The token of each scope prefix: its type, then any modifiers.
+const SCOPE_TOKENS: &[(&str, &str)] = &[
+    ("comment", "comment"),
+    ("comment.block.documentation", "comment documentation"),
+    ("comment.line.documentation", "comment documentation"),
+    ("string", "string"),
+    ("string.regexp", "regexp"),
+    ("constant", "variable readonly"),
+    ("constant.numeric", "number"),
+    ("constant.language", "keyword"),
+    ("constant.character.escape", "string"),
+    ("keyword", "keyword"),
+    ("keyword.operator", "operator"),
+    ("storage", "keyword"),
+    ("storage.type", "type"),
+    ("storage.modifier", "modifier"),
+    ("entity.name.function", "function declaration"),
+    ("entity.name.type", "type declaration"),
+    ("entity.name.class", "class declaration"),
+    ("entity.name.namespace", "namespace declaration"),
+    ("entity.other.attribute-name", "property"),
+    ("entity.other.inherited-class", "class"),
+    ("variable", "variable"),
+    ("variable.parameter", "parameter"),
+    ("variable.language", "variable defaultLibrary"),
+    ("variable.other.member", "property"),
+    ("support.function", "function defaultLibrary"),
+    ("support.class", "class defaultLibrary"),
+    ("support.type", "type defaultLibrary"),
+    ("support.type.property-name", "property"),
+    ("support.constant", "variable readonly defaultLibrary"),
+];
+
+/// A token's type, by its place in the legend, and its modifiers, as bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenKind {
+    pub token_type: u32,
+    pub modifiers: u32,
+}
+
+impl TokenKind {
+    /// Reads a token written as words, as in `function defaultLibrary`:
+    /// the type, then the modifiers.
+    pub fn parse(s: &str) -> Result<TokenKind, String> {
+        let mut words = s.split_whitespace();
+        let token_type = words.next().ok_or_else(|| "no token type".to_owned())?;
+        let token_type = TOKEN_TYPES
+            .iter()
+            .position(|&t| t == token_type)
+            .ok_or_else(|| format!("unknown token type: {}", token_type))?;
+        let mut modifiers = 0;
+        for word in words {
+            let modifier = TOKEN_MODIFIERS
+                .iter()
+                .position(|&m| m == word)
+                .ok_or_else(|| format!("unknown token modifier: {}", word))?;
+            modifiers |= 1 << modifier;
+        }
+        Ok(TokenKind { token_type: token_type as u32, modifiers })
+    }
+}
+
+/// The built-in tokens of scopes.
+pub fn default_kinds() -> ScopeMap<TokenKind> {
+    let mut kinds = ScopeMap::new();
+    for &(prefix, kind) in SCOPE_TOKENS {
+        kinds.insert(prefix, TokenKind::parse(kind).unwrap());
+    }
+    kinds
+}
+
+/// A token, where it is in UTF-16 code units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub line: u32,
+    pub start: u32,
+    pub length: u32,
+    pub kind: TokenKind,
+}
+
+/// The tokens of a highlighted file, in order. Spans next to each other
+/// with the same token are one token, as with an escape in a string.
+pub fn tokens(highlighted: &Highlighted, kinds: &ScopeMap<TokenKind>) -> Vec<SemanticToken> {
+    let lines: Vec<&str> = lines_with_endings(&highlighted.text)
+        .map(|line| line.trim_end_matches(&['\r', '\n'][..]))
+        .collect();
+    let mut tokens: Vec<SemanticToken> = Vec::new();
+    // the last token's line, its end in bytes, and its kind
+    let mut last: Option<(usize, usize, TokenKind)> = None;
+    for span in &highlighted.spans {
+        let content = match lines.get(span.line) {
+            Some(content) => content,
+            None => continue,
+        };
+        let end = span.end.min(content.len());
+        if span.start >= end {
+            continue;
+        }
+        let kind = match kinds.lookup(&highlighted.scopes[span.scope_id as usize]) {
+            Some(&kind) => kind,
+            None => continue,
+        };
+        let length = utf16_len(&content[span.start..end]);
+        let follows = last == Some((span.line, span.start, kind));
+        match tokens.last_mut() {
+            Some(token) if follows => token.length += length,
+            _ => tokens.push(SemanticToken {
+                line: span.line as u32,
+                start: utf16_len(&content[..span.start]),
+                length,
+                kind,
+            }),
+        }
+        last = Some((span.line, end, kind));
+    }
+    tokens
+}
+
+fn utf16_len(text: &str) -> u32 {
+    text.encode_utf16().count() as u32
+}
+
+/// The tokens as the protocol sends them, five numbers to a token: its
+/// line, less the line of the token before; its start, less the start of
+/// the token before if that is on the same line; its length; its type;
+/// and its modifiers.
+pub fn encode(tokens: &[SemanticToken]) -> Vec<u32> {
+    let mut data = Vec::with_capacity(tokens.len() * 5);
+    let (mut line, mut start) = (0, 0);
+    for token in tokens {
+        if token.line != line {
+            start = 0;
+        }
+        data.extend_from_slice(&[
+            token.line - line,
+            token.start - start,
+            token.length,
+            token.kind.token_type,
+            token.kind.modifiers,
+        ]);
+        line = token.line;
+        start = token.start;
+    }
+    data
+}
+
+/// The tokens of a highlighted file, as the result of a full
+/// `textDocument/semanticTokens` request, with the legend the server
+/// would have announced.
+pub fn export(highlighted: &Highlighted) -> Value {
+    let data = encode(&tokens(highlighted, &default_kinds()));
+    json!({
+        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": TOKEN_MODIFIERS },
+        "data": data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlight::{self, LineSpan};
+    use crate::registry::ParserRegistry;
+
+    fn kind(s: &str) -> TokenKind {
+        TokenKind::parse(s).unwrap()
+    }
+
+    /// The tokens `encode` was given.
+    fn decode(data: &[u32]) -> Vec<SemanticToken> {
+        let (mut line, mut start) = (0, 0);
+        data.chunks(5)
+            .map(|token| {
+                if token[0] > 0 {
+                    start = 0;
+                }
+                line += token[0];
+                start += token[1];
+                let kind = TokenKind { token_type: token[3], modifiers: token[4] };
+                SemanticToken { line, start, length: token[2], kind }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scopes_pick_tokens_by_prefix() {
+        let kinds = default_kinds();
+        let lookup = |names: &[&str]| {
+            let scope: Vec<String> = names.iter().map(|&name| name.to_owned()).collect();
+            kinds.lookup(&scope).cloned()
+        };
+        assert_eq!(lookup(&["source.rust", "keyword.other.rust"]), Some(kind("keyword")));
+        assert_eq!(
+            lookup(&["source.rust", "keyword.operator.arithmetic.rust"]),
+            Some(kind("operator"))
+        );
+        assert_eq!(lookup(&["source.rust", "constant.numeric.decimal.rust"]), Some(kind("number")));
+        assert_eq!(
+            lookup(&["source.json", "support.type.property-name.json"]),
+            Some(kind("property"))
+        );
+        let doc = lookup(&["source.rust", "comment.block.documentation.rust"]).unwrap();
+        assert_eq!(doc, TokenKind { token_type: 17, modifiers: 1 << 8 });
+        assert_eq!(kind("variable readonly defaultLibrary").modifiers, 0b10_0000_0100);
+        assert_eq!(lookup(&["source.rust"]), None);
+        assert_eq!(lookup(&["source.rust", "meta.embedded.block"]), None);
+
+        assert!(TokenKind::parse("").is_err());
+        assert!(TokenKind::parse("keyword loud").is_err());
+        assert!(TokenKind::parse("Keyword").is_err());
+    }
+
+    #[test]
+    fn tokens_are_encoded_as_the_protocol_has_them() {
+        let scope = |names: &[&str]| names.iter().map(|&name| name.to_owned()).collect();
+        let span = |line, start, end, scope_id| LineSpan { line, start, end, scope_id };
+        let highlighted = Highlighted {
+            text: "/* é\n  x */ let a = \"😀\\t\";\n".to_owned(),
+            language: "Rust".to_owned(),
+            scopes: vec![
+                scope(&["source.rust"]),
+                scope(&["source.rust", "comment.block.rust"]),
+                scope(&["source.rust", "keyword.other.rust"]),
+                scope(&["source.rust", "string.quoted.double.rust"]),
+                scope(&[
+                    "source.rust",
+                    "string.quoted.double.rust",
+                    "constant.character.escape.rust",
+                ]),
+            ],
+            spans: vec![
+                // a comment over two lines, newline and all
+                span(0, 0, 6, 1),
+                span(1, 0, 6, 1),
+                span(1, 6, 7, 0),
+                span(1, 7, 10, 2),
+                span(1, 10, 15, 0),
+                // a string with an astral character and an escape in it
+                span(1, 15, 20, 3),
+                span(1, 20, 22, 4),
+                span(1, 22, 23, 3),
+                span(1, 23, 25, 0),
+            ],
+        };
+        let tokens = tokens(&highlighted, &default_kinds());
+        let data = encode(&tokens);
+        #[rustfmt::skip]
+        let expected: &[u32] = &[
+            0, 0, 4, 17, 0,
+            1, 0, 6, 17, 0,
+            0, 7, 3, 15, 0,
+            0, 8, 6, 18, 0,
+        ];
+        assert_eq!(data, expected);
+        assert_eq!(decode(&data), tokens);
+        // the comment is a token on each line, without the newline
+        assert_eq!((tokens[0].line, tokens[0].start, tokens[0].length), (0, 0, 4));
+        assert_eq!((tokens[1].line, tokens[1].start, tokens[1].length), (1, 0, 6));
+
+        let exported = export(&highlighted);
+        assert_eq!(exported["data"], json!(data));
+        assert_eq!(exported["legend"]["tokenTypes"][17], "comment");
+        assert_eq!(exported["legend"]["tokenModifiers"][9], "defaultLibrary");
+    }
+
+    #[test]
+    fn highlighted_files_export_their_tokens() {
+        let text = "let x = 42; // hi\n";
+        let highlighted = highlight::highlight(&ParserRegistry::default(), "Rust", text).unwrap();
+        let tokens = tokens(&highlighted, &default_kinds());
+        let token =
+            |start, length, name| SemanticToken { line: 0, start, length, kind: kind(name) };
+        assert_eq!(tokens.first(), Some(&token(0, 3, "keyword")));
+        assert!(tokens.contains(&token(8, 2, "number")), "{:?}", tokens);
+        assert_eq!(tokens.last(), Some(&token(12, 5, "comment")));
+        assert_eq!(export(&highlighted)["data"].as_array().unwrap().len(), tokens.len() * 5);
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What core was last sent for each line, as a digest of the spans, so
+//! that a region whose spans core already has isn't sent again.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::packed::PackedSpans;
+
+use crate::line_map::LineMap;
+
+/// A digest of the spans sent for each line, or piece of a long line, kept
+/// by offset as the lexed lines are. Unlike those, it outlives highlighting
+/// starting again from scratch, which is when it saves the most; only
+/// core dropping or replacing spans, or an edit, loses a line's digest.
+pub type SentSpans = LineMap<u64>;
+
+/// A digest of `spans`, packed relative to the start of the `len` bytes
+/// they cover.
+pub fn digest(spans: &PackedSpans, len: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    len.hash(&mut hasher);
+    spans.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_core_lib::plugins::rpc::ScopeSpan;
+    use xi_rope::Interval;
+
+    fn spans(ranges: &[(usize, usize, u32)], start: usize) -> PackedSpans {
+        let spans: Vec<_> = ranges
+            .iter()
+            .map(|&(start, end, scope_id)| ScopeSpan { start, end, scope_id })
+            .collect();
+        PackedSpans::pack(&spans, start).unwrap()
+    }
+
+    #[test]
+    fn digests_are_relative_to_the_region() {
+        let a = digest(&spans(&[(0, 3, 1), (3, 10, 2)], 0), 10);
+        // the same spans further into a region
+        assert_eq!(digest(&spans(&[(20, 23, 1), (23, 30, 2)], 20), 10), a);
+        assert_ne!(digest(&spans(&[(0, 3, 1), (3, 10, 3)], 0), 10), a);
+        assert_ne!(digest(&spans(&[(0, 3, 1), (3, 10, 2)], 0), 11), a);
+
+        let mut sent = SentSpans::new(30);
+        sent.record(Interval::new(0, 10), a);
+        assert_eq!(sent.get(Interval::new(0, 10)), Some(a));
+    }
+}
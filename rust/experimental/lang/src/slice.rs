@@ -0,0 +1,136 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How many lines one idle callback highlights, either fixed or adapted to
+//! how long lines have been taking to lex.
+
+use std::time::Duration;
+
+/// The fewest lines an adaptive slice shrinks to, however slow they are.
+pub const MIN_LINES: usize = 8;
+/// The most lines a slice covers, however cheap they are.
+pub const MAX_LINES: usize = 1000;
+/// How long an adaptive slice aims to take.
+pub const TARGET_LATENCY_MS: u64 = 4;
+
+pub struct SliceSize {
+    lines: usize,
+    /// The latency the size is adjusted toward, if it adapts at all.
+    target: Option<Duration>,
+}
+
+impl SliceSize {
+    /// Always `lines` lines, which must be at least one.
+    pub fn fixed(lines: usize) -> SliceSize {
+        SliceSize { lines: lines.max(1), target: None }
+    }
+
+    /// Sized so that a slice takes about `target`, starting from the
+    /// largest size.
+    pub fn adaptive(target: Duration) -> SliceSize {
+        SliceSize { lines: MAX_LINES, target: Some(target) }
+    }
+
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// Accounts for a slice that lexed `lines` lines in `elapsed`, moving
+    /// halfway toward the size that would have taken the target time, so
+    /// that one unusual slice doesn't swing the size too far.
+    pub fn record(&mut self, lines: usize, elapsed: Duration) {
+        let target = match self.target {
+            Some(target) if lines > 0 => target,
+            _ => return,
+        };
+        let ideal = match nanos(elapsed) {
+            0 => MAX_LINES,
+            elapsed => (nanos(target) * lines as u128 / elapsed).min(MAX_LINES as u128) as usize,
+        };
+        // rounding away from the current size, so that it gets all the way
+        let lines = if ideal > self.lines {
+            self.lines + (ideal - self.lines + 1) / 2
+        } else {
+            self.lines - (self.lines - ideal + 1) / 2
+        };
+        self.lines = lines.max(MIN_LINES).min(MAX_LINES);
+    }
+}
+
+/// `duration` in nanoseconds.
+fn nanos(duration: Duration) -> u128 {
+    u128::from(duration.as_secs()) * 1_000_000_000 + u128::from(duration.subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn fixed_sizes_ignore_timings() {
+        let mut size = SliceSize::fixed(50);
+        size.record(50, ms(100));
+        assert_eq!(size.lines(), 50);
+        assert_eq!(SliceSize::fixed(0).lines(), 1);
+    }
+
+    #[test]
+    fn slow_lines_shrink_the_slice() {
+        let mut size = SliceSize::adaptive(ms(4));
+        // 0.4ms a line, so 10 lines fit the target
+        for _ in 0..20 {
+            let lines = size.lines();
+            size.record(lines, ms(4) * lines as u32 / 10);
+        }
+        assert!(size.lines() == 10 || size.lines() == 11, "{}", size.lines());
+
+        // and it never shrinks past the minimum
+        for _ in 0..20 {
+            size.record(size.lines(), ms(1000));
+        }
+        assert_eq!(size.lines(), MIN_LINES);
+    }
+
+    #[test]
+    fn fast_lines_grow_the_slice() {
+        let mut size = SliceSize::adaptive(ms(4));
+        for _ in 0..3 {
+            size.record(10, ms(1000));
+        }
+        let shrunk = size.lines();
+        assert!(shrunk < MAX_LINES / 4);
+
+        size.record(shrunk, Duration::from_micros(1));
+        assert!(size.lines() > shrunk);
+        for _ in 0..20 {
+            size.record(size.lines(), Duration::from_micros(1));
+        }
+        assert_eq!(size.lines(), MAX_LINES);
+        size.record(MAX_LINES, Duration::from_secs(0));
+        assert_eq!(size.lines(), MAX_LINES);
+    }
+
+    #[test]
+    fn empty_slices_are_ignored() {
+        let mut size = SliceSize::adaptive(ms(4));
+        size.record(10, ms(1000));
+        let lines = size.lines();
+        size.record(0, ms(1000));
+        assert_eq!(size.lines(), lines);
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks that the spans of each `update_spans` call are ones core can
+//! make sense of. Core doesn't reject spans out of order, past the region
+//! they update or of scopes it hasn't been told of; it shows them wrongly,
+//! which is easy to miss.
+
+use std::fmt;
+use std::iter;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use xi_core_lib::plugins::rpc::ScopeSpan;
+use xi_rope::Rope;
+
+use crate::trace_lazily;
+
+/// The least time between two traces of invalid spans.
+const REPORT_INTERVAL_MS: u64 = 1000;
+
+/// Why the spans of an update were rejected. Spans are numbered from 0
+/// in the update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanError {
+    /// The span starts before the one before it ends.
+    Unordered(usize),
+    /// The span ends before it starts.
+    Backwards(usize),
+    /// The span ends past the end of the region updated.
+    PastRegion(usize),
+    /// The span's scope id hasn't been announced with `add_scopes`.
+    UnknownScope(usize, u32),
+    /// The region updated ends past the end of the document.
+    #[cfg_attr(not(feature = "tools"), allow(dead_code))]
+    PastDocument,
+    /// A span, or the region, starts or ends at this offset in the
+    /// document, which is inside a character.
+    #[cfg_attr(not(feature = "tools"), allow(dead_code))]
+    InsideCharacter(usize),
+}
+
+impl fmt::Display for SpanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpanError::Unordered(i) => write!(f, "span {} starts before span {} ends", i, i - 1),
+            SpanError::Backwards(i) => write!(f, "span {} ends before it starts", i),
+            SpanError::PastRegion(i) => write!(f, "span {} ends past the region", i),
+            SpanError::UnknownScope(i, id) => {
+                write!(f, "span {} has scope id {}, which hasn't been announced", i, id)
+            }
+            SpanError::PastDocument => write!(f, "the region ends past the document"),
+            SpanError::InsideCharacter(offset) => {
+                write!(f, "offset {} is inside a character", offset)
+            }
+        }
+    }
+}
+
+/// Checks the spans of an update of a region `len` bytes long, sent once
+/// core has been told of `known_scopes` scopes.
+pub fn check_spans(len: usize, spans: &[ScopeSpan], known_scopes: usize) -> Result<(), SpanError> {
+    let mut end_of_last = 0;
+    for (i, span) in spans.iter().enumerate() {
+        if span.start < end_of_last {
+            return Err(SpanError::Unordered(i));
+        }
+        if span.end < span.start {
+            return Err(SpanError::Backwards(i));
+        }
+        if span.end > len {
+            return Err(SpanError::PastRegion(i));
+        }
+        if span.scope_id as usize >= known_scopes {
+            return Err(SpanError::UnknownScope(i, span.scope_id));
+        }
+        end_of_last = span.end;
+    }
+    Ok(())
+}
+
+/// Checks that an update of `start..start + len` fits in `text`, and that
+/// it and each of its spans start and end between characters.
+#[cfg_attr(not(feature = "tools"), allow(dead_code))]
+pub fn check_boundaries(
+    text: &Rope,
+    start: usize,
+    len: usize,
+    spans: &[ScopeSpan],
+) -> Result<(), SpanError> {
+    if start + len > text.len() {
+        return Err(SpanError::PastDocument);
+    }
+    let ends = spans.iter().flat_map(|span| iter::once(span.start).chain(iter::once(span.end)));
+    for offset in iter::once(0).chain(iter::once(len)).chain(ends) {
+        if !text.is_codepoint_boundary(start + offset) {
+            return Err(SpanError::InsideCharacter(start + offset));
+        }
+    }
+    Ok(())
+}
+
+/// Reports updates whose spans are invalid: fatally in debug builds, so
+/// that tests catch them, and otherwise as a trace. One bug tends to break
+/// every update, so traces are made at most once every `REPORT_INTERVAL_MS`,
+/// with a count of the ones left out.
+#[derive(Debug, Default)]
+pub struct SpanReporter {
+    last_report: Option<Instant>,
+    unreported: usize,
+}
+
+impl SpanReporter {
+    pub fn new() -> SpanReporter {
+        SpanReporter::default()
+    }
+
+    /// Checks the spans `parser` made for `start..start + len`.
+    pub fn check(
+        &mut self,
+        parser: &str,
+        start: usize,
+        len: usize,
+        spans: &[ScopeSpan],
+        known_scopes: usize,
+    ) {
+        let err = match check_spans(len, spans, known_scopes) {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        let message = format!("{} parser, spans for {}..{}: {}", parser, start, start + len, err);
+        if cfg!(debug_assertions) {
+            panic!("{}", message);
+        }
+        let now = Instant::now();
+        if self
+            .last_report
+            .map_or(false, |last| now - last < Duration::from_millis(REPORT_INTERVAL_MS))
+        {
+            self.unreported += 1;
+            return;
+        }
+        self.last_report = Some(now);
+        let unreported = mem::replace(&mut self.unreported, 0);
+        trace_lazily("invalid spans", &["experimental-lang"], || {
+            format!("{}; {} more left out since the last", message, unreported)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize, end: usize, scope_id: u32) -> ScopeSpan {
+        ScopeSpan { start, end, scope_id }
+    }
+
+    #[test]
+    fn spans_are_in_order_within_the_region() {
+        assert_eq!(check_spans(10, &[], 0), Ok(()));
+        assert_eq!(check_spans(10, &[span(0, 4, 0), span(4, 4, 1), span(6, 10, 0)], 2), Ok(()));
+        assert_eq!(
+            check_spans(10, &[span(0, 4, 0), span(3, 6, 0)], 1),
+            Err(SpanError::Unordered(1))
+        );
+        assert_eq!(check_spans(10, &[span(5, 4, 0)], 1), Err(SpanError::Backwards(0)));
+        assert_eq!(check_spans(10, &[span(5, 11, 0)], 1), Err(SpanError::PastRegion(0)));
+        assert_eq!(
+            check_spans(10, &[span(0, 4, 0), span(4, 8, 2)], 2),
+            Err(SpanError::UnknownScope(1, 2))
+        );
+    }
+
+    #[test]
+    fn spans_end_between_characters() {
+        let text = Rope::from("let é = 1;\n");
+        let e = 4;
+        assert_eq!(check_boundaries(&text, 0, text.len(), &[span(e, e + 2, 0)]), Ok(()));
+        assert_eq!(check_boundaries(&text, e, 2, &[span(0, 2, 0)]), Ok(()));
+        assert_eq!(
+            check_boundaries(&text, 0, text.len(), &[span(e, e + 1, 0)]),
+            Err(SpanError::InsideCharacter(e + 1))
+        );
+        assert_eq!(check_boundaries(&text, e + 1, 1, &[]), Err(SpanError::InsideCharacter(e + 1)));
+        assert_eq!(check_boundaries(&text, 4, text.len(), &[]), Err(SpanError::PastDocument));
+    }
+
+    #[test]
+    fn errors_say_which_span() {
+        assert_eq!(SpanError::Unordered(3).to_string(), "span 3 starts before span 2 ends");
+        assert_eq!(
+            SpanError::UnknownScope(0, 7).to_string(),
+            "span 0 has scope id 7, which hasn't been announced"
+        );
+    }
+}
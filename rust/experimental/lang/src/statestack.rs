@@ -12,35 +12,123 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::HashMap, hash::Hash};
+use std::{
+    collections::hash_map::{self, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-/// An entire state stack is represented as a single integer.
-#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct State(usize);
+use xi_plugin_lib::StateCache;
+
+/// An entire state stack is represented as a single integer, tagged with
+/// the namespace of the context that created it.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct State {
+    id: u32,
+    namespace: u32,
+}
+
+/// The most states a context numbers.
+const MAX_ID: u32 = std::u32::MAX;
+
+impl State {
+    fn new(id: u32, namespace: u32) -> State {
+        State { id, namespace }
+    }
+
+    fn id(self) -> u32 {
+        self.id
+    }
+
+    fn namespace(self) -> u32 {
+        self.namespace
+    }
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("State")
+            .field("id", &self.id())
+            .field("namespace", &self.namespace())
+            .finish()
+    }
+}
+
+/// Source of context namespaces; 0 is reserved for the empty stack, which
+/// is valid in every context. A namespace is only handed out again after
+/// `u32::MAX` more contexts have been made.
+static NEXT_NAMESPACE: AtomicUsize = AtomicUsize::new(0);
 
 struct Entry<T> {
     tos: T,
     prev: State,
 }
 
+/// Summary of the memory used by a [`Context`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of distinct non-empty states.
+    pub states: usize,
+    /// Number of stack elements if every state stored its full stack; the
+    /// ratio of this to `states` shows how much structural sharing we get.
+    pub elements: usize,
+    /// Depth of the deepest stack.
+    pub max_depth: usize,
+    /// Rough estimate of the heap memory held by the context, in bytes.
+    pub estimated_bytes: usize,
+}
+
 /// All states are interpreted in a context.
 pub struct Context<T> {
     // oddly enough, this is 1-based, as state 0 doesn't have an entry.
     entries: Vec<Entry<T>>,
 
     next: HashMap<(State, T), State>,
+
+    namespace: u32,
+
+    // pushing onto a stack past this many states gives the empty stack
+    max_states: usize,
 }
 
 impl<T: Clone + Hash + Eq> Context<T> {
     pub fn new() -> Context<T> {
-        Context { entries: Vec::new(), next: HashMap::new() }
+        let namespace =
+            (NEXT_NAMESPACE.fetch_add(1, Ordering::Relaxed) % MAX_ID as usize) as u32 + 1;
+        Context {
+            entries: Vec::new(),
+            next: HashMap::new(),
+            namespace,
+            max_states: MAX_ID as usize,
+        }
+    }
+
+    /// Returns `true` if `s` can be interpreted in this context.
+    pub fn owns(&self, s: State) -> bool {
+        s.id() == 0 || (s.namespace() == self.namespace && s.id() as usize <= self.entries.len())
+    }
+
+    /// Using a state from another context (for instance one cached by a
+    /// parser for a different language) is a bug. It panics in debug builds,
+    /// and the state is treated as the empty stack in release builds.
+    fn resolve(&self, s: State) -> State {
+        if self.owns(s) {
+            s
+        } else if cfg!(debug_assertions) {
+            panic!("{:?} does not belong to state context {}", s, self.namespace)
+        } else {
+            State::default()
+        }
     }
 
     fn entry(&self, s: State) -> Option<&Entry<T>> {
-        if s.0 == 0 {
+        let s = self.resolve(s);
+        if s.id() == 0 {
             None
         } else {
-            Some(&self.entries[s.0 - 1])
+            Some(&self.entries[s.id() as usize - 1])
         }
     }
 
@@ -53,11 +141,379 @@ impl<T: Clone + Hash + Eq> Context<T> {
         self.entry(s).map(|entry| entry.prev)
     }
 
+    /// The state of `el` pushed onto the stack of `s`. Once the context
+    /// has numbered all the states it can, a new stack is given the empty
+    /// one instead, and the parser starts over from there.
     pub fn push(&mut self, s: State, el: T) -> State {
-        let entries = &mut self.entries;
-        *self.next.entry((s, el.clone())).or_insert_with(|| {
-            entries.push(Entry { tos: el, prev: s });
-            State(entries.len())
+        let s = self.resolve(s);
+        match self.next.entry((s, el.clone())) {
+            hash_map::Entry::Occupied(next) => *next.get(),
+            hash_map::Entry::Vacant(_) if self.entries.len() >= self.max_states => State::default(),
+            hash_map::Entry::Vacant(next) => {
+                self.entries.push(Entry { tos: el, prev: s });
+                *next.insert(State::new(self.entries.len() as u32, self.namespace))
+            }
+        }
+    }
+
+    /// The number of distinct non-empty states.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Computes sharing and memory statistics for this context, in time
+    /// linear in the number of states.
+    pub fn stats(&self) -> Stats {
+        // An entry's parent always precedes it, so depths can be filled in
+        // with a single forward pass.
+        let mut depths = Vec::with_capacity(self.entries.len());
+        let mut elements = 0;
+        let mut max_depth = 0;
+        for entry in &self.entries {
+            let prev = entry.prev.id() as usize;
+            let depth = if prev == 0 { 1 } else { depths[prev - 1] + 1 };
+            depths.push(depth);
+            elements += depth;
+            max_depth = max_depth.max(depth);
+        }
+
+        let entry_bytes = self.entries.capacity() * mem::size_of::<Entry<T>>();
+        let next_bytes = self.next.capacity() * mem::size_of::<((State, T), State)>();
+        Stats {
+            states: self.entries.len(),
+            elements,
+            max_depth,
+            estimated_bytes: entry_bytes + next_bytes,
+        }
+    }
+
+    /// The number of elements on the stack of `s`.
+    pub fn depth(&self, s: State) -> usize {
+        let mut s = s;
+        let mut depth = 0;
+        while let Some(entry) = self.entry(s) {
+            s = entry.prev;
+            depth += 1;
+        }
+        depth
+    }
+
+    /// A hash of the full element stack of `s`. Unlike the numeric state,
+    /// this depends only on the elements, so it can be compared across
+    /// contexts and across runs.
+    pub fn fingerprint(&self, s: State) -> u64 {
+        let mut hasher = StableHasher::new();
+        let mut s = s;
+        let mut depth = 0u64;
+        while let Some(entry) = self.entry(s) {
+            entry.tos.hash(&mut hasher);
+            s = entry.prev;
+            depth += 1;
+        }
+        // not `write_usize`, which hashes 4 bytes on 32-bit targets
+        hasher.write_u64(depth);
+        hasher.finish()
+    }
+
+    /// Fingerprints the states stored for the first `num_lines` lines of
+    /// `cache`. Lines with no cached state map to `None`.
+    pub fn fingerprint_cache(
+        &self,
+        cache: &StateCache<State>,
+        num_lines: usize,
+    ) -> Vec<Option<u64>> {
+        (0..num_lines).map(|line_num| cache.get(line_num).map(|s| self.fingerprint(*s))).collect()
+    }
+
+    /// Releases any excess capacity held by the context.
+    pub fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+        self.next.shrink_to_fit();
+    }
+}
+
+impl<T: Clone + Hash + Eq> Default for Context<T> {
+    fn default() -> Context<T> {
+        Context::new()
+    }
+}
+
+/// A map from the states of a single context to values, indexed by the
+/// numeric state rather than hashed. States of any other context are never
+/// found, and aren't stored.
+pub struct StateMap<V> {
+    // indexed by state id, so the empty stack is the first entry
+    values: Vec<Option<V>>,
+    // 0 until a non-empty state is stored
+    namespace: u32,
+}
+
+impl<V: Copy> StateMap<V> {
+    pub fn new() -> StateMap<V> {
+        StateMap { values: Vec::new(), namespace: 0 }
+    }
+
+    fn owns(&self, s: State) -> bool {
+        s.id() == 0 || self.namespace == 0 || s.namespace() == self.namespace
+    }
+
+    pub fn get(&self, s: State) -> Option<V> {
+        if !self.owns(s) {
+            return None;
+        }
+        self.values.get(s.id() as usize).and_then(|&value| value)
+    }
+
+    /// Stores `value` for `s`, unless `s` is from another context than the
+    /// states already stored.
+    pub fn insert(&mut self, s: State, value: V) {
+        if !self.owns(s) {
+            return;
+        }
+        if s.id() != 0 {
+            self.namespace = s.namespace();
+        }
+        let index = s.id() as usize;
+        if index >= self.values.len() {
+            self.values.resize(index + 1, None);
+        }
+        self.values[index] = Some(value);
+    }
+
+    /// Every stored state and its value, in order of state.
+    pub fn iter(&self) -> impl Iterator<Item = (State, V)> + '_ {
+        let namespace = self.namespace;
+        self.values.iter().enumerate().filter_map(move |(id, value)| {
+            let state = if id == 0 { State::default() } else { State::new(id as u32, namespace) };
+            value.map(|value| (state, value))
         })
     }
+
+    pub fn len(&self) -> usize {
+        self.values.iter().filter(|value| value.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.iter().all(Option::is_none)
+    }
+
+    /// Rough estimate of the heap memory held by the map, in bytes.
+    pub fn estimated_bytes(&self) -> usize {
+        self.values.capacity() * mem::size_of::<Option<V>>()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.namespace = 0;
+    }
+}
+
+impl<V: Copy> Default for StateMap<V> {
+    fn default() -> StateMap<V> {
+        StateMap::new()
+    }
+}
+
+/// 64-bit FNV-1a. `DefaultHasher` is randomly seeded, which makes it
+/// useless for comparing fingerprints between runs.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> StableHasher {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_track_pushes() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.stats(), Stats::default());
+
+        let a = ctx.push(State::default(), 'a');
+        let ab = ctx.push(a, 'b');
+        let abc = ctx.push(ab, 'c');
+        assert_eq!(ctx.depth(abc), 3);
+        assert_eq!(ctx.depth(State::default()), 0);
+        let stats = ctx.stats();
+        assert_eq!(stats.states, 3);
+        assert_eq!(stats.elements, 1 + 2 + 3);
+        assert_eq!(stats.max_depth, 3);
+
+        // pushing an existing state is shared, not duplicated
+        assert_eq!(ctx.push(a, 'b'), ab);
+        assert_eq!(ctx.stats().states, 3);
+
+        let _ad = ctx.push(a, 'd');
+        let stats = ctx.stats();
+        assert_eq!(stats.states, 4);
+        assert_eq!(stats.elements, 1 + 2 + 3 + 2);
+        assert_eq!(stats.max_depth, 3);
+    }
+
+    #[test]
+    fn fingerprint_ignores_numeric_ids() {
+        let mut ctx_a = Context::new();
+        let a = ctx_a.push(State::default(), "outer");
+        let a = ctx_a.push(a, "inner");
+
+        let mut ctx_b = Context::new();
+        let _unrelated = ctx_b.push(State::default(), "x");
+        let other = ctx_b.push(State::default(), "y");
+        let _ = ctx_b.push(other, "inner");
+        let b = ctx_b.push(State::default(), "outer");
+        let b = ctx_b.push(b, "inner");
+
+        assert_ne!(a, b);
+        assert_eq!(ctx_a.fingerprint(a), ctx_b.fingerprint(b));
+        assert_eq!(ctx_a.fingerprint(State::default()), ctx_b.fingerprint(State::default()));
+    }
+
+    #[test]
+    fn fingerprint_is_the_same_on_every_target() {
+        // the depth, 0, hashed as 8 bytes whatever the size of `usize`
+        let ctx = Context::<u8>::new();
+        assert_eq!(ctx.fingerprint(State::default()), 0xa8c7_f832_281a_39c5);
+    }
+
+    #[test]
+    fn fingerprint_distinguishes_payloads() {
+        let mut ctx = Context::new();
+        let root = State::default();
+        let a = ctx.push(root, 1u32);
+        let b = ctx.push(root, 2u32);
+        let aa = ctx.push(a, 1);
+        let ab = ctx.push(a, 2);
+        let ba = ctx.push(b, 1);
+        assert_ne!(ctx.fingerprint(a), ctx.fingerprint(b));
+        assert_ne!(ctx.fingerprint(aa), ctx.fingerprint(ab));
+        assert_ne!(ctx.fingerprint(ab), ctx.fingerprint(ba));
+        assert_ne!(ctx.fingerprint(root), ctx.fingerprint(a));
+    }
+
+    #[test]
+    fn states_are_scoped_to_their_context() {
+        let mut old_ctx = Context::new();
+        let old_state = old_ctx.push(State::default(), 'a');
+
+        let mut new_ctx = Context::<char>::new();
+        assert!(old_ctx.owns(old_state));
+        assert!(!new_ctx.owns(old_state));
+        assert!(new_ctx.owns(State::default()));
+        let new_state = new_ctx.push(State::default(), 'a');
+        assert_ne!(old_state, new_state);
+    }
+
+    #[test]
+    fn namespaces_are_not_reused() {
+        let namespaces: Vec<u32> = (0..1000).map(|_| Context::<u8>::new().namespace).collect();
+        assert!(namespaces.iter().all(|&namespace| namespace > 0));
+        let mut distinct = namespaces.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), namespaces.len());
+    }
+
+    #[test]
+    fn full_context_gives_the_empty_stack() {
+        let mut ctx = Context::new();
+        ctx.max_states = 2;
+        let a = ctx.push(State::default(), 'a');
+        let ab = ctx.push(a, 'b');
+        assert_eq!(ctx.push(ab, 'c'), State::default());
+        assert_eq!(ctx.push(State::default(), 'x'), State::default());
+        // stacks it already has are still found
+        assert_eq!(ctx.push(a, 'b'), ab);
+        assert_eq!(ctx.tos(ab), Some('b'));
+        assert_eq!(ctx.len(), 2);
+    }
+
+    #[test]
+    fn states_past_the_end_are_foreign() {
+        let mut ctx = Context::new();
+        let a = ctx.push(State::default(), 'a');
+        assert!(ctx.owns(a));
+        assert!(!ctx.owns(State::new(2, ctx.namespace)));
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "does not belong to state context"))]
+    fn foreign_state_is_detected() {
+        let mut old_ctx = Context::new();
+        let old_state = old_ctx.push(State::default(), 'a');
+        let old_state = old_ctx.push(old_state, 'b');
+
+        let mut new_ctx = Context::new();
+        let _ = new_ctx.push(State::default(), 'c');
+        // in release builds the stale state is read as the empty stack
+        assert_eq!(new_ctx.tos(old_state), None);
+        assert_eq!(new_ctx.push(old_state, 'c'), new_ctx.push(State::default(), 'c'));
+    }
+
+    #[test]
+    fn shrink_to_fit_keeps_states() {
+        let mut ctx = Context::new();
+        let mut state = State::default();
+        for i in 0..100 {
+            state = ctx.push(state, i);
+        }
+        let before = ctx.stats();
+        ctx.shrink_to_fit();
+        let after = ctx.stats();
+        assert_eq!(before.states, after.states);
+        assert_eq!(before.elements, after.elements);
+        assert!(after.estimated_bytes <= before.estimated_bytes);
+        assert_eq!(ctx.tos(state), Some(99));
+    }
+
+    #[test]
+    fn state_map_only_holds_one_context() {
+        let mut ctx = Context::new();
+        let a = ctx.push(State::default(), 'a');
+        let ab = ctx.push(a, 'b');
+        let mut other = Context::new();
+        let x = other.push(State::default(), 'x');
+
+        let mut map = StateMap::new();
+        assert!(map.is_empty());
+        map.insert(ab, 2);
+        map.insert(State::default(), 0);
+        assert_eq!(map.get(ab), Some(2));
+        assert_eq!(map.get(a), None);
+        assert_eq!(map.get(State::default()), Some(0));
+
+        // a state of another context with the same number isn't confused
+        // with ours
+        assert_eq!(x.id(), a.id());
+        map.insert(a, 1);
+        map.insert(x, 9);
+        assert_eq!(map.get(x), None);
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(State::default(), 0), (a, 1), (ab, 2)]);
+        assert_eq!(map.len(), 3);
+
+        map.clear();
+        map.insert(x, 9);
+        assert_eq!(map.get(x), Some(9));
+        assert_eq!(map.get(a), None);
+    }
 }
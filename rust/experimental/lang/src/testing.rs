@@ -0,0 +1,437 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Editing sessions, replayed through the incremental path. A session is
+//! the text a document was opened with and the edits made to it, as core
+//! sent them, so that a bug report of highlighting going wrong after some
+//! editing can be turned into a regression test as it is. `xi-lang-tools replay`
+//! replays them from the command line, and the sessions under
+//! `testdata/sessions/` are replayed by the tests.
+//!
+//! A session is a JSON document:
+//!
+//! ```text
+//! {
+//!     "language": "Rust",
+//!     "text": "fn main() {}\n",
+//!     "steps": [
+//!         [3, 4, "start"],
+//!         [0, 0, "/*", "insert"],
+//!         {"check": [{"at": "1:5", "scope": "comment"}]}
+//!     ]
+//! }
+//! ```
+//!
+//! The text is a string, or a list of lines that each end in a newline.
+//! Each edit replaces `delete` bytes at `offset` with the text after, and
+//! may name its edit type as core does, `insert` if it doesn't: `insert`,
+//! `newline` and `delete` are typing, and others, like `paste` or `undo`,
+//! are not. At each check, and once the edits are done, the spans core
+//! would show are compared with a highlight of the text from scratch, and
+//! the scopes at each position (a line and column from 1) are checked;
+//! `"not_scope"` checks the scope isn't there.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde_json::Value;
+use xi_rope::{Interval, Rope, RopeDelta};
+
+use crate::registry::ParserRegistry;
+use crate::scope_tracker::Scope;
+use crate::text::lines_with_endings;
+use crate::view::mock::{resolved_scopes, run_idle, MockView};
+use crate::view::HighlightView;
+use crate::{is_typing, ViewState};
+
+const USAGE: &str = "usage: xi-lang-tools replay SESSION...";
+
+/// The edit type of an edit that doesn't name one.
+const DEFAULT_EDIT_TYPE: &str = "insert";
+
+/// A recorded editing session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub language: String,
+    /// The text the document was opened with.
+    pub text: String,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Replaces `delete` bytes at `offset` with `insert`.
+    Edit { offset: usize, delete: usize, insert: String, edit_type: String },
+    /// Lets typing pause and highlighting catch up, then checks the spans.
+    Check(Vec<Expectation>),
+}
+
+/// A scope that must, or must not, be at a position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expectation {
+    /// The line and column, from 1; the column counts characters.
+    pub line: usize,
+    pub column: usize,
+    /// A scope name, which matches it and the names it is a prefix of:
+    /// `comment` matches `comment.block.rust`.
+    pub scope: String,
+    pub present: bool,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Session, String> {
+        let source =
+            fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let json: Value =
+            serde_json::from_str(&source).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Session::from_json(&json).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    pub fn from_json(json: &Value) -> Result<Session, String> {
+        let language = json["language"].as_str().ok_or("no language")?.to_owned();
+        let text = match &json["text"] {
+            Value::String(text) => text.clone(),
+            Value::Array(lines) => {
+                let mut text = String::new();
+                for line in lines {
+                    text.push_str(line.as_str().ok_or("the lines of the text aren't strings")?);
+                    text.push('\n');
+                }
+                text
+            }
+            _ => return Err("no text".to_owned()),
+        };
+        let steps = json["steps"].as_array().ok_or("no steps")?;
+        let steps = steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| parse_step(step).map_err(|err| format!("step {}: {}", i + 1, err)))
+            .collect::<Result<_, _>>()?;
+        Ok(Session { language, text, steps })
+    }
+}
+
+fn parse_step(json: &Value) -> Result<Step, String> {
+    if let Some(checks) = json.get("check") {
+        let checks = checks.as_array().ok_or("a check isn't a list")?;
+        return checks.iter().map(parse_expectation).collect::<Result<_, _>>().map(Step::Check);
+    }
+    let edit = match json.as_array() {
+        Some(edit) if edit.len() == 3 || edit.len() == 4 => edit,
+        _ => return Err("not [offset, delete, insert] or a check".to_owned()),
+    };
+    let offset = edit[0].as_u64().ok_or("the offset isn't a number")? as usize;
+    let delete = edit[1].as_u64().ok_or("the length deleted isn't a number")? as usize;
+    let insert = edit[2].as_str().ok_or("the text inserted isn't a string")?.to_owned();
+    let edit_type = match edit.get(3) {
+        Some(edit_type) => edit_type.as_str().ok_or("the edit type isn't a string")?,
+        None => DEFAULT_EDIT_TYPE,
+    };
+    Ok(Step::Edit { offset, delete, insert, edit_type: edit_type.to_owned() })
+}
+
+fn parse_expectation(json: &Value) -> Result<Expectation, String> {
+    let at = json["at"].as_str().ok_or("a check has no \"at\"")?;
+    let position = || format!("not a line:column: {}", at);
+    let number = |part: Option<&str>| part?.parse::<usize>().ok().filter(|&n| n > 0);
+    let mut parts = at.splitn(2, ':');
+    let line = number(parts.next()).ok_or_else(position)?;
+    let column = number(parts.next()).ok_or_else(position)?;
+    let (scope, present) = match (json["scope"].as_str(), json["not_scope"].as_str()) {
+        (Some(scope), None) => (scope, true),
+        (None, Some(scope)) => (scope, false),
+        _ => return Err(format!("the check at {} needs one of scope or not_scope", at)),
+    };
+    Ok(Expectation { line, column, scope: scope.to_owned(), present })
+}
+
+/// Replays `session` through the incremental path, failing at the first
+/// check whose spans are wrong.
+pub fn replay(session: &Session) -> Result<(), String> {
+    let registry = ParserRegistry::default();
+    if registry.resolve(&session.language).is_none() {
+        return Err(format!("unknown language: {}", session.language));
+    }
+    let mut view = MockView::new(&session.language, &session.text);
+    let mut view_state = ViewState::new(Arc::default());
+    view_state.do_highlighting(&mut view, &registry, &mut HashMap::new());
+    run_idle(&mut view_state, &mut view);
+
+    for (i, step) in session.steps.iter().enumerate() {
+        let result = match step {
+            Step::Edit { offset, delete, insert, edit_type } => {
+                apply_edit(&mut view_state, &mut view, *offset, *delete, insert, edit_type)
+            }
+            Step::Check(expectations) => {
+                check(&registry, &session.language, &mut view_state, &mut view, expectations)
+            }
+        };
+        result.map_err(|err| format!("step {}: {}", i + 1, err))?;
+    }
+    check(&registry, &session.language, &mut view_state, &mut view, &[])
+        .map_err(|err| format!("after the last step: {}", err))
+}
+
+fn apply_edit(
+    view_state: &mut ViewState,
+    view: &mut MockView,
+    offset: usize,
+    delete: usize,
+    insert: &str,
+    edit_type: &str,
+) -> Result<(), String> {
+    let text = view.text();
+    let end = offset + delete;
+    if end > text.len() {
+        return Err(format!("{}..{} is past the end of the text, at {}", offset, end, text.len()));
+    }
+    if !text.is_codepoint_boundary(offset) || !text.is_codepoint_boundary(end) {
+        return Err(format!("{}..{} starts or ends inside a character", offset, end));
+    }
+    let delta = RopeDelta::simple_edit(Interval::new(offset, end), Rope::from(insert), text.len());
+    view.apply_edit(&delta);
+    view_state.update(view, Some(&delta), is_typing(edit_type, "core"));
+    run_idle(view_state, view);
+    Ok(())
+}
+
+/// Lets typing pause and the highlighting finish, then compares what core
+/// would show with a highlight from scratch, and checks `expectations`.
+fn check(
+    registry: &ParserRegistry,
+    language: &str,
+    view_state: &mut ViewState,
+    view: &mut MockView,
+    expectations: &[Expectation],
+) -> Result<(), String> {
+    if view_state.typing_until.is_some() {
+        view_state.typing_until = Some(Instant::now());
+    }
+    run_idle(view_state, view);
+    if view.get_frontier().is_some() {
+        return Err("highlighting stopped before the end".to_owned());
+    }
+
+    let text = view.text().to_string();
+    let replayed = resolved_scopes(view);
+    let mut fresh_view = MockView::new(language, &text);
+    let mut fresh_state = ViewState::new(Arc::default());
+    fresh_state.do_highlighting(&mut fresh_view, registry, &mut HashMap::new());
+    run_idle(&mut fresh_state, &mut fresh_view);
+    let fresh = resolved_scopes(&fresh_view);
+    if let Some(offset) = (0..text.len()).find(|&i| replayed[i] != fresh[i]) {
+        let (line, column) = position(&text, offset);
+        return Err(format!(
+            "at {}:{}, the spans are {}, but from scratch they are {}",
+            line,
+            column,
+            describe(&replayed[offset]),
+            describe(&fresh[offset])
+        ));
+    }
+
+    for expectation in expectations {
+        let offset = offset_of(&text, expectation.line, expectation.column).ok_or_else(|| {
+            format!("{}:{} is past the text", expectation.line, expectation.column)
+        })?;
+        let scope = &replayed[offset];
+        let found = scope.iter().flatten().any(|name| matches_name(&expectation.scope, name));
+        if found != expectation.present {
+            return Err(format!(
+                "at {}:{}, expected {}{}, but the scope is {}",
+                expectation.line,
+                expectation.column,
+                if expectation.present { "" } else { "no " },
+                expectation.scope,
+                describe(scope)
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn describe(scope: &Option<Scope>) -> String {
+    match scope {
+        Some(scope) => format!("`{}`", scope.join(" ")),
+        None => "unset".to_owned(),
+    }
+}
+
+/// Whether the scope name `pattern` matches `name`, as a whole or as the
+/// first of its dotted parts.
+fn matches_name(pattern: &str, name: &str) -> bool {
+    name.starts_with(pattern)
+        && (name.len() == pattern.len() || name[pattern.len()..].starts_with('.'))
+}
+
+/// The line and column of `offset`, from 1.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[..line_start].matches('\n').count() + 1;
+    (line, text[line_start..offset].chars().count() + 1)
+}
+
+/// The offset of a line and column, from 1, if it is in `text` and not
+/// past the end of its line.
+fn offset_of(text: &str, line: usize, column: usize) -> Option<usize> {
+    let content = lines_with_endings(text).nth(line - 1)?;
+    let line_start = lines_with_endings(text).take(line - 1).map(str::len).sum::<usize>();
+    let (offset, _) = content.char_indices().nth(column - 1)?;
+    Some(line_start + offset)
+}
+
+/// Runs the command with the arguments after `replay`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.is_empty() || args.iter().any(|arg| arg.starts_with("--")) {
+        return Err(USAGE.to_owned());
+    }
+    let mut failures = 0;
+    for path in args {
+        let result = Session::load(Path::new(path)).and_then(|session| {
+            replay(&session)?;
+            Ok(session.steps.len())
+        });
+        match result {
+            Ok(steps) => println!("{}: {} steps replayed", path, steps),
+            Err(err) => {
+                println!("{}: {}", path, err);
+                failures += 1;
+            }
+        }
+    }
+    match failures {
+        0 => Ok(()),
+        1 => Err("1 session failed".to_owned()),
+        _ => Err(format!("{} sessions failed", failures)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn sessions() -> Vec<PathBuf> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/sessions");
+        let mut sessions: Vec<_> =
+            fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        sessions.sort();
+        sessions
+    }
+
+    #[test]
+    fn recorded_sessions_replay() {
+        let sessions = sessions();
+        assert!(!sessions.is_empty());
+        for path in sessions {
+            let session = Session::load(&path).unwrap();
+            if let Err(err) = replay(&session) {
+                panic!("{}: {}", path.display(), err);
+            }
+        }
+    }
+
+    #[test]
+    fn sessions_are_read_from_json() {
+        let json = json!({
+            "language": "Rust",
+            "text": ["fn main() {", "}"],
+            "steps": [
+                [11, 0, "\n    x"],
+                [0, 2, "", "undo"],
+                {"check": [{"at": "2:5", "scope": "source"}, {"at": "1:1", "not_scope": "keyword"}]}
+            ]
+        });
+        let session = Session::from_json(&json).unwrap();
+        assert_eq!(session.text, "fn main() {\n}\n");
+        assert_eq!(
+            session.steps[0],
+            Step::Edit {
+                offset: 11,
+                delete: 0,
+                insert: "\n    x".to_owned(),
+                edit_type: "insert".to_owned()
+            }
+        );
+        match &session.steps[1] {
+            Step::Edit { edit_type, .. } => assert_eq!(edit_type, "undo"),
+            step => panic!("{:?}", step),
+        }
+        let checks = match &session.steps[2] {
+            Step::Check(checks) => checks,
+            step => panic!("{:?}", step),
+        };
+        let expectation = |line, column, scope: &str, present| Expectation {
+            line,
+            column,
+            scope: scope.to_owned(),
+            present,
+        };
+        assert_eq!(checks[0], expectation(2, 5, "source", true));
+        assert_eq!(checks[1], expectation(1, 1, "keyword", false));
+
+        let bad_step = json!({"language": "Rust", "text": "", "steps": [[0, "a", ""]]});
+        assert_eq!(
+            Session::from_json(&bad_step),
+            Err("step 1: the length deleted isn't a number".to_owned())
+        );
+        let bad_at = json!({"language": "Rust", "text": "", "steps": [
+            {"check": [{"at": "0:1", "scope": "comment"}]}
+        ]});
+        assert_eq!(Session::from_json(&bad_at), Err("step 1: not a line:column: 0:1".to_owned()));
+    }
+
+    #[test]
+    fn failed_checks_say_where() {
+        let session = |steps| {
+            let json = json!({"language": "Rust", "text": "let a = 1;\n", "steps": steps});
+            Session::from_json(&json).unwrap()
+        };
+        let comment =
+            session(json!([[0, 0, "// "], {"check": [{"at": "1:5", "scope": "comment"}]}]));
+        assert_eq!(replay(&comment), Ok(()));
+
+        let wrong =
+            session(json!([[0, 0, "// "], {"check": [{"at": "1:5", "not_scope": "comment"}]}]));
+        let err = replay(&wrong).unwrap_err();
+        assert!(
+            err.starts_with("step 2: at 1:5, expected no comment, but the scope is `"),
+            "{}",
+            err
+        );
+
+        let past = session(json!([[5, 20, ""]]));
+        assert_eq!(
+            replay(&past),
+            Err("step 1: 5..25 is past the end of the text, at 11".to_owned())
+        );
+    }
+
+    #[test]
+    fn positions_count_lines_and_characters_from_1() {
+        let text = "ab\néc\n";
+        assert_eq!(position(text, 0), (1, 1));
+        assert_eq!(position(text, 3), (2, 1));
+        assert_eq!(position(text, 5), (2, 2));
+        assert_eq!(offset_of(text, 2, 2), Some(5));
+        assert_eq!(offset_of(text, 2, 4), None);
+        assert_eq!(offset_of(text, 3, 1), None);
+        assert!(matches_name("comment", "comment.block.rust"));
+        assert!(!matches_name("comment", "commentary"));
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting and trimming of strings, for the toolchain CI builds with,
+//! whose `str` doesn't have these yet.
+
+/// The lines of `text`, each with its line ending; the last one has none
+/// if `text` doesn't end in a newline.
+pub fn lines_with_endings(text: &str) -> LinesWithEndings<'_> {
+    LinesWithEndings { rest: text }
+}
+
+pub struct LinesWithEndings<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for LinesWithEndings<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let end = self.rest.find('\n').map_or(self.rest.len(), |i| i + 1);
+        let (line, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(line)
+    }
+}
+
+/// `text` without `prefix`, if it starts with it.
+pub fn strip_prefix<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    if text.starts_with(prefix) {
+        Some(&text[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// `text` without `suffix`, if it ends with it.
+pub fn strip_suffix<'a>(text: &'a str, suffix: &str) -> Option<&'a str> {
+    if text.ends_with(suffix) {
+        Some(&text[..text.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+/// What comes before and after the first `separator` in `text`.
+pub fn split_once<'a>(text: &'a str, separator: &str) -> Option<(&'a str, &'a str)> {
+    text.find(separator).map(|i| (&text[..i], &text[i + separator.len()..]))
+}
+
+/// What comes before and after the last `separator` in `text`.
+pub fn rsplit_once<'a>(text: &'a str, separator: &str) -> Option<(&'a str, &'a str)> {
+    text.rfind(separator).map(|i| (&text[..i], &text[i + separator.len()..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_keep_their_endings() {
+        let lines = |text| lines_with_endings(text).collect::<Vec<_>>();
+        assert_eq!(lines("a\r\nb\n\nc"), vec!["a\r\n", "b\n", "\n", "c"]);
+        assert_eq!(lines("a\n"), vec!["a\n"]);
+        assert!(lines("").is_empty());
+    }
+
+    #[test]
+    fn splits_and_strips() {
+        assert_eq!(split_once("a=b=c", "="), Some(("a", "b=c")));
+        assert_eq!(rsplit_once("Rust (Doc) (x)", " ("), Some(("Rust (Doc)", "x)")));
+        assert_eq!(split_once("abc", "="), None);
+        assert_eq!(strip_prefix("#!sh", "#!"), Some("sh"));
+        assert_eq!(strip_suffix("Rust (x)", ")"), Some("Rust (x"));
+        assert_eq!(strip_prefix("sh", "#!"), None);
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coarse timing checks. Each fixture is repeated until it is a few
+//! thousand lines long and highlighted as `xi-lang-tools highlight` does, and
+//! the lines a second are compared with those in `timing/baseline.json`.
+//! Only a slowdown of `TOLERANCE` times or worse fails, which noise won't
+//! cause but something quadratic in the length of a document will.
+//!
+//! Timings differ between machines, and between debug and release
+//! builds, so each build profile has a baseline of its own, along with
+//! how fast a fixed loop of arithmetic ran when it was recorded. Timings
+//! are scaled by how fast that loop runs now, and if it runs a lot slower
+//! the machine is taken to be busy, and the check is skipped. Run with
+//! `UPDATE_BASELINES=1` to record the baseline for the build profile.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+use crate::bench::secs;
+use crate::golden;
+use crate::highlight;
+use crate::registry::ParserRegistry;
+
+/// How many times slower than its baseline a fixture must get to fail.
+const TOLERANCE: f64 = 3.0;
+
+/// How many times slower than when the baseline was recorded the
+/// calibration loop must get for the machine to be taken as busy.
+const BUSY_FACTOR: f64 = 2.0;
+
+/// How long fixtures are made by repeating them.
+const MIN_LINES: usize = 2000;
+
+/// Each timing is the best of this many runs.
+const RUNS: usize = 3;
+
+/// How many rounds of the calibration loop are timed.
+const CALIBRATION_ROUNDS: u64 = 20_000_000;
+
+/// Where the calibration loop's result goes, so that it has to be worked
+/// out.
+static CALIBRATION_SINK: AtomicUsize = AtomicUsize::new(0);
+
+fn baseline_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("timing/baseline.json")
+}
+
+fn profile() -> &'static str {
+    if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    }
+}
+
+/// Rounds of a fixed loop of arithmetic run a second, to compare the
+/// speed of the machine with the one the baseline was recorded on.
+fn calibrate() -> f64 {
+    let best = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let mut x = CALIBRATION_SINK.load(Ordering::Relaxed) as u64;
+            for i in 0..CALIBRATION_ROUNDS {
+                x = x.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(i);
+            }
+            CALIBRATION_SINK.store(x as usize, Ordering::Relaxed);
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    CALIBRATION_ROUNDS as f64 / secs(best)
+}
+
+/// `text` repeated until it has at least `MIN_LINES` lines.
+fn repeated(text: &str) -> String {
+    let mut text = text.to_owned();
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    let lines = text.matches('\n').count();
+    text.repeat((MIN_LINES + lines - 1) / lines)
+}
+
+/// The lines a second each fixture is highlighted at, by name, as in
+/// `rust/items.rs`, with the language it was highlighted as.
+fn measure() -> BTreeMap<String, (String, f64)> {
+    let registry = ParserRegistry::default();
+    let mut measured = BTreeMap::new();
+    for &(dir, language) in golden::LANGUAGES {
+        for path in golden::fixtures(dir) {
+            let text = repeated(&fs::read_to_string(&path).unwrap());
+            let lines = text.matches('\n').count();
+            let best = (0..RUNS)
+                .map(|_| {
+                    let start = Instant::now();
+                    highlight::highlight(&registry, language, &text).unwrap();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap();
+            let name = format!("{}/{}", dir, path.file_name().unwrap().to_string_lossy());
+            measured.insert(name, (language.to_owned(), lines as f64 / secs(best)));
+        }
+    }
+    measured
+}
+
+/// A fixture's timing next to its baseline, both in lines a second; the
+/// timing is scaled to the speed of the machine the baseline was
+/// recorded on.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    language: String,
+    fixture: String,
+    baseline: Option<f64>,
+    now: f64,
+}
+
+impl Row {
+    fn slowdown(&self) -> Option<f64> {
+        self.baseline.map(|baseline| baseline / self.now)
+    }
+
+    fn regressed(&self) -> bool {
+        self.slowdown().map_or(false, |slowdown| slowdown >= TOLERANCE)
+    }
+}
+
+fn compare(
+    baseline: &Value,
+    measured: &BTreeMap<String, (String, f64)>,
+    machine_speed: f64,
+) -> Vec<Row> {
+    measured
+        .iter()
+        .map(|(fixture, (language, now))| Row {
+            language: language.clone(),
+            fixture: fixture.clone(),
+            baseline: baseline["fixtures"][fixture].as_f64(),
+            now: now / machine_speed,
+        })
+        .collect()
+}
+
+/// The rows as a table, a section a language.
+fn table(rows: &[Row]) -> String {
+    let mut rows: Vec<&Row> = rows.iter().collect();
+    rows.sort_by(|a, b| (&a.language, &a.fixture).cmp(&(&b.language, &b.fixture)));
+    let mut out = String::new();
+    let mut language = None;
+    for row in rows {
+        if language != Some(&row.language) {
+            writeln!(out, "{}", row.language).unwrap();
+            language = Some(&row.language);
+        }
+        let baseline = row.baseline.map_or_else(|| "-".to_owned(), |b| format!("{:.0}", b));
+        let slowdown = row.slowdown().map_or_else(|| "-".to_owned(), |s| format!("{:.2}x", s));
+        writeln!(
+            out,
+            "    {:<28} {:>12} {:>12.0} lines/s {:>8}{}",
+            row.fixture,
+            baseline,
+            row.now,
+            slowdown,
+            if row.regressed() { "  slower" } else { "" }
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn read_baselines() -> Value {
+    match fs::read_to_string(baseline_path()) {
+        Ok(json) => serde_json::from_str(&json).unwrap(),
+        Err(_) => json!({}),
+    }
+}
+
+#[test]
+fn highlighting_is_not_much_slower_than_its_baseline() {
+    let mut baselines = read_baselines();
+    let calibration = calibrate();
+
+    if env::var_os("UPDATE_BASELINES").map_or(false, |value| value == "1") {
+        let measured = measure();
+        let fixtures: BTreeMap<_, _> =
+            measured.iter().map(|(fixture, &(_, now))| (fixture.clone(), now.round())).collect();
+        baselines[profile()] = json!({ "calibration": calibration.round(), "fixtures": fixtures });
+        let json = serde_json::to_string_pretty(&baselines).unwrap();
+        fs::write(baseline_path(), json + "\n").unwrap();
+        return;
+    }
+
+    let baseline = &baselines[profile()];
+    let recorded_calibration = match baseline["calibration"].as_f64() {
+        Some(calibration) => calibration,
+        None => {
+            eprintln!("no {} timing baseline; run with UPDATE_BASELINES=1 to record it", profile());
+            return;
+        }
+    };
+    let machine_speed = calibration / recorded_calibration;
+    if machine_speed < 1.0 / BUSY_FACTOR {
+        eprintln!(
+            "skipping the timing check: the machine runs at {:.2}x the speed of the baseline's, \
+             so is probably busy",
+            machine_speed
+        );
+        return;
+    }
+    let rows = compare(baseline, &measure(), machine_speed);
+    assert!(
+        !rows.iter().any(Row::regressed),
+        "highlighting is {}x slower than its {} baseline or worse \
+         (run with UPDATE_BASELINES=1 if that's expected):\n{}",
+        TOLERANCE,
+        profile(),
+        table(&rows)
+    );
+}
+
+#[test]
+fn slowdowns_are_reported_by_language() {
+    let baseline = json!({ "fixtures": { "rust/a.rs": 9000.0, "jinja/b.rs.j2": 4000.0 } });
+    let mut measured = BTreeMap::new();
+    measured.insert("rust/a.rs".to_owned(), ("Rust".to_owned(), 4000.0));
+    measured.insert("jinja/b.rs.j2".to_owned(), ("Rust (Jinja)".to_owned(), 4000.0));
+    measured.insert("rust/new.rs".to_owned(), ("Rust".to_owned(), 100.0));
+
+    // on a machine at half the speed, a.rs is 1.125x slower, not 2.25x
+    let rows = compare(&baseline, &measured, 0.5);
+    assert!(!rows.iter().any(Row::regressed));
+    let rows = compare(&baseline, &measured, 1.0);
+    let regressed: Vec<_> = rows.iter().filter(|row| row.regressed()).collect();
+    assert!(regressed.is_empty(), "{:?}", regressed);
+
+    measured.get_mut("rust/a.rs").unwrap().1 = 3000.0;
+    let rows = compare(&baseline, &measured, 1.0);
+    let table = table(&rows);
+    let lines: Vec<_> = table.lines().collect();
+    assert_eq!(lines[0], "Rust");
+    assert!(lines[1].starts_with("    rust/a.rs "), "{}", table);
+    assert!(lines[1].ends_with("3.00x  slower"), "{}", table);
+    assert!(lines[2].ends_with(" -"), "{}", table);
+    assert_eq!(lines[3], "Rust (Jinja)");
+    assert!(lines[4].ends_with("1.00x"), "{}", table);
+}
+
+#[test]
+fn fixtures_are_repeated_to_length() {
+    let text = repeated("fn a() {}\nfn b() {}");
+    assert_eq!(text.matches('\n').count(), MIN_LINES);
+    assert!(text.starts_with("fn a() {}\nfn b() {}\nfn a() {}\n"));
+}
@@ -0,0 +1,538 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The subset of the plugin `View` API used for highlighting, abstracted so
+//! that `ViewState` can be driven by a mock in tests.
+
+use std::time::Instant;
+
+use serde_json::Value;
+use xi_core_lib::{plugins::rpc::ScopeSpan, LanguageId};
+use xi_plugin_lib::{Cache, Error, StateCache, View};
+use xi_rope::RopeDelta;
+
+use crate::scope_tracker::Scope;
+use crate::statestack::State;
+
+/// Whether a failed fetch might succeed if tried again. Asking for a line
+/// past the end of the document never will.
+pub fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::RpcError(_) | Error::Other(_) => true,
+        Error::BadRequest | Error::WrongReturnType | Error::PeerDisconnect => false,
+    }
+}
+
+pub trait HighlightView {
+    fn get_language_id(&self) -> &LanguageId;
+    fn get_buf_size(&self) -> usize;
+    fn get_num_lines(&self) -> usize;
+    fn get_line(&mut self, line_num: usize) -> Result<&str, Error>;
+    fn offset_of_line(&mut self, line_num: usize) -> Result<usize, Error>;
+    fn line_of_offset(&mut self, offset: usize) -> Result<usize, Error>;
+    /// The whole text, fetched a chunk at a time.
+    fn get_document(&mut self) -> Result<String, Error>;
+
+    fn get_frontier(&self) -> Option<usize>;
+    fn get_prev(&self, line_num: usize) -> (usize, usize, State);
+    fn get(&self, line_num: usize) -> Option<&State>;
+    fn set(&mut self, line_num: usize, state: State);
+    fn update_frontier(&mut self, new_frontier: usize);
+    fn close_frontier(&mut self);
+    /// Drops all cached line states, resetting the frontier to the start.
+    fn clear_cache(&mut self);
+    /// Drops the states of the lines after `line_num`, moving the frontier
+    /// back to it.
+    fn invalidate_from(&mut self, line_num: usize);
+
+    fn add_scopes(&mut self, scopes: &[Scope]);
+    fn update_spans(&mut self, start: usize, len: usize, spans: &[ScopeSpan]);
+    /// Sends an edit to core, in the undo group of the last update unless
+    /// `new_undo_group` is set.
+    fn edit(
+        &mut self,
+        delta: RopeDelta,
+        priority: u64,
+        after_cursor: bool,
+        new_undo_group: bool,
+        author: &str,
+    );
+    fn schedule_idle(&mut self);
+    fn schedule_timer(&mut self, after: Instant);
+    fn request_is_pending(&self) -> bool;
+
+    fn add_status_item(&mut self, key: &str, value: &str, alignment: &str);
+    fn update_status_item(&mut self, key: &str, value: &str);
+    fn remove_status_item(&mut self, key: &str);
+
+    /// Answers a custom command, through core to the client.
+    fn respond_to_command(&mut self, method: &str, result: &Value);
+}
+
+impl HighlightView for View<StateCache<State>> {
+    fn get_language_id(&self) -> &LanguageId {
+        View::get_language_id(self)
+    }
+
+    fn get_buf_size(&self) -> usize {
+        View::get_buf_size(self)
+    }
+
+    fn get_num_lines(&self) -> usize {
+        View::get_num_lines(self)
+    }
+
+    fn get_line(&mut self, line_num: usize) -> Result<&str, Error> {
+        View::get_line(self, line_num)
+    }
+
+    fn offset_of_line(&mut self, line_num: usize) -> Result<usize, Error> {
+        View::offset_of_line(self, line_num)
+    }
+
+    fn line_of_offset(&mut self, offset: usize) -> Result<usize, Error> {
+        View::line_of_offset(self, offset)
+    }
+
+    fn get_document(&mut self) -> Result<String, Error> {
+        View::get_document(self)
+    }
+
+    fn get_frontier(&self) -> Option<usize> {
+        View::get_frontier(self)
+    }
+
+    fn get_prev(&self, line_num: usize) -> (usize, usize, State) {
+        View::get_prev(self, line_num)
+    }
+
+    fn get(&self, line_num: usize) -> Option<&State> {
+        View::get(self, line_num)
+    }
+
+    fn set(&mut self, line_num: usize, state: State) {
+        View::set(self, line_num, state)
+    }
+
+    fn update_frontier(&mut self, new_frontier: usize) {
+        View::update_frontier(self, new_frontier)
+    }
+
+    fn close_frontier(&mut self) {
+        View::close_frontier(self)
+    }
+
+    fn clear_cache(&mut self) {
+        self.get_cache().clear()
+    }
+
+    fn invalidate_from(&mut self, line_num: usize) {
+        View::invalidate_from(self, line_num)
+    }
+
+    fn add_scopes(&mut self, scopes: &[Scope]) {
+        View::add_scopes(self, scopes)
+    }
+
+    fn update_spans(&mut self, start: usize, len: usize, spans: &[ScopeSpan]) {
+        View::update_spans(self, start, len, spans)
+    }
+
+    fn edit(
+        &mut self,
+        delta: RopeDelta,
+        priority: u64,
+        after_cursor: bool,
+        new_undo_group: bool,
+        author: &str,
+    ) {
+        View::edit(self, delta, priority, after_cursor, new_undo_group, author.to_owned())
+    }
+
+    fn schedule_idle(&mut self) {
+        View::schedule_idle(self)
+    }
+
+    fn schedule_timer(&mut self, after: Instant) {
+        View::schedule_timer(self, after)
+    }
+
+    fn request_is_pending(&self) -> bool {
+        View::request_is_pending(self)
+    }
+
+    fn add_status_item(&mut self, key: &str, value: &str, alignment: &str) {
+        View::add_status_item(self, key, value, alignment)
+    }
+
+    fn update_status_item(&mut self, key: &str, value: &str) {
+        View::update_status_item(self, key, value)
+    }
+
+    fn remove_status_item(&mut self, key: &str) {
+        View::remove_status_item(self, key)
+    }
+
+    fn respond_to_command(&mut self, method: &str, result: &Value) {
+        View::respond_to_command(self, method, result)
+    }
+}
+
+/// Used by the tests, and by `xi-lang-tools bench`.
+#[cfg(any(test, feature = "tools"))]
+#[cfg_attr(not(test), allow(dead_code))]
+pub mod mock {
+    use super::*;
+    use crate::registry::ParserRegistry;
+    use crate::span_validator;
+    use crate::ViewState;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use xi_core_lib::plugin_rpc::{GetDataResponse, TextUnit};
+    use xi_plugin_lib::DataSource;
+    use xi_rope::spans::{Spans, SpansBuilder};
+    use xi_rope::{Interval, LinesMetric, Rope};
+
+    // Deliberately small, so that tests cross chunk boundaries.
+    const CHUNK_SIZE: usize = 64;
+
+    /// The text, and how much of it to hand back at a time.
+    struct MockDataSource(Rope, usize);
+
+    impl DataSource for MockDataSource {
+        fn get_data(
+            &self,
+            start: usize,
+            unit: TextUnit,
+            _max_size: usize,
+            _rev: u64,
+        ) -> Result<GetDataResponse, Error> {
+            let offset = unit
+                .resolve_offset(&self.0, start)
+                .ok_or_else(|| Error::Other("unable to resolve offset".into()))?;
+            let first_line = self.0.line_of_offset(offset);
+            let first_line_offset = offset - self.0.offset_of_line(first_line);
+            // snapped to a codepoint boundary, as core does
+            let mut end_off = offset + self.1;
+            if end_off >= self.0.len() {
+                end_off = self.0.len();
+            } else {
+                end_off = self.0.prev_codepoint_offset(end_off + 1).unwrap();
+            }
+            let chunk = self.0.slice_to_cow(offset..end_off).into_owned();
+            Ok(GetDataResponse { chunk, offset, first_line, first_line_offset })
+        }
+    }
+
+    /// One `update_spans` call.
+    #[derive(Debug, Clone)]
+    pub struct SpanUpdate {
+        pub start: usize,
+        pub len: usize,
+        pub spans: Vec<ScopeSpan>,
+        /// How many scopes had been announced when the update was sent.
+        pub known_scopes: usize,
+    }
+
+    /// One `edit` call.
+    #[derive(Debug, Clone)]
+    pub struct Edit {
+        pub delta: RopeDelta,
+        pub new_undo_group: bool,
+        pub author: String,
+    }
+
+    /// An in-memory view that records everything sent to core.
+    pub struct MockView {
+        language_id: LanguageId,
+        source: MockDataSource,
+        cache: StateCache<State>,
+        rev: u64,
+        /// The scope id at each offset, maintained across edits the same way
+        /// core maintains a plugin's layer.
+        pub styles: Spans<u32>,
+        /// Every scope announced with `add_scopes`, in order.
+        pub scopes: Vec<Scope>,
+        /// Every `update_spans` call, in order.
+        pub updates: Vec<SpanUpdate>,
+        /// Whether spans sent are kept in `styles` and `updates`; off when
+        /// measuring what the plugin itself holds on to.
+        pub record_spans: bool,
+        /// Every edit sent to core, in order.
+        pub edits: Vec<Edit>,
+        /// The number of times the state cache was cleared.
+        pub cache_clears: usize,
+        pub idle_scheduled: bool,
+        /// When the last timer was set to call `idle`, if one is pending.
+        pub timer: Option<Instant>,
+        pub request_pending: bool,
+        /// Hands back blank lines as `""` rather than `"\n"`.
+        pub blank_lines_empty: bool,
+        /// How many of the next line fetches fail.
+        pub failing_fetches: usize,
+        /// How many lines have been fetched, failed fetches included.
+        pub line_fetches: usize,
+        /// Every change to a status item, in order; `None` for a removal.
+        pub status_log: Vec<(String, Option<String>)>,
+        /// Every answer to a custom command, in order.
+        pub responses: Vec<(String, Value)>,
+    }
+
+    impl MockView {
+        pub fn new(language: &str, text: &str) -> MockView {
+            MockView::with_chunk_size(language, text, CHUNK_SIZE)
+        }
+
+        /// A view whose text is fetched `chunk_size` bytes at a time, which
+        /// for a realistic load is the megabyte core hands back.
+        pub fn with_chunk_size(language: &str, text: &str, chunk_size: usize) -> MockView {
+            let rope = Rope::from(text);
+            let cache = StateCache::new(rope.len(), 0, rope.measure::<LinesMetric>() + 1);
+            let styles = SpansBuilder::new(rope.len()).build();
+            MockView {
+                language_id: LanguageId::from(language),
+                source: MockDataSource(rope, chunk_size),
+                cache,
+                rev: 0,
+                styles,
+                scopes: Vec::new(),
+                updates: Vec::new(),
+                record_spans: true,
+                edits: Vec::new(),
+                cache_clears: 0,
+                idle_scheduled: false,
+                timer: None,
+                request_pending: false,
+                blank_lines_empty: false,
+                failing_fetches: 0,
+                line_fetches: 0,
+                status_log: Vec::new(),
+                responses: Vec::new(),
+            }
+        }
+
+        pub fn set_language(&mut self, language: &str) {
+            self.language_id = LanguageId::from(language);
+        }
+
+        pub fn text(&self) -> &Rope {
+            &self.source.0
+        }
+
+        /// Forgets every span sent, as core does when the theme changes.
+        pub fn drop_styles(&mut self) {
+            self.styles = SpansBuilder::new(self.source.0.len()).build();
+        }
+
+        /// Applies an edit, as core would before notifying the plugin.
+        pub fn apply_edit(&mut self, delta: &RopeDelta) {
+            self.source.0 = delta.apply(&self.source.0);
+            self.styles.apply_shape(delta);
+            self.rev += 1;
+            let num_lines = self.source.0.measure::<LinesMetric>() + 1;
+            self.cache.update(Some(delta), self.source.0.len(), num_lines, self.rev);
+        }
+    }
+
+    /// Runs idle slices until `view` has none scheduled, as the plugin
+    /// would with nothing else coming in.
+    pub fn run_idle(view_state: &mut ViewState, view: &mut MockView) {
+        view.idle_scheduled = true;
+        while view.idle_scheduled {
+            view.idle_scheduled = false;
+            view_state.idle(view);
+        }
+    }
+
+    /// The scope core would show at each byte of the document.
+    pub fn resolved_scopes(view: &MockView) -> Vec<Option<Scope>> {
+        let mut scopes = vec![None; view.styles.len()];
+        for (iv, &id) in view.styles.iter() {
+            for slot in &mut scopes[iv.start()..iv.end()] {
+                *slot = Some(view.scopes[id as usize].clone());
+            }
+        }
+        scopes
+    }
+
+    /// The scope at each byte of `text`, highlighted from scratch.
+    pub fn highlight_from_scratch(language: &str, text: &str) -> Vec<Option<Scope>> {
+        highlight_from_scratch_with(&ParserRegistry::default(), language, text)
+    }
+
+    pub fn highlight_from_scratch_with(
+        registry: &ParserRegistry,
+        language: &str,
+        text: &str,
+    ) -> Vec<Option<Scope>> {
+        let mut view = MockView::new(language, text);
+        let mut view_state = ViewState::new(Arc::default());
+        view_state.do_highlighting(&mut view, registry, &mut HashMap::new());
+        run_idle(&mut view_state, &mut view);
+        resolved_scopes(&view)
+    }
+
+    /// Replaces `len` bytes at `offset` (both clamped to the document)
+    /// with `text`, and lets the plugin catch up.
+    pub fn apply_edit(view_state: &mut ViewState, view: &mut MockView, edit: (usize, usize, &str)) {
+        let (offset, len, text) = edit;
+        let doc_len = view.text().len();
+        let start = offset.min(doc_len);
+        let end = (start + len).min(doc_len);
+        let delta = RopeDelta::simple_edit(Interval::new(start, end), Rope::from(text), doc_len);
+        view.apply_edit(&delta);
+        view_state.update(view, Some(&delta), false);
+        run_idle(view_state, view);
+    }
+
+    impl HighlightView for MockView {
+        fn get_language_id(&self) -> &LanguageId {
+            &self.language_id
+        }
+
+        fn get_buf_size(&self) -> usize {
+            self.source.0.len()
+        }
+
+        fn get_num_lines(&self) -> usize {
+            self.cache.get_num_lines()
+        }
+
+        fn get_line(&mut self, line_num: usize) -> Result<&str, Error> {
+            self.line_fetches += 1;
+            if self.failing_fetches > 0 {
+                self.failing_fetches -= 1;
+                return Err(Error::Other("fetch failed".into()));
+            }
+            let line = self.cache.get_line(&self.source, line_num)?;
+            if self.blank_lines_empty && line == "\n" {
+                Ok("")
+            } else {
+                Ok(line)
+            }
+        }
+
+        fn offset_of_line(&mut self, line_num: usize) -> Result<usize, Error> {
+            self.cache.offset_of_line(&self.source, line_num)
+        }
+
+        fn line_of_offset(&mut self, offset: usize) -> Result<usize, Error> {
+            self.cache.line_of_offset(&self.source, offset)
+        }
+
+        fn get_document(&mut self) -> Result<String, Error> {
+            self.cache.get_document(&self.source)
+        }
+
+        fn get_frontier(&self) -> Option<usize> {
+            self.cache.get_frontier()
+        }
+
+        fn get_prev(&self, line_num: usize) -> (usize, usize, State) {
+            self.cache.get_prev(line_num)
+        }
+
+        fn get(&self, line_num: usize) -> Option<&State> {
+            self.cache.get(line_num)
+        }
+
+        fn set(&mut self, line_num: usize, state: State) {
+            self.cache.set(&self.source, line_num, state)
+        }
+
+        fn update_frontier(&mut self, new_frontier: usize) {
+            self.cache.update_frontier(new_frontier)
+        }
+
+        fn close_frontier(&mut self) {
+            self.cache.close_frontier()
+        }
+
+        fn clear_cache(&mut self) {
+            self.cache_clears += 1;
+            self.cache.clear()
+        }
+
+        fn invalidate_from(&mut self, line_num: usize) {
+            self.cache.invalidate_from(line_num)
+        }
+
+        fn add_scopes(&mut self, scopes: &[Scope]) {
+            self.scopes.extend_from_slice(scopes);
+        }
+
+        fn update_spans(&mut self, start: usize, len: usize, spans: &[ScopeSpan]) {
+            // every test checks what it sends core, against the text too
+            if cfg!(test) {
+                let checked =
+                    span_validator::check_spans(len, spans, self.scopes.len()).and_then(|()| {
+                        span_validator::check_boundaries(self.text(), start, len, spans)
+                    });
+                if let Err(err) = checked {
+                    panic!("update_spans({}, {}, {:?}): {}", start, len, spans, err);
+                }
+            }
+            if !self.record_spans {
+                return;
+            }
+            let mut builder = SpansBuilder::new(len);
+            for span in spans {
+                builder.add_span(Interval::new(span.start, span.end), span.scope_id);
+            }
+            self.styles.edit(Interval::new(start, start + len), builder.build());
+
+            let known_scopes = self.scopes.len();
+            self.updates.push(SpanUpdate { start, len, spans: spans.to_vec(), known_scopes });
+        }
+
+        fn edit(
+            &mut self,
+            delta: RopeDelta,
+            _priority: u64,
+            _after_cursor: bool,
+            new_undo_group: bool,
+            author: &str,
+        ) {
+            self.edits.push(Edit { delta, new_undo_group, author: author.to_owned() });
+        }
+
+        fn schedule_idle(&mut self) {
+            self.idle_scheduled = true;
+        }
+
+        fn schedule_timer(&mut self, after: Instant) {
+            self.timer = Some(after);
+        }
+
+        fn request_is_pending(&self) -> bool {
+            self.request_pending
+        }
+
+        fn add_status_item(&mut self, key: &str, value: &str, _alignment: &str) {
+            self.status_log.push((key.to_owned(), Some(value.to_owned())));
+        }
+
+        fn update_status_item(&mut self, key: &str, value: &str) {
+            self.status_log.push((key.to_owned(), Some(value.to_owned())));
+        }
+
+        fn remove_status_item(&mut self, key: &str) {
+            self.status_log.push((key.to_owned(), None));
+        }
+
+        fn respond_to_command(&mut self, method: &str, result: &Value) {
+            self.responses.push((method.to_owned(), result.clone()));
+        }
+    }
+}
@@ -0,0 +1,11 @@
+{# generated from a template; don't edit by hand #}
+{% for field in fields %}
+pub const {{ field.name | upper }}: u32 = {{ loop.index }};
+{% endfor %}
+fn describe() -> &'static str {
+    "{{ description }}" // a tag inside a string
+}
+{% if debug %}
+/* a comment {{ not_closed_here
+   still in the comment }} */
+{% endif %}
@@ -0,0 +1,32 @@
+//! Crate docs.
+
+use std::collections::HashMap;
+
+/// A point, with a doc comment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point<'a, T: Copy> {
+    pub x: T,
+    name: &'a str,
+}
+
+impl<'a, T: Copy> Point<'a, T> {
+    pub const ORIGIN: u32 = 0x1f;
+
+    pub fn new(x: T, name: &'a str) -> Self {
+        let escaped = "tab\there \"quoted\" \u{1F600}";
+        let c = '\'';
+        let b = b'x';
+        Point { x, name }
+    }
+}
+
+fn main() {
+    let mut map: HashMap<&str, f64> = HashMap::new();
+    map.insert("pi", 3.14_f64);
+    if map.len() > 1 && !map.is_empty() {
+        println!("{} entries", map.len());
+    } else {
+        return;
+    }
+    let n = 1_000i64 as u8 + 0b1010 - 0o17;
+}
@@ -0,0 +1,16 @@
+/* A block comment
+   over several lines, /* with one nested */
+   and still going */
+fn strings() -> &'static str {
+    let raw = r#"a raw string
+with "quotes" in it
+"#;
+    let long = "a string that
+        goes on past the end of the line \
+        and ends here";
+    raw
+}
+
+// an unterminated comment runs to the end of the file
+/* never closed
+fn hidden() {}
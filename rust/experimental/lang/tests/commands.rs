@@ -0,0 +1,547 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Drives the plugin binary over its JSON-RPC connection, playing the
+//! part of core.
+
+#[macro_use]
+extern crate serde_json;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+const VIEW_ID: &str = "view-id-1";
+const TIMEOUT_MS: u64 = 10_000;
+/// How long the plugin must be quiet for to be done with its work.
+const QUIET_MS: u64 = 300;
+
+struct PluginProcess {
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<Value>,
+    /// Notifications that arrived while waiting for a response.
+    pending: VecDeque<Value>,
+    text: String,
+    /// The revision of `text`, which each edit moves on by one.
+    rev: u64,
+    next_id: u64,
+}
+
+impl PluginProcess {
+    fn spawn(language: &str, text: &str) -> PluginProcess {
+        let initialize = json!({
+            "plugin_id": 1,
+            "buffer_info": [{
+                "buffer_id": 1,
+                "views": [VIEW_ID],
+                "rev": 1,
+                "buf_size": text.len(),
+                "nb_lines": text.matches('\n').count() + 1,
+                "syntax": language,
+                "config": {
+                    "line_ending": "\n",
+                    "tab_size": 4,
+                    "translate_tabs_to_spaces": true,
+                    "use_tab_stops": true,
+                    "font_face": "InconsolataGo",
+                    "font_size": 14.0,
+                    "auto_indent": true,
+                    "scroll_past_end": false,
+                    "wrap_width": 0,
+                    "word_wrap": false,
+                    "autodetect_whitespace": true,
+                    "surrounding_pairs": [],
+                },
+            }],
+        });
+        PluginProcess::start(text, initialize)
+    }
+
+    /// Starts the plugin on a buffer holding `text`, described to it by
+    /// the params of `initialize`.
+    fn start(text: &str, initialize: Value) -> PluginProcess {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_xi-lang"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start plugin");
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let (tx, messages) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stdout.lines() {
+                let message = serde_json::from_str(&line.unwrap()).unwrap();
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut plugin = PluginProcess {
+            child,
+            stdin,
+            messages,
+            pending: VecDeque::new(),
+            text: text.to_owned(),
+            rev: initialize["buffer_info"][0]["rev"].as_u64().unwrap(),
+            next_id: 0,
+        };
+        plugin.notify("initialize", initialize);
+        plugin
+    }
+
+    fn send(&mut self, message: Value) {
+        writeln!(self.stdin, "{}", message).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "method": method, "params": params }));
+    }
+
+    fn custom_command(&mut self, view_id: &str, method: &str, params: Value) {
+        self.notify(
+            "custom_command",
+            json!({ "view_id": view_id, "method": method, "params": params }),
+        );
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(json!({ "id": id, "method": method, "params": params }));
+        loop {
+            let message = self.receive(Duration::from_millis(TIMEOUT_MS)).expect("no response");
+            if message["id"] == id {
+                return message["result"].clone();
+            }
+            self.pending.push_back(message);
+        }
+    }
+
+    /// Inserts `text` at `offset`, as core does when the user types it.
+    fn insert(&mut self, offset: usize, text: &str, edit_type: &str) {
+        let base_len = self.text.len();
+        let mut new_text = self.text.clone();
+        new_text.insert_str(offset, text);
+        let params = json!({
+            "view_id": VIEW_ID,
+            "delta": {
+                "base_len": base_len,
+                "els": [{ "copy": [0, offset] }, { "insert": text }, { "copy": [offset, base_len] }],
+            },
+            "new_len": new_text.len(),
+            "new_line_count": new_text.matches('\n').count() + 1,
+            "rev": self.rev + 1,
+            "undo_group": 1,
+            "edit_type": edit_type,
+            "author": "core",
+        });
+        self.update(&new_text, params);
+    }
+
+    /// Sends the `update` with `params`, which leaves the buffer holding
+    /// `new_text`.
+    fn update(&mut self, new_text: &str, params: Value) {
+        self.text = new_text.to_owned();
+        self.rev = params["rev"].as_u64().unwrap();
+        self.request("update", params);
+    }
+
+    /// The next notification from the plugin, those that arrived while
+    /// waiting for a response first.
+    fn next_notification(&mut self, timeout: Duration) -> Option<Value> {
+        match self.pending.pop_front() {
+            Some(message) => Some(message),
+            None => self.receive(timeout),
+        }
+    }
+
+    /// The next message from the plugin, answering any requests for the
+    /// document's text on the way.
+    fn receive(&mut self, timeout: Duration) -> Option<Value> {
+        loop {
+            let message = self.messages.recv_timeout(timeout).ok()?;
+            if message["method"] != "get_data" {
+                return Some(message);
+            }
+            let id = message["id"].clone();
+            let result = self.get_data(&message["params"]);
+            self.send(json!({ "id": id, "result": result }));
+        }
+    }
+
+    fn get_data(&self, params: &Value) -> Value {
+        let start = params["start"].as_u64().unwrap() as usize;
+        let line_start = |line: usize| -> usize {
+            let lines = self.text.split('\n').take(line);
+            lines.map(|l| l.len() + 1).sum::<usize>().min(self.text.len())
+        };
+        let offset = match params["unit"].as_str() {
+            Some("line") => line_start(start),
+            _ => start,
+        };
+        let first_line = self.text[..offset].matches('\n').count();
+        json!({
+            "chunk": &self.text[offset..],
+            "offset": offset,
+            "first_line": first_line,
+            "first_line_offset": offset - line_start(first_line),
+        })
+    }
+
+    /// The spans of the next run of `update_spans` covering the whole
+    /// document from the start, relative to the start. A busy machine can
+    /// make the plugin run out of idle time and send it in pieces.
+    fn next_full_update(&mut self) -> Value {
+        let mut spans = Vec::new();
+        let mut covered = None;
+        loop {
+            let message = self
+                .next_notification(Duration::from_millis(TIMEOUT_MS))
+                .expect("no full update_spans");
+            let params = &message["params"];
+            if message["method"] != "update_spans" {
+                continue;
+            }
+            let start = params["start"].as_u64().unwrap();
+            if start == 0 {
+                spans.clear();
+            } else if covered != Some(start) {
+                covered = None;
+                continue;
+            }
+            for span in params["spans"].as_array().unwrap() {
+                let mut span = span.clone();
+                for key in &["start", "end"] {
+                    span[key] = json!(span[key].as_u64().unwrap() + start);
+                }
+                spans.push(span);
+            }
+            let end = start + params["len"].as_u64().unwrap();
+            if end == self.text.len() as u64 {
+                return Value::Array(spans);
+            }
+            covered = Some(end);
+        }
+    }
+
+    /// The plugin's answer to the next custom command `method`.
+    fn command_response(&mut self, method: &str) -> Value {
+        loop {
+            let message = self
+                .next_notification(Duration::from_millis(TIMEOUT_MS))
+                .expect("no command_response");
+            let params = &message["params"];
+            if message["method"] == "command_response" && params["method"] == method {
+                assert_eq!(params["view_id"], VIEW_ID);
+                return params["result"].clone();
+            }
+        }
+    }
+
+    /// Hands notifications to `view` until the whole document has spans
+    /// and the plugin has gone quiet.
+    fn settle(&mut self, view: &mut CoreView) {
+        loop {
+            let covered = view.styles.iter().all(Option::is_some);
+            match self.next_notification(Duration::from_millis(if covered {
+                QUIET_MS
+            } else {
+                TIMEOUT_MS
+            })) {
+                Some(message) => view.receive(&message),
+                None if covered => return,
+                None => panic!("spans never covered the document: {:?}", view.styles),
+            }
+        }
+    }
+}
+
+/// What core keeps of a view's highlighting: the scopes the plugin has
+/// announced, and the scope id of each byte.
+struct CoreView {
+    scopes: Vec<Value>,
+    styles: Vec<Option<usize>>,
+}
+
+impl CoreView {
+    fn new(text: &str) -> CoreView {
+        CoreView { scopes: Vec::new(), styles: vec![None; text.len()] }
+    }
+
+    /// Applies a notification from the plugin, checking that it is well
+    /// formed as it goes.
+    fn receive(&mut self, message: &Value) {
+        let params = &message["params"];
+        match message["method"].as_str() {
+            Some("add_scopes") => {
+                assert_eq!(params["view_id"], VIEW_ID);
+                for scope in params["scopes"].as_array().unwrap() {
+                    let names = scope.as_array().unwrap();
+                    assert!(names.iter().all(Value::is_string), "{}", message);
+                    self.scopes.push(scope.clone());
+                }
+            }
+            Some("update_spans") => {
+                assert_eq!(params["view_id"], VIEW_ID);
+                assert!(params["rev"].is_u64(), "{}", message);
+                let start = params["start"].as_u64().unwrap() as usize;
+                let len = params["len"].as_u64().unwrap() as usize;
+                assert!(start + len <= self.styles.len(), "{}", message);
+                for style in &mut self.styles[start..start + len] {
+                    *style = None;
+                }
+                let mut end_of_last = 0;
+                for span in params["spans"].as_array().unwrap() {
+                    let span_start = span["start"].as_u64().unwrap() as usize;
+                    let span_end = span["end"].as_u64().unwrap() as usize;
+                    let scope_id = span["scope_id"].as_u64().unwrap() as usize;
+                    assert!(end_of_last <= span_start && span_start <= span_end, "{}", message);
+                    assert!(span_end <= len, "{}", message);
+                    // core drops spans whose scope it hasn't been told of
+                    assert!(scope_id < self.scopes.len(), "scope not yet announced: {}", message);
+                    for style in &mut self.styles[start + span_start..start + span_end] {
+                        *style = Some(scope_id);
+                    }
+                    end_of_last = span_end;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Makes room for `len` bytes inserted at `offset`, as core does when
+    /// the buffer is edited; they have no spans until the plugin sends
+    /// them.
+    fn insert(&mut self, offset: usize, len: usize) {
+        self.styles.splice(offset..offset, vec![None; len]);
+    }
+
+    /// The scope of each byte.
+    fn resolved(&self) -> Vec<Option<&Value>> {
+        self.styles.iter().map(|style| style.map(|id| &self.scopes[id])).collect()
+    }
+}
+
+impl Drop for PluginProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn rehighlight_command_starts_over() {
+    let text = "fn main() {\n    // comment\n    \"string\"\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    let spans = plugin.next_full_update();
+
+    plugin.custom_command(VIEW_ID, "experimental_lang.rehighlight", json!({}));
+    assert_eq!(plugin.next_full_update(), spans);
+
+    // a view the plugin doesn't know is ignored
+    plugin.custom_command("view-id-9", "experimental_lang.rehighlight", json!({}));
+    assert_eq!(plugin.next_notification(Duration::from_millis(300)), None);
+
+    plugin.custom_command(VIEW_ID, "experimental_lang.rehighlight", json!({}));
+    assert_eq!(plugin.next_full_update(), spans);
+}
+
+#[test]
+fn debug_dump_round_trips_through_the_trace() {
+    let text = "fn main() {\n    // comment\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.notify("tracing_config", json!({ "enabled": true }));
+    plugin.next_full_update();
+
+    plugin.custom_command(VIEW_ID, "experimental_lang.debug_dump", json!({ "first_line": 1 }));
+    let samples = plugin.request("collect_trace", json!({}));
+    let dump = samples
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|sample| sample["name"] == "debug dump")
+        .expect("no dump in the trace");
+    let dump: Value = serde_json::from_str(dump["args"]["xi_payload"].as_str().unwrap()).unwrap();
+    // and the client gets the same
+    assert_eq!(plugin.command_response("experimental_lang.debug_dump"), dump);
+
+    assert_eq!(dump["language"], "Rust");
+    let lines = dump["lines"].as_array().unwrap();
+    assert_eq!(lines.iter().map(|line| line["line"].clone()).collect::<Vec<_>>(), [1, 2]);
+    // the indent, then the comment, each a run of one span
+    let runs = &lines[0]["spans"]["scopes"];
+    assert_eq!(lines[0]["spans"]["lengths"], json!([4, 11]));
+    let scope = &dump["scopes"][runs[1][0].as_u64().unwrap() as usize];
+    assert_eq!(scope, &json!(["source.rust", "comment.line.double-slash.rust"]));
+}
+
+#[test]
+fn autoclose_is_answered_through_core() {
+    let text = "fn main() {\n    // comment\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.next_full_update();
+
+    let method = "experimental_lang.should_autoclose";
+    plugin.custom_command(VIEW_ID, method, json!({ "offset": 10, "character": "(" }));
+    let answer = plugin.command_response(method);
+    assert_eq!(answer["autoclose"], true);
+    assert_eq!(answer["closer"], ")");
+    // not inside a comment
+    plugin.custom_command(VIEW_ID, method, json!({ "offset": 26, "character": "(" }));
+    assert_eq!(plugin.command_response(method)["autoclose"], false);
+}
+
+#[test]
+fn expanded_selections_are_answered_through_core() {
+    let text = "fn main() {\n    // comment\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.next_full_update();
+
+    let method = "experimental_lang.expand_selection";
+    plugin.custom_command(VIEW_ID, method, json!({ "start": 20, "end": 20 }));
+    // out to the comment
+    let answer = plugin.command_response(method);
+    assert_eq!(answer["start"], 20);
+    assert_eq!(answer["expanded"], json!({ "start": 16, "end": 26 }));
+}
+
+#[test]
+fn matching_brackets_are_answered_through_core() {
+    let text = "fn main() {\n    // comment\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.next_full_update();
+
+    let method = "experimental_lang.matching_bracket";
+    plugin.custom_command(VIEW_ID, method, json!({ "offset": 10 }));
+    assert_eq!(plugin.command_response(method), json!({ "offset": 10, "match": 27 }));
+}
+
+#[test]
+fn folds_are_answered_through_core() {
+    let text = "use a;\nuse b;\n/* one\n   two\n*/\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.next_full_update();
+
+    let method = "experimental_lang.folds";
+    plugin.custom_command(VIEW_ID, method, json!({}));
+    let folds = plugin.command_response(method);
+    let expected = json!([
+        { "start_line": 0, "end_line": 1, "kind": "imports" },
+        { "start_line": 2, "end_line": 4, "kind": "comment" },
+    ]);
+    assert_eq!(folds, expected);
+}
+
+#[test]
+fn symbols_are_answered_through_core() {
+    let text = "struct Point;\n\nfn main() {}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.next_full_update();
+
+    let method = "experimental_lang.symbols";
+    plugin.custom_command(VIEW_ID, method, json!({}));
+    let expected = json!([
+        { "name": "Point", "kind": "struct", "line": 0, "children": [] },
+        { "name": "main", "kind": "function", "line": 2, "children": [] },
+    ]);
+    assert_eq!(plugin.command_response(method), expected);
+}
+
+#[test]
+fn stats_are_answered_through_core() {
+    let text = "fn main() {\n    // comment\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.next_full_update();
+
+    let method = "experimental_lang.stats";
+    plugin.custom_command(VIEW_ID, method, json!({}));
+    let stats = plugin.command_response(method);
+    assert_eq!(stats["language"], "Rust");
+    assert_eq!(stats["parser"], "Rust");
+}
+
+#[test]
+fn highlighting_follows_the_view_config() {
+    let text = "fn main() {\n    // comment\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    let spans = plugin.next_full_update();
+
+    let set_enabled = |plugin: &mut PluginProcess, enabled: bool| {
+        plugin.notify(
+            "config_changed",
+            json!({ "view_id": VIEW_ID, "changes": { "syntax_highlighting": enabled } }),
+        )
+    };
+    set_enabled(&mut plugin, false);
+    assert_eq!(plugin.next_full_update(), json!([]));
+    // turning it off again changes nothing
+    set_enabled(&mut plugin, false);
+    assert_eq!(plugin.next_notification(Duration::from_millis(300)), None);
+
+    set_enabled(&mut plugin, true);
+    assert_eq!(plugin.next_full_update(), spans);
+}
+
+#[test]
+fn new_lines_are_indented() {
+    let text = "fn main() {\n}\n";
+    let mut plugin = PluginProcess::spawn("Rust", text);
+    plugin.next_full_update();
+
+    plugin.insert(11, "\n", "newline");
+    let edit = loop {
+        let message = plugin.next_notification(Duration::from_millis(TIMEOUT_MS)).expect("no edit");
+        if message["method"] == "edit" {
+            break message["params"]["edit"].clone();
+        }
+    };
+    assert_eq!(edit["author"], "experimental-lang");
+    assert_eq!(
+        edit["delta"]["els"],
+        json!([{ "copy": [0, 12] }, { "insert": "    " }, { "copy": [12, 15] }])
+    );
+}
+
+#[test]
+fn typing_over_the_protocol_matches_a_fresh_highlight() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/protocol/typing.json");
+    let session: Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    let text = session["text"].as_str().unwrap();
+    let mut plugin = PluginProcess::start(text, session["initialize"].clone());
+    let mut view = CoreView::new(text);
+    plugin.settle(&mut view);
+
+    let insert = &session["update"]["delta"]["els"][1]["insert"];
+    let offset = session["update"]["delta"]["els"][0]["copy"][1].as_u64().unwrap() as usize;
+    view.insert(offset, insert.as_str().unwrap().len());
+    let text_after = session["text_after"].as_str().unwrap();
+    plugin.update(text_after, session["update"].clone());
+    plugin.settle(&mut view);
+
+    let mut fresh_plugin = PluginProcess::spawn("Rust", text_after);
+    let mut fresh = CoreView::new(text_after);
+    fresh_plugin.settle(&mut fresh);
+    assert_eq!(view.resolved(), fresh.resolved());
+}
@@ -0,0 +1,373 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A checklist of what a parser can get right, checked for each language
+//! against what it claims. The checks are small snippets, filled in with
+//! each language's own comment markers, quotes and keywords from the
+//! table below, highlighted with `xi-lang-tools highlight`; convergence after
+//! an edit is checked with `xi-lang-tools replay`. A new parser gets these
+//! checks by adding a row, and the row says what it claims to get right.
+
+#[macro_use]
+extern crate serde_json;
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use serde_json::Value;
+
+/// Something a parser can claim to get right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Capability {
+    /// A line comment runs to the end of its line, and no further.
+    LineComments,
+    /// A block comment carries on over lines until it is closed.
+    BlockComments,
+    /// An escaped quote doesn't end a string.
+    EscapedStrings,
+    /// A keyword inside an identifier isn't a keyword.
+    WholeWordKeywords,
+    /// A number with separators between its digits is one token.
+    NumberSeparators,
+    /// After an edit in the middle of a line, and after it is undone, the
+    /// spans are those of a highlight from scratch.
+    Convergence,
+}
+
+/// The text of a language the checks are made of. Tokens a language has
+/// no use for are left empty.
+#[derive(Debug, Clone, Copy)]
+struct Tokens {
+    line_comment: &'static str,
+    block_comment: (&'static str, &'static str),
+    quote: &'static str,
+    /// A quote, escaped so that it doesn't end a string.
+    escaped_quote: &'static str,
+    keyword: &'static str,
+    /// An identifier, which is in no scope but the document's.
+    identifier: &'static str,
+    /// A number with separators.
+    number: &'static str,
+    /// Text typed in the middle of a line for the convergence check;
+    /// best the start of something that runs on over lines, unclosed.
+    edit: &'static str,
+}
+
+const NO_TOKENS: Tokens = Tokens {
+    line_comment: "",
+    block_comment: ("", ""),
+    quote: "",
+    escaped_quote: "",
+    keyword: "",
+    identifier: "",
+    number: "",
+    edit: "",
+};
+
+struct Language {
+    name: &'static str,
+    claims: &'static [Capability],
+    tokens: Tokens,
+}
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        name: "Rust",
+        claims: &[
+            Capability::LineComments,
+            Capability::BlockComments,
+            Capability::EscapedStrings,
+            Capability::WholeWordKeywords,
+            Capability::NumberSeparators,
+            Capability::Convergence,
+        ],
+        tokens: Tokens {
+            line_comment: "//",
+            block_comment: ("/*", "*/"),
+            quote: "\"",
+            escaped_quote: "\\\"",
+            keyword: "let",
+            identifier: "value",
+            number: "1_000_000",
+            edit: "/*",
+        },
+    },
+    Language {
+        name: "Plain Text",
+        claims: &[Capability::Convergence],
+        tokens: Tokens { identifier: "value", edit: "some words", ..NO_TOKENS },
+    },
+];
+
+/// How many lines the convergence check's document has.
+const CONVERGENCE_LINES: usize = 40;
+
+/// A snippet, and the scopes expected of the text marked in it.
+#[derive(Debug, Default)]
+struct Snippet {
+    text: String,
+    checks: Vec<Check>,
+}
+
+#[derive(Debug)]
+struct Check {
+    start: usize,
+    end: usize,
+    /// A scope name, which matches it and the names it is a prefix of.
+    scope: &'static str,
+    present: bool,
+    /// Whether the text must be a single span.
+    one_span: bool,
+}
+
+impl Snippet {
+    fn text(mut self, text: &str) -> Snippet {
+        self.text.push_str(text);
+        self
+    }
+
+    fn mark(mut self, text: &str, scope: &'static str, present: bool, one_span: bool) -> Snippet {
+        let start = self.text.len();
+        self.text.push_str(text);
+        self.checks.push(Check { start, end: self.text.len(), scope, present, one_span });
+        self
+    }
+
+    /// Adds `text`, which must be in `scope` throughout.
+    fn within(self, text: &str, scope: &'static str) -> Snippet {
+        self.mark(text, scope, true, false)
+    }
+
+    /// Adds `text`, none of which may be in `scope`.
+    fn outside(self, text: &str, scope: &'static str) -> Snippet {
+        self.mark(text, scope, false, false)
+    }
+
+    /// Adds `text`, which must be one span in `scope`.
+    fn token(self, text: &str, scope: &'static str) -> Snippet {
+        self.mark(text, scope, true, true)
+    }
+}
+
+/// `token`, or why a check that needs it can't be made.
+fn needs(name: &str, token: &'static str) -> Result<&'static str, String> {
+    if token.is_empty() {
+        Err(format!("no {} token", name))
+    } else {
+        Ok(token)
+    }
+}
+
+/// The snippet that checks `capability`, made from `tokens`.
+fn snippet(capability: Capability, tokens: &Tokens) -> Result<Snippet, String> {
+    let keyword = needs("keyword", tokens.keyword)?;
+    let identifier = needs("identifier", tokens.identifier)?;
+    let snippet = Snippet::default();
+    Ok(match capability {
+        Capability::LineComments => {
+            let line_comment = needs("line comment", tokens.line_comment)?;
+            snippet
+                .text(identifier)
+                .text(" ")
+                .within(&format!("{} {} in a comment", line_comment, keyword), "comment")
+                .text("\n")
+                .outside(keyword, "comment")
+                .text(&format!(" {}\n", identifier))
+        }
+        Capability::BlockComments => {
+            let open = needs("block comment", tokens.block_comment.0)?;
+            let close = needs("block comment", tokens.block_comment.1)?;
+            snippet
+                .within(&format!("{} first", open), "comment")
+                .text("\n")
+                .within(&format!("{} second", keyword), "comment")
+                .text("\n")
+                .within(close, "comment")
+                .text(" ")
+                .outside(keyword, "comment")
+                .text("\n")
+        }
+        Capability::EscapedStrings => {
+            let quote = needs("quote", tokens.quote)?;
+            let escaped_quote = needs("escaped quote", tokens.escaped_quote)?;
+            snippet
+                .text(&format!("{} ", keyword))
+                .within(&format!("{}a", quote), "string")
+                .text(escaped_quote)
+                .within(&format!("b{}", quote), "string")
+                .text(" ")
+                .outside(keyword, "string")
+                .text("\n")
+        }
+        Capability::WholeWordKeywords => snippet
+            .within(keyword, "keyword")
+            .text(&format!(" {}\n", identifier))
+            .outside(&format!("{}{}", identifier, keyword), "keyword")
+            .text(" ")
+            .outside(&format!("{}{}", keyword, identifier), "keyword")
+            .text("\n"),
+        Capability::NumberSeparators => {
+            let number = needs("number", tokens.number)?;
+            snippet.text(&format!("{} ", keyword)).token(number, "constant.numeric").text("\n")
+        }
+        Capability::Convergence => unreachable!("convergence is checked by replaying a session"),
+    })
+}
+
+/// A file in the temporary directory, removed when dropped.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str, contents: &str) -> TempFile {
+        let path = env::temp_dir().join(format!("xi-lang-conformance-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        TempFile(path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Runs `xi-lang` with `args` and then `path`, returning what it printed,
+/// or what it printed to stderr if it failed.
+fn xi_lang(args: &[&str], path: &Path) -> Result<String, String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_xi-lang-tools"))
+        .args(args)
+        .arg(path)
+        .output()
+        .expect("failed to start xi-lang");
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        Err(format!("{}{}", stdout, String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+/// The scope of each byte of a text, and the spans, as offsets in it.
+type Highlighted = (Vec<String>, Vec<(usize, usize)>);
+
+/// `text` highlighted as `language`.
+fn highlight(language: &str, text: &str) -> Result<Highlighted, String> {
+    let file = TempFile::new("snippet", text);
+    let json: Value =
+        serde_json::from_str(&xi_lang(&["highlight", "--language", language], &file.0)?)
+            .map_err(|err| err.to_string())?;
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .filter(|&start| start < text.len())
+        .collect();
+    let mut scopes = vec![String::new(); text.len()];
+    let mut spans = Vec::new();
+    for span in json["spans"].as_array().unwrap() {
+        let line_start = line_starts[span["line"].as_u64().unwrap() as usize];
+        let start = line_start + span["start"].as_u64().unwrap() as usize;
+        let end = line_start + span["end"].as_u64().unwrap() as usize;
+        let names: Vec<&str> =
+            span["scope"].as_array().unwrap().iter().map(|name| name.as_str().unwrap()).collect();
+        for scope in &mut scopes[start..end] {
+            *scope = names.join(" ");
+        }
+        spans.push((start, end));
+    }
+    Ok((scopes, spans))
+}
+
+/// Whether the scope name `pattern` matches one of the names of `scope`,
+/// as a whole or as the first of its dotted parts.
+fn matches(pattern: &str, scope: &str) -> bool {
+    scope.split(' ').any(|name| {
+        name.starts_with(pattern)
+            && (name.len() == pattern.len() || name[pattern.len()..].starts_with('.'))
+    })
+}
+
+/// Where the checks of `snippet` fail.
+fn check_snippet(language: &str, snippet: &Snippet) -> Result<Vec<String>, String> {
+    let (scopes, spans) = highlight(language, &snippet.text)?;
+    let mut failures = Vec::new();
+    for check in &snippet.checks {
+        let text = &snippet.text[check.start..check.end];
+        let line = snippet.text[..check.start].matches('\n').count() + 1;
+        let wrong = (check.start..check.end)
+            .filter(|&i| !snippet.text.as_bytes()[i].is_ascii_whitespace())
+            .find(|&i| matches(check.scope, &scopes[i]) != check.present);
+        if let Some(i) = wrong {
+            failures.push(format!(
+                "{:?} on line {}: expected {}{}, but `{}` is in `{}`",
+                text,
+                line,
+                if check.present { "" } else { "no " },
+                check.scope,
+                &snippet.text[i..i + 1],
+                scopes[i]
+            ));
+        } else if check.one_span
+            && !spans.iter().any(|&(start, end)| start <= check.start && check.end <= end)
+        {
+            failures.push(format!("{:?} on line {}: expected one span", text, line));
+        }
+    }
+    Ok(failures)
+}
+
+/// Types `tokens.edit` in the middle of a line of a document, then undoes
+/// it, and replays that with `xi-lang-tools replay`, which compares the spans
+/// with a highlight from scratch.
+fn check_convergence(language: &str, tokens: &Tokens) -> Result<Vec<String>, String> {
+    let identifier = needs("identifier", tokens.identifier)?;
+    let edit = needs("edit", tokens.edit)?;
+    let lines: Vec<String> =
+        (0..CONVERGENCE_LINES).map(|i| format!("{} {} {}", identifier, i, identifier)).collect();
+    let offset = lines[0].len() + 1 + identifier.len() + 1;
+    let session = json!({
+        "language": language,
+        "text": lines,
+        "steps": [
+            [offset, 0, edit],
+            {"check": []},
+            [offset, edit.len(), "", "undo"],
+        ],
+    });
+    let file = TempFile::new("session.json", &session.to_string());
+    match xi_lang(&["replay"], &file.0) {
+        Ok(_) => Ok(Vec::new()),
+        Err(output) => Ok(vec![output.trim_end().to_owned()]),
+    }
+}
+
+#[test]
+fn languages_do_what_they_claim() {
+    let mut report = String::new();
+    for language in LANGUAGES {
+        for &capability in language.claims {
+            let failures = match capability {
+                Capability::Convergence => check_convergence(language.name, &language.tokens),
+                _ => snippet(capability, &language.tokens)
+                    .and_then(|snippet| check_snippet(language.name, &snippet)),
+            };
+            let failures = failures.unwrap_or_else(|err| vec![err]);
+            for failure in failures {
+                writeln!(report, "{}, {:?}: {}", language.name, capability, failure).unwrap();
+            }
+        }
+    }
+    assert!(report.is_empty(), "claims not met:\n{}", report);
+}
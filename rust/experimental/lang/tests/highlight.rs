@@ -0,0 +1,87 @@
+// Copyright 2018 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runs `xi-lang-tools highlight` over the fixtures, each time in a new process,
+//! with its own hash seeds. Scope ids are handed out in the order scopes
+//! are met, so anything that makes that order depend on a hash map's
+//! iteration shows up here as output that differs between runs, rather
+//! than as snapshot tests that fail now and then.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Each directory of fixtures under `testdata/`, and the language they
+/// are highlighted as.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("rust", "Rust"),
+    ("plaintext", "Plain Text"),
+    ("jinja", "Rust (Jinja)"),
+    ("literate", "Rust (Literate)"),
+    ("indented", "Rust (Indented)"),
+];
+
+/// How many times each fixture is highlighted.
+const RUNS: usize = 3;
+
+fn fixtures(dir: &str) -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(dir);
+    let mut fixtures: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(true, |ext| ext != "expected"))
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+fn highlight(path: &Path, language: &str) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_xi-lang-tools"))
+        .args(["highlight", "--language", language])
+        .arg(path)
+        .output()
+        .expect("failed to start xi-lang");
+    assert!(
+        output.status.success(),
+        "{}: {}",
+        path.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+#[test]
+fn output_is_the_same_every_run() {
+    for &(dir, language) in LANGUAGES {
+        let fixtures = fixtures(dir);
+        assert!(!fixtures.is_empty(), "no fixtures in testdata/{}", dir);
+        for path in fixtures {
+            let first = highlight(&path, language);
+            for run in 1..RUNS {
+                let output = highlight(&path, language);
+                if output != first {
+                    let first = String::from_utf8_lossy(&first);
+                    let output = String::from_utf8_lossy(&output);
+                    let line = first.lines().zip(output.lines()).position(|(a, b)| a != b);
+                    panic!(
+                        "{}: run {} differs from the first, from line {} of the output",
+                        path.display(),
+                        run + 1,
+                        line.map_or(first.lines().count().min(output.lines().count()), |i| i) + 1
+                    );
+                }
+            }
+        }
+    }
+}
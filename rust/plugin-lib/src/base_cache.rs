@@ -332,7 +332,7 @@ impl ChunkCache {
         // convenience method on `Delta`?
         if start < self.offset || start > self.offset + self.contents.len() {
             true
-        } else if delta.is_simple_delete() {
+        } else if delta.is_simple_delete() && end <= self.offset + self.contents.len() {
             self.simple_delete(start, end);
             false
         } else if let Some(text) = delta.as_simple_insert() {
@@ -522,6 +522,21 @@ mod tests {
         assert_eq!(c.offset, 0);
     }
 
+    #[test]
+    fn delete_past_chunk() {
+        let mut c = ChunkCache::default();
+        c.buf_size = 12;
+        c.contents = "one\ntwo\n".into();
+        c.recalculate_line_offsets();
+
+        // from inside the chunk to past its end, as when deleting to the
+        // end of a document not all cached
+        let d = Delta::simple_edit(Interval::new(5, 12), "".into(), 12);
+        c.update(Some(&d), d.new_document_len(), 2, 1);
+        assert_eq!(&c.contents, "");
+        assert_eq!(c.offset, 0);
+    }
+
     #[test]
     fn get_lines() {
         let remote_document = MockDataSource("this\nhas\nfour\nlines!".into());
@@ -336,6 +336,18 @@ impl<S: Clone + Default> StateCache<S> {
         self.truncate_cache(0);
     }
 
+    /// Clear the state of every line after `line_num`, and move the frontier
+    /// back to it. The state `line_num` starts in, and those of the lines
+    /// before it, are kept.
+    pub fn invalidate_from(&mut self, line_num: usize) {
+        let ix = match self.find_line(line_num) {
+            Ok(ix) => ix + 1,
+            Err(ix) => ix,
+        };
+        self.state_cache.truncate(ix);
+        self.truncate_frontier(line_num);
+    }
+
     /// The frontier keeps track of work needing to be done. A typical
     /// user will call `get_frontier` to get a line number, do the work
     /// on that line, insert state for the next line, and then call either
@@ -360,6 +372,11 @@ impl<S: Clone + Default> StateCache<S> {
     pub fn close_frontier(&mut self) {
         self.frontier.remove(0);
     }
+
+    /// Returns the number of lines in the document, as last reported by core.
+    pub fn get_num_lines(&self) -> usize {
+        self.buf_cache.num_lines
+    }
 }
 
 /// StateCache specific extensions on `View`
@@ -393,9 +410,17 @@ impl<S: Default + Clone> View<StateCache<S>> {
         self.cache.reset()
     }
 
+    pub fn invalidate_from(&mut self, line_num: usize) {
+        self.cache.invalidate_from(line_num)
+    }
+
     pub fn find_offset(&self, offset: usize) -> Result<usize, usize> {
         self.cache.find_offset(offset)
     }
+
+    pub fn get_num_lines(&self) -> usize {
+        self.cache.get_num_lines()
+    }
 }
 
 fn count_newlines(s: &str) -> usize {
@@ -15,6 +15,7 @@
 use serde::Deserialize;
 use serde_json::{self, Value};
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use crate::xi_core::plugin_rpc::{
     GetDataResponse, PluginBufferInfo, PluginEdit, ScopeSpan, TextUnit,
@@ -114,6 +115,12 @@ impl<C: Cache> View<C> {
         &self.config
     }
 
+    /// The view's config as core sent it, with any sections for plugins
+    /// that `BufferConfig` leaves out.
+    pub fn get_config_table(&self) -> &ConfigTable {
+        &self.config_table
+    }
+
     pub fn get_cache(&mut self) -> &mut C {
         &mut self.cache
     }
@@ -192,6 +199,13 @@ impl<C: Cache> View<C> {
         self.peer.schedule_idle(token);
     }
 
+    /// Schedules the plugin's `idle` to be called for this view once
+    /// `after` has passed. Like the RPC loop's timers, this is not precise.
+    pub fn schedule_timer(&self, after: Instant) {
+        let token: usize = self.view_id.into();
+        self.peer.schedule_timer(after, token);
+    }
+
     /// Returns `true` if an incoming RPC is pending. This is intended
     /// to reduce latency for bulk operations done in the background.
     pub fn request_is_pending(&self) -> bool {
@@ -227,6 +241,18 @@ impl<C: Cache> View<C> {
         });
         self.peer.send_rpc_notification("remove_status_item", &params);
     }
+
+    /// Answers a custom command: core forwards `result` to the client as
+    /// a `command_response` for `method`.
+    pub fn respond_to_command(&self, method: &str, result: &Value) {
+        let params = json!({
+            "plugin_id": self.plugin_id,
+            "view_id": self.view_id,
+            "method": method,
+            "result": result,
+        });
+        self.peer.send_rpc_notification("command_response", &params);
+    }
 }
 
 /// A simple wrapper type that acts as a `DataSource`.